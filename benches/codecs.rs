@@ -0,0 +1,64 @@
+//! Codec throughput on synthetic inputs: `cargo bench`
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use grleconvert::{decode_gdm_block, decode_grle_rle, encode_gdm_block, encode_grle_rle};
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::*;
+
+fn gdm_chunks() -> Vec<(&'static str, Vec<u16>)> {
+    vec![
+        ("uniform", uniform_chunk(5)),
+        ("four_value", four_value_chunk(1)),
+        ("random", random_chunk(8, 2)),
+    ]
+}
+
+fn grle_images() -> Vec<(&'static str, Vec<u8>)> {
+    let size = 1024;
+    vec![
+        ("uniform", uniform_image(size, size, 0)),
+        ("regions", region_image(size, size, 1)),
+        ("random", random_image(size, size, 2)),
+    ]
+}
+
+fn bench_gdm(c: &mut Criterion) {
+    let mut group = c.benchmark_group("gdm_block");
+    group.throughput(Throughput::Elements(CHUNK_PIXELS as u64));
+
+    for (name, chunk) in gdm_chunks() {
+        let block = encode_gdm_block(&chunk, 32);
+        group.bench_function(format!("encode/{}", name), |b| {
+            b.iter(|| encode_gdm_block(black_box(&chunk), 32))
+        });
+        group.bench_function(format!("decode/{}", name), |b| {
+            b.iter(|| decode_gdm_block(black_box(&block), 0, 32))
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_grle(c: &mut Criterion) {
+    let mut group = c.benchmark_group("grle_rle");
+
+    for (name, pixels) in grle_images() {
+        let stream = encode_grle_rle(&pixels);
+        group.throughput(Throughput::Bytes(pixels.len() as u64));
+        group.bench_function(format!("encode/{}", name), |b| {
+            b.iter(|| encode_grle_rle(black_box(&pixels)))
+        });
+        group.bench_function(format!("decode/{}", name), |b| {
+            b.iter(|| decode_grle_rle(black_box(&stream), pixels.len()))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_gdm, bench_grle);
+criterion_main!(benches);
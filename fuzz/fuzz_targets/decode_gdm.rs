@@ -0,0 +1,9 @@
+//! Arbitrary bytes into the GDM decoder: it must return Ok or Err, never panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = grleconvert::decode_gdm(data);
+});
@@ -2,13 +2,39 @@ use std::fs::File;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() < 3 {
-        eprintln!("Usage: {} <png1> <png2>", args[0]);
+
+    // Parse arguments
+    let mut paths: Vec<&str> = Vec::new();
+    let mut tolerance: u8 = 0;
+
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--tolerance" => {
+                i += 1;
+                tolerance = match args.get(i).and_then(|v| v.parse().ok()) {
+                    Some(t) => t,
+                    None => {
+                        eprintln!("--tolerance requires a value between 0 and 255");
+                        std::process::exit(1);
+                    }
+                };
+            }
+            arg => paths.push(arg),
+        }
+        i += 1;
+    }
+
+    if paths.len() != 2 {
+        eprintln!("Usage: {} <png1> <png2> [--tolerance N]", args[0]);
+        eprintln!();
+        eprintln!("Exits with 0 when every byte differs by at most N (default 0),");
+        eprintln!("and 1 when any byte exceeds the tolerance or the images are incompatible.");
         std::process::exit(1);
     }
 
-    let file1 = File::open(&args[1]).expect("Failed to open first file");
-    let file2 = File::open(&args[2]).expect("Failed to open second file");
+    let file1 = File::open(paths[0]).expect("Failed to open first file");
+    let file2 = File::open(paths[1]).expect("Failed to open second file");
 
     let decoder1 = png::Decoder::new(file1);
     let decoder2 = png::Decoder::new(file2);
@@ -16,18 +42,33 @@ fn main() {
     let mut reader1 = decoder1.read_info().expect("Failed to read first PNG");
     let mut reader2 = decoder2.read_info().expect("Failed to read second PNG");
 
-    let (width1, height1, ct1) = {
+    let (width1, height1, ct1, bpp, stride) = {
         let info = reader1.info();
-        (info.width as usize, info.height as usize, format!("{:?}", info.color_type))
+        (
+            info.width as usize,
+            info.height as usize,
+            format!("{:?} {:?}", info.color_type, info.bit_depth),
+            info.bytes_per_pixel(),
+            info.raw_row_length() - 1, // Row length without the filter byte
+        )
     };
     let (width2, height2, ct2) = {
         let info = reader2.info();
-        (info.width as usize, info.height as usize, format!("{:?}", info.color_type))
+        (info.width as usize, info.height as usize, format!("{:?} {:?}", info.color_type, info.bit_depth))
     };
 
     println!("PNG 1: {}x{}, {}", width1, height1, ct1);
     println!("PNG 2: {}x{}, {}", width2, height2, ct2);
 
+    if (width1, height1) != (width2, height2) {
+        eprintln!("Dimension mismatch: {}x{} vs {}x{}", width1, height1, width2, height2);
+        std::process::exit(1);
+    }
+    if ct1 != ct2 {
+        eprintln!("Color type mismatch: {} vs {}", ct1, ct2);
+        std::process::exit(1);
+    }
+
     let mut buf1 = vec![0; reader1.output_buffer_size()];
     let mut buf2 = vec![0; reader2.output_buffer_size()];
 
@@ -40,33 +81,47 @@ fn main() {
     let mut diff_count = 0;
     let mut first_diffs = Vec::new();
 
-    let width = width1;
-
-    for i in 0..buf1.len().min(buf2.len()) {
-        if buf1[i] != buf2[i] {
-            if first_diffs.len() < 20 {
-                let x = i % width;
-                let y = i / width;
-                first_diffs.push((x, y, buf1[i], buf2[i]));
+    // Both images share dimensions and color type, so byte offsets map to
+    // the same (x, y, channel) in each buffer. A pixel counts once however
+    // many of its channels differ.
+    for (y, (row1, row2)) in buf1.chunks(stride).zip(buf2.chunks(stride)).take(height1).enumerate() {
+        for (x, (px1, px2)) in row1.chunks(bpp).zip(row2.chunks(bpp)).enumerate() {
+            let mut differs = false;
+            for (channel, (&v1, &v2)) in px1.iter().zip(px2).enumerate() {
+                if v1.abs_diff(v2) > tolerance {
+                    differs = true;
+                    if first_diffs.len() < 20 {
+                        first_diffs.push((x, y, channel, v1, v2));
+                    }
+                }
+            }
+            if differs {
+                diff_count += 1;
             }
-            diff_count += 1;
         }
     }
 
-    println!("\nTotal different pixels: {}", diff_count);
+    println!("\nTotal different pixels: {} (tolerance {})", diff_count, tolerance);
     println!("First {} differences:", first_diffs.len());
-    for (x, y, v1, v2) in &first_diffs {
-        println!("  ({}, {}): {} vs {}", x, y, v1, v2);
+    for (x, y, channel, v1, v2) in &first_diffs {
+        if bpp > 1 {
+            println!("  ({}, {}) byte {}: {} vs {}", x, y, channel, v1, v2);
+        } else {
+            println!("  ({}, {}): {} vs {}", x, y, v1, v2);
+        }
     }
 
-    let nonzero1: usize = buf1.iter().filter(|&&b| b != 0).count();
-    let nonzero2: usize = buf2.iter().filter(|&&b| b != 0).count();
-    println!("\nNon-zero pixels in test: {}", nonzero1);
-    println!("Non-zero pixels in orig: {}", nonzero2);
+    let nonzero = |buf: &[u8]| buf.chunks(stride).take(height1).flat_map(|row| row.chunks(bpp)).filter(|px| px.iter().any(|&b| b != 0)).count();
+    println!("\nNon-zero pixels in test: {}", nonzero(&buf1));
+    println!("Non-zero pixels in orig: {}", nonzero(&buf2));
 
     // Sample some specific chunks
     println!("\nChunk 0 (0,0) first 32x32 pixels:");
-    let chunk_size = 32;
+    let chunk_size = (32 * bpp).min(stride);
     println!("Test first row: {:?}", &buf1[0..chunk_size]);
     println!("Orig first row: {:?}", &buf2[0..chunk_size]);
+
+    if diff_count > 0 {
+        std::process::exit(1);
+    }
 }
@@ -1,16 +1,21 @@
-/// Generates a pixel value translation guide for GDM and GRLE files
-/// by parsing the map's i3d file and related XML configuration files.
-///
-/// Usage: pixel_guide <map.i3d> [output.md] [--data-dir <path>]
-///
-/// Parses map-specific config files (referenced in maps.xml) with fallback
-/// to base game files when --data-dir is provided.
-
+//! Generates a pixel value translation guide for GDM and GRLE files
+//! by parsing the map's i3d file and related XML configuration files.
+//!
+//! Usage: pixel_guide <map.i3d> [output.md] [--data-dir <path>] [--format md|html]
+//!                    [--sample <file.gdm|file.grle> [--used-only]]
+//!
+//! Parses map-specific config files (referenced in maps.xml) with fallback
+//! to base game files when --data-dir is provided. With --sample, the layer
+//! stored in that file also lists which of its options actually occur.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
+use grleconvert::{decode_gdm, decode_grle, join_multiline_tags, read_xml, I3d, LayerKind};
+
 /// Configuration data loaded from map XML files
 #[derive(Default, Debug)]
 struct MapConfig {
@@ -37,7 +42,8 @@ fn main() {
     let args: Vec<String> = env::args().collect();
 
     if args.len() < 2 {
-        eprintln!("Usage: {} <map.i3d> [output.md] [--data-dir <path>]", args[0]);
+        eprintln!("Usage: {} <map.i3d> [output.md] [--data-dir <path>] [--format md|html]", args[0]);
+        eprintln!("       [--sample <file.gdm|file.grle> [--used-only]]");
         eprintln!();
         eprintln!("Generates a pixel value translation guide for GDM and GRLE files");
         eprintln!("by parsing the map's i3d file.");
@@ -48,11 +54,16 @@ fn main() {
         eprintln!("  - Foliage type listings (with default state descriptions)");
         eprintln!();
         eprintln!("For detailed foliage state info, provide --data-dir to the base game data folder.");
+        eprintln!("Use --format html for a self-contained page with color swatches.");
+        eprintln!("Use --sample to mark which options occur in a real density map, and");
+        eprintln!("--used-only to leave the unused ones out of that layer's tables.");
         eprintln!();
         eprintln!("Examples:");
         eprintln!("  {} mapUS.i3d", args[0]);
         eprintln!("  {} mapUS.i3d pixel_guide.md", args[0]);
         eprintln!("  {} mapUS.i3d pixel_guide.md --data-dir /path/to/data", args[0]);
+        eprintln!("  {} mapUS.i3d pixel_guide.html --format html", args[0]);
+        eprintln!("  {} mapUS.i3d --sample data/densityMap_ground.gdm --used-only", args[0]);
         std::process::exit(1);
     }
 
@@ -61,12 +72,31 @@ fn main() {
     // Parse optional arguments
     let mut output_path: Option<&str> = None;
     let mut data_dir: Option<&str> = None;
+    let mut html = false;
+    let mut sample_path: Option<&str> = None;
+    let mut used_only = false;
 
     let mut i = 2;
     while i < args.len() {
         if args[i] == "--data-dir" && i + 1 < args.len() {
             data_dir = Some(&args[i + 1]);
             i += 2;
+        } else if args[i] == "--format" && i + 1 < args.len() {
+            html = match args[i + 1].as_str() {
+                "md" | "markdown" => false,
+                "html" => true,
+                other => {
+                    eprintln!("Unknown format: {} (expected md or html)", other);
+                    std::process::exit(1);
+                }
+            };
+            i += 2;
+        } else if args[i] == "--sample" && i + 1 < args.len() {
+            sample_path = Some(&args[i + 1]);
+            i += 2;
+        } else if args[i] == "--used-only" {
+            used_only = true;
+            i += 1;
         } else if output_path.is_none() && !args[i].starts_with("--") {
             output_path = Some(&args[i]);
             i += 1;
@@ -75,7 +105,20 @@ fn main() {
         }
     }
 
-    match generate_guide(i3d_path, output_path, data_dir) {
+    if used_only && sample_path.is_none() {
+        eprintln!("--used-only needs --sample <file>");
+        std::process::exit(1);
+    }
+
+    let sample = match sample_path.map(load_sample).transpose() {
+        Ok(sample) => sample,
+        Err(e) => {
+            eprintln!("Error: Cannot read sample: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    match generate_guide(i3d_path, output_path, data_dir, html, sample.as_ref(), used_only) {
         Ok(()) => {}
         Err(e) => {
             eprintln!("Error: {}", e);
@@ -84,8 +127,16 @@ fn main() {
     }
 }
 
-fn generate_guide(i3d_path: &str, output_path: Option<&str>, data_dir: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
-    let content = fs::read_to_string(i3d_path)?;
+fn generate_guide(
+    i3d_path: &str,
+    output_path: Option<&str>,
+    data_dir: Option<&str>,
+    html: bool,
+    sample: Option<&Sample>,
+    used_only: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let content = join_multiline_tags(&read_xml(i3d_path)?);
+    let i3d = I3d::parse_str(&content);
     let map_name = Path::new(i3d_path)
         .file_stem()
         .and_then(|s| s.to_str())
@@ -104,22 +155,38 @@ fn generate_guide(i3d_path: &str, output_path: Option<&str>, data_dir: Option<&s
     let mut sections = Vec::new();
 
     // Parse InfoLayers (GRLE files) - pass config for farmlands
-    sections.extend(parse_info_layers(&content, &map_config));
+    sections.extend(parse_info_layers(&content, &i3d, &map_config));
 
     // Parse DetailLayers (GDM files) - pass config for fill types and ground types
-    sections.extend(parse_detail_layers(&content, &map_config));
+    sections.extend(parse_detail_layers(&content, &i3d, &map_config));
 
     // Parse FoliageMultiLayers (GDM files)
-    sections.extend(parse_foliage_layers(&content, i3d_path, data_dir));
+    sections.extend(parse_foliage_layers(&content, &i3d, i3d_path, data_dir));
+
+    // The sample only describes the layer stored in it
+    let sample_section = sample.and_then(|sample| {
+        let index = sections.iter().position(|s| file_stem(&s.filename) == file_stem(&sample.filename));
+        if index.is_none() {
+            eprintln!("Warning: {} does not match any layer file in the i3d", sample.filename);
+        }
+        index.map(|index| (index, sample))
+    });
+    if let (Some((index, sample)), true) = (sample_section, used_only) {
+        for group in sections[index].groups.iter_mut().filter(|g| lists_real_values(g)) {
+            let counts = group_counts(group, sample);
+            group.options.retain(|(value, _)| counts.contains_key(value));
+        }
+    }
 
     // Generate TOC
+    let mut anchors = HashSet::new();
     for section in &sections {
-        output.push_str(&format!("- [{}](#{})\n", section.name, section.name.to_lowercase().replace(' ', "-").replace(['(', ')'], "")));
+        output.push_str(&format!("- [{}](#{})\n", section.name, unique_anchor(&section.name, &mut anchors)));
     }
     output.push_str("\n---\n\n");
 
     // Generate content
-    for section in &sections {
+    for (index, section) in sections.iter().enumerate() {
         output.push_str(&format!("## {}\n\n", section.name));
         output.push_str(&format!("**File:** `{}`\n\n", section.filename));
 
@@ -134,31 +201,52 @@ fn generate_guide(i3d_path: &str, output_path: Option<&str>, data_dir: Option<&s
 
         // Special handling for height layer
         if section.name.contains("Height") && section.name.contains("terrainDetailHeight") {
-            generate_height_layer_table(&mut output, &section);
+            generate_height_layer_table(&mut output, section);
         }
         // For single-group layers, show direct RGB values
         else if section.groups.len() == 1 {
             let group = &section.groups[0];
             output.push_str(&format!("### {}\n\n", if group.name.is_empty() { "Values".to_string() } else { group.name.clone() }));
 
+            // Values wider than the layer (e.g. farmland 300 in an 8-channel layer) can't be painted
+            let max_value = if section.num_channels >= 32 { u32::MAX as u64 } else { (1u64 << section.num_channels) - 1 };
+            let overflow_note = |shifted: u64| {
+                if shifted > max_value {
+                    eprintln!(
+                        "Warning: {} value {} does not fit in {} channels",
+                        section.name, shifted, section.num_channels
+                    );
+                    format!(" (does not fit in {} channels)", section.num_channels)
+                } else {
+                    String::new()
+                }
+            };
+            // Like the multi-group tables, the option values sit at the group's first channel
+            let shifted = |value: u32| (value as u64) << group.first_channel.min(32);
+
             if is_rgb {
                 output.push_str("| RGB | Hex | Meaning |\n");
                 output.push_str("|-----|-----|--------|\n");
                 for (value, name) in &group.options {
-                    let (r, g, b) = value_to_rgb(*value, section.num_channels);
-                    output.push_str(&format!("| `{}, {}, {}` | `#{:02X}{:02X}{:02X}` | {} |\n", r, g, b, r, g, b, name));
+                    let (r, g, b) = value_to_rgb(shifted(*value) as u32, section.num_channels);
+                    output.push_str(&format!("| `{}, {}, {}` | `#{:02X}{:02X}{:02X}` | {}{} |\n", r, g, b, r, g, b, name, overflow_note(shifted(*value))));
                 }
             } else {
                 output.push_str("| Gray | Hex | Meaning |\n");
                 output.push_str("|------|-----|--------|\n");
                 for (value, name) in &group.options {
-                    output.push_str(&format!("| `{}` | `#{:02X}` | {} |\n", value, value, name));
+                    let (gray, _, _) = value_to_rgb(shifted(*value) as u32, section.num_channels);
+                    output.push_str(&format!("| `{}` | `#{:02X}` | {}{} |\n", gray, gray, name, overflow_note(shifted(*value))));
                 }
             }
-            output.push_str("\n");
+            output.push('\n');
         } else if !section.groups.is_empty() {
             // Multi-group layers - generate all practical combinations
-            generate_multi_group_table(&mut output, &section, is_rgb);
+            generate_multi_group_table(&mut output, section, is_rgb);
+        }
+
+        if let Some((_, sample)) = sample_section.filter(|(i, _)| *i == index) {
+            generate_sample_table(&mut output, section, sample);
         }
 
         output.push_str("---\n\n");
@@ -170,6 +258,10 @@ fn generate_guide(i3d_path: &str, output_path: Option<&str>, data_dir: Option<&s
     output.push_str("- **RGB files**: Paint with the exact RGB values shown\n");
     output.push_str("- For layers with multiple attributes, find your combination in the table above\n");
 
+    if html {
+        output = markdown_to_html(&format!("Pixel Color Guide for {}", map_name), &output);
+    }
+
     // Output
     if let Some(path) = output_path {
         let mut file = fs::File::create(path)?;
@@ -182,6 +274,260 @@ fn generate_guide(i3d_path: &str, output_path: Option<&str>, data_dir: Option<&s
     Ok(())
 }
 
+// ============================================================================
+// Sample usage
+// ============================================================================
+
+/// Pixel counts of a decoded density map given with --sample
+struct Sample {
+    filename: String,
+    counts: HashMap<u32, usize>,
+    total: usize,
+}
+
+fn load_sample(path: &str) -> Result<Sample, Box<dyn std::error::Error>> {
+    let data = fs::read(path)?;
+    let values: Vec<u32> = match Path::new(path).extension().and_then(|e| e.to_str()) {
+        Some("gdm") => decode_gdm(&data)?.image.values,
+        Some("grle") => decode_grle(&data)?.pixels.iter().map(|&p| p as u32).collect(),
+        _ => return Err(format!("{} is not a .gdm or .grle file", path).into()),
+    };
+    let mut counts = HashMap::new();
+    for &value in &values {
+        *counts.entry(value).or_insert(0) += 1;
+    }
+    let filename = Path::new(path).file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    Ok(Sample { filename, counts, total: values.len() })
+}
+
+fn file_stem(filename: &str) -> &str {
+    Path::new(filename).file_stem().and_then(|s| s.to_str()).unwrap_or(filename)
+}
+
+/// Whether the group's options are real values at `first_channel`. The height
+/// layer's G channel group only lists example heights.
+fn lists_real_values(group: &ChannelGroup) -> bool {
+    !group.name.starts_with("Height")
+}
+
+/// Pixels per value of the group's bits
+fn group_counts(group: &ChannelGroup, sample: &Sample) -> BTreeMap<u32, usize> {
+    let mask = if group.num_channels >= 32 { u32::MAX } else { (1u32 << group.num_channels) - 1 };
+    let mut counts = BTreeMap::new();
+    for (&value, &count) in &sample.counts {
+        *counts.entry((value >> group.first_channel) & mask).or_insert(0) += count;
+    }
+    counts
+}
+
+/// List each option of the layer as used or unused in the sample, plus values
+/// the sample holds that no option names
+fn generate_sample_table(output: &mut String, section: &LayerSection, sample: &Sample) {
+    output.push_str(&format!("### Values in `{}`\n\n", sample.filename));
+    output.push_str("| Group | Value | Pixels | Share | Meaning |\n");
+    output.push_str("|-------|-------|--------|-------|---------|\n");
+
+    for group in section.groups.iter().filter(|g| lists_real_values(g)) {
+        let counts = group_counts(group, sample);
+        let mut rows: Vec<(u32, Option<usize>, &str)> = group
+            .options
+            .iter()
+            .map(|(value, name)| (*value, counts.get(value).copied(), name.as_str()))
+            .collect();
+        for (&value, &count) in &counts {
+            if !group.options.iter().any(|(v, _)| *v == value) {
+                rows.push((value, Some(count), "(no option)"));
+            }
+        }
+        rows.sort_by_key(|(value, _, _)| *value);
+
+        for (value, count, name) in rows {
+            let (pixels, share) = match count {
+                Some(count) => (count.to_string(), format!("{:.2}%", 100.0 * count as f64 / sample.total.max(1) as f64)),
+                None => ("unused".to_string(), String::new()),
+            };
+            output.push_str(&format!("| {} | `{}` | {} | {} | {} |\n", group.name, value, pixels, share, name));
+        }
+    }
+    output.push('\n');
+}
+
+// ============================================================================
+// HTML output
+// ============================================================================
+
+/// Render the generated markdown guide as a self-contained HTML page.
+/// Only handles the subset the guide uses: headings, paragraphs, lists,
+/// tables, rules, `code`, **bold** and #anchor links. Hex cells in tables
+/// become color swatches.
+fn markdown_to_html(title: &str, markdown: &str) -> String {
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!("<title>{}</title>\n", html_escape(title)));
+    html.push_str("<style>\n");
+    html.push_str("body { font-family: sans-serif; max-width: 960px; margin: 2em auto; padding: 0 1em; }\n");
+    html.push_str("table { border-collapse: collapse; margin-bottom: 1em; }\n");
+    html.push_str("th, td { border: 1px solid #ccc; padding: 4px 8px; text-align: left; }\n");
+    html.push_str("td.swatch { font-family: monospace; min-width: 6em; }\n");
+    html.push_str("</style>\n</head>\n<body>\n");
+
+    let mut paragraph: Vec<String> = Vec::new();
+    let mut list: Vec<String> = Vec::new();
+    let mut table: Vec<&str> = Vec::new();
+    let mut anchors = HashSet::new();
+
+    for line in markdown.lines().chain(std::iter::once("")) {
+        let line = line.trim_end();
+
+        // Close blocks that this line does not continue
+        if !line.starts_with('|') && !table.is_empty() {
+            html.push_str(&table_to_html(&table));
+            table.clear();
+        }
+        if !line.starts_with("- ") && !list.is_empty() {
+            html.push_str("<ul>\n");
+            for item in list.drain(..) {
+                html.push_str(&format!("<li>{}</li>\n", item));
+            }
+            html.push_str("</ul>\n");
+        }
+        let starts_block = line.is_empty() || line.starts_with('#') || line.starts_with('|')
+            || line.starts_with("- ") || line == "---";
+        if starts_block && !paragraph.is_empty() {
+            html.push_str(&format!("<p>{}</p>\n", paragraph.join(" ")));
+            paragraph.clear();
+        }
+
+        if line.is_empty() {
+            continue;
+        } else if let Some(text) = line.strip_prefix("### ") {
+            html.push_str(&format!("<h3>{}</h3>\n", inline_html(text)));
+        } else if let Some(text) = line.strip_prefix("## ") {
+            let anchor = unique_anchor(text, &mut anchors);
+            html.push_str(&format!("<h2 id=\"{}\">{}</h2>\n", html_escape(&anchor), inline_html(text)));
+        } else if let Some(text) = line.strip_prefix("# ") {
+            html.push_str(&format!("<h1>{}</h1>\n", inline_html(text)));
+        } else if line == "---" {
+            html.push_str("<hr>\n");
+        } else if line.starts_with('|') {
+            table.push(line);
+        } else if let Some(text) = line.strip_prefix("- ") {
+            list.push(inline_html(text));
+        } else {
+            paragraph.push(inline_html(line));
+        }
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Render markdown table rows (header, separator, body) as an HTML table
+fn table_to_html(rows: &[&str]) -> String {
+    let cells = |row: &str| -> Vec<String> {
+        row.trim().trim_matches('|').split('|').map(|c| c.trim().to_string()).collect()
+    };
+
+    let mut html = String::from("<table>\n");
+    let header = rows.first().map(|r| cells(r)).unwrap_or_default();
+
+    // Which channel a bare 2-digit hex value paints, from the first header cell
+    let channel = header.first().map(|h| h.as_str()).unwrap_or("");
+
+    html.push_str("<tr>");
+    for cell in &header {
+        html.push_str(&format!("<th>{}</th>", inline_html(cell)));
+    }
+    html.push_str("</tr>\n");
+
+    for row in rows.iter().skip(2) {
+        html.push_str("<tr>");
+        for cell in cells(row) {
+            match swatch_color(&cell, channel) {
+                Some((r, g, b)) => {
+                    // Dark text on light swatches, light text on dark ones
+                    let luma = 299 * r as u32 + 587 * g as u32 + 114 * b as u32;
+                    let text = if luma > 128_000 { "#000" } else { "#fff" };
+                    html.push_str(&format!(
+                        "<td class=\"swatch\" style=\"background:#{:02X}{:02X}{:02X};color:{}\">{}</td>",
+                        r, g, b, text, inline_html(&cell)
+                    ));
+                }
+                None => html.push_str(&format!("<td>{}</td>", inline_html(&cell))),
+            }
+        }
+        html.push_str("</tr>\n");
+    }
+
+    html.push_str("</table>\n");
+    html
+}
+
+/// Color for a table cell holding `#RRGGBB` or `#XX` (in backticks).
+/// `#XX` is a single channel value: R or G when the table's first column says so, gray otherwise.
+fn swatch_color(cell: &str, channel: &str) -> Option<(u8, u8, u8)> {
+    let hex = cell.strip_prefix("`#")?.strip_suffix('`')?;
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    match hex.len() {
+        6 => Some(((value >> 16) as u8, (value >> 8) as u8, value as u8)),
+        2 => {
+            let v = value as u8;
+            Some(match channel {
+                "R" => (v, 0, 0),
+                "G" => (0, v, 0),
+                _ => value_to_rgb(v as u32, 8),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Inline markdown (`code`, **bold**, [text](#anchor)) to HTML
+fn inline_html(text: &str) -> String {
+    let mut html = String::new();
+    let mut rest = text;
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('`') {
+            if let Some(end) = after.find('`') {
+                html.push_str(&format!("<code>{}</code>", html_escape(&after[..end])));
+                rest = &after[end + 1..];
+                continue;
+            }
+        } else if let Some(after) = rest.strip_prefix("**") {
+            if let Some(end) = after.find("**") {
+                html.push_str(&format!("<strong>{}</strong>", inline_html(&after[..end])));
+                rest = &after[end + 2..];
+                continue;
+            }
+        } else if let Some(after) = rest.strip_prefix('[') {
+            if let Some((label, target)) = after.split_once("](") {
+                if let Some(end) = target.find(')') {
+                    html.push_str(&format!(
+                        "<a href=\"{}\">{}</a>",
+                        html_escape(&target[..end]), inline_html(label)
+                    ));
+                    rest = &target[end + 1..];
+                    continue;
+                }
+            }
+        }
+
+        let c = rest.chars().next().unwrap_or_default();
+        html.push_str(&html_escape(&c.to_string()));
+        rest = &rest[c.len_utf8()..];
+    }
+
+    html
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
 /// Generate a table of all practical combinations for multi-group layers
 fn generate_multi_group_table(output: &mut String, section: &LayerSection, is_rgb: bool) {
     // Special handling for known layer types
@@ -211,7 +557,7 @@ fn generate_multi_group_table(output: &mut String, section: &LayerSection, is_rg
                     output.push_str(&format!("| `{}` | `#{:02X}` | {} |\n", shifted, shifted, name));
                 }
             }
-            output.push_str("\n");
+            output.push('\n');
         }
     }
 }
@@ -243,7 +589,7 @@ fn generate_ground_table(output: &mut String, section: &LayerSection, is_rgb: bo
                 output.push_str(&format!("| `{}` | `#{:02X}` | {} |\n", value, value, name));
             }
         }
-        output.push_str("\n");
+        output.push('\n');
     }
 
     // Spray type additions
@@ -267,7 +613,7 @@ fn generate_ground_table(output: &mut String, section: &LayerSection, is_rgb: bo
                 output.push_str(&format!("| `+{}` | {} |\n", add_value, name));
             }
         }
-        output.push_str("\n");
+        output.push('\n');
     }
 
     // Water flag
@@ -320,7 +666,7 @@ fn generate_ground_table(output: &mut String, section: &LayerSection, is_rgb: bo
             output.push_str(&format!("| `{}` | `#{:02X}` | {} |\n", val, val, desc));
         }
     }
-    output.push_str("\n");
+    output.push('\n');
 }
 
 /// Generate fruits/foliage table with type + state combinations
@@ -346,7 +692,7 @@ fn generate_fruits_table(output: &mut String, section: &LayerSection, is_rgb: bo
                 output.push_str(&format!("| `{}` | `#{:02X}` | {} |\n", value, value, name));
             }
         }
-        output.push_str("\n");
+        output.push('\n');
 
         // Show growth states with their additions
         output.push_str("### Growth States (add to type)\n\n");
@@ -368,7 +714,7 @@ fn generate_fruits_table(output: &mut String, section: &LayerSection, is_rgb: bo
                 output.push_str(&format!("| `+{}` | {} |\n", add_value, name));
             }
         }
-        output.push_str("\n");
+        output.push('\n');
 
         // Common examples
         output.push_str("### Common Complete Colors\n\n");
@@ -401,7 +747,7 @@ fn generate_fruits_table(output: &mut String, section: &LayerSection, is_rgb: bo
                 }
             }
         }
-        output.push_str("\n");
+        output.push('\n');
     }
 }
 
@@ -420,7 +766,7 @@ fn generate_height_layer_table(output: &mut String, section: &LayerSection) {
         for (value, name) in &fill.options {
             output.push_str(&format!("| `{}` | `#{:02X}` | {} |\n", value, value, name));
         }
-        output.push_str("\n");
+        output.push('\n');
     }
 
     // Height examples - G channel
@@ -432,7 +778,7 @@ fn generate_height_layer_table(output: &mut String, section: &LayerSection) {
         for (value, name) in &height.options {
             output.push_str(&format!("| `{}` | `#{:02X}` | {} |\n", value, value, name));
         }
-        output.push_str("\n");
+        output.push('\n');
     }
 
     // Combined examples
@@ -451,7 +797,7 @@ fn generate_height_layer_table(output: &mut String, section: &LayerSection) {
     for (r, g, desc) in examples {
         output.push_str(&format!("| `{}` | `{}` | `0` | `#{:02X}{:02X}00` | {} |\n", r, g, r, g, desc));
     }
-    output.push_str("\n");
+    output.push('\n');
 }
 
 /// Generate environment layer table
@@ -467,7 +813,7 @@ fn generate_environment_table(output: &mut String, section: &LayerSection) {
         for (value, name) in &area.options {
             output.push_str(&format!("| `{}` | `#{:02X}` | {} |\n", value, value, name));
         }
-        output.push_str("\n");
+        output.push('\n');
     }
 
     // Water proximity flag
@@ -496,7 +842,20 @@ fn generate_environment_table(output: &mut String, section: &LayerSection) {
             }
         }
     }
-    output.push_str("\n");
+    output.push('\n');
+}
+
+/// Link anchor for a section heading. Repeated names get -1, -2, ... appended,
+/// as GitHub does for its own heading ids.
+fn unique_anchor(name: &str, taken: &mut HashSet<String>) -> String {
+    let base = name.to_lowercase().replace(' ', "-").replace(['(', ')'], "");
+    let mut anchor = base.clone();
+    let mut n = 0;
+    while !taken.insert(anchor.clone()) {
+        n += 1;
+        anchor = format!("{}-{}", base, n);
+    }
+    anchor
 }
 
 /// Convert a packed value to RGB components based on channel count
@@ -517,6 +876,7 @@ fn value_to_rgb(value: u32, num_channels: u32) -> (u8, u8, u8) {
 struct LayerSection {
     name: String,
     filename: String,
+    #[allow(dead_code)]
     file_type: String,
     num_channels: u32,
     description: String,
@@ -535,7 +895,7 @@ fn load_map_config(i3d_path: &str, data_dir: Option<&str>) -> MapConfig {
     };
 
     // Find maps.xml and mod root
-    let (maps_xml_path, mod_root) = match find_maps_xml(i3d_dir) {
+    let (maps_xml_path, mod_root) = match find_maps_xml(i3d_dir, Path::new(i3d_path)) {
         Some(path) => {
             let root = path.parent().and_then(|p| p.parent()).map(|p| p.to_path_buf());
             (Some(path), root)
@@ -545,7 +905,7 @@ fn load_map_config(i3d_path: &str, data_dir: Option<&str>) -> MapConfig {
 
     let maps_content = maps_xml_path
         .as_ref()
-        .and_then(|p| fs::read_to_string(p).ok());
+        .and_then(|p| read_xml(p).ok());
 
     // Load fill types (densityMapHeightTypes)
     config.fill_types = load_config_file(
@@ -620,7 +980,7 @@ where
     if let Some(content) = maps_content {
         if let Some(filename) = find_config_filename(content, element_name) {
             if let Some(config_path) = resolve_path(&filename, mod_root, data_dir) {
-                if let Ok(config_content) = fs::read_to_string(&config_path) {
+                if let Ok(config_content) = read_xml(&config_path) {
                     let result = parser(&config_content);
                     if !result.is_empty() {
                         eprintln!("Loaded {} from {}", element_name, config_path.display());
@@ -635,7 +995,7 @@ where
     if !base_game_path.is_empty() {
         if let Some(data_path) = data_dir {
             let path = Path::new(data_path).join(base_game_path);
-            if let Ok(content) = fs::read_to_string(&path) {
+            if let Ok(content) = read_xml(&path) {
                 let result = parser(&content);
                 if !result.is_empty() {
                     eprintln!("Loaded {} from {}", element_name, path.display());
@@ -659,13 +1019,16 @@ fn find_config_filename(maps_content: &str, element_name: &str) -> Option<String
     None
 }
 
+/// List of (value, name) pairs as produced by the config parsers
+type ValueList = Vec<(u32, String)>;
+
 /// Load fieldGround.xml for ground types and spray types
 fn load_field_ground_config(
     maps_content: &Option<String>,
     mod_root: &Option<PathBuf>,
     data_dir: Option<&str>,
-) -> (Vec<(u32, String)>, Vec<(u32, String)>) {
-    let parse_both = |content: &str| -> (Vec<(u32, String)>, Vec<(u32, String)>) {
+) -> (ValueList, ValueList) {
+    let parse_both = |content: &str| -> (ValueList, ValueList) {
         (parse_ground_types(content), parse_spray_types(content))
     };
 
@@ -673,7 +1036,7 @@ fn load_field_ground_config(
     if let Some(content) = maps_content {
         if let Some(filename) = find_config_filename(content, "fieldGround") {
             if let Some(config_path) = resolve_path(&filename, mod_root, data_dir) {
-                if let Ok(config_content) = fs::read_to_string(&config_path) {
+                if let Ok(config_content) = read_xml(&config_path) {
                     let (ground, spray) = parse_both(&config_content);
                     if !ground.is_empty() {
                         eprintln!("Loaded fieldGround from {}", config_path.display());
@@ -687,7 +1050,7 @@ fn load_field_ground_config(
     // Fall back to base game file
     if let Some(data_path) = data_dir {
         let path = Path::new(data_path).join("maps/maps_fieldGround.xml");
-        if let Ok(content) = fs::read_to_string(&path) {
+        if let Ok(content) = read_xml(&path) {
             let (ground, spray) = parse_both(&content);
             if !ground.is_empty() {
                 eprintln!("Loaded fieldGround from {}", path.display());
@@ -805,7 +1168,7 @@ fn load_weed_config(
     if let Some(content) = maps_content {
         if let Some(filename) = find_config_filename(content, "weed") {
             if let Some(config_path) = resolve_path(&filename, mod_root, data_dir) {
-                if let Ok(config_content) = fs::read_to_string(&config_path) {
+                if let Ok(config_content) = read_xml(&config_path) {
                     if let Some(value) = parse_weed_blocking_value(&config_content) {
                         eprintln!("Loaded weed config from {}", config_path.display());
                         return Some(value);
@@ -818,7 +1181,7 @@ fn load_weed_config(
     // Fall back to base game file
     if let Some(data_path) = data_dir {
         let path = Path::new(data_path).join("maps/maps_weed.xml");
-        if let Ok(content) = fs::read_to_string(&path) {
+        if let Ok(content) = read_xml(&path) {
             if let Some(value) = parse_weed_blocking_value(&content) {
                 eprintln!("Loaded weed config from {}", path.display());
                 return Some(value);
@@ -860,7 +1223,7 @@ fn load_fruit_types(
                 if let Some(filename) = extract_attr(line, "filename") {
                     // Try to load the fruit XML to get the name
                     if let Some(fruit_path) = resolve_path(&filename, mod_root, data_dir) {
-                        if let Ok(fruit_content) = fs::read_to_string(&fruit_path) {
+                        if let Ok(fruit_content) = read_xml(&fruit_path) {
                             // Look for <fruitType name="..."> in the fruit XML
                             for fruit_line in fruit_content.lines() {
                                 if fruit_line.contains("<fruitType") && fruit_line.contains("name=") {
@@ -897,7 +1260,7 @@ fn load_fruit_types(
     if let Some(content) = maps_content {
         if let Some(filename) = find_config_filename(content, "fruitTypes") {
             if let Some(config_path) = resolve_path(&filename, mod_root, data_dir) {
-                if let Ok(config_content) = fs::read_to_string(&config_path) {
+                if let Ok(config_content) = read_xml(&config_path) {
                     fruit_types = parse_fruit_types_file(&config_content, mod_root, data_dir);
                     if !fruit_types.is_empty() {
                         eprintln!("Loaded fruitTypes from {}", config_path.display());
@@ -911,7 +1274,7 @@ fn load_fruit_types(
     // Fall back to base game fruitTypes
     if let Some(data_path) = data_dir {
         let path = Path::new(data_path).join("maps/maps_fruitTypes.xml");
-        if let Ok(content) = fs::read_to_string(&path) {
+        if let Ok(content) = read_xml(&path) {
             fruit_types = parse_fruit_types_file(&content, mod_root, data_dir);
             if !fruit_types.is_empty() {
                 eprintln!("Loaded fruitTypes from {}", path.display());
@@ -942,23 +1305,11 @@ fn titlecase_name(name: &str) -> String {
             .join(" ");
     }
 
-    // Handle camelCase
-    let mut result = String::new();
-    for (i, c) in name.chars().enumerate() {
-        if i == 0 {
-            result.extend(c.to_uppercase());
-        } else if c.is_uppercase() {
-            result.push(' ');
-            result.extend(c.to_uppercase());
-        } else {
-            result.push(c);
-        }
-    }
-    result
+    titlecase(name)
 }
 
 /// Find maps.xml in the directory or its parent
-fn find_maps_xml(dir: &Path) -> Option<PathBuf> {
+fn find_maps_xml(dir: &Path, i3d_path: &Path) -> Option<PathBuf> {
     // Helper to find mod/DLC root (directory containing modDesc.xml or dlcDesc.xml)
     let find_mod_root = |start: &Path| -> Option<(PathBuf, PathBuf)> {
         let mut current = Some(start.to_path_buf());
@@ -978,7 +1329,7 @@ fn find_maps_xml(dir: &Path) -> Option<PathBuf> {
 
     // First, check modDesc.xml or dlcDesc.xml for authoritative configFilename
     if let Some((mod_root, desc_path)) = find_mod_root(dir) {
-        if let Ok(content) = fs::read_to_string(&desc_path) {
+        if let Ok(content) = read_xml(&desc_path) {
             // Look for <map ... configFilename="...">
             for line in content.lines() {
                 if line.contains("<map ") && line.contains("configFilename=") {
@@ -993,21 +1344,39 @@ fn find_maps_xml(dir: &Path) -> Option<PathBuf> {
         }
     }
 
-    // For base game maps (no modDesc.xml/dlcDesc.xml), look for mapXX.xml in current directory
-    if let Ok(entries) = std::fs::read_dir(dir) {
-        for entry in entries.flatten() {
-            let name = entry.file_name();
-            let name_str = name.to_string_lossy();
-            // Match mapUS.xml, mapEU.xml, mapAS.xml, etc. but not mapUS_something.xml
-            if name_str.starts_with("map") && name_str.ends_with(".xml")
-                && !name_str.contains(".i3d")
-                && !name_str.contains("_") {
-                return Some(entry.path());
-            }
-        }
+    // For base game maps (no modDesc.xml/dlcDesc.xml), prefer the xml named after the i3d
+    // (map_US.i3d -> map_US.xml), as long as it really is a map config
+    let stem_xml = i3d_path.with_extension("xml");
+    if is_map_config(&stem_xml) {
+        return Some(stem_xml);
     }
 
-    None
+    // Otherwise any mapXX.xml here that references config files. Names alone can't tell
+    // map_US.xml apart from mapUS_farmlands.xml, so look at the content.
+    let mut candidates: Vec<PathBuf> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                name.starts_with("map") && name.ends_with(".xml") && !name.contains(".i3d")
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    candidates.sort();
+    candidates.into_iter().find(|path| is_map_config(path))
+}
+
+/// Whether a file is a map config (maps.xml style), i.e. it points at other config files
+fn is_map_config(path: &Path) -> bool {
+    let content = match read_xml(path) {
+        Ok(c) => c,
+        Err(_) => return false,
+    };
+    ["densityMapHeightTypes", "fieldGround", "farmlands", "fruitTypes", "weed"]
+        .iter()
+        .any(|element| find_config_filename(&content, element).is_some())
 }
 
 struct ChannelGroup {
@@ -1017,7 +1386,7 @@ struct ChannelGroup {
     options: Vec<(u32, String)>,
 }
 
-fn parse_info_layers(content: &str, config: &MapConfig) -> Vec<LayerSection> {
+fn parse_info_layers(content: &str, i3d: &I3d, config: &MapConfig) -> Vec<LayerSection> {
     let mut sections = Vec::new();
 
     // Find InfoLayer definitions
@@ -1038,7 +1407,7 @@ fn parse_info_layers(content: &str, config: &MapConfig) -> Vec<LayerSection> {
                 .unwrap_or(1);
 
             // Try to find filename from fileId
-            let filename = find_filename_by_id(content, &file_id)
+            let filename = find_filename_by_id(i3d, &file_id, LayerKind::InfoLayer)
                 .unwrap_or_else(|| format!("infoLayer_{}.grle", name));
 
             current_section = Some(LayerSection {
@@ -1081,12 +1450,8 @@ fn parse_info_layers(content: &str, config: &MapConfig) -> Vec<LayerSection> {
                     options: Vec::new(),
                 });
             } else if line.starts_with("<Option ") {
-                if let Some(ref mut group) = current_group {
-                    let value: u32 = extract_attr(line, "value")
-                        .and_then(|s| s.parse().ok())
-                        .unwrap_or(0);
-                    let name = extract_attr(line, "name").unwrap_or_default();
-                    group.options.push((value, name));
+                if let (Some(group), Some(option)) = (current_group.as_mut(), parse_option(line)) {
+                    group.options.push(option);
                 }
             } else if line.starts_with("</Group>") {
                 if let Some(group) = current_group.take() {
@@ -1106,12 +1471,15 @@ fn parse_info_layers(content: &str, config: &MapConfig) -> Vec<LayerSection> {
     sections
 }
 
-fn parse_detail_layers(content: &str, config: &MapConfig) -> Vec<LayerSection> {
+fn parse_detail_layers(content: &str, i3d: &I3d, config: &MapConfig) -> Vec<LayerSection> {
     let mut sections = Vec::new();
 
     let mut in_detail_layer = false;
     let mut current_section: Option<LayerSection> = None;
     let mut current_group: Option<ChannelGroup> = None;
+    let mut current_name = String::new();
+    // The DetailLayer tag plus child elements that carry height attributes
+    let mut height_attrs = String::new();
 
     for line in content.lines() {
         let line = line.trim();
@@ -1125,7 +1493,7 @@ fn parse_detail_layers(content: &str, config: &MapConfig) -> Vec<LayerSection> {
                 .and_then(|s| s.parse().ok())
                 .unwrap_or(1);
 
-            let filename = find_filename_by_id(content, &density_map_id)
+            let filename = find_filename_by_id(i3d, &density_map_id, LayerKind::DetailLayer)
                 .unwrap_or_else(|| format!("densityMap_{}.gdm", name));
 
             let display_name = match name.as_str() {
@@ -1142,86 +1510,28 @@ fn parse_detail_layers(content: &str, config: &MapConfig) -> Vec<LayerSection> {
                 description: String::new(),
                 groups: Vec::new(),
             });
+            current_name = name.clone();
+            height_attrs = line.to_string();
 
             if line.ends_with("/>") {
                 if let Some(mut section) = current_section.take() {
                     // For height layer without groups, add height-specific info
                     if section.groups.is_empty() && name == "terrainDetailHeight" {
-                        // Parse height-specific attributes
-                        let height_first: u32 = extract_attr(line, "heightFirstChannel")
-                            .and_then(|s| s.parse().ok())
-                            .unwrap_or(8);
-                        let height_num: u32 = extract_attr(line, "heightNumChannels")
-                            .and_then(|s| s.parse().ok())
-                            .unwrap_or(8);
-                        let combined: Vec<u32> = extract_attr(line, "combinedValuesChannels")
-                            .map(|s| s.split_whitespace().filter_map(|x| x.parse().ok()).collect())
-                            .unwrap_or_default();
-
-                        let type_channels = if combined.len() >= 2 { combined[1] } else { height_first };
-                        let max_height = extract_attr(line, "maxHeight")
-                            .and_then(|s| s.parse::<f32>().ok())
-                            .unwrap_or(4.0);
-                        let max_height_val = (1u32 << height_num) - 1;
-                        let height_per_unit = max_height / max_height_val as f32;
-
-                        section.description = format!(
-                            "Height data for terrain fill (piles).\n\n\
-                            - **Fill Type**: Bits 0-{} (R channel, values 0-{})\n\
-                            - **Height**: Bits {}-{} (G channel, values 0-{}, representing 0-{:.1}m)\n\n\
-                            Paint R with fill type index, G with height value (each unit = {:.3}m).",
-                            type_channels - 1,
-                            (1u32 << type_channels) - 1,
-                            height_first,
-                            height_first + height_num - 1,
-                            max_height_val,
-                            max_height,
-                            height_per_unit
-                        );
-
-                        // Add fill type group if we have types loaded
-                        if config.has_fill_types {
-                            section.groups.push(ChannelGroup {
-                                name: "Fill Type (R channel)".to_string(),
-                                first_channel: 0,
-                                num_channels: type_channels,
-                                options: config.fill_types.clone(),
-                            });
-                        } else {
-                            // No fill types found - add a note about using --data-dir
-                            section.description.push_str("\n\n**Note:** Fill type definitions not found. Use `--data-dir` to specify the base game data folder for fill type names.");
-                        }
-
-                        // Add height examples - these will be shown separately since they go in G channel
-                        // We use first_channel=0 to show raw values, not shifted
-                        let mut height_examples = Vec::new();
-                        let example_heights = [0, 1, 10, 25, 50, 63, 100, 127, 200, 255];
-                        for &h in &example_heights {
-                            if h <= max_height_val {
-                                let meters = h as f32 * height_per_unit;
-                                let desc = if h == 0 {
-                                    "Empty".to_string()
-                                } else if h == max_height_val {
-                                    format!("{:.1}m (max)", max_height)
-                                } else {
-                                    format!("{:.2}m", meters)
-                                };
-                                height_examples.push((h, desc));
-                            }
-                        }
-
-                        section.groups.push(ChannelGroup {
-                            name: "Height (G channel value)".to_string(),
-                            first_channel: 0, // Show as raw G value, not shifted
-                            num_channels: height_num,
-                            options: height_examples,
-                        });
+                        add_height_layer_info(&mut section, line, config);
                     }
                     sections.push(section);
                 }
                 in_detail_layer = false;
             }
         } else if in_detail_layer {
+            if ["heightFirstChannel", "heightNumChannels", "combinedValuesChannels", "maxHeight"]
+                .iter()
+                .any(|attr| line.contains(&format!("{}=", attr)))
+            {
+                height_attrs.push(' ');
+                height_attrs.push_str(line);
+            }
+
             if line.starts_with("<Group ") {
                 let name = extract_attr(line, "name").unwrap_or_default();
                 let first_channel: u32 = extract_attr(line, "firstChannel")
@@ -1238,12 +1548,8 @@ fn parse_detail_layers(content: &str, config: &MapConfig) -> Vec<LayerSection> {
                     options: Vec::new(),
                 });
             } else if line.starts_with("<Option ") {
-                if let Some(ref mut group) = current_group {
-                    let value: u32 = extract_attr(line, "value")
-                        .and_then(|s| s.parse().ok())
-                        .unwrap_or(0);
-                    let name = extract_attr(line, "name").unwrap_or_default();
-                    group.options.push((value, name));
+                if let (Some(group), Some(option)) = (current_group.as_mut(), parse_option(line)) {
+                    group.options.push(option);
                 }
             } else if line.starts_with("</Group>") {
                 if let Some(group) = current_group.take() {
@@ -1260,7 +1566,10 @@ fn parse_detail_layers(content: &str, config: &MapConfig) -> Vec<LayerSection> {
                 }
 
                 if line.starts_with("</DetailLayer>") {
-                    if let Some(section) = current_section.take() {
+                    if let Some(mut section) = current_section.take() {
+                        if section.groups.is_empty() && current_name == "terrainDetailHeight" {
+                            add_height_layer_info(&mut section, &height_attrs, config);
+                        }
                         sections.push(section);
                     }
                     in_detail_layer = false;
@@ -1272,7 +1581,81 @@ fn parse_detail_layers(content: &str, config: &MapConfig) -> Vec<LayerSection> {
     sections
 }
 
-fn parse_foliage_layers(content: &str, i3d_path: &str, data_dir: Option<&str>) -> Vec<LayerSection> {
+/// Describe a terrainDetailHeight layer: fill type in R, height in G.
+/// `attrs` holds the DetailLayer tag plus any child elements carrying the height attributes.
+fn add_height_layer_info(section: &mut LayerSection, attrs: &str, config: &MapConfig) {
+    // Parse height-specific attributes
+    let height_first: u32 = extract_attr(attrs, "heightFirstChannel")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(8);
+    let height_num: u32 = extract_attr(attrs, "heightNumChannels")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(8);
+    let combined: Vec<u32> = extract_attr(attrs, "combinedValuesChannels")
+        .map(|s| s.split_whitespace().filter_map(|x| x.parse().ok()).collect())
+        .unwrap_or_default();
+
+    let type_channels = if combined.len() >= 2 { combined[1] } else { height_first };
+    let max_height = extract_attr(attrs, "maxHeight")
+        .and_then(|s| s.parse::<f32>().ok())
+        .unwrap_or(4.0);
+    let max_height_val = (1u32 << height_num) - 1;
+    let height_per_unit = max_height / max_height_val as f32;
+
+    section.description = format!(
+        "Height data for terrain fill (piles).\n\n\
+        - **Fill Type**: Bits 0-{} (R channel, values 0-{})\n\
+        - **Height**: Bits {}-{} (G channel, values 0-{}, representing 0-{:.1}m)\n\n\
+        Paint R with fill type index, G with height value (each unit = {:.3}m).",
+        type_channels - 1,
+        (1u32 << type_channels) - 1,
+        height_first,
+        height_first + height_num - 1,
+        max_height_val,
+        max_height,
+        height_per_unit
+    );
+
+    // Add fill type group if we have types loaded
+    if config.has_fill_types {
+        section.groups.push(ChannelGroup {
+            name: "Fill Type (R channel)".to_string(),
+            first_channel: 0,
+            num_channels: type_channels,
+            options: config.fill_types.clone(),
+        });
+    } else {
+        // No fill types found - add a note about using --data-dir
+        section.description.push_str("\n\n**Note:** Fill type definitions not found. Use `--data-dir` to specify the base game data folder for fill type names.");
+    }
+
+    // Add height examples - these will be shown separately since they go in G channel
+    // We use first_channel=0 to show raw values, not shifted
+    let mut height_examples = Vec::new();
+    let example_heights = [0, 1, 10, 25, 50, 63, 100, 127, 200, 255];
+    for &h in &example_heights {
+        if h <= max_height_val {
+            let meters = h as f32 * height_per_unit;
+            let desc = if h == 0 {
+                "Empty".to_string()
+            } else if h == max_height_val {
+                format!("{:.1}m (max)", max_height)
+            } else {
+                format!("{:.2}m", meters)
+            };
+            height_examples.push((h, desc));
+        }
+    }
+
+    section.groups.push(ChannelGroup {
+        name: "Height (G channel value)".to_string(),
+        first_channel: 0, // Show as raw G value, not shifted
+        num_channels: height_num,
+        options: height_examples,
+    });
+}
+
+fn parse_foliage_layers(content: &str, i3d: &I3d, i3d_path: &str, data_dir: Option<&str>) -> Vec<LayerSection> {
     let mut sections = Vec::new();
     let _i3d_dir = Path::new(i3d_path).parent();
 
@@ -1304,7 +1687,7 @@ fn parse_foliage_layers(content: &str, i3d_path: &str, data_dir: Option<&str>) -
             } else if line.starts_with("</FoliageMultiLayer>") {
                 in_foliage_multi = false;
 
-                let filename = find_filename_by_id(content, &current_density_map_id)
+                let filename = find_filename_by_id(i3d, &current_density_map_id, LayerKind::FoliageMultiLayer)
                     .unwrap_or_else(|| format!("densityMap_{}.gdm", current_density_map_id));
 
                 // Determine layer name from foliage types
@@ -1340,7 +1723,7 @@ fn parse_foliage_layers(content: &str, i3d_path: &str, data_dir: Option<&str>) -
                     let state_channels = current_num_channels - current_type_index_channels;
                     if state_channels > 0 {
                         // Try to load state info from foliage XML files
-                        let state_options = load_foliage_states(&foliage_types, content, data_dir);
+                        let state_options = load_foliage_states(&foliage_types, i3d, data_dir);
 
                         groups.push(ChannelGroup {
                             name: "Growth State".to_string(),
@@ -1351,7 +1734,7 @@ fn parse_foliage_layers(content: &str, i3d_path: &str, data_dir: Option<&str>) -
                     }
                 } else {
                     // Single-type layer (weed, stones, etc.)
-                    let state_options = load_single_foliage_states(&foliage_types, content, data_dir, &layer_name);
+                    let state_options = load_single_foliage_states(&foliage_types, i3d, data_dir, &layer_name);
 
                     if !state_options.is_empty() {
                         groups.push(ChannelGroup {
@@ -1383,7 +1766,7 @@ fn parse_foliage_layers(content: &str, i3d_path: &str, data_dir: Option<&str>) -
     sections
 }
 
-fn load_foliage_states(foliage_types: &[(String, String)], i3d_content: &str, data_dir: Option<&str>) -> Vec<(u32, String)> {
+fn load_foliage_states(foliage_types: &[(String, String)], i3d: &I3d, data_dir: Option<&str>) -> Vec<(u32, String)> {
     // If data_dir is provided, try to load states from actual foliage XML files
     if let Some(data_path) = data_dir {
         // Try to get states from the first crop type (they all share similar structure)
@@ -1393,7 +1776,7 @@ fn load_foliage_states(foliage_types: &[(String, String)], i3d_content: &str, da
                 continue;
             }
 
-            if let Some(states) = load_states_from_foliage_xml(i3d_content, xml_id, data_path) {
+            if let Some(states) = load_states_from_foliage_xml(i3d, xml_id, data_path) {
                 if !states.is_empty() {
                     return states;
                 }
@@ -1418,37 +1801,27 @@ fn load_foliage_states(foliage_types: &[(String, String)], i3d_content: &str, da
 }
 
 /// Load foliage states from the actual XML file
-fn load_states_from_foliage_xml(i3d_content: &str, xml_id: &str, data_dir: &str) -> Option<Vec<(u32, String)>> {
+fn load_states_from_foliage_xml(i3d: &I3d, xml_id: &str, data_dir: &str) -> Option<Vec<(u32, String)>> {
     // Find the filename for this foliage XML ID in the i3d
-    let search = format!("fileId=\"{}\"", xml_id);
-    for line in i3d_content.lines() {
-        if line.contains(&search) && line.contains("filename=") {
-            if let Some(filename) = extract_attr(line, "filename") {
-                // Convert $data path to actual path
-                let actual_path = if filename.starts_with("$data/") {
-                    format!("{}/{}", data_dir, &filename[6..])
-                } else {
-                    filename.clone()
-                };
+    let filename = i3d.file(xml_id)?;
 
-                // Try to read and parse the foliage XML
-                if let Ok(xml_content) = fs::read_to_string(&actual_path) {
-                    return Some(parse_foliage_states(&xml_content));
-                }
-            }
-        }
-    }
-    None
+    // Convert $data path to actual path
+    let actual_path = if let Some(relative) = filename.strip_prefix("$data/") {
+        format!("{}/{}", data_dir, relative)
+    } else {
+        filename.to_string()
+    };
+
+    // Try to read and parse the foliage XML
+    let xml_content = read_xml(&actual_path).ok()?;
+    Some(parse_foliage_states(&xml_content))
 }
 
 /// Parse foliageState elements from a foliage XML file
 fn parse_foliage_states(xml_content: &str) -> Vec<(u32, String)> {
-    let mut states = Vec::new();
-    let mut state_index = 0u32;
-
     // First state (index 0) is always "None/Empty" - not in the file
-    states.push((0, "None/Empty".to_string()));
-    state_index = 1;
+    let mut states = vec![(0, "None/Empty".to_string())];
+    let mut state_index = 1u32;
 
     for line in xml_content.lines() {
         let line = line.trim();
@@ -1459,13 +1832,14 @@ fn parse_foliage_states(xml_content: &str) -> Vec<(u32, String)> {
 
                 // Add extra info based on attributes
                 let mut description = readable_name;
-                if line.contains("isHarvestReady=\"true\"") {
+                let is_set = |attr: &str| extract_attr(line, attr).as_deref() == Some("true");
+                if is_set("isHarvestReady") {
                     description.push_str(" (harvest ready)");
-                } else if line.contains("isWithered=\"true\"") {
+                } else if is_set("isWithered") {
                     description.push_str(" (withered)");
-                } else if line.contains("isCut=\"true\"") {
+                } else if is_set("isCut") {
                     description.push_str(" (cut)");
-                } else if line.contains("isGrowing=\"true\"") && !description.to_lowercase().contains("invisible") {
+                } else if is_set("isGrowing") && !description.to_lowercase().contains("invisible") {
                     description.push_str(" (growing)");
                 }
 
@@ -1494,11 +1868,11 @@ fn camel_to_readable(s: &str) -> String {
     result
 }
 
-fn load_single_foliage_states(foliage_types: &[(String, String)], i3d_content: &str, data_dir: Option<&str>, layer_name: &str) -> Vec<(u32, String)> {
+fn load_single_foliage_states(foliage_types: &[(String, String)], i3d: &I3d, data_dir: Option<&str>, layer_name: &str) -> Vec<(u32, String)> {
     // If data_dir is provided, try to load from actual XML files
     if let Some(data_path) = data_dir {
         if let Some((_, xml_id)) = foliage_types.first() {
-            if let Some(states) = load_states_from_foliage_xml(i3d_content, xml_id, data_path) {
+            if let Some(states) = load_states_from_foliage_xml(i3d, xml_id, data_path) {
                 if !states.is_empty() {
                     return states;
                 }
@@ -1642,59 +2016,68 @@ fn get_info_layer_values(name: &str, num_channels: u32, config: &MapConfig) -> C
     }
 }
 
+/// Value of `attr="..."` or `attr='...'`
 fn extract_attr(line: &str, attr: &str) -> Option<String> {
-    let search = format!("{}=\"", attr);
-    if let Some(start) = line.find(&search) {
-        let value_start = start + search.len();
-        if let Some(end) = line[value_start..].find('"') {
-            return Some(line[value_start..value_start + end].to_string());
+    for quote in ['"', '\''] {
+        let search = format!("{}={}", attr, quote);
+        if let Some(start) = line.find(&search) {
+            let value_start = start + search.len();
+            if let Some(end) = line[value_start..].find(quote) {
+                return Some(line[value_start..value_start + end].to_string());
+            }
         }
     }
     None
 }
 
-fn find_filename_by_id(content: &str, file_id: &str) -> Option<String> {
-    let search = format!("fileId=\"{}\"", file_id);
-    for line in content.lines() {
-        if line.contains(&search) && line.contains("filename=") {
-            if let Some(filename) = extract_attr(line, "filename") {
-                // Extract just the filename part
-                let path = Path::new(&filename);
-                if let Some(name) = path.file_name() {
-                    let name_str = name.to_string_lossy();
-                    // Convert .png reference to actual format
-                    if name_str.ends_with(".png") {
-                        let base = &name_str[..name_str.len() - 4];
-                        if base.contains("infoLayer") {
-                            return Some(format!("{}.grle", base));
-                        } else if base.contains("densityMap") {
-                            return Some(format!("{}.gdm", base));
-                        }
-                    }
-                    return Some(name_str.to_string());
-                }
-            }
-        }
+/// Value and name of an `<Option>` line. Options without a usable value are
+/// skipped; they would otherwise all collapse onto 0.
+fn parse_option(line: &str) -> Option<(u32, String)> {
+    let value = extract_attr(line, "value").and_then(|s| parse_option_value(&s))?;
+    Some((value, extract_attr(line, "name").unwrap_or_default()))
+}
+
+/// Parse an `<Option>` value written as decimal or `0x`-prefixed hex
+fn parse_option_value(value: &str) -> Option<u32> {
+    let value = value.trim();
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
     }
-    None
 }
 
+/// Name of the file the game loads for a layer. The i3d references a
+/// `.png`, but the file on disk has the extension of the layer's format.
+fn find_filename_by_id(i3d: &I3d, file_id: &str, kind: LayerKind) -> Option<String> {
+    let filename = i3d.file(file_id)?;
+    let path = Path::new(filename).with_extension(kind.extension());
+    Some(path.file_name()?.to_string_lossy().into_owned())
+}
+
+/// Split a camelCase name into capitalized words. Acronym runs stay together
+/// ("GRLEInfo" -> "GRLE Info"), a number is its own word ("type2State" ->
+/// "Type 2 State"), and a suffix after a number stays on it ("i3d" -> "I 3d").
 fn titlecase(s: &str) -> String {
+    let chars: Vec<char> = s.chars().collect();
     let mut result = String::new();
-    let mut prev_upper = false;
 
-    for (i, c) in s.chars().enumerate() {
+    for (i, &c) in chars.iter().enumerate() {
         if i == 0 {
             result.extend(c.to_uppercase());
-            prev_upper = c.is_uppercase();
-        } else if c.is_uppercase() && !prev_upper {
-            result.push(' ');
-            result.push(c);
-            prev_upper = true;
+            continue;
+        }
+        let prev = chars[i - 1];
+        let next_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+        let word_start = if c.is_uppercase() {
+            // The last capital of an acronym starts the next word
+            prev.is_lowercase() || prev.is_ascii_digit() || (prev.is_uppercase() && next_lower)
         } else {
-            result.push(c);
-            prev_upper = c.is_uppercase();
+            c.is_ascii_digit() && prev.is_alphabetic()
+        };
+        if word_start {
+            result.push(' ');
         }
+        result.push(c);
     }
 
     result
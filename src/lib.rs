@@ -0,0 +1,1618 @@
+//! Codecs for GIANTS Engine density map files (GRLE and GDM).
+//!
+//! Everything here works on in-memory buffers or generic `Read`/`Write`
+//! streams. Reading PNGs, discovering parameters from i3d files and writing
+//! to disk is left to the caller.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod zip;
+
+// ============================================================================
+// Errors
+// ============================================================================
+
+#[derive(Debug)]
+pub enum ConvertError {
+    /// Image dimensions the target format cannot represent
+    InvalidDimensions(String),
+    /// Encoding parameters that are inconsistent with each other
+    InvalidParams(String),
+    /// Input that is not a well-formed GRLE/GDM file
+    InvalidFormat(String),
+    /// A file whose header declares a format version this crate does not know
+    UnsupportedVersion { format: &'static str, version: u32 },
+    /// Input shorter than the smallest header (or header fields) it must hold
+    TooSmall { need: usize, got: usize },
+    /// Header declaring a side above the `max_dimension()` decode limit
+    TooLarge { width: usize, height: usize, max: usize },
+    /// GDM header whose compression range boundaries do not strictly increase
+    /// from 0 to the channel count, or leave a range wider than a block value
+    BadCompressionRanges { boundaries: Vec<usize>, num_channels: usize },
+    /// Failure reading from or writing to a stream
+    Io(std::io::Error),
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConvertError::InvalidDimensions(msg) => write!(f, "{}", msg),
+            ConvertError::InvalidParams(msg) => write!(f, "{}", msg),
+            ConvertError::InvalidFormat(msg) => write!(f, "{}", msg),
+            ConvertError::UnsupportedVersion { format, version } => {
+                write!(f, "Unsupported {} version: {}", format, version)
+            }
+            ConvertError::TooSmall { need, got } => {
+                write!(f, "File too small: need at least {} bytes, got {}", need, got)
+            }
+            ConvertError::TooLarge { width, height, max } => {
+                write!(f, "Header declares {}x{}, larger than the {} pixel maximum side", width, height, max)
+            }
+            ConvertError::BadCompressionRanges { boundaries, num_channels } => write!(
+                f,
+                "Compression range boundaries {:?} must strictly increase from 0 to the {} channels, at most {} apart",
+                boundaries, num_channels, GDM_MAX_BIT_DEPTH
+            ),
+            ConvertError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConvertError {}
+
+impl From<std::io::Error> for ConvertError {
+    fn from(e: std::io::Error) -> Self {
+        ConvertError::Io(e)
+    }
+}
+
+// ============================================================================
+// Images
+// ============================================================================
+
+/// Largest side `max_dimension()` allows unless `set_max_dimension` changes it
+pub const DEFAULT_MAX_DIMENSION: usize = 16384;
+
+static MAX_DIMENSION: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_DIMENSION);
+
+/// Largest width or height the decoders accept. Headers declaring more are
+/// rejected before any pixel buffer is allocated.
+pub fn max_dimension() -> usize {
+    MAX_DIMENSION.load(Ordering::Relaxed)
+}
+
+/// Change the decode size limit for the whole process
+pub fn set_max_dimension(side: usize) {
+    MAX_DIMENSION.store(side, Ordering::Relaxed);
+}
+
+fn check_max_dimension(width: usize, height: usize) -> Result<(), ConvertError> {
+    let max = max_dimension();
+    if width > max || height > max {
+        return Err(ConvertError::TooLarge { width, height, max });
+    }
+    Ok(())
+}
+
+/// Image as packed per-pixel values, row-major.
+/// For RGB sources the value is `r | g << 8 | b << 16`.
+#[derive(Debug, Clone)]
+pub struct DecodedImage {
+    pub width: usize,
+    pub height: usize,
+    pub values: Vec<u32>,
+}
+
+fn read_u16_le(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32_le(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+// ============================================================================
+// GRLE Decoder
+// ============================================================================
+
+/// Decode a GRLE RLE stream (including the leading 0x00 byte) into `expected_size` pixels
+pub fn decode_grle_rle(data: &[u8], expected_size: usize) -> Vec<u8> {
+    decode_grle_rle_counted(data, expected_size).0
+}
+
+/// Like `decode_grle_rle`, also returning how many pixels the stream itself
+/// produced before the output was zero-filled to `expected_size`
+pub fn decode_grle_rle_counted(data: &[u8], expected_size: usize) -> (Vec<u8>, usize) {
+    decode_grle_rle_with_progress(data, expected_size, &mut |_, _| {})
+}
+
+/// Like `decode_grle_rle_counted`, calling `progress(pixels_done, expected_size)`
+/// about every percent of the output
+fn decode_grle_rle_with_progress(data: &[u8], expected_size: usize, progress: &mut dyn FnMut(usize, usize)) -> (Vec<u8>, usize) {
+    let step = (expected_size / 100).max(1);
+    let mut next_report = step;
+    let mut output = Vec::with_capacity(expected_size);
+    // Streams normally start with a 0x00 flag byte. Some omit it, and then the
+    // first byte is already a pixel value; only a zero byte can be the flag.
+    let mut i = match data.first() {
+        Some(0) => 1,
+        _ => 0,
+    };
+
+    while i + 1 < data.len() && output.len() < expected_size {
+        let prev = data[i];
+        let new_val = data[i + 1];
+        i += 2;
+
+        if prev == new_val {
+            // Same value: read extended count with 0xff continuation
+            let mut count = 0usize;
+            while i < data.len() && data[i] == 0xff {
+                count += 255;
+                i += 1;
+            }
+            if i < data.len() {
+                count += data[i] as usize;
+                i += 1;
+            }
+            count += 2; // Counts are offset by 2
+
+            let to_emit = count.min(expected_size - output.len());
+            output.extend(std::iter::repeat(prev).take(to_emit));
+        } else {
+            // Transition: emit 1 pixel of prev, back up to re-read new as next prev
+            output.push(prev);
+            i -= 1;
+        }
+
+        if output.len() >= next_report {
+            progress(output.len(), expected_size);
+            next_report = output.len() + step;
+        }
+    }
+
+    let produced = output.len();
+    output.resize(expected_size, 0);
+    progress(expected_size, expected_size);
+    (output, produced)
+}
+
+/// A decoded GRLE file: pixel bytes plus the header fields
+#[derive(Debug, Clone)]
+pub struct DecodedGrle {
+    pub version: u16,
+    pub width: usize,
+    pub height: usize,
+    /// Stream length declared in the header
+    pub declared_len: usize,
+    /// Bytes actually present after the 20-byte header
+    pub available_len: usize,
+    /// Channel count the file was decoded with
+    pub num_channels: usize,
+    /// Pixels the RLE stream produced; fewer than width * height means the
+    /// rest of `pixels` is zero fill
+    pub decoded_pixels: usize,
+    /// `grle_bytes_per_pixel(num_channels)` little-endian bytes per pixel
+    pub pixels: Vec<u8>,
+}
+
+impl DecodedGrle {
+    /// Pixel values with the bytes of each pixel combined
+    pub fn values(&self) -> Vec<u32> {
+        let bytes_per_pixel = grle_bytes_per_pixel(self.num_channels);
+        self.pixels
+            .chunks(bytes_per_pixel)
+            .map(|bytes| bytes.iter().rev().fold(0u32, |value, &b| (value << 8) | b as u32))
+            .collect()
+    }
+}
+
+/// Bytes each pixel takes in a GRLE stream. Info layers with more than 8
+/// channels store their values little-endian over several bytes.
+pub fn grle_bytes_per_pixel(num_channels: usize) -> usize {
+    ((num_channels + 7) / 8).clamp(1, 4)
+}
+
+/// Split values into the GRLE pixel bytes for `num_channels` channels
+pub fn grle_pixel_bytes(values: &[u32], num_channels: usize) -> Vec<u8> {
+    let bytes_per_pixel = grle_bytes_per_pixel(num_channels);
+    values.iter().flat_map(|v| v.to_le_bytes().into_iter().take(bytes_per_pixel)).collect()
+}
+
+/// GRLE header versions with the layout this decoder understands
+pub const GRLE_KNOWN_VERSIONS: &[u16] = &[1];
+
+/// Size of the GRLE header before the RLE stream
+const GRLE_HEADER_LEN: usize = 20;
+/// u16 format version
+const GRLE_VERSION_OFFSET: usize = 4;
+/// u16 width and height in units of `GRLE_SIZE_UNIT`; offsets 8-9 are padding
+const GRLE_WIDTH_OFFSET: usize = 6;
+const GRLE_HEIGHT_OFFSET: usize = 10;
+/// u16 of unknown meaning, 256 in all known files; offsets 14-16 are padding
+const GRLE_UNKNOWN_OFFSET: usize = 12;
+/// 3-byte (stream length - 1)
+const GRLE_STREAM_LEN_OFFSET: usize = 17;
+/// Width and height are stored divided by this
+pub const GRLE_SIZE_UNIT: usize = 256;
+/// Most pixels one stream byte can expand to: 0xff count bytes add 255 each
+const GRLE_MAX_PIXELS_PER_BYTE: usize = 255;
+
+/// Fields of the 20-byte GRLE header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrleHeader {
+    pub version: u16,
+    pub width: usize,
+    pub height: usize,
+    pub unknown: u16,
+    /// Length of the RLE stream after the header
+    pub declared_len: usize,
+}
+
+impl GrleHeader {
+    /// Read the header at the start of `data`. Rejects sizes the declared stream
+    /// could never hold, so a bogus header is not taken for a gigantic image.
+    pub fn parse(data: &[u8]) -> Result<GrleHeader, ConvertError> {
+        if data.len() >= 4 && &data[0..4] != b"GRLE" {
+            return Err(ConvertError::InvalidFormat("Not a valid GRLE file".into()));
+        }
+        if data.len() < GRLE_HEADER_LEN {
+            return Err(ConvertError::TooSmall { need: GRLE_HEADER_LEN, got: data.len() });
+        }
+
+        let stream_len = &data[GRLE_STREAM_LEN_OFFSET..GRLE_STREAM_LEN_OFFSET + 3];
+        let header = GrleHeader {
+            version: read_u16_le(data, GRLE_VERSION_OFFSET),
+            width: read_u16_le(data, GRLE_WIDTH_OFFSET) as usize * GRLE_SIZE_UNIT,
+            height: read_u16_le(data, GRLE_HEIGHT_OFFSET) as usize * GRLE_SIZE_UNIT,
+            unknown: read_u16_le(data, GRLE_UNKNOWN_OFFSET),
+            declared_len: u32::from_le_bytes([stream_len[0], stream_len[1], stream_len[2], 0]) as usize + 1,
+        };
+
+        let pixels = header.width * header.height;
+        if pixels > header.declared_len * GRLE_MAX_PIXELS_PER_BYTE {
+            return Err(ConvertError::InvalidFormat(format!(
+                "GRLE header declares {}x{}, more pixels than its {}-byte stream can hold",
+                header.width, header.height, header.declared_len
+            )));
+        }
+        Ok(header)
+    }
+}
+
+/// Bytes `decode_grle_with_params` returns for this header: `pixels` holds
+/// `grle_bytes_per_pixel(num_channels)` bytes per pixel. The header has no
+/// channel count, so it comes from the caller as it does for decoding.
+pub fn grle_decoded_len(header: &GrleHeader, num_channels: usize) -> usize {
+    header.width.saturating_mul(header.height).saturating_mul(grle_bytes_per_pixel(num_channels))
+}
+
+/// Decode a complete 8-channel GRLE file.
+/// A declared stream length that disagrees with the file is reported through
+/// `declared_len`/`available_len` rather than as an error.
+pub fn decode_grle(data: &[u8]) -> Result<DecodedGrle, ConvertError> {
+    decode_grle_with_params(data, &GrleParams::default())
+}
+
+/// Decode a complete GRLE file with the channel count from `params`
+pub fn decode_grle_with_params(data: &[u8], params: &GrleParams) -> Result<DecodedGrle, ConvertError> {
+    let header = GrleHeader::parse(data)?;
+    if !GRLE_KNOWN_VERSIONS.contains(&header.version) {
+        return Err(ConvertError::UnsupportedVersion { format: "GRLE", version: header.version as u32 });
+    }
+    decode_grle_any_version_with_params(data, params)
+}
+
+/// Decode an 8-channel GRLE file as version 1, whatever version its header declares
+pub fn decode_grle_any_version(data: &[u8]) -> Result<DecodedGrle, ConvertError> {
+    decode_grle_any_version_with_params(data, &GrleParams::default())
+}
+
+/// Decode a GRLE file as version 1 with the channel count from `params`
+pub fn decode_grle_any_version_with_params(data: &[u8], params: &GrleParams) -> Result<DecodedGrle, ConvertError> {
+    decode_grle_any_version_with_progress(data, params, &mut |_, _| {})
+}
+
+/// Like `decode_grle_any_version_with_params`, calling `progress(pixels_done, total)`
+/// as the stream is expanded. The version is not checked; see `GRLE_KNOWN_VERSIONS`.
+pub fn decode_grle_any_version_with_progress(data: &[u8], params: &GrleParams, progress: &mut dyn FnMut(usize, usize)) -> Result<DecodedGrle, ConvertError> {
+    let GrleHeader { version, width, height, declared_len, .. } = GrleHeader::parse(data)?;
+    check_max_dimension(width, height)?;
+    let available_len = data.len() - GRLE_HEADER_LEN;
+
+    // Never consume bytes past the declared stream length
+    let compressed_data = &data[GRLE_HEADER_LEN..GRLE_HEADER_LEN + declared_len.min(available_len)];
+    let num_channels = params.num_channels;
+    let bytes_per_pixel = grle_bytes_per_pixel(num_channels);
+    let (pixels, produced) = decode_grle_rle_with_progress(compressed_data, width * height * bytes_per_pixel, progress);
+    let decoded_pixels = produced / bytes_per_pixel;
+
+    Ok(DecodedGrle { version, width, height, declared_len, available_len, num_channels, decoded_pixels, pixels })
+}
+
+// ============================================================================
+// GRLE Encoder
+// ============================================================================
+
+/// Encode pixels into a GRLE RLE stream (including the leading 0x00 byte)
+pub fn encode_grle_rle(pixels: &[u8]) -> Vec<u8> {
+    // GRLE RLE format:
+    // - Initial 0x00 byte (padding/flag)
+    // - Decoder reads pairs (data[i], data[i+1]):
+    //   - If same: run - read count bytes (0xff=+255, final byte=remainder), emit count+2 pixels
+    //   - If different: transition - emit first pixel, back up 1 byte
+    //
+    // Each pixel value appears once in the stream, except runs which have value twice + count.
+    //
+    // Emitting every run of 2+ as a run is already the smallest choice: two equal
+    // bytes always read as a run, and splitting a run never saves a count byte.
+    // Only the end of the stream leaves room, see below.
+
+    let mut output = Vec::new();
+    output.push(0x00);
+
+    if pixels.is_empty() {
+        return output;
+    }
+
+    let mut i = 0;
+    while i < pixels.len() {
+        let value = pixels[i];
+
+        // Count consecutive identical values
+        let mut run_len = 1;
+        while i + run_len < pixels.len() && pixels[i + run_len] == value {
+            run_len += 1;
+        }
+
+        if run_len >= 2 {
+            // Run: emit (value, value, count) where count = run_len - 2
+            output.push(value);
+            output.push(value);
+
+            let mut remaining = run_len - 2;
+            while remaining >= 255 {
+                output.push(0xff);
+                remaining -= 255;
+            }
+            output.push(remaining as u8);
+
+            i += run_len;
+        } else {
+            // Single pixel - emit value, decoder handles via transition backup
+            output.push(value);
+            i += 1;
+        }
+    }
+
+    // The image ends in a single pixel when it is one pixel long or its last two
+    // pixels differ. That pixel has no following byte to pair with, so the
+    // decoder would stop before emitting it. One different byte makes it a
+    // transition, after which the image is complete. An image ending in a run
+    // already ends with that run's count byte.
+    let n = pixels.len();
+    if n == 1 || pixels[n - 1] != pixels[n - 2] {
+        output.push(pixels[n - 1].wrapping_add(1));
+    }
+
+    output
+}
+
+/// GRLE header fields that are not derived from the image.
+/// Carried over from a template GRLE when re-encoding.
+#[derive(Debug, Clone)]
+pub struct GrleHeaderFields {
+    pub version: u16,
+    pub unknown: u16, // Offset 12, 256 in all known files
+}
+
+impl Default for GrleHeaderFields {
+    fn default() -> Self {
+        GrleHeaderFields {
+            version: 1,
+            unknown: 256,
+        }
+    }
+}
+
+/// Parameters for reading and building a GRLE file
+#[derive(Debug, Clone)]
+pub struct GrleParams {
+    /// Channels of the info layer; more than 8 take several bytes per pixel
+    pub num_channels: usize,
+    pub header: GrleHeaderFields,
+}
+
+impl Default for GrleParams {
+    fn default() -> Self {
+        GrleParams { num_channels: 8, header: GrleHeaderFields::default() }
+    }
+}
+
+impl GrleParams {
+    pub fn new() -> Self {
+        GrleParams::default()
+    }
+
+    /// Channel count, e.g. the `numChannels` an i3d declares for the layer
+    pub fn num_channels(mut self, num_channels: usize) -> Self {
+        self.num_channels = num_channels;
+        self
+    }
+
+    /// Header fields to write, e.g. from a template file
+    pub fn header(mut self, header: GrleHeaderFields) -> Self {
+        self.header = header;
+        self
+    }
+}
+
+/// Build a complete GRLE file with default header fields.
+/// Width and height must be multiples of 256.
+pub fn encode_grle(pixels: &[u8], width: usize, height: usize) -> Vec<u8> {
+    encode_grle_with_params(pixels, width, height, &GrleParams::default())
+}
+
+/// Build a complete GRLE file using the given parameters.
+/// Width and height must be multiples of 256, and `pixels` holds the bytes of
+/// every pixel (see `grle_pixel_bytes`). The channel count is not stored.
+pub fn encode_grle_with_params(pixels: &[u8], width: usize, height: usize, params: &GrleParams) -> Vec<u8> {
+    let header = &params.header;
+
+    // Encode RLE
+    let compressed = encode_grle_rle(pixels);
+
+    // Build GRLE file
+    // Header format (20 bytes):
+    // 0-3:   Magic "GRLE"
+    // 4-5:   Version (1)
+    // 6-7:   Width / 256
+    // 8-9:   Padding (0)
+    // 10-11: Height / 256
+    // 12-13: Unknown (256) - possibly channels or bits
+    // 14-15: Padding (0)
+    // 16-19: Compressed size
+    let mut output = Vec::new();
+
+    // Magic
+    output.extend_from_slice(b"GRLE");
+
+    // Version (1 unless taken from a template)
+    output.extend_from_slice(&header.version.to_le_bytes());
+
+    // Width / 256
+    output.extend_from_slice(&((width / 256) as u16).to_le_bytes());
+
+    // Padding
+    output.extend_from_slice(&[0u8; 2]);
+
+    // Height / 256
+    output.extend_from_slice(&((height / 256) as u16).to_le_bytes());
+
+    // Unknown field (256 unless taken from a template)
+    output.extend_from_slice(&header.unknown.to_le_bytes());
+
+    // Padding
+    output.extend_from_slice(&[0u8; 2]);
+
+    // Compressed size: stored as 0x00 followed by 3-byte LE value
+    // Value stored is (compressed.len() - 1)
+    let comp_size = (compressed.len() - 1) as u32;
+    output.push(0x00);
+    output.push((comp_size & 0xFF) as u8);
+    output.push(((comp_size >> 8) & 0xFF) as u8);
+    output.push(((comp_size >> 16) & 0xFF) as u8);
+
+    // Compressed data
+    output.extend_from_slice(&compressed);
+
+    output
+}
+
+// ============================================================================
+// GDM Decoder
+// ============================================================================
+
+/// Decode one GDM block at `pos`, returning the chunk's values and the block size in bytes.
+/// The block must lie within `data`; `decode_gdm` checks each header before calling this.
+pub fn decode_gdm_block(data: &[u8], pos: usize, chunk_size: usize) -> (Vec<u16>, usize) {
+    let bit_depth = data[pos];
+    let palette_count = data[pos + 1] as usize;
+    let palette_size = 2 * palette_count;
+    let bitmap_size = if bit_depth > 0 { (bit_depth as usize) * 128 } else { 0 };
+    let block_size = 2 + palette_size + bitmap_size;
+
+    let palette: Vec<u16> = (0..palette_count)
+        .map(|i| u16::from_le_bytes([data[pos + 2 + i*2], data[pos + 3 + i*2]]))
+        .collect();
+
+    let total_pixels = chunk_size * chunk_size;
+    let mut pixels = Vec::with_capacity(total_pixels);
+
+    if bit_depth == 0 {
+        let value = *palette.first().unwrap_or(&0);
+        pixels.resize(total_pixels, value);
+    } else {
+        let bitmap = &data[pos + 2 + palette_size..pos + 2 + palette_size + bitmap_size];
+        let bits_per_pixel = bit_depth as usize;
+        let mask = (1u32 << bits_per_pixel) - 1;
+
+        for pixel_idx in 0..total_pixels {
+            let bit_pos = pixel_idx * bits_per_pixel;
+            let byte_idx = bit_pos / 8;
+            let bit_offset = bit_pos % 8;
+
+            // Values above 9 bits can span three bytes
+            let mut raw_value = bitmap[byte_idx] as u32;
+            for k in 1..3 {
+                if byte_idx + k < bitmap.len() {
+                    raw_value |= (bitmap[byte_idx + k] as u32) << (8 * k);
+                }
+            }
+
+            let idx_or_value = ((raw_value >> bit_offset) & mask) as usize;
+
+            // Any block with a palette stores indices into it
+            let pixel_value = if !palette.is_empty() {
+                *palette.get(idx_or_value).unwrap_or(&0)
+            } else {
+                idx_or_value as u16
+            };
+
+            pixels.push(pixel_value);
+        }
+    }
+
+    (pixels, block_size)
+}
+
+/// Deepest block bit depth; chunk values are at most 16 bits
+pub const GDM_MAX_BIT_DEPTH: usize = 16;
+
+/// Chunk side is 2^5; block bitmaps are sized for 32x32 chunks
+const GDM_CHUNK_LOG2: u8 = 5;
+
+/// Most channels a GDM can hold: ranges are recombined into one u32 per pixel
+pub const GDM_MAX_CHANNELS: usize = 32;
+
+/// Size of the block at `pos`, checking its header against the data so a
+/// corrupt header is reported with its chunk instead of reading out of bounds
+fn gdm_block_len(data: &[u8], pos: usize, chunk_idx: usize) -> Result<usize, ConvertError> {
+    let header = data.get(pos..pos + 2).ok_or_else(|| {
+        ConvertError::InvalidFormat(format!("Unexpected end of data at chunk {}", chunk_idx))
+    })?;
+    let (bit_depth, palette_count) = (header[0] as usize, header[1] as usize);
+    if bit_depth > GDM_MAX_BIT_DEPTH {
+        return Err(ConvertError::InvalidFormat(format!(
+            "Block of chunk {} declares {} bits per pixel, more than {}", chunk_idx, bit_depth, GDM_MAX_BIT_DEPTH
+        )));
+    }
+    if palette_count > 1 << bit_depth {
+        return Err(ConvertError::InvalidFormat(format!(
+            "Block of chunk {} declares {} palette entries, more than its {}-bit indices can address",
+            chunk_idx, palette_count, bit_depth
+        )));
+    }
+    if pos + 2 + 2 * palette_count > data.len() {
+        return Err(ConvertError::InvalidFormat(format!(
+            "Palette of chunk {} ({} entries) runs past the end of the data", chunk_idx, palette_count
+        )));
+    }
+    let len = 2 + 2 * palette_count + bit_depth * 128;
+    if pos + len > data.len() {
+        return Err(ConvertError::InvalidFormat(format!(
+            "Block of chunk {} runs past the end of the data", chunk_idx
+        )));
+    }
+    Ok(len)
+}
+
+/// Walk `count` block headers from `pos` without decoding them. Returns where the last
+/// block ends, or None if a header is implausible or a block runs past the data.
+fn gdm_blocks_end(data: &[u8], mut pos: usize, count: usize) -> Option<usize> {
+    for _ in 0..count {
+        let bit_depth = *data.get(pos)? as usize;
+        let palette_count = *data.get(pos + 1)? as usize;
+        if bit_depth > 16 {
+            return None;
+        }
+        pos += 2 + 2 * palette_count + bit_depth * 128;
+        if pos > data.len() {
+            return None;
+        }
+    }
+    Some(pos)
+}
+
+/// A decoded GDM file: combined channel values plus the header fields
+#[derive(Debug, Clone)]
+pub struct DecodedGdm {
+    /// Pixel values with all compression ranges recombined
+    pub image: DecodedImage,
+    pub num_channels: usize,
+    pub num_compression_ranges: usize,
+    /// FoliageMultiLayer type index bits (always 0 for !MDF)
+    pub type_index_channels: usize,
+    /// Header max_bpp byte (offset 6 of !MDF, 10 of "MDF); not used to decode
+    pub max_bpp: u8,
+    /// Largest bit depth of any block in the file
+    pub deepest_block_bits: u8,
+    /// Bytes read, including the header
+    pub bytes_consumed: usize,
+}
+
+/// `!MDF` header, 9 bytes: the magic, then one byte each. There is no
+/// version or type index field.
+const SHORT_GDM_HEADER_LEN: usize = 9;
+/// Side is 2^(dim_log2 + 5)
+const SHORT_GDM_DIM_LOG2_OFFSET: usize = 4;
+/// Chunk side is 2^chunk_log2, always 5
+const SHORT_GDM_CHUNK_LOG2_OFFSET: usize = 5;
+/// Maximum bits per pixel; stored but not needed for decoding
+const SHORT_GDM_MAX_BPP_OFFSET: usize = 6;
+const SHORT_GDM_CHANNELS_OFFSET: usize = 7;
+const SHORT_GDM_RANGES_OFFSET: usize = 8;
+
+/// `"MDF` header, 16 bytes: magic, u32 version, then one byte each up to the
+/// type index, and two reserved bytes
+const LONG_GDM_HEADER_LEN: usize = 16;
+
+/// Which of the two GDM headers a file starts with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GdmVariant {
+    /// `"MDF`: 16 bytes with a version, type index channels and reserved bytes
+    Long,
+    /// `!MDF`: 9 bytes
+    Short,
+}
+
+/// Fields of a GDM header and the compression boundary bytes that follow it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GdmHeader {
+    pub variant: GdmVariant,
+    /// `"MDF` format version (0 in every known file); `!MDF` has none
+    pub version: Option<u32>,
+    pub dimension: usize,
+    pub chunk_size: usize,
+    /// Header max_bpp byte; not needed to decode
+    pub max_bpp: u8,
+    pub num_channels: usize,
+    pub num_compression_ranges: usize,
+    /// Range split points, empty for a single compression range
+    pub compression_channels: Vec<usize>,
+    /// FoliageMultiLayer type index bits (always 0 for !MDF)
+    pub type_index_channels: usize,
+    /// `"MDF` bytes 14-15
+    pub reserved: [u8; 2],
+}
+
+impl GdmHeader {
+    /// Read the header and boundary bytes at the start of `data`. The version
+    /// and size are reported as stored; `decode_gdm` is the one that rejects
+    /// unknown versions and sides above `max_dimension()`.
+    pub fn parse(data: &[u8]) -> Result<GdmHeader, ConvertError> {
+        // The shorter !MDF header is the least any GDM file can hold
+        if data.len() < 4 {
+            return Err(ConvertError::TooSmall { need: SHORT_GDM_HEADER_LEN, got: data.len() });
+        }
+
+        let variant = match &data[0..4] {
+            b"\"MDF" => GdmVariant::Long,
+            b"!MDF" => GdmVariant::Short,
+            _ => return Err(ConvertError::InvalidFormat("Not a valid GDM file".into())),
+        };
+        let header_len = match variant {
+            GdmVariant::Long => LONG_GDM_HEADER_LEN,
+            GdmVariant::Short => SHORT_GDM_HEADER_LEN,
+        };
+        if data.len() < header_len {
+            return Err(ConvertError::TooSmall { need: header_len, got: data.len() });
+        }
+
+        let (version, dim_log2, chunk_log2, max_bpp, num_channels, num_compression_ranges, type_index_channels, reserved) =
+            match variant {
+                GdmVariant::Long => (
+                    Some(read_u32_le(data, 4)),
+                    data[8],
+                    data[9],
+                    data[10],
+                    data[11] as usize,
+                    data[12] as usize,
+                    data[13] as usize,
+                    [data[14], data[15]],
+                ),
+                // !MDF has no version, type index or reserved bytes
+                GdmVariant::Short => (
+                    None,
+                    data[SHORT_GDM_DIM_LOG2_OFFSET],
+                    data[SHORT_GDM_CHUNK_LOG2_OFFSET],
+                    data[SHORT_GDM_MAX_BPP_OFFSET],
+                    data[SHORT_GDM_CHANNELS_OFFSET] as usize,
+                    data[SHORT_GDM_RANGES_OFFSET] as usize,
+                    0,
+                    [0, 0],
+                ),
+            };
+
+        if chunk_log2 != GDM_CHUNK_LOG2 {
+            return Err(ConvertError::InvalidFormat(format!(
+                "GDM header declares chunk_log2 {}; only {} (32x32 chunks) is supported", chunk_log2, GDM_CHUNK_LOG2
+            )));
+        }
+        if num_channels > GDM_MAX_CHANNELS {
+            return Err(ConvertError::InvalidFormat(format!(
+                "GDM header declares {} channels, more than {}", num_channels, GDM_MAX_CHANNELS
+            )));
+        }
+
+        let boundaries_end = header_len + num_compression_ranges.saturating_sub(1);
+        if data.len() < boundaries_end {
+            return Err(ConvertError::TooSmall { need: boundaries_end, got: data.len() });
+        }
+        let compression_channels: Vec<usize> = data[header_len..boundaries_end].iter().map(|&b| b as usize).collect();
+
+        // A boundary byte that is off would otherwise underflow the range widths. The
+        // widths add up to the channel count, at most 32 bits once recombined, and each
+        // range comes from one block of at most 16-bit values.
+        let mut boundaries = vec![0];
+        boundaries.extend_from_slice(&compression_channels);
+        boundaries.push(num_channels);
+        if boundaries.windows(2).any(|w| w[0] >= w[1] || w[1] - w[0] > GDM_MAX_BIT_DEPTH) {
+            return Err(ConvertError::BadCompressionRanges { boundaries, num_channels });
+        }
+
+        Ok(GdmHeader {
+            variant,
+            version,
+            // A huge dim_log2 would overflow; the decoder's size limit rejects it
+            dimension: 1usize.checked_shl(dim_log2 as u32 + 5).unwrap_or(usize::MAX),
+            chunk_size: 1 << chunk_log2,
+            max_bpp,
+            num_channels,
+            num_compression_ranges,
+            compression_channels,
+            type_index_channels,
+            reserved,
+        })
+    }
+
+    /// Length of the fixed header, without the boundary bytes
+    pub fn header_len(&self) -> usize {
+        match self.variant {
+            GdmVariant::Long => LONG_GDM_HEADER_LEN,
+            GdmVariant::Short => SHORT_GDM_HEADER_LEN,
+        }
+    }
+}
+
+/// Parse just the header of a GDM file; see `GdmHeader::parse`
+pub fn parse_gdm_header(data: &[u8]) -> Result<GdmHeader, ConvertError> {
+    GdmHeader::parse(data)
+}
+
+/// Bytes `decode_gdm` returns for this header: one `u32` value per pixel.
+/// Saturates for sides no decoder would accept.
+pub fn gdm_decoded_len(header: &GdmHeader) -> usize {
+    header.dimension.saturating_mul(header.dimension).saturating_mul(std::mem::size_of::<u32>())
+}
+
+/// Header fields and derived layout shared by the GDM decoder and index
+struct GdmLayout {
+    dimension: usize,
+    max_bpp: u8,
+    num_channels: usize,
+    chunk_size: usize,
+    num_compression_ranges: usize,
+    type_index_channels: usize,
+    bits_per_range: Vec<usize>,
+    chunks_per_dim: usize,
+    /// Offset of the first block, after the header and boundary bytes
+    data_start: usize,
+}
+
+fn read_gdm_layout(data: &[u8]) -> Result<GdmLayout, ConvertError> {
+    let header = GdmHeader::parse(data)?;
+    if let Some(version) = header.version.filter(|&v| v != 0) {
+        return Err(ConvertError::UnsupportedVersion { format: "GDM", version });
+    }
+    let GdmHeader { dimension, chunk_size, max_bpp, num_channels, num_compression_ranges, type_index_channels, .. } = header;
+
+    // Check the side before anything is sized from it
+    check_max_dimension(dimension, dimension)?;
+
+    let header_size = header.header_len();
+    let mut compression_boundaries = vec![0usize];
+    compression_boundaries.extend_from_slice(&header.compression_channels);
+    compression_boundaries.push(num_channels);
+    let bits_per_range: Vec<usize> = compression_boundaries.windows(2).take(num_compression_ranges).map(|w| w[1] - w[0]).collect();
+
+    let chunks_per_dim = dimension / chunk_size;
+
+    // One boundary byte per split, but some files keep a reserved byte even for a single
+    // range. Take whichever layout makes the block stream end exactly at the end of the file.
+    let total_blocks = chunks_per_dim * chunks_per_dim * num_compression_ranges;
+    let mut compression_boundaries_size = num_compression_ranges.saturating_sub(1);
+    if gdm_blocks_end(data, header_size + compression_boundaries_size, total_blocks) != Some(data.len())
+        && gdm_blocks_end(data, header_size + compression_boundaries_size + 1, total_blocks) == Some(data.len())
+    {
+        compression_boundaries_size += 1;
+    }
+    let data_start = header_size + compression_boundaries_size;
+
+    Ok(GdmLayout {
+        dimension,
+        max_bpp,
+        num_channels,
+        chunk_size,
+        num_compression_ranges,
+        type_index_channels,
+        bits_per_range,
+        chunks_per_dim,
+        data_start,
+    })
+}
+
+/// Decode a complete GDM file ("MDF or !MDF)
+pub fn decode_gdm(data: &[u8]) -> Result<DecodedGdm, ConvertError> {
+    decode_gdm_with_progress(data, &mut |_, _| {})
+}
+
+/// Like `decode_gdm`, calling `progress(chunks_done, total_chunks)` after each chunk
+pub fn decode_gdm_with_progress(data: &[u8], progress: &mut dyn FnMut(usize, usize)) -> Result<DecodedGdm, ConvertError> {
+    let GdmLayout {
+        dimension,
+        max_bpp,
+        num_channels,
+        chunk_size,
+        num_compression_ranges,
+        type_index_channels,
+        bits_per_range,
+        chunks_per_dim,
+        data_start,
+    } = read_gdm_layout(data)?;
+    let total_chunks = chunks_per_dim * chunks_per_dim;
+
+    let mut values = vec![0u32; dimension * dimension];
+
+    let mut pos = data_start;
+    let mut deepest_block_bits = 0u8;
+
+    for chunk_idx in 0..total_chunks {
+        let mut range_values: Vec<Vec<u16>> = Vec::new();
+
+        for _range_idx in 0..num_compression_ranges {
+            gdm_block_len(data, pos, chunk_idx)?;
+            deepest_block_bits = deepest_block_bits.max(data[pos]);
+            let (pixels, block_size) = decode_gdm_block(data, pos, chunk_size);
+            range_values.push(pixels);
+            pos += block_size;
+        }
+
+        let chunk_row = chunk_idx / chunks_per_dim;
+        let chunk_col = chunk_idx % chunks_per_dim;
+        let base_y = chunk_row * chunk_size;
+        let base_x = chunk_col * chunk_size;
+
+        for pixel_idx in 0..(chunk_size * chunk_size) {
+            let mut combined: u32 = 0;
+            let mut shift = 0;
+            for (range_idx, pixels) in range_values.iter().enumerate() {
+                let val = pixels[pixel_idx] as u32;
+                combined |= val << shift;
+                shift += bits_per_range[range_idx];
+            }
+
+            let py = pixel_idx / chunk_size;
+            let px = pixel_idx % chunk_size;
+            values[(base_y + py) * dimension + base_x + px] = combined;
+        }
+        progress(chunk_idx + 1, total_chunks);
+    }
+
+    Ok(DecodedGdm {
+        image: DecodedImage { width: dimension, height: dimension, values },
+        num_channels,
+        num_compression_ranges,
+        type_index_channels,
+        max_bpp,
+        deepest_block_bits,
+        bytes_consumed: pos,
+    })
+}
+
+/// Location of one encoded block inside a GDM file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GdmBlockRef {
+    /// Byte offset of the block header
+    pub offset: usize,
+    /// Block size in bytes, including the header
+    pub len: usize,
+}
+
+/// Byte offsets of every block in a GDM file, so single chunks can be
+/// re-encoded and spliced without rewriting the rest
+#[derive(Debug, Clone)]
+pub struct GdmIndex {
+    pub chunk_size: usize,
+    pub chunks_per_dim: usize,
+    pub num_compression_ranges: usize,
+    /// Offset of the first block, after the header and boundary bytes
+    pub data_start: usize,
+    /// One entry per range for each chunk, chunks in row-major order
+    pub blocks: Vec<GdmBlockRef>,
+}
+
+impl GdmIndex {
+    /// The per-range blocks of the chunk at (`chunk_row`, `chunk_col`)
+    pub fn chunk(&self, chunk_row: usize, chunk_col: usize) -> Option<&[GdmBlockRef]> {
+        if chunk_row >= self.chunks_per_dim || chunk_col >= self.chunks_per_dim {
+            return None;
+        }
+        let start = (chunk_row * self.chunks_per_dim + chunk_col) * self.num_compression_ranges;
+        self.blocks.get(start..start + self.num_compression_ranges)
+    }
+}
+
+/// Scan a GDM file's block headers without decoding the pixels
+pub fn index_gdm(data: &[u8]) -> Result<GdmIndex, ConvertError> {
+    let layout = read_gdm_layout(data)?;
+    let total_blocks = layout.chunks_per_dim * layout.chunks_per_dim * layout.num_compression_ranges;
+
+    let mut blocks = Vec::with_capacity(total_blocks);
+    let mut pos = layout.data_start;
+    for block_idx in 0..total_blocks {
+        let chunk_idx = block_idx / layout.num_compression_ranges.max(1);
+        let len = gdm_block_len(data, pos, chunk_idx)?;
+        blocks.push(GdmBlockRef { offset: pos, len });
+        pos += len;
+    }
+
+    Ok(GdmIndex {
+        chunk_size: layout.chunk_size,
+        chunks_per_dim: layout.chunks_per_dim,
+        num_compression_ranges: layout.num_compression_ranges,
+        data_start: layout.data_start,
+        blocks,
+    })
+}
+
+// ============================================================================
+// GDM Encoder
+// ============================================================================
+
+/// Encode one chunk of range values into a GDM block
+pub fn encode_gdm_block(pixels: &[u16], chunk_size: usize) -> Vec<u8> {
+    let total_pixels = chunk_size * chunk_size;
+
+    // Find unique values in this chunk
+    let mut unique_values: Vec<u16> = pixels.to_vec();
+    unique_values.sort_unstable();
+    unique_values.dedup();
+
+    let mut output = Vec::new();
+
+    if unique_values.len() == 1 {
+        // Uniform chunk: bit_depth = 0, palette_count = 1
+        output.push(0u8); // bit_depth
+        output.push(1u8); // palette_count
+        output.extend_from_slice(&unique_values[0].to_le_bytes());
+        return output;
+    }
+
+    let bits_for = |v: u32| (32 - v.leading_zeros()).max(1) as u8;
+    let raw_depth = bits_for(*unique_values.last().unwrap() as u32);
+    let palette_depth = bits_for(unique_values.len() as u32 - 1);
+
+    // Up to 4 values always use a 1/2-bit palette. Larger palettes (up to 8 bits,
+    // count fits in a byte) only when they make the block smaller than raw values.
+    let palette_bytes = 2 * unique_values.len() + 128 * palette_depth as usize;
+    let use_palette = unique_values.len() <= 4
+        || (unique_values.len() <= 255 && palette_bytes < 128 * raw_depth as usize);
+
+    let bit_depth = if use_palette { palette_depth } else { raw_depth };
+    let bitmap_size = (bit_depth as usize) * 128;
+    let mut bitmap = vec![0u8; bitmap_size];
+
+    let codes: Vec<u32> = if use_palette {
+        output.push(bit_depth);
+        output.push(unique_values.len() as u8);
+
+        // Write palette
+        for &val in &unique_values {
+            output.extend_from_slice(&val.to_le_bytes());
+        }
+
+        // Palette index of each pixel. The palette is sorted, so indices (and
+        // the output bytes) only depend on the chunk's values.
+        pixels
+            .iter()
+            .take(total_pixels)
+            .map(|p| unique_values.binary_search(p).unwrap_or(0) as u32)
+            .collect()
+    } else {
+        output.push(bit_depth);
+        output.push(0u8); // No palette, raw values
+        pixels.iter().take(total_pixels).map(|&p| p as u32).collect()
+    };
+
+    for (pixel_idx, &code) in codes.iter().enumerate() {
+        let bit_pos = pixel_idx * (bit_depth as usize);
+        let byte_idx = bit_pos / 8;
+        let bit_offset = bit_pos % 8;
+
+        // Values above 9 bits can span three bytes
+        let shifted = code << bit_offset;
+        for k in 0..3 {
+            if byte_idx + k < bitmap.len() {
+                bitmap[byte_idx + k] |= (shifted >> (8 * k)) as u8;
+            }
+        }
+    }
+
+    output.extend_from_slice(&bitmap);
+    output
+}
+
+/// "MDF header bytes that are not derived from the image or layer parameters.
+/// Carried over verbatim from a template GDM when re-encoding.
+#[derive(Debug, Clone, Default)]
+pub struct GdmHeaderFields {
+    /// `None` writes the largest bit depth of any block the encoder emits
+    pub max_bpp: Option<u8>,
+    /// Smallest value written when `max_bpp` is `None`, e.g. a template's byte
+    pub max_bpp_floor: u8,
+    pub type_index_channels: u8,
+    pub reserved: [u8; 2],
+}
+
+/// Parameters for building a GDM file
+#[derive(Debug, Clone, Default)]
+pub struct GdmParams {
+    pub num_channels: usize,
+    /// Range split points, empty for a single compression range
+    pub compression_channels: Vec<usize>,
+    /// FoliageMultiLayer type index bits, overrides `header.type_index_channels`
+    pub type_index_channels: Option<usize>,
+    pub header: GdmHeaderFields,
+}
+
+impl GdmParams {
+    /// A single compression range over `num_channels` bits and default header fields
+    pub fn new(num_channels: usize) -> Self {
+        GdmParams { num_channels, ..Default::default() }
+    }
+
+    /// Split the channels into compression ranges at these channel indices
+    pub fn compression_channels(mut self, splits: Vec<usize>) -> Self {
+        self.compression_channels = splits;
+        self
+    }
+
+    /// FoliageMultiLayer type index bits (the bits above are the growth state)
+    pub fn type_index_channels(mut self, channels: usize) -> Self {
+        self.type_index_channels = Some(channels);
+        self
+    }
+
+    /// Header fields to write, e.g. from a template file
+    pub fn header(mut self, header: GdmHeaderFields) -> Self {
+        self.header = header;
+        self
+    }
+}
+
+/// Channels, compression ranges and type index as the i3d declares them
+impl From<&LayerDef> for GdmParams {
+    fn from(layer: &LayerDef) -> Self {
+        GdmParams {
+            num_channels: layer.num_channels,
+            compression_channels: layer.compression_channels.clone(),
+            type_index_channels: layer.type_index_channels,
+            header: GdmHeaderFields::default(),
+        }
+    }
+}
+
+/// Why `width`x`height` can't be a GDM image, with the nearest square,
+/// power-of-two sizes that can (the header stores the side as 2^(dim_log2 + 5))
+fn gdm_dimension_error(width: usize, height: usize) -> String {
+    let side = width.max(height);
+    let larger = side.max(32).next_power_of_two();
+    let smaller = larger / 2;
+    let sizes = if larger == side || smaller < 32 {
+        format!("{0}x{0}", larger)
+    } else if side - smaller < larger - side {
+        format!("{0}x{0} (or {1}x{1})", smaller, larger)
+    } else {
+        format!("{0}x{0} (or {1}x{1})", larger, smaller)
+    };
+    let reason = if width != height {
+        "must be square"
+    } else if side < 32 {
+        "must be at least 32x32"
+    } else {
+        "must have a power-of-two side"
+    };
+    format!("GDM images {}, got {}x{}; nearest legal size: {}", reason, width, height, sizes)
+}
+
+/// Build a complete "MDF GDM file from packed pixel values
+pub fn encode_gdm(pixels: &DecodedImage, params: &GdmParams) -> Result<Vec<u8>, ConvertError> {
+    encode_gdm_with_progress(pixels, params, &mut |_, _| {})
+}
+
+/// Like `encode_gdm`, calling `progress(chunks_done, total_chunks)` after each chunk
+pub fn encode_gdm_with_progress(pixels: &DecodedImage, params: &GdmParams, progress: &mut dyn FnMut(usize, usize)) -> Result<Vec<u8>, ConvertError> {
+    let width = pixels.width;
+    let height = pixels.height;
+
+    if width != height || width < 32 || !width.is_power_of_two() {
+        return Err(ConvertError::InvalidDimensions(gdm_dimension_error(width, height)));
+    }
+    if pixels.values.len() != width * height {
+        return Err(ConvertError::InvalidDimensions(format!(
+            "Expected {} pixel values for {}x{}, got {}",
+            width * height, width, height, pixels.values.len()
+        )));
+    }
+
+    let dimension = width;
+
+    // Calculate dimension log2 (dimension = 2^(dim_log2 + 5))
+    let dim_log2 = dimension.trailing_zeros() as usize - 5;
+
+    let chunk_size = 1usize << GDM_CHUNK_LOG2;
+    let chunks_per_dim = dimension / chunk_size;
+
+    let num_channels = params.num_channels;
+    let compression_channels = &params.compression_channels;
+    if num_channels > GDM_MAX_CHANNELS {
+        return Err(ConvertError::InvalidParams(format!(
+            "{} channels exceed the GDM limit of {}", num_channels, GDM_MAX_CHANNELS
+        )));
+    }
+
+    // Lower bits hold the foliage type index, the remaining bits the growth state
+    let type_index_channels = match params.type_index_channels {
+        Some(n) if n > num_channels => {
+            return Err(ConvertError::InvalidParams(format!(
+                "Type index channels ({}) exceed channel count ({})", n, num_channels
+            )));
+        }
+        Some(n) => n as u8,
+        None => params.header.type_index_channels,
+    };
+
+    // Determine compression ranges from the split points (same layout the decoder rebuilds)
+    let mut compression_boundaries = vec![0usize];
+    compression_boundaries.extend_from_slice(compression_channels);
+    compression_boundaries.push(num_channels);
+
+    if compression_boundaries.windows(2).any(|w| w[0] >= w[1]) {
+        return Err(ConvertError::InvalidParams(format!(
+            "Compression splits must be increasing and between 1 and {}, got {:?}",
+            num_channels.saturating_sub(1), compression_channels
+        )));
+    }
+
+    let bits_per_range: Vec<usize> = compression_boundaries.windows(2).map(|w| w[1] - w[0]).collect();
+    if let Some(&bits) = bits_per_range.iter().find(|&&b| b > 16) {
+        return Err(ConvertError::InvalidParams(format!(
+            "Compression range of {} channels exceeds the 16-bit block limit", bits
+        )));
+    }
+    let num_compression_ranges = bits_per_range.len();
+
+    let channel_values = &pixels.values;
+
+    // Build GDM file
+    let mut output = Vec::new();
+
+    // Header ("MDF variant)
+    output.extend_from_slice(b"\"MDF");
+    output.extend_from_slice(&0u32.to_le_bytes()); // version
+    output.push(dim_log2 as u8);
+    output.push(GDM_CHUNK_LOG2);
+    const MAX_BPP_OFFSET: usize = 10;
+    output.push(params.header.max_bpp.unwrap_or(0));
+    output.push(num_channels as u8);
+    output.push(num_compression_ranges as u8);
+    output.push(type_index_channels);
+    output.extend_from_slice(&params.header.reserved); // padding to 16 bytes
+
+    // Compression boundaries (one byte per split, none for a single range)
+    for &cc in compression_channels {
+        output.push(cc as u8);
+    }
+
+    // Encode chunks
+    let mut max_bit_depth = 0u8;
+    for chunk_idx in 0..(chunks_per_dim * chunks_per_dim) {
+        let chunk_row = chunk_idx / chunks_per_dim;
+        let chunk_col = chunk_idx % chunks_per_dim;
+        let base_y = chunk_row * chunk_size;
+        let base_x = chunk_col * chunk_size;
+
+        // Extract pixel values for this chunk
+        let mut chunk_pixels: Vec<u32> = Vec::with_capacity(chunk_size * chunk_size);
+        for py in 0..chunk_size {
+            for px in 0..chunk_size {
+                let img_x = base_x + px;
+                let img_y = base_y + py;
+                chunk_pixels.push(channel_values[img_y * dimension + img_x]);
+            }
+        }
+
+        // Encode each compression range
+        let mut shift = 0;
+        for &range_bits in &bits_per_range {
+            let mask = (1u32 << range_bits) - 1;
+
+            // Extract range values from combined pixel values
+            let range_pixels: Vec<u16> = chunk_pixels
+                .iter()
+                .map(|&v| ((v >> shift) & mask) as u16)
+                .collect();
+
+            let block = encode_gdm_block(&range_pixels, chunk_size);
+            max_bit_depth = max_bit_depth.max(block[0]);
+            output.extend_from_slice(&block);
+
+            shift += range_bits;
+        }
+        progress(chunk_idx + 1, chunks_per_dim * chunks_per_dim);
+    }
+
+    if params.header.max_bpp.is_none() {
+        output[MAX_BPP_OFFSET] = max_bit_depth.max(params.header.max_bpp_floor);
+    }
+
+    Ok(output)
+}
+
+// ============================================================================
+// Streams
+// ============================================================================
+
+/// Decode a GDM file read to the end of `reader`, e.g. an entry of an archive
+pub fn decode_gdm_from_reader<R: Read>(mut reader: R) -> Result<DecodedGdm, ConvertError> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    decode_gdm(&data)
+}
+
+/// Decode a GRLE file read to the end of `reader`
+pub fn decode_grle_from_reader<R: Read>(mut reader: R, params: &GrleParams) -> Result<DecodedGrle, ConvertError> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    decode_grle_with_params(&data, params)
+}
+
+/// Encode a GRLE file into `writer` and flush it; returns the bytes written
+pub fn encode_grle_to_writer<W: Write>(mut writer: W, pixels: &[u8], width: usize, height: usize, params: &GrleParams) -> Result<usize, ConvertError> {
+    let data = encode_grle_with_params(pixels, width, height, params);
+    writer.write_all(&data)?;
+    writer.flush()?;
+    Ok(data.len())
+}
+
+/// Encode a GDM file into `writer` and flush it; returns the bytes written
+pub fn encode_gdm_to_writer<W: Write>(mut writer: W, pixels: &DecodedImage, params: &GdmParams) -> Result<usize, ConvertError> {
+    let data = encode_gdm(pixels, params)?;
+    writer.write_all(&data)?;
+    writer.flush()?;
+    Ok(data.len())
+}
+
+// ============================================================================
+// I3D Layer Definitions
+// ============================================================================
+
+/// Which i3d element defines a density map
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerKind {
+    /// `<InfoLayer>`, stored as GRLE
+    InfoLayer,
+    /// `<DetailLayer>`, stored as GDM
+    DetailLayer,
+    /// `<FoliageMultiLayer>`, stored as GDM
+    FoliageMultiLayer,
+}
+
+impl LayerKind {
+    /// Extension of the file the game loads for this layer: `grle` or `gdm`
+    pub fn extension(&self) -> &'static str {
+        match self {
+            LayerKind::InfoLayer => "grle",
+            LayerKind::DetailLayer | LayerKind::FoliageMultiLayer => "gdm",
+        }
+    }
+}
+
+/// Encoding parameters of one layer, as declared in the i3d
+#[derive(Debug, Clone)]
+pub struct LayerDef {
+    pub kind: LayerKind,
+    pub name: String,
+    /// `fileId` (InfoLayer) or `densityMapId` of the layer's `<File>` entry
+    pub file_id: String,
+    pub num_channels: usize,
+    /// GDM range split points, empty for a single range
+    pub compression_channels: Vec<usize>,
+    /// FoliageMultiLayer type index bits
+    pub type_index_channels: Option<usize>,
+    /// `<Group>`/`<Option>` value names declared inside the layer element
+    pub groups: Vec<LayerGroup>,
+}
+
+/// A named bit field of a layer and the values the i3d gives names to
+#[derive(Debug, Clone)]
+pub struct LayerGroup {
+    pub name: String,
+    pub first_channel: usize,
+    pub num_channels: usize,
+    /// (value within the group, name)
+    pub options: Vec<(u32, String)>,
+}
+
+impl LayerDef {
+    /// Names the i3d gives to the fields of a pixel value, e.g. "type: grass".
+    /// None when no group has a named option for its part of the value.
+    pub fn meaning(&self, value: u32) -> Option<String> {
+        let parts: Vec<String> = self
+            .groups
+            .iter()
+            .filter_map(|group| {
+                let mask = if group.num_channels >= 32 { u32::MAX } else { (1u32 << group.num_channels) - 1 };
+                let field = value.checked_shr(group.first_channel as u32).unwrap_or(0) & mask;
+                let (_, option) = group.options.iter().find(|(v, _)| *v == field)?;
+                Some(if group.name.is_empty() { option.clone() } else { format!("{}: {}", group.name, option) })
+            })
+            .collect();
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(", "))
+        }
+    }
+}
+
+/// The parts of a map i3d needed to encode its density maps.
+/// Parse once and query per file instead of rescanning the XML.
+#[derive(Debug, Clone, Default)]
+pub struct I3d {
+    /// `<File fileId=".." filename=".."/>` entries
+    pub files: HashMap<String, String>,
+    pub layers: Vec<LayerDef>,
+}
+
+impl I3d {
+    /// Read and parse an i3d file
+    pub fn parse(path: &Path) -> std::io::Result<I3d> {
+        Ok(I3d::parse_str(&read_xml(path)?))
+    }
+
+    /// Parse i3d content. Like the rest of the tool this is line-based; tags
+    /// that the editor wrapped over several lines are joined first.
+    pub fn parse_str(content: &str) -> I3d {
+        let mut i3d = I3d::default();
+        let mut in_layer = false;
+
+        for line in join_multiline_tags(content).lines() {
+            if line.contains("<File ") {
+                if let (Some(id), Some(filename)) = (attr_str(line, "fileId"), attr_str(line, "filename")) {
+                    i3d.files.entry(id.to_string()).or_insert_with(|| filename.to_string());
+                }
+            } else if line.contains("<InfoLayer ") {
+                if let (Some(file_id), Some(num_channels)) = (attr_str(line, "fileId"), attr_usize(line, "numChannels")) {
+                    i3d.layers.push(LayerDef {
+                        kind: LayerKind::InfoLayer,
+                        name: attr_str(line, "name").unwrap_or_default().to_string(),
+                        file_id: file_id.to_string(),
+                        num_channels,
+                        compression_channels: Vec::new(),
+                        type_index_channels: None,
+                        groups: Vec::new(),
+                    });
+                }
+            } else if line.contains("<DetailLayer ") {
+                if let (Some(file_id), Some(num_channels)) = (attr_str(line, "densityMapId"), attr_usize(line, "numDensityMapChannels")) {
+                    i3d.layers.push(LayerDef {
+                        kind: LayerKind::DetailLayer,
+                        name: attr_str(line, "name").unwrap_or_default().to_string(),
+                        file_id: file_id.to_string(),
+                        num_channels,
+                        compression_channels: attr_splits(line, "compressionChannels", num_channels),
+                        type_index_channels: None,
+                        groups: Vec::new(),
+                    });
+                }
+            } else if line.contains("<FoliageMultiLayer ") {
+                if let (Some(file_id), Some(num_channels)) = (attr_str(line, "densityMapId"), attr_usize(line, "numChannels")) {
+                    i3d.layers.push(LayerDef {
+                        kind: LayerKind::FoliageMultiLayer,
+                        name: String::new(),
+                        file_id: file_id.to_string(),
+                        num_channels,
+                        compression_channels: attr_splits(line, "compressionChannels", num_channels),
+                        type_index_channels: attr_usize(line, "numTypeIndexChannels"),
+                        groups: Vec::new(),
+                    });
+                }
+            } else if line.contains("<Group ") {
+                if let Some(layer) = i3d.layers.last_mut().filter(|_| in_layer) {
+                    layer.groups.push(LayerGroup {
+                        name: attr_str(line, "name").unwrap_or_default().to_string(),
+                        first_channel: attr_usize(line, "firstChannel").unwrap_or(0),
+                        num_channels: attr_usize(line, "numChannels").unwrap_or(1),
+                        options: Vec::new(),
+                    });
+                }
+            } else if line.contains("<Option ") {
+                let group = i3d.layers.last_mut().filter(|_| in_layer).and_then(|l| l.groups.last_mut());
+                if let (Some(group), Some(value)) = (group, attr_str(line, "value").and_then(parse_number)) {
+                    group.options.push((value, attr_str(line, "name").unwrap_or_default().to_string()));
+                }
+            }
+
+            // Groups and options belong to the layer element that encloses them
+            let trimmed = line.trim();
+            if ["<InfoLayer ", "<DetailLayer ", "<FoliageMultiLayer "].iter().any(|t| trimmed.starts_with(t)) {
+                in_layer = !trimmed.ends_with("/>");
+            } else if ["</InfoLayer>", "</DetailLayer>", "</FoliageMultiLayer>"].iter().any(|t| trimmed.starts_with(t)) {
+                in_layer = false;
+            }
+        }
+
+        i3d
+    }
+
+    /// Filename of a `<File>` entry
+    pub fn file(&self, file_id: &str) -> Option<&str> {
+        self.files.get(file_id).map(|s| s.as_str())
+    }
+
+    /// Layer whose `<File>` entry has the basename `png_name` (i3d files reference
+    /// density maps by their .png name, e.g. "infoLayer_farmlands.png")
+    pub fn layer_for_png(&self, png_name: &str) -> Option<&LayerDef> {
+        let mut ids: Vec<&String> = self
+            .files
+            .iter()
+            .filter(|(_, filename)| filename.rsplit(['/', '\\']).next() == Some(png_name))
+            .map(|(id, _)| id)
+            .collect();
+        ids.sort();
+
+        // InfoLayers win over DetailLayers over FoliageMultiLayers, as before
+        [LayerKind::InfoLayer, LayerKind::DetailLayer, LayerKind::FoliageMultiLayer]
+            .iter()
+            .find_map(|&kind| {
+                self.layers
+                    .iter()
+                    .find(|l| l.kind == kind && ids.iter().any(|id| **id == l.file_id))
+            })
+    }
+}
+
+/// Read an XML or i3d file for the line-based parsers: a UTF-8 byte order
+/// mark is dropped and CRLF or lone CR line endings become LF
+pub fn read_xml<P: AsRef<Path>>(path: P) -> std::io::Result<String> {
+    Ok(normalize_xml_text(&std::fs::read_to_string(path)?))
+}
+
+/// Text written by Windows tools can start with a BOM, which would stick to
+/// the first tag, and mix in CRs that `lines()` leaves in place
+fn normalize_xml_text(content: &str) -> String {
+    let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+    content.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// Join tags whose attributes are wrapped over several lines, so every tag
+/// starts and ends on one line for the line-based parsers. Line endings are
+/// normalized as by `read_xml` first.
+pub fn join_multiline_tags(content: &str) -> String {
+    let content = normalize_xml_text(content);
+    let mut output = String::with_capacity(content.len());
+    let mut pending = String::new();
+    let mut in_tag = false;
+    let mut quote: Option<char> = None;
+    // Inside `<!-- ... -->` or `<?...?>`, where quotes and `>` mean nothing
+    // until the closing marker
+    let mut close_marker: Option<&str> = None;
+
+    for line in content.lines() {
+        if pending.is_empty() {
+            pending.push_str(line);
+        } else {
+            pending.push(' ');
+            pending.push_str(line.trim());
+        }
+
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            if let Some(marker) = close_marker {
+                match rest.find(marker) {
+                    Some(end) => {
+                        rest = &rest[end + marker.len()..];
+                        close_marker = None;
+                        in_tag = false;
+                    }
+                    None => break,
+                }
+                continue;
+            }
+            match (c, quote) {
+                ('"' | '\'', None) if in_tag => quote = Some(c),
+                (c, Some(q)) if c == q => quote = None,
+                ('<', None) if rest.starts_with("<!--") || rest.starts_with("<?") => {
+                    let (open, close) = if rest.starts_with("<?") { ("<?", "?>") } else { ("<!--", "-->") };
+                    close_marker = Some(close);
+                    in_tag = true;
+                    rest = &rest[open.len()..];
+                    continue;
+                }
+                ('<', None) => in_tag = true,
+                ('>', None) => in_tag = false,
+                _ => {}
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+
+        if !in_tag {
+            output.push_str(&pending);
+            output.push('\n');
+            pending.clear();
+        }
+    }
+    output.push_str(&pending);
+
+    output
+}
+
+/// Value of `attr="..."` (or `attr='...'`) on an XML line
+fn attr_str<'a>(line: &'a str, attr: &str) -> Option<&'a str> {
+    let pattern = format!("{}=", attr);
+    // Skip matches inside longer names ("numChannels" in "heightNumChannels")
+    let rest = line
+        .match_indices(&pattern)
+        .filter(|&(i, _)| line[..i].ends_with(char::is_whitespace))
+        .find_map(|(i, _)| {
+            let rest = &line[i + pattern.len()..];
+            rest.starts_with(['"', '\'']).then_some(rest)
+        })?;
+    let quote = rest.chars().next()?;
+    let rest = &rest[1..];
+    Some(&rest[..rest.find(quote)?])
+}
+
+/// Decimal or `0x`-prefixed hex
+fn parse_number(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn attr_usize(line: &str, attr: &str) -> Option<usize> {
+    attr_str(line, attr)?.parse().ok()
+}
+
+/// Compression range boundaries: one split point, or several separated by
+/// spaces or commas ("4 8 12"). The outer boundaries 0 and `num_channels` are
+/// implied, so they are dropped if the list spells them out. A list with an
+/// entry that is not a number gives no splits at all.
+fn attr_splits(line: &str, attr: &str, num_channels: usize) -> Vec<usize> {
+    let Some(value) = attr_str(line, attr) else { return Vec::new() };
+    let splits: Option<Vec<usize>> = value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse().ok())
+        .collect();
+    splits.unwrap_or_default().into_iter().filter(|&c| c > 0 && c < num_channels).collect()
+}
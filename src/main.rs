@@ -1,7 +1,58 @@
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use grleconvert::{
+    decode_gdm, decode_gdm_from_reader, decode_gdm_with_progress, decode_grle, decode_grle_any_version_with_progress, decode_grle_with_params,
+    encode_gdm, encode_gdm_with_progress, encode_grle_to_writer, encode_grle_with_params, grle_pixel_bytes, index_gdm,
+    parse_gdm_header, set_max_dimension, ConvertError, DecodedGrle, DecodedImage, GdmHeaderFields, GdmParams, GdmVariant, GrleHeader,
+    GrleHeaderFields, GrleParams, I3d, LayerKind, DEFAULT_MAX_DIMENSION, GDM_MAX_BIT_DEPTH, GRLE_KNOWN_VERSIONS,
+};
+use grleconvert::zip;
+
+// ============================================================================
+// Logging
+// ============================================================================
+
+/// 0 = errors only (--quiet), 1 = default, 2 = -v, 3 = -vv
+static VERBOSITY: AtomicU8 = AtomicU8::new(1);
+
+fn verbosity() -> u8 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+/// Print a diagnostic line to stderr if the verbosity level is at least `$level`
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)*) => {
+        if verbosity() >= $level {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Default-level output (suppressed by --quiet)
+macro_rules! info {
+    ($($arg:tt)*) => { log_at!(1, $($arg)*) };
+}
+
+/// Warnings (suppressed by --quiet)
+macro_rules! warn {
+    ($($arg:tt)*) => { log_at!(1, "Warning: {}", format!($($arg)*)) };
+}
+
+/// Extra detail shown with -v
+macro_rules! verbose {
+    ($($arg:tt)*) => { log_at!(2, $($arg)*) };
+}
+
+/// Low-level detail shown with -vv
+macro_rules! debug {
+    ($($arg:tt)*) => { log_at!(3, $($arg)*) };
+}
 
 // ============================================================================
 // I3D Parsing for encoding parameters
@@ -11,7 +62,20 @@ use std::path::{Path, PathBuf};
 struct LayerParams {
     layer_type: LayerType,
     num_channels: usize,
-    compression_channels: Option<usize>, // For GDM with multiple ranges
+    compression_channels: Vec<usize>, // GDM range split points, empty for a single range
+    type_index_channels: Option<usize>, // FoliageMultiLayer type index bits (rest is state)
+    expected_size: Option<(usize, usize, PathBuf)>, // Size of the layer's existing file next to the i3d
+}
+
+impl LayerParams {
+    /// GDM encoding parameters for this layer; GRLE layers only need the header
+    fn gdm_params(&self, header: &GdmHeaderFields) -> GdmParams {
+        let mut params = GdmParams::new(self.num_channels)
+            .compression_channels(self.compression_channels.clone())
+            .header(header.clone());
+        params.type_index_channels = self.type_index_channels;
+        params
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -20,10 +84,21 @@ enum LayerType {
     GdmLayer,   // GDM (DetailLayer or FoliageMultiLayer)
 }
 
-/// Find i3d file by walking up the directory hierarchy
-fn find_i3d_file(start_path: &Path) -> Option<PathBuf> {
+/// True for a mod or DLC root (directory containing modDesc.xml or dlcDesc.xml)
+fn is_mod_root(dir: &Path) -> bool {
+    dir.join("modDesc.xml").exists() || dir.join("dlcDesc.xml").exists()
+}
+
+/// Find i3d file by walking up the directory hierarchy.
+/// The walk stops at the mod root so i3d files of sibling mods are never picked up.
+/// When a directory holds several i3d files, the one that references `target_filename`
+/// wins; an error lists them if that still leaves more than one.
+fn find_i3d_file(start_path: &Path, target_filename: &str, cache: &mut I3dCache) -> Result<Option<PathBuf>, String> {
     let mut current = if start_path.is_file() {
-        start_path.parent()?.to_path_buf()
+        match start_path.parent() {
+            Some(p) => p.to_path_buf(),
+            None => return Ok(None),
+        }
     } else {
         start_path.to_path_buf()
     };
@@ -31,14 +106,60 @@ fn find_i3d_file(start_path: &Path) -> Option<PathBuf> {
     loop {
         // Look for *.i3d in current directory
         if let Ok(entries) = std::fs::read_dir(&current) {
-            for entry in entries.flatten() {
-                let path = entry.path();
-                if path.extension().and_then(|e| e.to_str()) == Some("i3d") {
-                    return Some(path);
+            let mut candidates: Vec<PathBuf> = entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("i3d"))
+                .collect();
+            candidates.sort();
+
+            if candidates.len() == 1 {
+                return Ok(candidates.pop());
+            }
+
+            if candidates.len() > 1 {
+                debug!("Candidates in {}: {:?}", current.display(), candidates);
+
+                // Only i3d files that define a layer for the target are of use
+                let matching: Vec<&PathBuf> = candidates
+                    .iter()
+                    .filter(|p| {
+                        cache
+                            .get(p)
+                            .and_then(|i3d| layer_params_for_file(i3d, target_filename))
+                            .is_some()
+                    })
+                    .collect();
+
+                // Prefer the map's own i3d (mapUS.i3d, map.i3d) over props in the same folder
+                let is_map = |path: &&PathBuf| {
+                    path.file_stem()
+                        .and_then(|s| s.to_str())
+                        .map(|s| s.to_lowercase().starts_with("map"))
+                        .unwrap_or(false)
+                };
+                let maps: Vec<&PathBuf> = matching.iter().copied().filter(is_map).collect();
+
+                match (matching.len(), maps.len()) {
+                    (0, _) => {}
+                    (1, _) => return Ok(Some(matching[0].clone())),
+                    (_, 1) => return Ok(Some(maps[0].clone())),
+                    _ => {
+                        let names: Vec<String> = matching.iter().map(|p| p.display().to_string()).collect();
+                        return Err(format!(
+                            "Several i3d files define {}: {} (use --i3d to choose)",
+                            target_filename, names.join(", ")
+                        ));
+                    }
                 }
             }
         }
 
+        if is_mod_root(&current) {
+            debug!("Reached mod root {}", current.display());
+            break;
+        }
+
         // Move up one directory
         if let Some(parent) = current.parent() {
             current = parent.to_path_buf();
@@ -46,685 +167,1272 @@ fn find_i3d_file(start_path: &Path) -> Option<PathBuf> {
             break;
         }
     }
-    None
+    Ok(None)
 }
 
-/// Parse i3d file to find layer parameters for a given filename
-fn parse_i3d_for_file(i3d_path: &Path, target_filename: &str) -> Option<LayerParams> {
-    let content = std::fs::read_to_string(i3d_path).ok()?;
-
-    // Step 1: Find fileId for the target filename (match basename, ignore path prefix like $data/)
-    let target_basename = Path::new(target_filename)
-        .file_name()?
-        .to_str()?;
-
-    // Also try with .png extension since i3d references PNG files
-    let target_png = if target_basename.ends_with(".png") {
-        target_basename.to_string()
-    } else {
-        let stem = Path::new(target_basename).file_stem()?.to_str()?;
-        format!("{}.png", stem)
-    };
+/// Parsed i3d files by path, so each is read and scanned only once per run
+#[derive(Default)]
+struct I3dCache {
+    parsed: HashMap<PathBuf, Option<I3d>>,
+}
 
-    eprintln!("Looking for file: {}", target_png);
-
-    // Find <File fileId="NNN" filename="...target_png"/>
-    let mut file_id: Option<&str> = None;
-    for line in content.lines() {
-        if line.contains("<File") && line.contains(&target_png) {
-            // Extract fileId
-            if let Some(start) = line.find("fileId=\"") {
-                let rest = &line[start + 8..];
-                if let Some(end) = rest.find('"') {
-                    file_id = Some(&rest[..end]);
-                    eprintln!("Found fileId: {}", &rest[..end]);
-                    break;
+impl I3dCache {
+    fn get(&mut self, path: &Path) -> Option<&I3d> {
+        self.parsed
+            .entry(path.to_path_buf())
+            .or_insert_with(|| match I3d::parse(path) {
+                Ok(i3d) => Some(i3d),
+                Err(e) => {
+                    warn!("Could not read {}: {}", path.display(), e);
+                    None
                 }
-            }
-        }
+            })
+            .as_ref()
     }
+}
 
-    let file_id = file_id?;
+/// Look up the layer parameters for a given filename in a parsed i3d
+fn layer_params_for_file(i3d: &I3d, target_filename: &str) -> Option<LayerParams> {
+    // Match the basename with a .png extension, since i3d files reference PNG files
+    let target_png = format!("{}.png", base_stem(target_filename));
+    debug!("Looking for file: {}", target_png);
 
-    // Step 2: Find layer definition that references this fileId
-    // Check InfoLayer (GRLE)
-    for line in content.lines() {
-        if line.contains("<InfoLayer") && line.contains(&format!("fileId=\"{}\"", file_id)) {
-            // Extract numChannels
-            if let Some(num_channels) = extract_attr(line, "numChannels") {
-                eprintln!("Found InfoLayer with {} channels → GRLE", num_channels);
-                return Some(LayerParams {
-                    layer_type: LayerType::InfoLayer,
-                    num_channels,
-                    compression_channels: None,
-                });
-            }
-        }
-    }
+    let layer = i3d.layer_for_png(&target_png)?;
+    debug!("Found fileId: {}", layer.file_id);
 
-    // Check DetailLayer (GDM) - uses densityMapId
-    for line in content.lines() {
-        if line.contains("<DetailLayer") && line.contains(&format!("densityMapId=\"{}\"", file_id)) {
-            let num_channels = extract_attr(line, "numDensityMapChannels")?;
-            let compression_channels = extract_attr(line, "compressionChannels");
-            eprintln!("Found DetailLayer with {} channels, compression: {:?} → GDM",
-                     num_channels, compression_channels);
-            return Some(LayerParams {
-                layer_type: LayerType::GdmLayer,
-                num_channels,
-                compression_channels,
-            });
+    let layer_type = match layer.kind {
+        LayerKind::InfoLayer => {
+            verbose!("Found InfoLayer with {} channels → GRLE", layer.num_channels);
+            LayerType::InfoLayer
+        }
+        LayerKind::DetailLayer => {
+            verbose!("Found DetailLayer with {} channels, compression: {:?} → GDM",
+                     layer.num_channels, layer.compression_channels);
+            LayerType::GdmLayer
+        }
+        LayerKind::FoliageMultiLayer => {
+            verbose!("Found FoliageMultiLayer with {} channels, compression: {:?}, type index: {:?} → GDM",
+                     layer.num_channels, layer.compression_channels, layer.type_index_channels);
+            LayerType::GdmLayer
         }
+    };
+
+    Some(LayerParams {
+        layer_type,
+        num_channels: layer.num_channels,
+        compression_channels: layer.compression_channels.clone(),
+        type_index_channels: layer.type_index_channels,
+        expected_size: None,
+    })
+}
+
+/// Width and height stored in a GRLE or GDM header
+fn stored_dimensions(header: &[u8]) -> Option<(usize, usize)> {
+    let gdm_side = |dim_log2: u8| 1usize.checked_shl(dim_log2 as u32 + 5).map(|side| (side, side));
+    match header.get(0..4)? {
+        b"GRLE" => GrleHeader::parse(header).ok().map(|h| (h.width, h.height)),
+        b"\"MDF" if header.len() >= 9 => gdm_side(header[8]),
+        b"!MDF" if header.len() >= 5 => gdm_side(header[4]),
+        _ => None,
     }
+}
 
-    // Check FoliageMultiLayer (GDM) - uses densityMapId
-    for line in content.lines() {
-        if line.contains("<FoliageMultiLayer") && line.contains(&format!("densityMapId=\"{}\"", file_id)) {
-            let num_channels = extract_attr(line, "numChannels")?;
-            let compression_channels = extract_attr(line, "compressionChannels");
-            eprintln!("Found FoliageMultiLayer with {} channels, compression: {:?} → GDM",
-                     num_channels, compression_channels);
-            return Some(LayerParams {
-                layer_type: LayerType::GdmLayer,
-                num_channels,
-                compression_channels,
-            });
-        }
+/// Size of the GRLE/GDM file the i3d references for this layer, if it exists.
+/// A PNG of a different size would load misaligned in the game.
+fn existing_layer_size(i3d_path: &Path, i3d: &I3d, target_filename: &str) -> Option<(usize, usize, PathBuf)> {
+    let layer = i3d.layer_for_png(&format!("{}.png", base_stem(target_filename)))?;
+    let file = i3d.file(&layer.file_id)?;
+    if file.starts_with('$') {
+        return None; // Game data, not part of this map
     }
+    let path = i3d_path.parent()?.join(file).with_extension(layer.kind.extension());
 
-    None
+    let mut header = [0u8; 20];
+    let len = File::open(&path).and_then(|mut f| f.read(&mut header)).ok()?;
+    let (width, height) = stored_dimensions(&header[..len])?;
+    debug!("Existing {} is {}x{}", path.display(), width, height);
+    Some((width, height, path))
 }
 
-fn extract_attr(line: &str, attr_name: &str) -> Option<usize> {
-    let pattern = format!("{}=\"", attr_name);
-    if let Some(start) = line.find(&pattern) {
-        let rest = &line[start + pattern.len()..];
-        if let Some(end) = rest.find('"') {
-            return rest[..end].parse().ok();
+/// Compare the size being encoded with the layer's existing file
+fn check_expected_size(params: &LayerParams, width: usize, height: usize, strict: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some((expected_width, expected_height, ref path)) = params.expected_size {
+        if (width, height) != (expected_width, expected_height) {
+            let msg = format!(
+                "Image is {}x{} but the layer's existing {} is {}x{}",
+                width, height, path.display(), expected_width, expected_height
+            );
+            if strict {
+                return Err(ConvertError::InvalidFormat(msg).into());
+            }
+            warn!("{}", msg);
         }
     }
-    None
+    Ok(())
 }
 
 // ============================================================================
 // Utility functions
 // ============================================================================
 
-fn read_u16_le(data: &[u8], offset: usize) -> u16 {
-    u16::from_le_bytes([data[offset], data[offset + 1]])
+/// Open an input file for reading, or stdin when the path is "-"
+fn open_input(path: &str) -> std::io::Result<Box<dyn Read>> {
+    if path == "-" {
+        return Ok(Box::new(std::io::stdin().lock()));
+    }
+    Ok(Box::new(BufReader::new(File::open(path)?)))
 }
 
-fn read_u32_le(data: &[u8], offset: usize) -> u32 {
-    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+/// Read a whole input file, or stdin when the path is "-"
+fn read_input(path: &str) -> std::io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    open_input(path)?.read_to_end(&mut data)?;
+    Ok(data)
 }
 
-// ============================================================================
-// GRLE Decoder
-// ============================================================================
+/// Set by --force; existing output files are only replaced when true, and
+/// GRLE files with an unknown version are decoded anyway
+static FORCE: AtomicBool = AtomicBool::new(false);
 
-fn decode_grle_rle(data: &[u8], expected_size: usize) -> Vec<u8> {
-    let mut output = Vec::with_capacity(expected_size);
-    let mut i = 1; // Skip first byte (0x00 flag/padding)
+/// Set by --overwrite-if-newer; outputs at least as new as their input are
+/// skipped, and older ones are replaced without --force
+static OVERWRITE_IF_NEWER: AtomicBool = AtomicBool::new(false);
 
-    while i + 1 < data.len() && output.len() < expected_size {
-        let prev = data[i];
-        let new_val = data[i + 1];
-        i += 2;
+/// --overwrite-if-newer: whether `output` exists and was modified no earlier
+/// than `input`, so converting again would give the same file
+fn up_to_date(input_path: &str, output_path: &str) -> bool {
+    if !OVERWRITE_IF_NEWER.load(Ordering::Relaxed) || input_path == "-" || output_path == "-" {
+        return false;
+    }
+    let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    match (modified(input_path), modified(output_path)) {
+        (Some(input), Some(output)) => output >= input,
+        _ => false,
+    }
+}
 
-        if prev == new_val {
-            // Same value: read extended count with 0xff continuation
-            let mut count = 0usize;
-            while i < data.len() && data[i] == 0xff {
-                count += 255;
-                i += 1;
-            }
-            if i < data.len() {
-                count += data[i] as usize;
-                i += 1;
-            }
-            count += 2; // Counts are offset by 2
+/// `up_to_date`, logging the skip for a single conversion
+fn skip_up_to_date(input_path: &str, output_path: &str) -> bool {
+    let skip = up_to_date(input_path, output_path);
+    if skip {
+        info!("{} is up to date, skipped", output_path);
+    }
+    skip
+}
 
-            let to_emit = count.min(expected_size - output.len());
-            output.extend(std::iter::repeat(prev).take(to_emit));
-        } else {
-            // Transition: emit 1 pixel of prev, back up to re-read new as next prev
-            output.push(prev);
-            i -= 1;
-        }
+/// Open an output file for writing, or stdout when the path is "-".
+/// Refuses to replace an existing file unless --force (or --overwrite-if-newer,
+/// which skips up-to-date outputs before they get here) was given.
+fn create_output(path: &str) -> std::io::Result<Box<dyn Write>> {
+    if path == "-" {
+        return Ok(Box::new(BufWriter::new(std::io::stdout())));
     }
 
-    output.resize(expected_size, 0);
-    output
+    let file = if FORCE.load(Ordering::Relaxed) || OVERWRITE_IF_NEWER.load(Ordering::Relaxed) {
+        File::create(path)?
+    } else {
+        File::options().write(true).create_new(true).open(path).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::AlreadyExists {
+                std::io::Error::new(e.kind(), format!("{} already exists (use --force to overwrite)", path))
+            } else {
+                e
+            }
+        })?
+    };
+    Ok(Box::new(BufWriter::new(file)))
 }
 
-fn convert_grle_to_png(input_path: &str, output_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let mut file = BufReader::new(File::open(input_path)?);
-    let mut data = Vec::new();
-    file.read_to_end(&mut data)?;
+/// Write a complete output file, or stdout when the path is "-"
+fn write_output(path: &str, data: &[u8]) -> std::io::Result<()> {
+    let mut w = create_output(path)?;
+    w.write_all(data)?;
+    w.flush()
+}
 
-    if &data[0..4] != b"GRLE" {
-        return Err("Not a valid GRLE file".into());
+/// Set by --progress; long decodes and encodes report a percentage on stderr
+static PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Progress callback that rewrites a `label: NN%` line on stderr whenever the
+/// percentage changes, ending the line at 100%. Does nothing without --progress.
+fn progress_reporter(label: &str) -> impl FnMut(usize, usize) + '_ {
+    let enabled = PROGRESS.load(Ordering::Relaxed);
+    let mut last = None;
+    move |done, total| {
+        if !enabled {
+            return;
+        }
+        let pct = (done * 100).checked_div(total).unwrap_or(100);
+        if last == Some(pct) {
+            return;
+        }
+        last = Some(pct);
+        eprint!("\r{}: {:>3}%", label, pct);
+        if pct >= 100 {
+            eprintln!();
+        }
     }
+}
 
-    let version = read_u16_le(&data, 4);
-    let width = (read_u16_le(&data, 6) as usize) * 256;
-    let height = (read_u16_le(&data, 10) as usize) * 256;
-    let channels = 1usize;
-
-    eprintln!("GRLE version: {}", version);
-    eprintln!("Size: {}x{}", width, height);
-    eprintln!("Channels: {}", channels);
+/// Set by --stats; decoders print a one-line summary of the decoded values
+static STATS: AtomicBool = AtomicBool::new(false);
+
+/// --stats: distinct values, share of nonzero pixels and the value range.
+/// Goes to stdout, or stderr when the decoded file is written to stdout.
+fn print_stats(output_path: &str, values: &[u32]) {
+    let distinct = values.iter().collect::<std::collections::HashSet<_>>().len();
+    let nonzero = values.iter().filter(|&&v| v != 0).count();
+    let line = format!(
+        "Stats: {} distinct values, {:.2}% nonzero, min {}, max {}",
+        distinct,
+        100.0 * nonzero as f64 / values.len().max(1) as f64,
+        values.iter().min().copied().unwrap_or(0),
+        values.iter().max().copied().unwrap_or(0)
+    );
+    if output_path == "-" {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
 
-    let compressed_data = &data[20..];
-    let expected_size = width * height * channels;
+/// --size-limit in bytes; 0 compares against the uncompressed size instead
+static SIZE_LIMIT: AtomicUsize = AtomicUsize::new(0);
 
-    let pixels = decode_grle_rle(compressed_data, expected_size);
+/// Parse a byte count such as `4000000`, `512K` or `8M` (binary units)
+fn parse_byte_size(value: &str) -> Option<usize> {
+    let value = value.trim();
+    let (digits, unit) = match value.char_indices().last()? {
+        (i, 'k' | 'K') => (&value[..i], 1 << 10),
+        (i, 'm' | 'M') => (&value[..i], 1 << 20),
+        _ => (value, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit).filter(|&n| n > 0)
+}
 
-    let file = File::create(output_path)?;
-    let w = BufWriter::new(file);
+/// Warn when an encoded GDM is over --size-limit, or without one, larger than
+/// its values would take uncompressed. Chunks with many distinct values get
+/// deep blocks with large palettes, and then compression no longer pays off.
+fn warn_if_oversized(output_path: &str, gdm: &[u8], pixels: usize, num_channels: usize) {
+    let (limit, what) = match SIZE_LIMIT.load(Ordering::Relaxed) {
+        0 => ((pixels * num_channels + 7) / 8, "its uncompressed size"),
+        n => (n, "--size-limit"),
+    };
+    if gdm.len() <= limit {
+        return;
+    }
+    warn!("{} is {} bytes, more than {} of {} bytes", output_path, gdm.len(), what, limit);
+
+    let Ok(index) = index_gdm(gdm) else { return };
+    let deepest = index.blocks.iter().map(|b| gdm[b.offset]).max().unwrap_or(0);
+    if deepest > 0 {
+        let count = index.blocks.iter().filter(|b| gdm[b.offset] == deepest).count();
+        warn!(
+            "{} of {} blocks are {} bits per pixel: their {}x{} chunks hold many distinct values. \
+             Fewer distinct values per chunk (less noise, no gradients) shrink the file; `grleconvert analyze` shows the breakdown",
+            count, index.blocks.len(), deepest, index.chunk_size, index.chunk_size
+        );
+    }
+}
 
-    let mut encoder = png::Encoder::new(w, width as u32, height as u32);
-    encoder.set_color(png::ColorType::Grayscale);
-    encoder.set_depth(png::BitDepth::Eight);
-    encoder.set_compression(png::Compression::Default);
+/// Set by --verify-header; encoders reopen what they wrote and check its header
+static VERIFY_HEADER: AtomicBool = AtomicBool::new(false);
 
-    let mut writer = encoder.write_header()?;
-    writer.write_image_data(&pixels)?;
+/// First `len` bytes of a written file (fewer if it is shorter)
+fn read_written_header(path: &str, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(len);
+    File::open(path)?.take(len as u64).read_to_end(&mut header)?;
+    Ok(header)
+}
 
-    eprintln!("Saved to {}", output_path);
+/// Turn (field, stored, intended) mismatches into one --verify-header error
+fn header_mismatches(path: &str, checks: &[(&str, usize, usize)]) -> Result<(), Box<dyn std::error::Error>> {
+    let wrong: Vec<String> = checks
+        .iter()
+        .filter(|(_, stored, intended)| stored != intended)
+        .map(|(field, stored, intended)| format!("{} {} (expected {})", field, stored, intended))
+        .collect();
+    if !wrong.is_empty() {
+        return Err(format!("--verify-header: {} stores {}", path, wrong.join(", ")).into());
+    }
+    verbose!("Header of {} verified", path);
     Ok(())
 }
 
-// ============================================================================
-// GRLE Encoder
-// ============================================================================
-
-fn encode_grle_rle(pixels: &[u8]) -> Vec<u8> {
-    // GRLE RLE format:
-    // - Initial 0x00 byte (padding/flag)
-    // - Decoder reads pairs (data[i], data[i+1]):
-    //   - If same: run - read count bytes (0xff=+255, final byte=remainder), emit count+2 pixels
-    //   - If different: transition - emit first pixel, back up 1 byte
-    //
-    // Each pixel value appears once in the stream, except runs which have value twice + count.
-
-    let mut output = Vec::new();
-    output.push(0x00);
+/// Check the header of a written GRLE file against the values it was encoded with
+fn verify_grle_header(path: &str, header: &GrleHeaderFields, width: usize, height: usize) -> Result<(), Box<dyn std::error::Error>> {
+    if path == "-" || !VERIFY_HEADER.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let stored = GrleHeader::parse(&read_written_header(path, 20)?)
+        .map_err(|e| format!("--verify-header: {}: {}", path, e))?;
+    let file_len = std::fs::metadata(path)?.len() as usize;
+    header_mismatches(path, &[
+        ("version", stored.version as usize, header.version as usize),
+        ("width", stored.width, width),
+        ("height", stored.height, height),
+        ("stream length", stored.declared_len, file_len - 20),
+    ])
+}
 
-    if pixels.is_empty() {
-        return output;
+/// Check the header of a written GDM file against the values it was encoded with
+fn verify_gdm_header(path: &str, params: &GdmParams, dimension: usize) -> Result<(), Box<dyn std::error::Error>> {
+    if path == "-" || !VERIFY_HEADER.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let splits = &params.compression_channels;
+    let data = read_written_header(path, 16 + splits.len())?;
+    if data.len() < 16 + splits.len() || &data[0..4] != b"\"MDF" {
+        return Err(format!("--verify-header: {} does not start with a \"MDF header", path).into());
+    }
+    let type_index = params.type_index_channels.unwrap_or(params.header.type_index_channels as usize);
+    let mut checks = vec![
+        ("dimension", 1usize.checked_shl(data[8] as u32 + 5).unwrap_or(0), dimension),
+        ("channels", data[11] as usize, params.num_channels),
+        ("compression ranges", data[12] as usize, splits.len() + 1),
+        ("type index channels", data[13] as usize, type_index),
+    ];
+    for (i, &split) in splits.iter().enumerate() {
+        checks.push(("compression split", data[16 + i] as usize, split));
     }
+    header_mismatches(path, &checks)
+}
 
-    let mut i = 0;
-    while i < pixels.len() {
-        let value = pixels[i];
+/// Decode output formats selected with --out-format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutFormat {
+    Png,
+    /// Headerless little-endian values plus a `<output>.json` sidecar
+    Raw,
+    /// NumPy `.npy` (format version 1.0)
+    Npy,
+}
 
-        // Count consecutive identical values
-        let mut run_len = 1;
-        while i + run_len < pixels.len() && pixels[i + run_len] == value {
-            run_len += 1;
+impl OutFormat {
+    fn parse(value: &str) -> Option<OutFormat> {
+        match value {
+            "png" => Some(OutFormat::Png),
+            "raw" => Some(OutFormat::Raw),
+            "npy" => Some(OutFormat::Npy),
+            _ => None,
         }
+    }
 
-        if run_len >= 2 {
-            // Run: emit (value, value, count) where count = run_len - 2
-            output.push(value);
-            output.push(value);
+    fn extension(self) -> &'static str {
+        match self {
+            OutFormat::Png => "png",
+            OutFormat::Raw => "raw",
+            OutFormat::Npy => "npy",
+        }
+    }
+}
 
-            let mut remaining = run_len - 2;
-            while remaining >= 255 {
-                output.push(0xff);
-                remaining -= 255;
-            }
-            output.push(remaining as u8);
+/// Write decoded values as a raw or .npy array, row-major. The element size is the
+/// smallest of u8/u16/u32 that holds `num_channels` bits.
+fn write_array(path: &str, format: OutFormat, width: usize, height: usize, values: &[u32], num_channels: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let (dtype, descr, size) = match num_channels {
+        0..=8 => ("uint8", "|u1", 1),
+        9..=16 => ("uint16", "<u2", 2),
+        _ => ("uint32", "<u4", 4),
+    };
 
-            i += run_len;
-        } else {
-            // Single pixel - emit value, decoder handles via transition backup
-            output.push(value);
-            i += 1;
+    let mut data = Vec::with_capacity(values.len() * size + 128);
+    if format == OutFormat::Npy {
+        // Magic, version 1.0, header length; the header dict is padded with
+        // spaces so the data starts on a 64-byte boundary
+        let mut header = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': ({}, {}), }}",
+            descr, height, width
+        );
+        while (10 + header.len() + 1) % 64 != 0 {
+            header.push(' ');
         }
+        header.push('\n');
+        data.extend_from_slice(b"\x93NUMPY\x01\x00");
+        data.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        data.extend_from_slice(header.as_bytes());
     }
-
-    // Edge case: single pixel image needs padding for decoder
-    if output.len() == 2 {
-        let v = output[1];
-        output.push(v);
-        output.push(0x00);
+    for &v in values {
+        data.extend_from_slice(&v.to_le_bytes()[..size]);
+    }
+    write_output(path, &data)?;
+
+    if format == OutFormat::Raw {
+        let sidecar = format!(
+            "{{\n  \"width\": {},\n  \"height\": {},\n  \"dtype\": \"{}\",\n  \"byteorder\": \"little\",\n  \"channels\": {}\n}}\n",
+            width, height, dtype, num_channels
+        );
+        if path == "-" {
+            warn!("No sidecar for raw output on stdout: {}x{} {} little-endian", width, height, dtype);
+        } else {
+            write_output(&format!("{}.json", path), sidecar.as_bytes())?;
+        }
     }
 
-    output
+    info!("Saved to {} ({}x{} {})", path, width, height, dtype);
+    Ok(())
 }
 
-fn convert_png_to_grle(input_path: &str, output_path: &str, params: &LayerParams) -> Result<(), Box<dyn std::error::Error>> {
-    // Read PNG
-    let file = File::open(input_path)?;
-    let decoder = png::Decoder::new(BufReader::new(file));
-    let mut reader = decoder.read_info()?;
-
-    let mut pixels = vec![0u8; reader.output_buffer_size()];
-    let info = reader.next_frame(&mut pixels)?;
-
+/// Expand indexed PNG pixels through the PLTE palette.
+/// Gray palettes give 8-bit grayscale (an identity ramp keeps the index values
+/// as-is), anything else gives RGB.
+fn expand_indexed(pixels: &[u8], info: &png::OutputInfo, palette: &[u8]) -> Result<(png::ColorType, Vec<u8>), Box<dyn std::error::Error>> {
     let width = info.width as usize;
     let height = info.height as usize;
-
-    eprintln!("PNG: {}x{}", width, height);
-    eprintln!("Encoding as GRLE with {} channels", params.num_channels);
-
-    // GRLE dimensions must be multiples of 256
-    if width % 256 != 0 || height % 256 != 0 {
-        return Err(format!("Dimensions must be multiples of 256, got {}x{}", width, height).into());
-    }
-
-    // Convert to grayscale if needed
-    let grayscale_pixels = match info.color_type {
-        png::ColorType::Grayscale => pixels[..width * height].to_vec(),
-        png::ColorType::Rgb => {
-            let mut gray = Vec::with_capacity(width * height);
-            for chunk in pixels.chunks(3) {
-                gray.push(chunk[0]); // Just use R channel
-            }
-            gray
-        }
-        png::ColorType::Rgba => {
-            let mut gray = Vec::with_capacity(width * height);
-            for chunk in pixels.chunks(4) {
-                gray.push(chunk[0]); // Just use R channel
-            }
-            gray
+    let bits = info.bit_depth as usize;
+    let per_byte = 8 / bits;
+    let mask = ((1u16 << bits) - 1) as u8;
+
+    // Unpack 1/2/4/8-bit indices, rows are padded to whole bytes
+    let mut indices = Vec::with_capacity(width * height);
+    for row in pixels.chunks(info.line_size).take(height) {
+        for x in 0..width {
+            let byte = row[x / per_byte];
+            let shift = 8 - bits * (x % per_byte + 1);
+            indices.push((byte >> shift) & mask);
         }
-        _ => return Err("Unsupported PNG color type".into()),
-    };
-
-    // Encode RLE
-    let compressed = encode_grle_rle(&grayscale_pixels);
-
-    // Build GRLE file
-    // Header format (20 bytes):
-    // 0-3:   Magic "GRLE"
-    // 4-5:   Version (1)
-    // 6-7:   Width / 256
-    // 8-9:   Padding (0)
-    // 10-11: Height / 256
-    // 12-13: Unknown (1) - possibly channels or bits
-    // 14-15: Padding (0)
-    // 16-19: Compressed size
-    let mut output = Vec::new();
-
-    // Magic
-    output.extend_from_slice(b"GRLE");
+    }
 
-    // Version (1)
-    output.extend_from_slice(&1u16.to_le_bytes());
+    let entries: Vec<&[u8]> = palette.chunks(3).collect();
+    let identity = entries.iter().enumerate().all(|(i, e)| e.iter().all(|&c| c as usize == i));
+    if identity {
+        return Ok((png::ColorType::Grayscale, indices));
+    }
 
-    // Width / 256
-    output.extend_from_slice(&((width / 256) as u16).to_le_bytes());
+    if let Some(&idx) = indices.iter().find(|&&idx| idx as usize >= entries.len()) {
+        return Err(format!("Palette index {} out of range ({} entries)", idx, entries.len()).into());
+    }
 
-    // Padding
-    output.extend_from_slice(&[0u8; 2]);
+    let gray = entries.iter().all(|e| e[0] == e[1] && e[1] == e[2]);
+    if gray {
+        Ok((png::ColorType::Grayscale, indices.iter().map(|&idx| entries[idx as usize][0]).collect()))
+    } else {
+        Ok((png::ColorType::Rgb, indices.iter().flat_map(|&idx| entries[idx as usize].iter().copied()).collect()))
+    }
+}
 
-    // Height / 256
-    output.extend_from_slice(&((height / 256) as u16).to_le_bytes());
+/// Map a --png-compression value to the png crate setting
+fn parse_png_compression(value: &str) -> Option<png::Compression> {
+    match value {
+        "default" => Some(png::Compression::Default),
+        "fast" => Some(png::Compression::Fast),
+        "best" => Some(png::Compression::Best),
+        _ => None,
+    }
+}
 
-    // Unknown field (256) - seen in all GRLE files
-    output.extend_from_slice(&256u16.to_le_bytes());
+/// How decoded images are written as PNG
+#[derive(Debug, Clone)]
+struct PngOptions {
+    compression: png::Compression,
+    /// --zero-transparent: grayscale output becomes RGBA with value 0 fully transparent
+    zero_transparent: bool,
+    /// --colorize: value -> RGB for viewing; output is no longer re-encodable
+    colors: Option<HashMap<u32, [u8; 3]>>,
+    /// --no-color-management: tag the PNG with gamma 1.0 so its bytes are read as raw values
+    linear: bool,
+}
 
-    // Padding
-    output.extend_from_slice(&[0u8; 2]);
+impl Default for PngOptions {
+    fn default() -> Self {
+        PngOptions { compression: png::Compression::Default, zero_transparent: false, colors: None, linear: false }
+    }
+}
 
-    // Compressed size: stored as 0x00 followed by 3-byte LE value
-    // Value stored is (compressed.len() - 1)
-    let comp_size = (compressed.len() - 1) as u32;
-    output.push(0x00);
-    output.push((comp_size & 0xFF) as u8);
-    output.push(((comp_size >> 8) & 0xFF) as u8);
-    output.push(((comp_size >> 16) & 0xFF) as u8);
+impl PngOptions {
+    /// Array outputs hold the raw values, so the display options do not apply
+    fn warn_unused(&self, out_format: OutFormat) {
+        if self.zero_transparent {
+            warn!("--zero-transparent ignored for {} output", out_format.extension());
+        }
+        if self.colors.is_some() {
+            warn!("--colorize ignored for {} output", out_format.extension());
+        }
+    }
 
-    // Compressed data
-    output.extend_from_slice(&compressed);
+    /// 8-bit PNG encoder with the compression and color tagging options applied
+    fn encoder<W: Write>(&self, w: W, width: usize, height: usize, color_type: png::ColorType) -> png::Encoder<'static, W> {
+        let mut encoder = png::Encoder::new(w, width as u32, height as u32);
+        encoder.set_color(color_type);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(self.compression);
+        if self.linear {
+            // Untagged PNGs leave the guess to the reader. gAMA 1.0 with no
+            // sRGB chunk says the samples are linear, so no transfer curve is
+            // applied to them; an sRGB tag would mark them as gamma-encoded.
+            encoder.set_source_gamma(png::ScaledFloat::from_scaled(100000));
+        }
+        encoder
+    }
 
-    // Write file
-    let mut file = File::create(output_path)?;
-    file.write_all(&output)?;
+    /// RGB samples from the --colorize palette, or None without one.
+    /// Values the palette has no color for are black.
+    fn colorize(&self, values: &[u32]) -> Option<Vec<u8>> {
+        let colors = self.colors.as_ref()?;
+        let mut missing: Vec<u32> = values.iter().copied().filter(|v| !colors.contains_key(v)).collect();
+        missing.sort_unstable();
+        missing.dedup();
+        if !missing.is_empty() {
+            warn!("{} value(s) have no palette color and are black: {:?}", missing.len(), &missing[..missing.len().min(10)]);
+        }
+        Some(values.iter().flat_map(|v| colors.get(v).copied().unwrap_or([0, 0, 0])).collect())
+    }
 
-    eprintln!("Saved to {} ({} bytes)", output_path, output.len());
-    Ok(())
+    /// Apply the display options to 8-bit samples about to be written
+    fn style(&self, color_type: png::ColorType, samples: Vec<u8>) -> (png::ColorType, Vec<u8>) {
+        if !self.zero_transparent {
+            return (color_type, samples);
+        }
+        if color_type != png::ColorType::Grayscale {
+            warn!("--zero-transparent ignored: output is {:?}, not grayscale", color_type);
+            return (color_type, samples);
+        }
+        let rgba = samples.iter().flat_map(|&v| [v, v, v, if v == 0 { 0 } else { 255 }]).collect();
+        (png::ColorType::Rgba, rgba)
+    }
 }
 
-// ============================================================================
-// GDM Decoder
-// ============================================================================
-
-fn decode_gdm_block(data: &[u8], pos: usize, chunk_size: usize) -> (Vec<u16>, usize) {
-    let bit_depth = data[pos];
-    let palette_count = data[pos + 1] as usize;
-    let palette_size = 2 * palette_count;
-    let bitmap_size = if bit_depth > 0 { (bit_depth as usize) * 128 } else { 0 };
-    let block_size = 2 + palette_size + bitmap_size;
-
-    let palette: Vec<u16> = (0..palette_count)
-        .map(|i| u16::from_le_bytes([data[pos + 2 + i*2], data[pos + 3 + i*2]]))
-        .collect();
+/// Read a --colorize palette: a JSON object of decimal values to "#RRGGBB"
+/// colors, e.g. `{"0": "#000000", "1": "#7FBF3F"}`
+fn load_palette(path: &str) -> Result<HashMap<u32, [u8; 3]>, Box<dyn std::error::Error>> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Cannot read palette {}: {}", path, e))?;
+    let body = text
+        .trim()
+        .strip_prefix('{')
+        .and_then(|t| t.strip_suffix('}'))
+        .ok_or_else(|| format!("Palette {} must be a JSON object like {{\"1\": \"#FF0000\"}}", path))?;
+    let unquote = |s: &str| s.trim().strip_prefix('"').and_then(|t| t.strip_suffix('"')).map(str::to_string);
+
+    let mut colors = HashMap::new();
+    for entry in body.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let bad = || format!("Palette {}: expected \"<value>\": \"#RRGGBB\", got {}", path, entry);
+        let (key, color) = entry.split_once(':').ok_or_else(bad)?;
+        let value: u32 = unquote(key).and_then(|k| k.parse().ok()).ok_or_else(bad)?;
+        let hex = unquote(color).and_then(|c| c.strip_prefix('#').map(str::to_string)).filter(|h| h.len() == 6).ok_or_else(bad)?;
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| bad());
+        colors.insert(value, [channel(0)?, channel(2)?, channel(4)?]);
+    }
+    Ok(colors)
+}
 
-    let total_pixels = chunk_size * chunk_size;
-    let mut pixels = Vec::with_capacity(total_pixels);
+/// Parse a `WxH` size such as `300x300`
+fn parse_size(value: &str) -> Option<(usize, usize)> {
+    let (w, h) = value.split_once(['x', 'X'])?;
+    let (w, h) = (w.trim().parse().ok()?, h.trim().parse().ok()?);
+    if w == 0 || h == 0 {
+        return None;
+    }
+    Some((w, h))
+}
 
-    if bit_depth == 0 {
-        let value = *palette.first().unwrap_or(&0);
-        pixels.resize(total_pixels, value);
-    } else {
-        let bitmap = &data[pos + 2 + palette_size..pos + 2 + palette_size + bitmap_size];
-        let bits_per_pixel = bit_depth as usize;
-        let mask = (1u16 << bits_per_pixel) - 1;
+/// Parse a `first:end` channel range such as `0:8` (channels 0-7)
+fn parse_channel_range(value: &str) -> Option<(usize, usize)> {
+    let (first, end) = value.split_once(':')?;
+    let (first, end) = (first.trim().parse().ok()?, end.trim().parse().ok()?);
+    if first >= end {
+        return None;
+    }
+    Some((first, end))
+}
 
-        for pixel_idx in 0..total_pixels {
-            let bit_pos = pixel_idx * bits_per_pixel;
-            let byte_idx = bit_pos / 8;
-            let bit_offset = bit_pos % 8;
+/// One `--channel-layout` field: PNG channel (0-3 for R, G, B, A) and the
+/// bits of the packed value it holds
+#[derive(Debug, Clone, Copy)]
+struct LayoutField {
+    channel: usize,
+    shift: usize,
+    bits: usize,
+}
 
-            let mut raw_value = bitmap[byte_idx] as u16;
-            if byte_idx + 1 < bitmap.len() {
-                raw_value |= (bitmap[byte_idx + 1] as u16) << 8;
-            }
+impl LayoutField {
+    fn mask(&self) -> u32 {
+        ((1u64 << self.bits) - 1) as u32
+    }
+}
 
-            let idx_or_value = ((raw_value >> bit_offset) & mask) as usize;
+/// --channel-layout: which PNG channel fills which bit range of a GDM value,
+/// e.g. `r:0-7,g:8-15`. Replaces the fixed R | G << 8 | B << 16 | A << 24.
+#[derive(Debug, Clone)]
+struct ChannelLayout {
+    fields: Vec<LayoutField>,
+}
 
-            let pixel_value = if bit_depth <= 2 && !palette.is_empty() {
-                *palette.get(idx_or_value).unwrap_or(&0)
-            } else {
-                idx_or_value as u16
+impl ChannelLayout {
+    /// Parse `<channel>:<first>-<last>` fields (or `<channel>:<bit>`). Each
+    /// channel appears at most once and bit ranges cannot overlap.
+    fn parse(spec: &str) -> Result<ChannelLayout, String> {
+        let mut fields = Vec::new();
+        let mut used = 0u64;
+        for part in spec.split(',').map(str::trim) {
+            let bad = || format!("expected fields like r:0-7, got '{}'", part);
+            let (name, range) = part.split_once(':').ok_or_else(bad)?;
+            let channel = match name.trim().to_lowercase().as_str() {
+                "r" => 0,
+                "g" => 1,
+                "b" => 2,
+                "a" => 3,
+                _ => return Err(format!("unknown channel '{}' (expected r, g, b or a)", name.trim())),
             };
-
-            pixels.push(pixel_value);
+            if fields.iter().any(|f: &LayoutField| f.channel == channel) {
+                return Err(format!("channel '{}' is given twice", name.trim()));
+            }
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            let (first, last): (usize, usize) = (first.trim().parse().map_err(|_| bad())?, last.trim().parse().map_err(|_| bad())?);
+            if first > last || last > 31 {
+                return Err(format!("bit range {}-{} must be ascending and within bits 0-31", first, last));
+            }
+            let bits = last - first + 1;
+            if bits > 8 {
+                return Err(format!("{} is {} bits; a PNG channel holds at most 8", part, bits));
+            }
+            let field_bits = ((1u64 << bits) - 1) << first;
+            if used & field_bits != 0 {
+                return Err(format!("{} overlaps an earlier field", part));
+            }
+            used |= field_bits;
+            fields.push(LayoutField { channel, shift: first, bits });
         }
+        Ok(ChannelLayout { fields })
     }
 
-    (pixels, block_size)
-}
-
-fn convert_gdm_to_png(input_path: &str, output_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let mut file = BufReader::new(File::open(input_path)?);
-    let mut data = Vec::new();
-    file.read_to_end(&mut data)?;
-
-    if data.len() < 16 {
-        return Err("File too small".into());
+    /// Bits of a packed value that some field covers
+    fn value_mask(&self) -> u32 {
+        self.fields.iter().fold(0, |m, f| m | f.mask() << f.shift)
     }
 
-    let magic = &data[0..4];
-    if magic != b"\"MDF" && magic != b"!MDF" {
-        return Err("Not a valid GDM file".into());
+    /// Channels a value needs: one more than the highest bit filled
+    fn value_channels(&self) -> usize {
+        self.fields.iter().map(|f| f.shift + f.bits).max().unwrap_or(0)
     }
 
-    let (dimension, num_channels, chunk_size, num_compression_ranges, header_size) =
-        if magic == b"\"MDF" {
-            let version = read_u32_le(&data, 4);
-            if version != 0 {
-                return Err(format!("Unsupported GDM version: {}", version).into());
-            }
-
-            let dim_log2 = data[8] as usize;
-            let chunk_log2 = data[9] as usize;
-            let num_channels = data[11] as usize;
-            let num_compression_ranges = data[12] as usize;
-
-            let dimension = 1 << (dim_log2 + 5);
-            let chunk_size = 1 << chunk_log2;
-
-            (dimension, num_channels, chunk_size, num_compression_ranges, 16usize)
-        } else {
-            let dim_log2 = data[4] as usize;
-            let chunk_log2 = data[5] as usize;
-            let num_channels = data[7] as usize;
-            let num_compression_ranges = data[8] as usize;
-
-            let dimension = 1 << (dim_log2 + 5);
-            let chunk_size = 1 << chunk_log2;
+    fn uses_alpha(&self) -> bool {
+        self.fields.iter().any(|f| f.channel == 3)
+    }
 
-            (dimension, num_channels, chunk_size, num_compression_ranges, 9usize)
+    /// Pack 8-bit PNG samples. Grayscale input gives its gray sample for R, G and B.
+    fn pack(&self, color_type: png::ColorType, bit_depth: png::BitDepth, pixels: &[u8], pixel_count: usize) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+        if bit_depth != png::BitDepth::Eight {
+            return Err(ConvertError::InvalidParams("--channel-layout reads 8-bit PNGs".into()).into());
+        }
+        let stride = match color_type {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::Rgb => 3,
+            png::ColorType::Rgba => 4,
+            _ => return Err(ConvertError::InvalidFormat("Unsupported PNG color type".into()).into()),
         };
-
-    eprintln!("GDM: {}x{}, {} channels, {} compression ranges",
-              dimension, dimension, num_channels, num_compression_ranges);
-
-    let mut compression_boundaries = vec![0u8];
-    for i in 0..(num_compression_ranges.saturating_sub(1)) {
-        compression_boundaries.push(data[header_size + i]);
+        Ok(pixels[..pixel_count * stride]
+            .chunks(stride)
+            .map(|px| {
+                let rgba = match *px {
+                    [v] => [v, v, v, 255],
+                    [v, a] => [v, v, v, a],
+                    [r, g, b] => [r, g, b, 255],
+                    _ => [px[0], px[1], px[2], px[3]],
+                };
+                self.fields.iter().fold(0, |v, f| v | (rgba[f.channel] as u32 & f.mask()) << f.shift)
+            })
+            .collect())
     }
-    compression_boundaries.push(num_channels as u8);
 
-    let mut bits_per_range = Vec::new();
-    for i in 0..num_compression_ranges {
-        let start_ch = compression_boundaries[i] as usize;
-        let end_ch = compression_boundaries[i + 1] as usize;
-        bits_per_range.push(end_ch - start_ch);
+    /// Inverse of `pack`: grayscale if only R is used, RGB without A, else RGBA.
+    /// Channels that no field fills are 0 (alpha 255).
+    fn unpack(&self, values: &[u32]) -> (png::ColorType, Vec<u8>) {
+        let (color_type, stride) = match self.fields.iter().map(|f| f.channel).max().unwrap_or(0) {
+            0 => (png::ColorType::Grayscale, 1),
+            3 => (png::ColorType::Rgba, 4),
+            _ => (png::ColorType::Rgb, 3),
+        };
+        let samples = values
+            .iter()
+            .flat_map(|&v| {
+                let mut rgba = [0, 0, 0, 255];
+                for f in &self.fields {
+                    rgba[f.channel] = ((v >> f.shift) & f.mask()) as u8;
+                }
+                rgba.into_iter().take(stride)
+            })
+            .collect();
+        (color_type, samples)
     }
+}
 
-    let chunks_per_dim = dimension / chunk_size;
-    let total_chunks = chunks_per_dim * chunks_per_dim;
-
-    let compression_boundaries_size = if num_compression_ranges > 1 { num_compression_ranges - 1 } else { 0 };
-    let data_start = header_size + compression_boundaries_size;
+/// How GDM values map to PNG channels, in both directions
+#[derive(Debug, Clone)]
+enum PngChannels {
+    /// Grey up to 8 channels, then R | G << 8 | B << 16 | A << 24
+    Packed,
+    /// --split-foliage: type index in R, state in G
+    SplitFoliage,
+    /// --channel-layout
+    Layout(ChannelLayout),
+}
 
-    let use_rgb = num_channels > 8;
+impl PngChannels {
+    fn layout(&self) -> Option<&ChannelLayout> {
+        match self {
+            PngChannels::Layout(layout) => Some(layout),
+            _ => None,
+        }
+    }
+}
 
-    let bytes_per_pixel = if use_rgb { 3 } else { 1 };
-    let mut image = vec![0u8; dimension * dimension * bytes_per_pixel];
+/// Copy the top-left `new_width` x `new_height` region, filling anything outside the source
+fn resize_canvas<T: Copy>(pixels: &[T], width: usize, height: usize, new_width: usize, new_height: usize, fill: T) -> Vec<T> {
+    let mut out = vec![fill; new_width * new_height];
+    for y in 0..height.min(new_height) {
+        let n = width.min(new_width);
+        out[y * new_width..y * new_width + n].copy_from_slice(&pixels[y * width..y * width + n]);
+    }
+    out
+}
 
-    let mut pos = data_start;
+/// Alpha of each pixel of an 8-bit RGBA image, for --mask-alpha
+fn alpha_mask(color_type: png::ColorType, bit_depth: png::BitDepth, pixels: &[u8], pixel_count: usize) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let stride = match color_type {
+        png::ColorType::Rgba => 4,
+        png::ColorType::GrayscaleAlpha => 2,
+        _ => 0,
+    };
+    if stride == 0 || bit_depth != png::BitDepth::Eight {
+        return Err(ConvertError::InvalidParams("--mask-alpha expects an 8-bit RGBA or grayscale+alpha PNG".into()).into());
+    }
+    Ok(pixels[..pixel_count * stride].chunks(stride).map(|px| px[stride - 1]).collect())
+}
 
-    for chunk_idx in 0..total_chunks {
-        let mut range_values: Vec<Vec<u16>> = Vec::new();
+/// Put the existing value back wherever the painting is fully transparent.
+/// Returns how many pixels were kept.
+fn keep_transparent<T: Copy>(values: &mut [T], alpha: &[u8], existing: &[T]) -> usize {
+    let mut kept = 0;
+    for ((value, &a), &old) in values.iter_mut().zip(alpha).zip(existing) {
+        if a == 0 {
+            *value = old;
+            kept += 1;
+        }
+    }
+    kept
+}
 
-        for _range_idx in 0..num_compression_ranges {
-            if pos + 2 > data.len() {
-                return Err("Unexpected end of data".into());
-            }
+// ============================================================================
+// GRLE Decoder
+// ============================================================================
 
-            let (pixels, block_size) = decode_gdm_block(&data, pos, chunk_size);
-            range_values.push(pixels);
-            pos += block_size;
+/// Decode a GRLE file, accepting unknown versions only with --force
+fn read_grle(input_path: &str, num_channels: usize) -> Result<DecodedGrle, Box<dyn std::error::Error>> {
+    let data = read_input(input_path)?;
+    let params = GrleParams::new().num_channels(num_channels);
+    let version = GrleHeader::parse(&data)?.version;
+    if !GRLE_KNOWN_VERSIONS.contains(&version) {
+        if !FORCE.load(Ordering::Relaxed) {
+            info!("Use --force to decode GRLE version {} as version 1", version);
+            return Err(ConvertError::UnsupportedVersion { format: "GRLE", version: version as u32 }.into());
         }
+        warn!("Unknown GRLE version {}, decoding as version 1 (--force)", version);
+    }
+    Ok(decode_grle_any_version_with_progress(&data, &params, &mut progress_reporter("Decoding"))?)
+}
 
-        let chunk_row = chunk_idx / chunks_per_dim;
-        let chunk_col = chunk_idx % chunks_per_dim;
-        let base_y = chunk_row * chunk_size;
-        let base_x = chunk_col * chunk_size;
+/// Channel count to decode a GRLE with: --channels, else the `numChannels` the
+/// i3d declares for the layer, else 8
+fn grle_channels(input_path: &str, i3d_path: Option<&str>, manual_channels: Option<usize>, i3d_cache: &mut I3dCache) -> usize {
+    if let Some(channels) = manual_channels {
+        return channels;
+    }
+    let target_png = format!("{}.png", base_stem(input_path));
+    let i3d_file = match i3d_path {
+        Some(path) => Some(PathBuf::from(path)),
+        None if input_path == "-" => None,
+        None => {
+            let input_abs = std::fs::canonicalize(input_path).unwrap_or_else(|_| PathBuf::from(input_path));
+            find_i3d_file(&input_abs, &target_png, i3d_cache).unwrap_or_else(|e| {
+                warn!("{}", e);
+                None
+            })
+        }
+    };
+    let layer = i3d_file.as_deref().and_then(|path| i3d_cache.get(path)).and_then(|i3d| i3d.layer_for_png(&target_png));
+    match layer {
+        Some(layer) => {
+            verbose!("i3d declares {} channels for {}", layer.num_channels, target_png);
+            layer.num_channels
+        }
+        None => 8,
+    }
+}
 
-        for pixel_idx in 0..(chunk_size * chunk_size) {
-            let mut combined: u32 = 0;
-            let mut shift = 0;
-            for (range_idx, pixels) in range_values.iter().enumerate() {
-                let val = pixels[pixel_idx] as u32;
-                combined |= val << shift;
-                shift += bits_per_range[range_idx];
-            }
+fn convert_grle_to_png(input_path: &str, output_path: &str, strict: bool, png_options: &PngOptions, crop: Option<(usize, usize)>, out_format: OutFormat, num_channels: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let grle = read_grle(input_path, num_channels)?;
+    let (width, height) = (grle.width, grle.height);
 
-            let py = pixel_idx / chunk_size;
-            let px = pixel_idx % chunk_size;
-            let img_x = base_x + px;
-            let img_y = base_y + py;
+    info!("GRLE version: {}", grle.version);
+    info!("Size: {}x{}", width, height);
+    info!("Channels: {}", num_channels);
+    verbose!("RLE stream produced {} of {} pixels", grle.decoded_pixels, width * height);
+    if STATS.load(Ordering::Relaxed) {
+        print_stats(output_path, &grle.values());
+    }
 
-            if use_rgb {
-                let r = (combined & 0xFF) as u8;
-                let g = ((combined >> 8) & 0xFF) as u8;
-                let b = ((combined >> 16) & 0xFF) as u8;
-                let img_idx = (img_y * dimension + img_x) * 3;
-                image[img_idx] = r;
-                image[img_idx + 1] = g;
-                image[img_idx + 2] = b;
-            } else {
-                let img_idx = img_y * dimension + img_x;
-                image[img_idx] = (combined & 0xFF) as u8;
-            }
+    if grle.declared_len != grle.available_len {
+        let msg = format!(
+            "Compressed size mismatch: header declares {} bytes, file has {} bytes after the header",
+            grle.declared_len, grle.available_len
+        );
+        if strict {
+            return Err(ConvertError::InvalidFormat(msg).into());
         }
+        warn!("{}", msg);
     }
 
-    eprintln!("Data consumed: {} / {} bytes", pos, data.len());
-
-    let file = File::create(output_path)?;
-    let w = BufWriter::new(file);
+    // --crop recovers the original region of an image padded with --pad
+    let (width, height, values) = match crop {
+        Some((crop_w, crop_h)) => {
+            if crop_w > width || crop_h > height {
+                return Err(ConvertError::InvalidParams(format!("Cannot crop {}x{} image to {}x{}", width, height, crop_w, crop_h)).into());
+            }
+            verbose!("Cropping {}x{} to {}x{}", width, height, crop_w, crop_h);
+            (crop_w, crop_h, resize_canvas(&grle.values(), width, height, crop_w, crop_h, 0))
+        }
+        None => (width, height, grle.values()),
+    };
 
-    let mut encoder = png::Encoder::new(w, dimension as u32, dimension as u32);
-    if use_rgb {
-        encoder.set_color(png::ColorType::Rgb);
-    } else {
-        encoder.set_color(png::ColorType::Grayscale);
+    if out_format != OutFormat::Png {
+        png_options.warn_unused(out_format);
+        return write_array(output_path, out_format, width, height, &values, num_channels.max(8));
     }
-    encoder.set_depth(png::BitDepth::Eight);
-    encoder.set_compression(png::Compression::Default);
 
+    // Like GDM, layers wider than 8 channels spread over R, G and B
+    let (color_type, image) = match png_options.colorize(&values) {
+        Some(rgb) => (png::ColorType::Rgb, rgb),
+        None => packed_png_pixels(&values, num_channels),
+    };
+    let (color_type, image) = png_options.style(color_type, image);
+    let w = create_output(output_path)?;
+
+    let encoder = png_options.encoder(w, width, height, color_type);
     let mut writer = encoder.write_header()?;
     writer.write_image_data(&image)?;
+    writer.finish()?;
 
-    eprintln!("Saved to {}", output_path);
+    info!("Saved to {}", output_path);
     Ok(())
 }
 
 // ============================================================================
-// GDM Encoder
+// GRLE Encoder
 // ============================================================================
 
-fn encode_gdm_block(pixels: &[u16], chunk_size: usize) -> Vec<u8> {
-    let total_pixels = chunk_size * chunk_size;
+/// Read the preserved header fields from an existing GRLE file
+fn read_grle_template(path: &str) -> Result<GrleHeaderFields, Box<dyn std::error::Error>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
 
-    // Find unique values in this chunk
-    let mut unique_values: Vec<u16> = pixels.iter().copied().collect();
-    unique_values.sort_unstable();
-    unique_values.dedup();
+    let header = GrleHeader::parse(&data).map_err(|e| format!("Template is not a valid GRLE file: {}: {}", path, e))?;
+    Ok(GrleHeaderFields { version: header.version, unknown: header.unknown })
+}
 
-    let mut output = Vec::new();
+/// Apply --grle-flag over the template's (or the default 256) offset-12 field
+fn with_grle_flag(header: GrleHeaderFields, grle_flag: Option<u16>) -> GrleHeaderFields {
+    GrleHeaderFields { unknown: grle_flag.unwrap_or(header.unknown), ..header }
+}
 
-    if unique_values.len() == 1 {
-        // Uniform chunk: bit_depth = 0, palette_count = 1
-        output.push(0u8); // bit_depth
-        output.push(1u8); // palette_count
-        output.extend_from_slice(&unique_values[0].to_le_bytes());
-    } else if unique_values.len() <= 4 {
-        // Use palette with bit_depth 1 or 2
-        let bit_depth = if unique_values.len() <= 2 { 1 } else { 2 };
-        let palette_count = unique_values.len();
+/// A PNG to encode, with indexed images already expanded to RGB(A)
+struct PngImage {
+    width: usize,
+    height: usize,
+    color_type: png::ColorType,
+    /// Sample bit depth of `pixels`; expanded indexed images are 8-bit
+    bit_depth: png::BitDepth,
+    pixels: Vec<u8>,
+}
 
-        output.push(bit_depth);
-        output.push(palette_count as u8);
+/// Read and decode a PNG once, so several encoders can share it
+fn read_png(input_path: &str) -> Result<PngImage, Box<dyn std::error::Error>> {
+    let decoder = png::Decoder::new(Cursor::new(read_input(input_path)?));
+    let mut reader = decoder.read_info()?;
+    // next_frame assembles the Adam7 passes, so the buffer is in pixel order either way
+    if reader.info().interlaced {
+        verbose!("De-interlacing Adam7 PNG");
+    }
+
+    let palette = reader.info().palette.as_ref().map(|p| p.to_vec());
+    let mut pixels = vec![0u8; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels)?;
+
+    let width = info.width as usize;
+    let height = info.height as usize;
 
-        // Write palette
-        for &val in &unique_values {
-            output.extend_from_slice(&val.to_le_bytes());
+    let (color_type, bit_depth, pixels) = match (info.color_type, palette) {
+        (png::ColorType::Indexed, Some(palette)) => {
+            verbose!("Expanding indexed PNG ({} palette entries)", palette.len() / 3);
+            let (color_type, pixels) = expand_indexed(&pixels, &info, &palette)?;
+            (color_type, png::BitDepth::Eight, pixels)
         }
+        (png::ColorType::Indexed, None) => return Err(ConvertError::InvalidFormat("Indexed PNG without a palette".into()).into()),
+        (color_type, _) => (color_type, info.bit_depth, pixels),
+    };
 
-        // Create value to index mapping
-        let value_to_idx: std::collections::HashMap<u16, usize> = unique_values
-            .iter()
-            .enumerate()
-            .map(|(i, &v)| (v, i))
-            .collect();
+    info!("PNG: {}x{}", width, height);
+    Ok(PngImage { width, height, color_type, bit_depth, pixels })
+}
 
-        // Encode bitmap
-        let bitmap_size = (bit_depth as usize) * 128;
-        let mut bitmap = vec![0u8; bitmap_size];
+fn convert_png_to_grle(png: &PngImage, output_path: &str, params: &LayerParams, header: &GrleHeaderFields, strict: bool, pad: Option<u8>, mask_from: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let PngImage { width, height, color_type, bit_depth, ref pixels } = *png;
+    info!("Encoding as GRLE with {} channels", params.num_channels);
+
+    // GRLE dimensions must be multiples of 256 (unless --pad makes them so)
+    let legal_size = |n: usize| ((n + 255) / 256).max(1) * 256;
+    let (padded_width, padded_height) = (legal_size(width), legal_size(height));
+    if pad.is_none() && (padded_width, padded_height) != (width, height) {
+        return Err(ConvertError::InvalidDimensions(format!(
+            "Dimensions must be multiples of 256, got {}x{} (use --pad <value> to pad)",
+            width, height
+        )).into());
+    }
 
-        for (pixel_idx, &pixel) in pixels.iter().enumerate().take(total_pixels) {
-            let idx = value_to_idx[&pixel];
-            let bit_pos = pixel_idx * (bit_depth as usize);
-            let byte_idx = bit_pos / 8;
-            let bit_offset = bit_pos % 8;
+    // The strides below are one byte per sample; packed 1/2/4-bit rows and
+    // 16-bit samples would be read as the wrong pixels
+    if bit_depth != png::BitDepth::Eight {
+        return Err(ConvertError::InvalidFormat(format!(
+            "{}-bit PNGs cannot be encoded as GRLE, only 8-bit ones", bit_depth as u8
+        )).into());
+    }
+    let stride = match color_type {
+        png::ColorType::Grayscale => 1,
+        // The gray sample is the value; alpha only matters for --mask-alpha
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::Rgb => 3,
+        png::ColorType::Rgba => 4,
+        _ => return Err(ConvertError::InvalidFormat("Unsupported PNG color type".into()).into()),
+    };
+    let wide = params.num_channels > 8;
 
-            bitmap[byte_idx] |= (idx as u8) << bit_offset;
-            if bit_offset + (bit_depth as usize) > 8 && byte_idx + 1 < bitmap.len() {
-                bitmap[byte_idx + 1] |= (idx as u8) >> (8 - bit_offset);
+    // Up to 8 channels only R is kept from RGB/RGBA input. Catch colour
+    // images where G or B were painted instead.
+    if stride > 2 && !wide {
+        let mut mismatched = pixels[..width * height * stride]
+            .chunks(stride)
+            .enumerate()
+            .filter(|(_, px)| px[1] != px[0] || px[2] != px[0]);
+        if let Some((first, _)) = mismatched.next() {
+            let msg = format!(
+                "G/B channels differ from R on {} pixels (first at {},{}); only R is encoded",
+                mismatched.count() + 1, first % width, first / width
+            );
+            if strict {
+                return Err(ConvertError::InvalidFormat(msg).into());
             }
+            warn!("{}", msg);
         }
+    }
 
-        output.extend_from_slice(&bitmap);
-    } else {
-        // Need higher bit depth - find max value to determine bits needed
-        let max_val = *unique_values.last().unwrap();
-        let bit_depth = (16 - max_val.leading_zeros()).max(1) as u8;
+    // Wider layers take bits 8-23 from G and B, and 24-31 from alpha, as for GDM
+    let values: Vec<u32> = pixels[..width * height * stride]
+        .chunks(stride)
+        .map(|px| match (wide, px) {
+            (true, &[r, g, b]) => r as u32 | (g as u32) << 8 | (b as u32) << 16,
+            (true, &[r, g, b, a]) => {
+                let a = if params.num_channels > 24 { (a as u32) << 24 } else { 0 };
+                r as u32 | (g as u32) << 8 | (b as u32) << 16 | a
+            }
+            _ => px[0] as u32,
+        })
+        .collect();
 
-        output.push(bit_depth);
-        output.push(0u8); // No palette for high bit depths
+    let alpha = match mask_from {
+        Some(_) => Some(alpha_mask(color_type, bit_depth, pixels, width * height)?),
+        None => None,
+    };
 
-        // Encode raw values in bitmap
-        let bitmap_size = (bit_depth as usize) * 128;
-        let mut bitmap = vec![0u8; bitmap_size];
+    let (mut values, alpha, width, height) = match pad {
+        Some(fill) if (padded_width, padded_height) != (width, height) => {
+            info!(
+                "Padded {}x{} to {}x{} with {} (decode with --crop {}x{} to recover it)",
+                width, height, padded_width, padded_height, fill, width, height
+            );
+            let padded = resize_canvas(&values, width, height, padded_width, padded_height, fill as u32);
+            // The padding is opaque, so it is not taken from the template
+            let alpha = alpha.map(|a| resize_canvas(&a, width, height, padded_width, padded_height, 255));
+            (padded, alpha, padded_width, padded_height)
+        }
+        _ => (values, alpha, width, height),
+    };
+    check_expected_size(params, width, height, strict)?;
+
+    let grle_params = GrleParams::new().num_channels(params.num_channels).header(header.clone());
+    if let (Some(path), Some(alpha)) = (mask_from, alpha) {
+        let existing = decode_grle_with_params(&read_input(path)?, &grle_params)?;
+        if (existing.width, existing.height) != (width, height) {
+            return Err(ConvertError::InvalidParams(format!(
+                "--mask-alpha template {} is {}x{}, the image is {}x{}",
+                path, existing.width, existing.height, width, height
+            )).into());
+        }
+        let kept = keep_transparent(&mut values, &alpha, &existing.values());
+        info!("Kept {} transparent pixels from {}", kept, path);
+    }
 
-        for (pixel_idx, &pixel) in pixels.iter().enumerate().take(total_pixels) {
-            let bit_pos = pixel_idx * (bit_depth as usize);
-            let byte_idx = bit_pos / 8;
-            let bit_offset = bit_pos % 8;
+    // Write file
+    let pixels = grle_pixel_bytes(&values, params.num_channels);
+    let written = encode_grle_to_writer(create_output(output_path)?, &pixels, width, height, &grle_params)?;
+    verify_grle_header(output_path, header, width, height)?;
 
-            let val = pixel as u16;
-            bitmap[byte_idx] |= (val << bit_offset) as u8;
-            if byte_idx + 1 < bitmap.len() {
-                bitmap[byte_idx + 1] |= (val >> (8 - bit_offset)) as u8;
-            }
-        }
+    info!("Saved to {} ({} bytes)", output_path, written);
+    Ok(())
+}
 
-        output.extend_from_slice(&bitmap);
-    }
+// ============================================================================
+// GDM Decoder
+// ============================================================================
+
+/// Trailing bytes after the last GDM block that are accepted as padding
+const GDM_TRAILING_TOLERANCE: usize = 16;
 
-    output
+/// 8-bit PNG samples for packed values: grey up to 8 channels, RGB up to 24,
+/// and RGBA beyond so bits 24-31 go to alpha and layers lose nothing
+fn packed_png_pixels(values: &[u32], num_channels: usize) -> (png::ColorType, Vec<u8>) {
+    if num_channels <= 8 {
+        return (png::ColorType::Grayscale, values.iter().map(|&v| v as u8).collect());
+    }
+    if num_channels <= 24 {
+        return (png::ColorType::Rgb, values.iter().flat_map(|&v| { let [r, g, b, _] = v.to_le_bytes(); [r, g, b] }).collect());
+    }
+    (png::ColorType::Rgba, values.iter().flat_map(|&v| v.to_le_bytes()).collect())
 }
 
-fn convert_png_to_gdm(input_path: &str, output_path: &str, params: &LayerParams) -> Result<(), Box<dyn std::error::Error>> {
-    // Read PNG
-    let file = File::open(input_path)?;
-    let decoder = png::Decoder::new(BufReader::new(file));
-    let mut reader = decoder.read_info()?;
+fn convert_gdm_to_png(input_path: &str, output_path: &str, strict: bool, png_options: &PngOptions, channels: &PngChannels, channel_range: Option<(usize, usize)>, out_format: OutFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let split_foliage = matches!(channels, PngChannels::SplitFoliage);
+    let layout = channels.layout();
+    let data = read_input(input_path)?;
+    let gdm = decode_gdm_with_progress(&data, &mut progress_reporter("Decoding"))?;
 
-    let mut pixels = vec![0u8; reader.output_buffer_size()];
-    let info = reader.next_frame(&mut pixels)?;
+    let dimension = gdm.image.width;
+    let num_channels = gdm.num_channels;
+    let num_compression_ranges = gdm.num_compression_ranges;
+    let type_index_channels = gdm.type_index_channels;
 
-    let width = info.width as usize;
-    let height = info.height as usize;
+    info!("GDM: {}x{}, {} channels, {} compression ranges",
+              dimension, dimension, num_channels, num_compression_ranges);
+    if type_index_channels > 0 {
+        info!("Type index: {} channels, state: {} channels",
+              type_index_channels, num_channels.saturating_sub(type_index_channels));
+    }
+
+    debug!("Data consumed: {} / {} bytes", gdm.bytes_consumed, data.len());
+    verbose!("Header max_bpp: {} (deepest block: {} bits)", gdm.max_bpp, gdm.deepest_block_bits);
+    if STATS.load(Ordering::Relaxed) {
+        print_stats(output_path, &gdm.image.values);
+    }
+
+    // No block can be deeper than 16 bits, so a larger max_bpp byte is
+    // probably a flag with a meaning this decoder does not know
+    if gdm.max_bpp as usize > GDM_MAX_BIT_DEPTH {
+        let msg = format!("Header max_bpp byte is {}, not a bit depth; decoding without it", gdm.max_bpp);
+        if strict {
+            return Err(ConvertError::InvalidFormat(msg).into());
+        }
+        warn!("{}", msg);
+    }
+
+    // A few trailing bytes can be padding; more usually means the header was misread
+    let leftover = data.len().saturating_sub(gdm.bytes_consumed);
+    if leftover > GDM_TRAILING_TOLERANCE {
+        let msg = format!(
+            "Decoded {} of {} bytes ({} left over); the header's channel/range layout may not match the data",
+            gdm.bytes_consumed, data.len(), leftover
+        );
+        if strict {
+            return Err(ConvertError::InvalidFormat(msg).into());
+        }
+        warn!("{}", msg);
+    }
+
+    // --grayscale/--channel-range keep channels first..end, shifted down to bit 0
+    let (values, num_channels, split_foliage) = match channel_range {
+        Some((first, end)) => {
+            if first >= num_channels {
+                return Err(ConvertError::InvalidParams(format!("Channel range {}:{} starts past the file's {} channels", first, end, num_channels)).into());
+            }
+            if end - first > 8 {
+                return Err(ConvertError::InvalidParams(format!("Channel range {}:{} is {} channels; at most 8 fit a grayscale PNG", first, end, end - first)).into());
+            }
+            if split_foliage {
+                warn!("--split-foliage ignored: --channel-range selects the channels");
+            }
+            verbose!("Keeping channels {} to {}", first, end - 1);
+            let mask = (1u32 << (end - first)) - 1;
+            let values = gdm.image.values.iter().map(|&v| (v >> first) & mask).collect();
+            (values, end - first, false)
+        }
+        None => (gdm.image.values, num_channels, split_foliage),
+    };
 
-    if width != height {
-        return Err("GDM requires square dimensions".into());
+    // Arrays hold the packed values; splitting only concerns PNG channels
+    if out_format != OutFormat::Png {
+        if split_foliage {
+            warn!("--split-foliage ignored for {} output", out_format.extension());
+        }
+        if layout.is_some() {
+            warn!("--channel-layout ignored for {} output", out_format.extension());
+        }
+        png_options.warn_unused(out_format);
+        return write_array(output_path, out_format, dimension, dimension, &values, num_channels);
     }
 
-    let dimension = width;
+    // --split-foliage writes the type index into R and the state into G
+    let split = if split_foliage && type_index_channels == 0 {
+        warn!("--split-foliage ignored: file has no type index channels");
+        false
+    } else {
+        split_foliage
+    };
+    if split && (type_index_channels > 8 || num_channels.saturating_sub(type_index_channels) > 8) {
+        return Err(ConvertError::InvalidParams(format!(
+            "Cannot split {} channels with a {}-bit type index into 8-bit R/G",
+            num_channels, type_index_channels
+        )).into());
+    }
 
-    // Calculate dimension log2 (dimension = 2^(dim_log2 + 5))
-    let dim_log2 = (dimension.trailing_zeros() as usize).saturating_sub(5);
-    if (1 << (dim_log2 + 5)) != dimension {
-        return Err(format!("Dimension must be power of 2 >= 32, got {}", dimension).into());
+    if split && png_options.colors.is_some() {
+        warn!("--split-foliage ignored: --colorize colors the packed values");
     }
+    if let Some(layout) = layout {
+        let mask = layout.value_mask();
+        let dropped = values.iter().filter(|&&v| v & !mask != 0).count();
+        if dropped > 0 {
+            let msg = format!("{} values have bits outside --channel-layout; those bits are not written", dropped);
+            if strict {
+                return Err(ConvertError::InvalidFormat(msg).into());
+            }
+            warn!("{}", msg);
+        }
+    }
+    let (color_type, image) = if let Some(layout) = layout {
+        layout.unpack(&values)
+    } else if let Some(rgb) = png_options.colorize(&values) {
+        (png::ColorType::Rgb, rgb)
+    } else if split {
+        let type_mask = (1u32 << type_index_channels) - 1;
+        let image = values.iter().flat_map(|&combined| {
+            [(combined & type_mask) as u8, (combined >> type_index_channels) as u8, 0]
+        }).collect();
+        (png::ColorType::Rgb, image)
+    } else {
+        packed_png_pixels(&values, num_channels)
+    };
+    let (color_type, image) = png_options.style(color_type, image);
 
-    let chunk_size = 32usize;
-    let chunk_log2 = 5usize;
-    let chunks_per_dim = dimension / chunk_size;
+    let w = create_output(output_path)?;
 
-    let num_channels = params.num_channels;
-    let compression_channels = params.compression_channels;
+    let encoder = png_options.encoder(w, dimension, dimension, color_type);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&image)?;
+    writer.finish()?;
 
-    eprintln!("PNG: {}x{}", width, height);
-    eprintln!("Encoding as GDM with {} channels", num_channels);
-    if let Some(cc) = compression_channels {
-        eprintln!("Compression split at channel {}", cc);
+    info!("Saved to {}", output_path);
+    Ok(())
+}
+
+// ============================================================================
+// GDM Encoder
+// ============================================================================
+
+/// Read the preserved header fields from an existing GDM file
+fn read_gdm_template(path: &str) -> Result<GdmHeaderFields, Box<dyn std::error::Error>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+
+    // !MDF has no type index or reserved bytes (they parse as 0), only max_bpp carries over.
+    // The template's max_bpp is only a floor: edited data may need deeper blocks.
+    let header = parse_gdm_header(&data).map_err(|e| format!("Template is not a valid GDM file: {}: {}", path, e))?;
+    Ok(GdmHeaderFields {
+        max_bpp: None,
+        max_bpp_floor: header.max_bpp,
+        type_index_channels: header.type_index_channels as u8,
+        reserved: header.reserved,
+    })
+}
+
+/// Apply --max-bpp over the computed max_bpp byte (and any template floor)
+fn with_max_bpp(header: GdmHeaderFields, max_bpp: Option<u8>) -> GdmHeaderFields {
+    GdmHeaderFields { max_bpp: max_bpp.or(header.max_bpp), ..header }
+}
+
+fn convert_png_to_gdm(png: &PngImage, output_path: &str, params: &LayerParams, header: &GdmHeaderFields, strict: bool, channels: &PngChannels, mask_from: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let PngImage { width, height, color_type, bit_depth, ref pixels } = *png;
+    let split_foliage = matches!(channels, PngChannels::SplitFoliage);
+    let layout = channels.layout();
+    info!("Encoding as GDM with {} channels", params.num_channels);
+    check_expected_size(params, width, height, strict)?;
+    if !params.compression_channels.is_empty() {
+        info!("Compression split at channels {:?}", params.compression_channels);
+    }
+    if let Some(n) = params.type_index_channels {
+        info!("Type index: {} channels, state: {} channels", n, params.num_channels.saturating_sub(n));
     }
 
-    // Determine number of compression ranges
-    let (num_compression_ranges, bits_per_range): (usize, Vec<usize>) =
-        if let Some(cc) = compression_channels {
-            (2, vec![cc, num_channels - cc])
-        } else {
-            (1, vec![num_channels])
-        };
+    // --split-foliage images carry the type index in R and the state in G
+    let split_shift = if split_foliage {
+        let type_bits = params
+            .type_index_channels
+            .or(Some(header.type_index_channels as usize).filter(|&n| n > 0))
+            .ok_or("--split-foliage needs type index channels (from the i3d, --type-index-channels or --template)")?;
+        if !matches!(color_type, png::ColorType::Rgb | png::ColorType::Rgba) {
+            return Err(ConvertError::InvalidParams("--split-foliage expects an RGB or RGBA PNG".into()).into());
+        }
+        Some(type_bits)
+    } else {
+        None
+    };
+    let pack_rgb = |r: u32, g: u32, b: u32| match split_shift {
+        Some(shift) => r | (g << shift),
+        None => r | (g << 8) | (b << 16),
+    };
+    // Alpha carries bits 24-31 for layers wider than 24 channels, otherwise it is ignored
+    let alpha_shift = if params.num_channels > 24 && split_shift.is_none() && layout.is_none() { Some(24) } else { None };
+
+    if let Some(layout) = layout.filter(|l| l.value_channels() > params.num_channels) {
+        return Err(ConvertError::InvalidParams(format!(
+            "--channel-layout fills {} bits, more than the layer's {} channels",
+            layout.value_channels(), params.num_channels
+        )).into());
+    }
 
-    // Convert PNG pixels to channel values
-    let _use_rgb = num_channels > 8;
+    // Packed 1/2/4-bit grayscale rows would be read as one byte per pixel
+    if !matches!(bit_depth, png::BitDepth::Eight | png::BitDepth::Sixteen) {
+        return Err(ConvertError::InvalidFormat(format!(
+            "{}-bit PNGs cannot be encoded as GDM, only 8- and 16-bit ones", bit_depth as u8
+        )).into());
+    }
+    if layout.is_none() && bit_depth == png::BitDepth::Sixteen && !matches!(color_type, png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha) {
+        return Err(ConvertError::InvalidFormat("16-bit PNGs are only supported as grayscale".into()).into());
+    }
 
-    let channel_values: Vec<u32> = match info.color_type {
+    // Convert PNG pixels to channel values. Grayscale+alpha keeps the gray
+    // sample; alpha only matters for --mask-alpha.
+    let channel_values: Vec<u32> = match color_type {
+        _ if layout.is_some() => layout.unwrap().pack(color_type, bit_depth, pixels, width * height)?,
+        png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha if bit_depth == png::BitDepth::Sixteen => {
+            // 16-bit samples are big-endian
+            let stride = if color_type == png::ColorType::Grayscale { 2 } else { 4 };
+            pixels[..width * height * stride]
+                .chunks(stride)
+                .map(|s| u16::from_be_bytes([s[0], s[1]]) as u32)
+                .collect()
+        }
         png::ColorType::Grayscale => {
             pixels[..width * height].iter().map(|&v| v as u32).collect()
         }
+        png::ColorType::GrayscaleAlpha => {
+            pixels[..width * height * 2].chunks(2).map(|s| s[0] as u32).collect()
+        }
         png::ColorType::Rgb => {
             let mut values = Vec::with_capacity(width * height);
             for chunk in pixels.chunks(3) {
                 let r = chunk[0] as u32;
                 let g = chunk[1] as u32;
                 let b = chunk[2] as u32;
-                values.push(r | (g << 8) | (b << 16));
+                values.push(pack_rgb(r, g, b));
             }
             values
         }
@@ -734,117 +1442,1220 @@ fn convert_png_to_gdm(input_path: &str, output_path: &str, params: &LayerParams)
                 let r = chunk[0] as u32;
                 let g = chunk[1] as u32;
                 let b = chunk[2] as u32;
-                values.push(r | (g << 8) | (b << 16));
+                let a = alpha_shift.map_or(0, |shift| (chunk[3] as u32) << shift);
+                values.push(pack_rgb(r, g, b) | a);
             }
             values
         }
-        _ => return Err("Unsupported PNG color type".into()),
+        _ => return Err(ConvertError::InvalidFormat("Unsupported PNG color type".into()).into()),
     };
 
-    // Build GDM file
-    let mut output = Vec::new();
-
-    // Header ("MDF variant)
-    output.extend_from_slice(b"\"MDF");
-    output.extend_from_slice(&0u32.to_le_bytes()); // version
-    output.push(dim_log2 as u8);
-    output.push(chunk_log2 as u8);
-    output.push(2u8); // max_bpp (expected <= 2 for palette mode)
-    output.push(num_channels as u8);
-    output.push(num_compression_ranges as u8);
-    output.push(0u8); // type_index_channels
-    output.extend_from_slice(&[0u8; 2]); // padding to 16 bytes
-
-    // Compression boundaries (if more than 1 range)
-    if num_compression_ranges > 1 {
-        if let Some(cc) = compression_channels {
-            output.push(cc as u8);
-        }
-    }
-
-    // Encode chunks
-    for chunk_idx in 0..(chunks_per_dim * chunks_per_dim) {
-        let chunk_row = chunk_idx / chunks_per_dim;
-        let chunk_col = chunk_idx % chunks_per_dim;
-        let base_y = chunk_row * chunk_size;
-        let base_x = chunk_col * chunk_size;
-
-        // Extract pixel values for this chunk
-        let mut chunk_pixels: Vec<u32> = Vec::with_capacity(chunk_size * chunk_size);
-        for py in 0..chunk_size {
-            for px in 0..chunk_size {
-                let img_x = base_x + px;
-                let img_y = base_y + py;
-                chunk_pixels.push(channel_values[img_y * dimension + img_x]);
-            }
-        }
-
-        // Encode each compression range
-        let mut shift = 0;
-        for range_idx in 0..num_compression_ranges {
-            let range_bits = bits_per_range[range_idx];
-            let mask = (1u32 << range_bits) - 1;
-
-            // Extract range values from combined pixel values
-            let range_pixels: Vec<u16> = chunk_pixels
-                .iter()
-                .map(|&v| ((v >> shift) & mask) as u16)
-                .collect();
+    let mut channel_values = channel_values;
+    if let Some(path) = mask_from {
+        if alpha_shift.is_some() {
+            return Err(ConvertError::InvalidParams("--mask-alpha cannot be used when alpha holds channels 24-31".into()).into());
+        }
+        if layout.is_some_and(ChannelLayout::uses_alpha) {
+            return Err(ConvertError::InvalidParams("--mask-alpha cannot be used when --channel-layout reads alpha".into()).into());
+        }
+        let alpha = alpha_mask(color_type, bit_depth, pixels, width * height)?;
+        let existing = decode_gdm_from_reader(open_input(path)?)?.image;
+        if (existing.width, existing.height) != (width, height) {
+            return Err(ConvertError::InvalidParams(format!(
+                "--mask-alpha template {} is {}x{}, the image is {}x{}",
+                path, existing.width, existing.height, width, height
+            )).into());
+        }
+        let kept = keep_transparent(&mut channel_values, &alpha, &existing.values);
+        info!("Kept {} transparent pixels from {}", kept, path);
+    }
+
+    let image = DecodedImage { width, height, values: channel_values };
+    let gdm_params = params.gdm_params(header);
+    let output = encode_gdm_with_progress(&image, &gdm_params, &mut progress_reporter("Encoding"))?;
 
-            let block = encode_gdm_block(&range_pixels, chunk_size);
-            output.extend_from_slice(&block);
+    // Write file
+    write_output(output_path, &output)?;
+    verify_gdm_header(output_path, &gdm_params, width)?;
+
+    info!("Saved to {} ({} bytes)", output_path, output.len());
+    warn_if_oversized(output_path, &output, width * height, params.num_channels);
+    Ok(())
+}
+
+// ============================================================================
+// Direct GDM <-> GRLE
+// ============================================================================
 
-            shift += range_bits;
+/// Re-encode a GDM as GRLE without a PNG in between. GRLE holds 8 bits per
+/// pixel, so larger values are an error rather than being truncated.
+fn convert_gdm_to_grle(input_path: &str, output_path: &str, header: &GrleHeaderFields, pad: Option<u8>) -> Result<(), Box<dyn std::error::Error>> {
+    let gdm = decode_gdm_from_reader(open_input(input_path)?)?;
+    let (width, height) = (gdm.image.width, gdm.image.height);
+    info!("GDM: {}x{}, {} channels", width, height, gdm.num_channels);
+
+    let too_wide = gdm.image.values.iter().filter(|&&v| v > 0xff).count();
+    if too_wide > 0 {
+        let max = gdm.image.values.iter().max().copied().unwrap_or(0);
+        return Err(format!("{} pixels do not fit in GRLE's 8 bits (largest value {})", too_wide, max).into());
+    }
+    let pixels: Vec<u8> = gdm.image.values.iter().map(|&v| v as u8).collect();
+
+    let legal_size = |n: usize| ((n + 255) / 256).max(1) * 256;
+    let (pixels, width, height) = match pad {
+        _ if width % 256 == 0 && height % 256 == 0 => (pixels, width, height),
+        Some(fill) => {
+            let (padded_width, padded_height) = (legal_size(width), legal_size(height));
+            info!("Padded {}x{} to {}x{} with {}", width, height, padded_width, padded_height, fill);
+            (resize_canvas(&pixels, width, height, padded_width, padded_height, fill), padded_width, padded_height)
+        }
+        None => {
+            return Err(ConvertError::InvalidDimensions(format!(
+                "GRLE dimensions must be multiples of 256, got {}x{} (use --pad <value> to pad)", width, height
+            )).into());
         }
+    };
+
+    let written = encode_grle_to_writer(create_output(output_path)?, &pixels, width, height, &GrleParams::new().header(header.clone()))?;
+    verify_grle_header(output_path, header, width, height)?;
+    info!("Saved to {} ({} bytes)", output_path, written);
+    Ok(())
+}
+
+/// Re-encode a GRLE as GDM without a PNG in between
+fn convert_grle_to_gdm(input_path: &str, output_path: &str, params: &GdmParams) -> Result<(), Box<dyn std::error::Error>> {
+    let grle = read_grle(input_path, 8)?;
+    info!("GRLE: {}x{}", grle.width, grle.height);
+    info!("Encoding as GDM with {} channels", params.num_channels);
+
+    let image = DecodedImage {
+        width: grle.width,
+        height: grle.height,
+        values: grle.values(),
+    };
+    let output = encode_gdm(&image, params)?;
+    write_output(output_path, &output)?;
+    verify_gdm_header(output_path, params, grle.width)?;
+    info!("Saved to {} ({} bytes)", output_path, output.len());
+    warn_if_oversized(output_path, &output, grle.width * grle.height, params.num_channels);
+    Ok(())
+}
+
+// ============================================================================
+// Histogram
+// ============================================================================
+
+/// `grleconvert histogram <input> [--i3d <path>]`: count each distinct pixel value
+/// (the packed value for GDM). With --i3d, values are annotated with the
+/// `<Group>`/`<Option>` names the i3d declares for the layer.
+fn run_histogram(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input_path: Option<&str> = None;
+    let mut i3d_path: Option<&str> = None;
+    let mut from_format: Option<String> = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--i3d" if i + 1 < args.len() => {
+                i3d_path = Some(&args[i + 1]);
+                i += 1;
+            }
+            "--from" if i + 1 < args.len() => {
+                from_format = Some(args[i + 1].to_lowercase());
+                i += 1;
+            }
+            arg if input_path.is_none() && (arg == "-" || !arg.starts_with('-')) => input_path = Some(arg),
+            arg => return Err(format!("Unknown histogram argument: {}", arg).into()),
+        }
+        i += 1;
     }
+    let input_path = input_path.ok_or("Usage: grleconvert histogram <input.gdm|input.grle> [--i3d <path>]")?;
 
-    // Write file
-    let mut file = File::create(output_path)?;
-    file.write_all(&output)?;
+    let data = read_input(input_path)?;
+    let values: Vec<u32> = match from_format.unwrap_or_else(|| file_extension(input_path)).as_str() {
+        "gdm" => decode_gdm(&data)?.image.values,
+        "grle" => decode_grle(&data)?.pixels.iter().map(|&p| p as u32).collect(),
+        other => return Err(format!("Cannot make a histogram of '{}' input (expected gdm or grle)", other).into()),
+    };
+
+    let i3d = match i3d_path {
+        Some(path) => Some(I3d::parse(Path::new(path)).map_err(|e| format!("Cannot read {}: {}", path, e))?),
+        None => None,
+    };
+    let target_png = format!("{}.png", base_stem(input_path));
+    let layer = i3d.as_ref().and_then(|i3d| i3d.layer_for_png(&target_png));
+    if i3d.is_some() && layer.is_none() {
+        warn!("{} is not a layer in the i3d; showing values without meanings", target_png);
+    }
+
+    let mut counts: HashMap<u32, usize> = HashMap::new();
+    for &v in &values {
+        *counts.entry(v).or_insert(0) += 1;
+    }
+    let mut counts: Vec<(u32, usize)> = counts.into_iter().collect();
+    counts.sort_unstable();
+
+    println!("{:>10} {:>10} {:>7}  Meaning", "Value", "Count", "Share");
+    for (value, count) in counts {
+        let share = 100.0 * count as f64 / values.len().max(1) as f64;
+        let meaning = layer.and_then(|l| l.meaning(value)).unwrap_or_default();
+        println!("{:>10} {:>10} {:>6.2}%  {}", value, count, share, meaning);
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Analyze
+// ============================================================================
 
-    eprintln!("Saved to {} ({} bytes)", output_path, output.len());
+/// `grleconvert analyze <input.gdm>`: count the blocks of each bit depth and the
+/// bytes they take, to see how well a file compresses
+fn run_analyze(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let input_path = match args {
+        [path] => path.as_str(),
+        _ => return Err("Usage: grleconvert analyze <input.gdm>".into()),
+    };
+    let data = read_input(input_path)?;
+    let index = index_gdm(&data)?;
+
+    // bit depth -> (blocks, bytes, smallest palette, largest palette)
+    let mut depths: HashMap<u8, (usize, usize, u8, u8)> = HashMap::new();
+    for block in &index.blocks {
+        let (bit_depth, palette_count) = (data[block.offset], data[block.offset + 1]);
+        let entry = depths.entry(bit_depth).or_insert((0, 0, u8::MAX, 0));
+        entry.0 += 1;
+        entry.1 += block.len;
+        entry.2 = entry.2.min(palette_count);
+        entry.3 = entry.3.max(palette_count);
+    }
+    let mut depths: Vec<_> = depths.into_iter().collect();
+    depths.sort_unstable();
+
+    let block_bytes: usize = index.blocks.iter().map(|b| b.len).sum();
+    println!(
+        "{}: {}x{} chunks of {}x{}, {} range(s), {} blocks",
+        input_path,
+        index.chunks_per_dim,
+        index.chunks_per_dim,
+        index.chunk_size,
+        index.chunk_size,
+        index.num_compression_ranges,
+        index.blocks.len()
+    );
+    println!(
+        "{} bytes: {} header, {} blocks, {} trailing",
+        data.len(),
+        index.data_start,
+        block_bytes,
+        data.len() - index.data_start - block_bytes
+    );
+    println!();
+    println!("{:>9} {:>8} {:>7} {:>10} {:>7}  Palette", "Bit depth", "Blocks", "Share", "Bytes", "Share");
+    for (bit_depth, (blocks, bytes, min_palette, max_palette)) in depths {
+        let palette = if min_palette == max_palette {
+            min_palette.to_string()
+        } else {
+            format!("{}-{}", min_palette, max_palette)
+        };
+        println!(
+            "{:>9} {:>8} {:>6.2}% {:>10} {:>6.2}%  {}",
+            bit_depth,
+            blocks,
+            100.0 * blocks as f64 / index.blocks.len().max(1) as f64,
+            bytes,
+            100.0 * bytes as f64 / block_bytes.max(1) as f64,
+            palette
+        );
+    }
     Ok(())
 }
 
+// ============================================================================
+// Growth states
+// ============================================================================
+
+/// APNG frame delay per growth state, in milliseconds
+const STATE_FRAME_MS: u16 = 1000;
+
+/// `grleconvert states <input.gdm>`: one mask PNG per growth state of a
+/// FoliageMultiLayer (white where the state occurs), or one APNG of all of them
+fn run_states(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input_path: Option<&str> = None;
+    let mut type_index: Option<usize> = None;
+    let mut out_dir: Option<PathBuf> = None;
+    let mut apng = false;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--type-index-channels" => {
+                i += 1;
+                type_index = Some(args.get(i).and_then(|v| v.parse().ok()).ok_or("--type-index-channels expects a channel count")?);
+            }
+            "--out-dir" => {
+                i += 1;
+                out_dir = Some(PathBuf::from(args.get(i).ok_or("--out-dir expects a directory")?));
+            }
+            "--apng" => apng = true,
+            "--force" | "-f" => FORCE.store(true, Ordering::Relaxed),
+            "--quiet" | "-q" => VERBOSITY.store(0, Ordering::Relaxed),
+            arg if input_path.is_none() && !arg.starts_with('-') => input_path = Some(arg),
+            arg => return Err(format!("Unknown states argument: {}", arg).into()),
+        }
+        i += 1;
+    }
+    let input_path = input_path.ok_or("Usage: grleconvert states <input.gdm> [--type-index-channels N] [--out-dir DIR] [--apng]")?;
+
+    let gdm = decode_gdm_from_reader(open_input(input_path)?)?;
+    let type_index_channels = type_index.unwrap_or(gdm.type_index_channels);
+    if type_index_channels == 0 {
+        return Err(ConvertError::InvalidParams(format!("{} has no type index channels (use --type-index-channels)", input_path)).into());
+    }
+    if type_index_channels >= gdm.num_channels {
+        return Err(ConvertError::InvalidParams(format!(
+            "{} type index channels leave no state bits in {} channels",
+            type_index_channels, gdm.num_channels
+        )).into());
+    }
+    let state_mask = (1u32 << (gdm.num_channels - type_index_channels)) - 1;
+    let states: Vec<u32> = gdm.image.values.iter().map(|&v| (v >> type_index_channels) & state_mask).collect();
+
+    let mut present: Vec<u32> = states.clone();
+    present.sort_unstable();
+    present.dedup();
+    info!("{}: {} growth state(s): {:?}", input_path, present.len(), present);
+
+    let dir = match out_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(&dir)?;
+            dir
+        }
+        None => Path::new(input_path).with_file_name(""),
+    };
+    let stem = base_stem(input_path);
+    let mask = |state: u32| -> Vec<u8> { states.iter().map(|&s| if s == state { 255 } else { 0 }).collect() };
+    let size = gdm.image.width as u32;
+
+    if apng {
+        let output = dir.join(format!("{}_states.png", stem)).to_string_lossy().into_owned();
+        let mut encoder = png::Encoder::new(create_output(&output)?, size, size);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_animated(present.len() as u32, 0)?;
+        encoder.set_frame_delay(STATE_FRAME_MS, 1000)?;
+        let mut writer = encoder.write_header()?;
+        for &state in &present {
+            writer.write_image_data(&mask(state))?;
+        }
+        writer.finish()?;
+        info!("Saved {} frames to {}", present.len(), output);
+        return Ok(());
+    }
+
+    for &state in &present {
+        let output = dir.join(format!("{}_state{}.png", stem, state)).to_string_lossy().into_owned();
+        let mut encoder = png::Encoder::new(create_output(&output)?, size, size);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&mask(state))?;
+        writer.finish()?;
+        info!("Saved state {} to {}", state, output);
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Stitch
+// ============================================================================
+
+/// `grleconvert stitch --pattern <tiles> --grid <cols>x<rows> <output.png>`: decode
+/// tile files named by `{r}` (row) and `{c}` (column) and assemble them into one PNG
+fn run_stitch(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut pattern: Option<&str> = None;
+    let mut grid: Option<(usize, usize)> = None;
+    let mut output_path: Option<&str> = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--pattern" => {
+                i += 1;
+                pattern = Some(args.get(i).ok_or("--pattern expects a tile name like densityMap_ground_{r}_{c}.gdm")?);
+            }
+            "--grid" => {
+                i += 1;
+                grid = Some(args.get(i).and_then(|v| parse_size(v)).ok_or("--grid expects <columns>x<rows>, like 2x2")?);
+            }
+            "--force" | "-f" => FORCE.store(true, Ordering::Relaxed),
+            "--quiet" | "-q" => VERBOSITY.store(0, Ordering::Relaxed),
+            arg if output_path.is_none() && (arg == "-" || !arg.starts_with('-')) => output_path = Some(arg),
+            arg => return Err(format!("Unknown stitch argument: {}", arg).into()),
+        }
+        i += 1;
+    }
+    let usage = "Usage: grleconvert stitch --pattern <tile_{r}_{c}.gdm> --grid <cols>x<rows> <output.png>";
+    let (Some(pattern), Some((cols, rows)), Some(output_path)) = (pattern, grid, output_path) else {
+        return Err(usage.into());
+    };
+    if !pattern.contains("{r}") || !pattern.contains("{c}") {
+        return Err("--pattern must contain both {r} and {c}".into());
+    }
+
+    // Tiles in row-major order; all must match the first one's size and channels
+    let mut tiles: Vec<(DecodedImage, usize)> = Vec::with_capacity(cols * rows);
+    for r in 0..rows {
+        for c in 0..cols {
+            let path = pattern.replace("{r}", &r.to_string()).replace("{c}", &c.to_string());
+            let (image, channels) = match file_extension(&path).as_str() {
+                "gdm" => {
+                    let gdm = decode_gdm_from_reader(open_input(&path)?)?;
+                    (gdm.image, gdm.num_channels)
+                }
+                "grle" => {
+                    let grle = read_grle(&path, 8)?;
+                    let values = grle.values();
+                    (DecodedImage { width: grle.width, height: grle.height, values }, 8)
+                }
+                other => return Err(format!("stitch only reads .gdm and .grle tiles, not '{}'", other).into()),
+            };
+            if let Some((first, first_channels)) = tiles.first() {
+                if (image.width, image.height, channels) != (first.width, first.height, *first_channels) {
+                    return Err(format!(
+                        "Tile {} is {}x{} with {} channels, tile 0,0 is {}x{} with {}",
+                        path, image.width, image.height, channels, first.width, first.height, first_channels
+                    ).into());
+                }
+            }
+            verbose!("Tile {},{}: {}", r, c, path);
+            tiles.push((image, channels));
+        }
+    }
+
+    let (tile_width, tile_height, num_channels) = (tiles[0].0.width, tiles[0].0.height, tiles[0].1);
+    let (width, height) = (tile_width * cols, tile_height * rows);
+    let mut values = vec![0u32; width * height];
+    for (index, (tile, _)) in tiles.iter().enumerate() {
+        let (r, c) = (index / cols, index % cols);
+        for y in 0..tile_height {
+            let dest = (r * tile_height + y) * width + c * tile_width;
+            values[dest..dest + tile_width].copy_from_slice(&tile.values[y * tile_width..(y + 1) * tile_width]);
+        }
+    }
+
+    let (color_type, image) = packed_png_pixels(&values, num_channels);
+    let mut encoder = png::Encoder::new(create_output(output_path)?, width as u32, height as u32);
+    encoder.set_color(color_type);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&image)?;
+    writer.finish()?;
+
+    info!("Stitched {}x{} tiles of {}x{} into {} ({}x{})", cols, rows, tile_width, tile_height, output_path, width, height);
+    Ok(())
+}
+
+// ============================================================================
+// Batch
+// ============================================================================
+
+/// What a batch worker did with one input
+#[derive(Clone)]
+enum BatchOutput {
+    Written(String),
+    /// --overwrite-if-newer found the output up to date
+    Skipped(String),
+}
+
+/// Decode or encode one batch input, by its extension
+fn batch_convert(input_path: &str, naming: &BatchNaming, out_format: OutFormat, strict: bool, split_foliage: bool) -> Result<BatchOutput, String> {
+    match file_extension(input_path).as_str() {
+        "gdm" | "grle" => batch_decode(input_path, naming, out_format, strict, split_foliage),
+        "png" => batch_encode(input_path, naming, strict, split_foliage),
+        other => Err(format!("batch converts .gdm, .grle and .png files, not '{}'", other)),
+    }
+}
+
+/// Decode one batch input next to itself, or into `out_dir`
+fn batch_decode(input_path: &str, naming: &BatchNaming, out_format: OutFormat, strict: bool, split_foliage: bool) -> Result<BatchOutput, String> {
+    let out_dir = naming.out_dir.clone().unwrap_or_else(|| Path::new(input_path).with_file_name(""));
+    let output = decode_output_path(input_path, out_format, Some(&out_dir), naming.template.as_deref(), naming.i3d.as_deref(), &mut naming.i3d_cache())
+        .map_err(|e| e.to_string())?;
+    if up_to_date(input_path, &output) {
+        return Ok(BatchOutput::Skipped(output));
+    }
+    let result = if file_extension(input_path) == "grle" {
+        let num_channels = grle_channels(input_path, naming.i3d.as_deref(), None, &mut naming.i3d_cache());
+        convert_grle_to_png(input_path, &output, strict, &PngOptions::default(), None, out_format, num_channels)
+    } else {
+        let channels = if split_foliage { PngChannels::SplitFoliage } else { PngChannels::Packed };
+        convert_gdm_to_png(input_path, &output, strict, &PngOptions::default(), &channels, None, out_format)
+    };
+    result.map(|()| BatchOutput::Written(output)).map_err(|e| e.to_string())
+}
+
+/// Encode one batch PNG to the format of its i3d layer, next to itself or into
+/// `out_dir`. Without a layer there is nothing to take the channel count from.
+fn batch_encode(input_path: &str, naming: &BatchNaming, strict: bool, split_foliage: bool) -> Result<BatchOutput, String> {
+    let input_abs = std::fs::canonicalize(input_path).unwrap_or_else(|_| PathBuf::from(input_path));
+    let filename = input_abs.file_name().and_then(|f| f.to_str()).unwrap_or(input_path);
+    let params = {
+        let mut i3d_cache = naming.i3d_cache();
+        let i3d_file = match naming.i3d {
+            Some(ref path) => Some(PathBuf::from(path)),
+            None => find_i3d_file(&input_abs, filename, &mut i3d_cache)?,
+        };
+        i3d_file.as_ref().and_then(|path| {
+            let i3d = i3d_cache.get(path)?;
+            let mut params = layer_params_for_file(i3d, filename)?;
+            params.expected_size = existing_layer_size(path, i3d, filename);
+            Some(params)
+        })
+    };
+    let params = params.ok_or_else(|| format!("no i3d layer for {} (encode it on its own with --channels)", filename))?;
+
+    let extension = match params.layer_type {
+        LayerType::InfoLayer => "grle",
+        LayerType::GdmLayer => "gdm",
+    };
+    let out_dir = naming.out_dir.clone().unwrap_or_else(|| Path::new(input_path).with_file_name(""));
+    std::fs::create_dir_all(&out_dir).map_err(|e| e.to_string())?;
+    let output = out_dir.join(format!("{}.{}", base_stem(input_path), extension)).to_string_lossy().into_owned();
+    if up_to_date(input_path, &output) {
+        return Ok(BatchOutput::Skipped(output));
+    }
+
+    let result = read_png(input_path).and_then(|png| match params.layer_type {
+        LayerType::InfoLayer => convert_png_to_grle(&png, &output, &params, &GrleHeaderFields::default(), strict, None, None),
+        LayerType::GdmLayer => {
+            let channels = if split_foliage { PngChannels::SplitFoliage } else { PngChannels::Packed };
+            convert_png_to_gdm(&png, &output, &params, &GdmHeaderFields::default(), strict, &channels, None)
+        }
+    });
+    result.map(|()| BatchOutput::Written(output)).map_err(|e| e.to_string())
+}
+
+/// Output naming and i3d lookups shared by all batch workers
+#[derive(Default)]
+struct BatchNaming {
+    out_dir: Option<PathBuf>,
+    template: Option<String>,
+    i3d: Option<String>,
+    /// One cache for the whole batch, so each i3d is parsed once
+    i3d_cache: Mutex<I3dCache>,
+}
+
+impl BatchNaming {
+    fn i3d_cache(&self) -> std::sync::MutexGuard<'_, I3dCache> {
+        self.i3d_cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// `grleconvert batch [--jobs N] <inputs...>`: decode GDM and GRLE files and
+/// encode PNGs on a pool of worker threads. Results are printed in input order once all are done; a
+/// failing or panicking file is reported without stopping the others.
+fn run_batch(args: &[String]) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut inputs: Vec<&str> = Vec::new();
+    let mut jobs = 1usize;
+    let mut naming = BatchNaming::default();
+    let mut out_format = OutFormat::Png;
+    let mut strict = false;
+    let mut split_foliage = false;
+    let mut summary = false;
+    let mut progress = false;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--max-dimension" => {
+                i += 1;
+                set_max_dimension(args.get(i).and_then(|v| v.parse().ok()).ok_or("--max-dimension expects a side length in pixels")?);
+            }
+            "--jobs" | "-j" => {
+                i += 1;
+                jobs = args.get(i).and_then(|v| v.parse().ok()).filter(|&n| n > 0).ok_or("--jobs expects a thread count of 1 or more")?;
+            }
+            "--out-dir" => {
+                i += 1;
+                naming.out_dir = Some(PathBuf::from(args.get(i).ok_or("--out-dir expects a directory")?));
+            }
+            "--name-template" => {
+                i += 1;
+                naming.template = Some(args.get(i).ok_or("--name-template expects a file name template")?.clone());
+            }
+            "--i3d" => {
+                i += 1;
+                naming.i3d = Some(args.get(i).ok_or("--i3d expects a path")?.clone());
+            }
+            "--out-format" => {
+                i += 1;
+                out_format = args.get(i).and_then(|v| OutFormat::parse(&v.to_lowercase())).ok_or("--out-format expects png, raw or npy")?;
+            }
+            "--strict" => strict = true,
+            "--split-foliage" => split_foliage = true,
+            "--summary" => summary = true,
+            "--progress" => progress = true,
+            "--overwrite-if-newer" => OVERWRITE_IF_NEWER.store(true, Ordering::Relaxed),
+            "--force" | "-f" => FORCE.store(true, Ordering::Relaxed),
+            "--quiet" | "-q" => VERBOSITY.store(0, Ordering::Relaxed),
+            arg if !arg.starts_with('-') => inputs.push(arg),
+            arg => return Err(format!("Unknown batch argument: {}", arg).into()),
+        }
+        i += 1;
+    }
+    if inputs.is_empty() {
+        return Err("Usage: grleconvert batch [--jobs N] [--out-dir DIR] [--summary] <input.gdm|input.grle|input.png>...".into());
+    }
+
+    // Workers take the next unclaimed input until none are left
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<BatchOutput, String>>>> = Mutex::new(vec![None; inputs.len()]);
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(inputs.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(&input) = inputs.get(index) else { break };
+                let result = std::panic::catch_unwind(|| batch_convert(input, &naming, out_format, strict, split_foliage))
+                    .unwrap_or_else(|_| Err("panicked while converting".to_string()));
+                let mut results = results.lock().unwrap_or_else(|e| e.into_inner());
+                results[index] = Some(result);
+                if progress {
+                    let done = results.iter().filter(|r| r.is_some()).count();
+                    eprint!("\r{}/{} files", done, inputs.len());
+                    if done == inputs.len() {
+                        eprintln!();
+                    }
+                }
+            });
+        }
+    });
+
+    let mut failed = 0;
+    let mut skipped = 0;
+    let mut converted = Vec::new();
+    for (&input, result) in inputs.iter().zip(results.into_inner().unwrap_or_else(|e| e.into_inner())) {
+        match result.unwrap_or_else(|| Err("not converted".to_string())) {
+            Ok(BatchOutput::Written(output)) => {
+                println!("ok     {} -> {}", input, output);
+                converted.push((input, output));
+            }
+            Ok(BatchOutput::Skipped(output)) => {
+                println!("skip   {} -> {} (up to date)", input, output);
+                skipped += 1;
+            }
+            Err(e) => {
+                println!("FAILED {}: {}", input, e);
+                failed += 1;
+            }
+        }
+    }
+    if OVERWRITE_IF_NEWER.load(Ordering::Relaxed) {
+        println!("{} converted, {} skipped, {} failed", converted.len(), skipped, failed);
+    } else {
+        println!("{} converted, {} failed", inputs.len() - failed, failed);
+    }
+    if summary {
+        print_batch_summary(&converted);
+    }
+    Ok(failed == 0)
+}
+
+/// --summary: files and bytes read and written per input format (per output
+/// format for encoded PNGs), and the output size relative to the input
+fn print_batch_summary(converted: &[(&str, String)]) {
+    let size = |path: &str| std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    // format -> (files, input bytes, output bytes)
+    let mut totals: BTreeMap<String, (usize, u64, u64)> = BTreeMap::new();
+    for (input, output) in converted {
+        let format = match file_extension(input).as_str() {
+            "png" => format!("png->{}", file_extension(output)),
+            other => other.to_string(),
+        };
+        let entry = totals.entry(format).or_insert((0, 0, 0));
+        entry.0 += 1;
+        entry.1 += size(input);
+        entry.2 += size(output);
+    }
+    let all = totals.values().fold((0, 0, 0), |acc, t| (acc.0 + t.0, acc.1 + t.1, acc.2 + t.2));
+
+    let ratio = |input: u64, output: u64| if input == 0 { "-".to_string() } else { format!("{:.2}x", output as f64 / input as f64) };
+    println!();
+    println!("{:<10} {:>6} {:>14} {:>14} {:>7}", "Format", "Files", "Input bytes", "Output bytes", "Ratio");
+    for (format, (files, input, output)) in &totals {
+        println!("{:<10} {:>6} {:>14} {:>14} {:>7}", format, files, input, output, ratio(*input, *output));
+    }
+    println!("{:<10} {:>6} {:>14} {:>14} {:>7}", "total", all.0, all.1, all.2, ratio(all.1, all.2));
+}
+
+// ============================================================================
+// List layers
+// ============================================================================
+
+/// `grleconvert --list-layers <map.i3d>`: one line per density map layer the
+/// i3d defines, with its storage format, channels and the file it lives in
+fn run_list_layers(i3d_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let i3d = I3d::parse(Path::new(i3d_path)).map_err(|e| format!("Cannot read {}: {}", i3d_path, e))?;
+    if i3d.layers.is_empty() {
+        warn!("{} defines no InfoLayer, DetailLayer or FoliageMultiLayer", i3d_path);
+        return Ok(());
+    }
+
+    let or_dash = |s: String| if s.is_empty() { "-".to_string() } else { s };
+    let rows: Vec<[String; 7]> = i3d
+        .layers
+        .iter()
+        .map(|layer| {
+            let format = if layer.kind == LayerKind::InfoLayer { "GRLE" } else { "GDM" };
+            let splits: Vec<String> = layer.compression_channels.iter().map(|c| c.to_string()).collect();
+            let file = i3d.file(&layer.file_id).map_or_else(|| format!("(no file {})", layer.file_id), str::to_string);
+            [
+                or_dash(layer.name.clone()),
+                format!("{:?}", layer.kind),
+                format.to_string(),
+                layer.num_channels.to_string(),
+                or_dash(splits.join(",")),
+                layer.type_index_channels.map_or_else(|| "-".to_string(), |n| n.to_string()),
+                file,
+            ]
+        })
+        .collect();
+
+    let headings = ["Name", "Kind", "Format", "Channels", "Compression", "Type index", "File"];
+    let mut widths = headings.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let print_row = |cells: &[&str]| {
+        let line: Vec<String> = cells.iter().zip(widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(&headings);
+    for row in &rows {
+        print_row(&row.iter().map(String::as_str).collect::<Vec<_>>());
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Zip archives
+// ============================================================================
+
+/// --into/--entry: scratch file the PNG is encoded to first
+fn archive_scratch_path(entry: &str) -> String {
+    let name = Path::new(entry).file_name().and_then(|n| n.to_str()).unwrap_or(entry);
+    std::env::temp_dir().join(format!("grleconvert_{}_{}", std::process::id(), name)).to_string_lossy().into_owned()
+}
+
+/// The archive's bytes, empty if it does not exist yet
+fn read_archive(archive: &str) -> std::io::Result<Vec<u8>> {
+    match std::fs::read(archive) {
+        Ok(bytes) => Ok(bytes),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(std::io::Error::new(e.kind(), format!("Cannot read {}: {}", archive, e))),
+    }
+}
+
+/// Like an output file, an entry that is already there needs --force
+fn check_archive_entry(archive: &str, entry: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if !FORCE.load(Ordering::Relaxed) && zip::entry_names(&read_archive(archive)?)?.iter().any(|name| name == entry) {
+        return Err(format!("{} already holds {} (use --force to replace it)", archive, entry).into());
+    }
+    Ok(())
+}
+
+/// Store the encoded scratch file in `archive` as `entry`. The archive is
+/// rewritten next to itself and renamed over the original, so a failure
+/// leaves it untouched; a missing archive is created.
+fn store_in_archive(scratch: &str, archive: &str, entry: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let data = std::fs::read(scratch);
+    let _ = std::fs::remove_file(scratch);
+    let data = data?;
+    let existing = read_archive(archive)?;
+    let updated = zip::write_entry(&existing, entry, &data, std::time::SystemTime::now())?;
+    let partial = format!("{}.partial", archive);
+    std::fs::write(&partial, &updated)?;
+    std::fs::rename(&partial, archive)?;
+    info!("Stored as {} in {} ({} bytes)", entry, archive, data.len());
+    Ok(())
+}
+
+// ============================================================================
+// Info
+// ============================================================================
+
+/// --info: the header fields of a GDM or GRLE file, one per line, without
+/// decoding the pixels
+fn run_info(input_path: &str, input_ext: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let data = read_input(input_path)?;
+    match input_ext {
+        "gdm" => {
+            let header = parse_gdm_header(&data)?;
+            let magic = match header.variant {
+                GdmVariant::Long => "\"MDF",
+                GdmVariant::Short => "!MDF",
+            };
+            let version = header.version.map_or("-".to_string(), |v| v.to_string());
+            let splits: Vec<String> = header.compression_channels.iter().map(|c| c.to_string()).collect();
+            println!("Format: GDM ({})", magic);
+            println!("Version: {}", version);
+            println!("Size: {}x{}", header.dimension, header.dimension);
+            println!("Chunk size: {}", header.chunk_size);
+            println!("Channels: {}", header.num_channels);
+            println!("Compression ranges: {}", header.num_compression_ranges);
+            println!("Compression split: {}", if splits.is_empty() { "-".to_string() } else { splits.join(",") });
+            println!("Type index channels: {}", header.type_index_channels);
+            println!("Max bpp: {}", header.max_bpp);
+        }
+        "grle" => {
+            let header = GrleHeader::parse(&data)?;
+            println!("Format: GRLE");
+            println!("Version: {}", header.version);
+            println!("Size: {}x{}", header.width, header.height);
+            println!("Offset 12 field: {}", header.unknown);
+            println!("Stream bytes: {}", header.declared_len);
+            println!("File bytes: {}", data.len());
+        }
+        other => return Err(format!("--info reads .gdm or .grle files, not '{}'", other).into()),
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Self-test
+// ============================================================================
+
+/// Known-good files written by this tool: a 256x256 GRLE and a 64x64,
+/// 12-channel GDM split at channel 8
+const SELFTEST_GRLE: &[u8] = include_bytes!("selftest/sample.grle");
+const SELFTEST_GDM: &[u8] = include_bytes!("selftest/sample.gdm");
+
+/// Decode the sample and re-encode it; the result must match byte for byte
+fn selftest_grle() -> Result<(), String> {
+    let grle = decode_grle(SELFTEST_GRLE).map_err(|e| e.to_string())?;
+    if (grle.width, grle.height) != (256, 256) {
+        return Err(format!("decoded {}x{}, expected 256x256", grle.width, grle.height));
+    }
+    let stored = GrleHeader::parse(SELFTEST_GRLE).map_err(|e| e.to_string())?;
+    let header = GrleHeaderFields { version: stored.version, unknown: stored.unknown };
+    let encoded = encode_grle_with_params(&grle.pixels, grle.width, grle.height, &GrleParams::new().header(header));
+    if encoded != SELFTEST_GRLE {
+        return Err(format!("re-encoded {} bytes differ from the {}-byte sample", encoded.len(), SELFTEST_GRLE.len()));
+    }
+    Ok(())
+}
+
+/// FNV-1a over pixel values, to spot decoder changes without storing the expected image
+fn values_checksum(values: impl Iterator<Item = u32>) -> u64 {
+    values.fold(0xcbf2_9ce4_8422_2325, |hash, v| (hash ^ v as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Checksum of the decoded SELFTEST_GDM values
+const SELFTEST_GDM_CHECKSUM: u64 = 0x6faf_4b25_c1bf_7045;
+
+/// Decode the sample and check its values, then re-encode and decode again. Block
+/// choices may change between versions, so only the values have to survive.
+fn selftest_gdm() -> Result<(), String> {
+    let gdm = decode_gdm(SELFTEST_GDM).map_err(|e| e.to_string())?;
+    if (gdm.image.width, gdm.num_channels, gdm.num_compression_ranges) != (64, 12, 2) {
+        return Err(format!(
+            "decoded {}x{} with {} channels in {} ranges, expected 64x64 with 12 in 2",
+            gdm.image.width, gdm.image.height, gdm.num_channels, gdm.num_compression_ranges
+        ));
+    }
+    let checksum = values_checksum(gdm.image.values.iter().copied());
+    if checksum != SELFTEST_GDM_CHECKSUM {
+        return Err(format!("decoded values checksum {:016x}, expected {:016x}", checksum, SELFTEST_GDM_CHECKSUM));
+    }
+    let params = GdmParams::new(12).compression_channels(vec![8]);
+    let encoded = encode_gdm(&gdm.image, &params).map_err(|e| e.to_string())?;
+    let roundtrip = decode_gdm(&encoded).map_err(|e| e.to_string())?;
+    if roundtrip.image.values != gdm.image.values {
+        return Err("re-encoded values differ from the sample".into());
+    }
+    Ok(())
+}
+
+/// `grleconvert selftest`: run the codecs on the embedded samples. Returns true if all pass.
+fn run_selftest() -> bool {
+    let mut ok = true;
+    for (name, result) in [("GRLE", selftest_grle()), ("GDM", selftest_gdm())] {
+        match result {
+            Ok(()) => println!("{:<5} PASS", name),
+            Err(e) => {
+                println!("{:<5} FAIL: {}", name, e);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
 // ============================================================================
 // Main
 // ============================================================================
 
+/// Exit statuses, so scripts can tell a configuration problem from a failure.
+/// Usage errors and anything not covered below exit with 1.
+const EXIT_PARAMS: i32 = 2;
+const EXIT_IO: i32 = 3;
+const EXIT_MALFORMED: i32 = 4;
+
+/// Exit status for an error that ended a conversion
+fn exit_code(e: &(dyn std::error::Error + 'static)) -> i32 {
+    if e.is::<std::io::Error>() {
+        return EXIT_IO;
+    }
+    if let Some(e) = e.downcast_ref::<ConvertError>() {
+        return match e {
+            ConvertError::Io(_) => EXIT_IO,
+            ConvertError::InvalidParams(_) => EXIT_PARAMS,
+            _ => EXIT_MALFORMED,
+        };
+    }
+    match e.downcast_ref::<png::DecodingError>() {
+        Some(png::DecodingError::IoError(_)) => EXIT_IO,
+        Some(_) => EXIT_MALFORMED,
+        None if matches!(e.downcast_ref::<png::EncodingError>(), Some(png::EncodingError::IoError(_))) => EXIT_IO,
+        None => 1,
+    }
+}
+
+/// Print the error and exit with its status from `exit_code`
+fn fail(e: Box<dyn std::error::Error>) -> ! {
+    eprintln!("Error: {}", e);
+    std::process::exit(exit_code(e.as_ref()));
+}
+
+/// File stem without a trailing source format, so the default decode
+/// output "name.gdm.png" maps back to "name"
+fn base_stem(path: &str) -> String {
+    let stem = Path::new(path).file_stem().and_then(|s| s.to_str()).unwrap_or(path);
+    match file_extension(stem).as_str() {
+        "gdm" | "grle" => Path::new(stem).file_stem().and_then(|s| s.to_str()).unwrap_or(stem).to_string(),
+        _ => stem.to_string(),
+    }
+}
+
+/// Default decode output: the full input filename plus ".png" (or the
+/// --out-format extension), so that
+/// "name.gdm" and "name.grle" in the same folder never share a PNG
+fn decoded_output_path(input_path: &str, format: OutFormat) -> String {
+    let name = Path::new(input_path).file_name().and_then(|s| s.to_str()).unwrap_or(input_path);
+    format!("{}.{}", name, format.extension())
+}
+
+/// Decode output name from --name-template: `{stem}` is the input name without
+/// its extensions, `{layer}` the i3d layer stored in it, `{map}` the i3d file stem
+/// and `{ext}` the output extension, which is appended if the template has none
+fn templated_output_name(template: &str, input_path: &str, format: OutFormat, i3d_path: Option<&str>, i3d_cache: &mut I3dCache) -> Result<String, Box<dyn std::error::Error>> {
+    let stem = base_stem(input_path);
+    let mut name = template.replace("{stem}", &stem).replace("{ext}", format.extension());
+
+    if name.contains("{layer}") || name.contains("{map}") {
+        let target_png = format!("{}.png", stem);
+        let i3d_file = match i3d_path {
+            Some(path) => Some(PathBuf::from(path)),
+            None => {
+                let input_abs = std::fs::canonicalize(input_path).unwrap_or_else(|_| PathBuf::from(input_path));
+                find_i3d_file(&input_abs, &target_png, i3d_cache).map_err(ConvertError::InvalidParams)?
+            }
+        };
+        let i3d_file = i3d_file
+            .ok_or_else(|| ConvertError::InvalidParams(format!("--name-template needs an i3d for {} (use --i3d)", input_path)))?;
+        let map = i3d_file.file_stem().and_then(|s| s.to_str()).unwrap_or_default().to_string();
+        let layer = i3d_cache
+            .get(&i3d_file)
+            .and_then(|i3d| i3d.layer_for_png(&target_png))
+            .map(|layer| layer.name.clone());
+        if name.contains("{layer}") {
+            let layer = layer
+                .ok_or_else(|| ConvertError::InvalidParams(format!("{} does not define a layer for {}", i3d_file.display(), target_png)))?;
+            name = name.replace("{layer}", &layer);
+        }
+        name = name.replace("{map}", &map);
+    }
+
+    if Path::new(&name).extension().is_none() {
+        name = format!("{}.{}", name, format.extension());
+    }
+    Ok(name)
+}
+
+/// Where a decode without an explicit output goes: the templated or default
+/// name, inside `out_dir` when given
+fn decode_output_path(input_path: &str, format: OutFormat, out_dir: Option<&Path>, template: Option<&str>, i3d_path: Option<&str>, i3d_cache: &mut I3dCache) -> Result<String, Box<dyn std::error::Error>> {
+    let name = match template {
+        Some(template) => templated_output_name(template, input_path, format, i3d_path, i3d_cache)?,
+        None => decoded_output_path(input_path, format),
+    };
+    Ok(match out_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)?;
+            dir.join(name).to_string_lossy().into_owned()
+        }
+        None => name,
+    })
+}
+
+/// `value` as a quoted JSON string
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// One-line JSON summary of an encode for --print-params
+fn params_json(input_path: &str, output_path: &str, use_grle: bool, params: &LayerParams, i3d: Option<&Path>) -> String {
+    let splits: Vec<String> = params.compression_channels.iter().map(|c| c.to_string()).collect();
+    format!(
+        "{{\"input\": {}, \"output\": {}, \"format\": \"{}\", \"layer_type\": \"{:?}\", \"num_channels\": {}, \"compression_channels\": [{}], \"type_index_channels\": {}, \"i3d\": {}}}",
+        json_string(input_path),
+        json_string(output_path),
+        if use_grle { "grle" } else { "gdm" },
+        params.layer_type,
+        params.num_channels,
+        splits.join(", "),
+        params.type_index_channels.map_or("null".to_string(), |n| n.to_string()),
+        i3d.map_or("null".to_string(), |p| json_string(&p.display().to_string())),
+    )
+}
+
+/// Lowercased file extension, or "" when there is none
+fn file_extension(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase()
+}
+
 fn print_usage() {
     eprintln!("Usage: grleconvert <input> [output]");
     eprintln!();
-    eprintln!("Converts between GIANTS Engine density map formats and PNG.");
+    eprintln!("Converts between GIANTS Engine density map formats and PNG.");
+    eprintln!();
+    eprintln!("Explicit direction (no guessing from extensions):");
+    eprintln!("  grleconvert decode <input.gdm|input.grle> [output]");
+    eprintln!("  grleconvert encode <input.png> [output] [--format gdm|grle]");
+    eprintln!();
+    eprintln!("Decoding (automatic):");
+    eprintln!("  grleconvert input.gdm              → input.gdm.png");
+    eprintln!("  grleconvert input.grle             → input.grle.png");
+    eprintln!();
+    eprintln!("Between GDM and GRLE (no PNG in between):");
+    eprintln!("  grleconvert input.gdm output.grle  (values must fit in 8 bits)");
+    eprintln!("  grleconvert input.grle output.gdm  (8 channels unless --channels)");
+    eprintln!();
+    eprintln!("Encoding (requires i3d file in directory hierarchy):");
+    eprintln!("  grleconvert input.png              → input.gdm or input.grle");
+    eprintln!("  grleconvert input.gdm.png          → input.gdm");
+    eprintln!("  grleconvert input.png out.grle out.gdm → both formats from one decode");
+    eprintln!();
+    eprintln!("Histogram (count of each pixel value, names from the i3d's <Option>s):");
+    eprintln!("  grleconvert histogram input.gdm [--i3d map.i3d]");
+    eprintln!();
+    eprintln!("Batch (one output next to each input, N files at a time; PNGs encode to their i3d layer's format):");
+    eprintln!("  grleconvert batch --jobs 8 data/*.gdm data/*.grle [--out-dir DIR] [--name-template T] [--summary] [--progress]");
+    eprintln!("  grleconvert batch --jobs 8 data/*.png [--overwrite-if-newer] [--summary]");
+    eprintln!();
+    eprintln!("Analyze (block bit depths and their share of a GDM file):");
+    eprintln!("  grleconvert analyze input.gdm");
+    eprintln!();
+    eprintln!("Growth states (one white-on-black mask PNG per FoliageMultiLayer state, or one APNG):");
+    eprintln!("  grleconvert states foliage.gdm [--type-index-channels N] [--out-dir DIR] [--apng]");
+    eprintln!();
+    eprintln!("Stitch (decode tile files named by row {{r}} and column {{c}} into one PNG):");
+    eprintln!("  grleconvert stitch --pattern 'densityMap_ground_{{r}}_{{c}}.gdm' --grid 2x2 out.png");
+    eprintln!();
+    eprintln!("Info (header fields of a GDM or GRLE file, without decoding):");
+    eprintln!("  grleconvert input.gdm --info");
     eprintln!();
-    eprintln!("Decoding (automatic):");
-    eprintln!("  grleconvert input.gdm              → input.png");
-    eprintln!("  grleconvert input.grle             → input.png");
+    eprintln!("List layers (every layer an i3d defines, with format, channels and file):");
+    eprintln!("  grleconvert --list-layers map.i3d");
     eprintln!();
-    eprintln!("Encoding (requires i3d file in directory hierarchy):");
-    eprintln!("  grleconvert input.png              → input.gdm or input.grle");
+    eprintln!("Self-test (round-trips built-in sample files):");
+    eprintln!("  grleconvert selftest");
+    eprintln!();
+    eprintln!("Streams (use - for stdin/stdout):");
+    eprintln!("  cat input.gdm | grleconvert - --from gdm > output.png");
+    eprintln!("  grleconvert input.png --to grle > output.grle");
     eprintln!();
     eprintln!("Options:");
+    eprintln!("  --from <format>     Input format (gdm, grle, png) when reading stdin");
+    eprintln!("  --to <format>       Output format (gdm, grle); writes stdout if no output");
+    eprintln!("  --emit <fmt,..>     Encode a PNG to each of grle,gdm, named after the input");
+    eprintln!("  --into <zip> --entry <path>");
+    eprintln!("                      Store the encoded file in a zip archive as this entry");
+    eprintln!("  --assume-format <gdm|grle>");
+    eprintln!("                      Encode to this format when no i3d layer matches the PNG");
     eprintln!("  --i3d <path>        Specify i3d file path for encoding");
     eprintln!("  --channels <n>      Manual channel count (when no i3d)");
-    eprintln!("  --compress-at <n>   Manual compression split (for GDM)");
+    eprintln!("  --compress-at <n,..> Manual compression split(s) (for GDM)");
+    eprintln!("  --type-index-channels <n>");
+    eprintln!("                      FoliageMultiLayer type index bits (for GDM)");
+    eprintln!("  --split-foliage     FoliageMultiLayer as RGB: type index in R, state in G");
+    eprintln!("  --grayscale         Decode a GDM as grayscale of its low 8 channels");
+    eprintln!("  --channel-range <a:b>");
+    eprintln!("                      Decode a GDM as grayscale of channels a to b-1 (at most 8)");
+    eprintln!("  --channel-layout <spec>");
+    eprintln!("                      GDM bits each PNG channel holds, e.g. r:0-7,g:8-15 (both ways)");
+    eprintln!("  --template <path>   Copy preserved header fields from an existing file");
+    eprintln!("  --max-bpp <n>       GDM max_bpp header byte (default: largest block bit depth)");
+    eprintln!("  --grle-flag <n>     GRLE header field at offset 12 (default 256, as in most files)");
+    eprintln!("  --mask-alpha        Fully transparent pixels keep the --template file's values");
+    eprintln!("  --verify-header     Re-read the written file's header and check it matches");
+    eprintln!("  --size-limit <n>    Warn when an encoded GDM is over n bytes (K/M suffixes;");
+    eprintln!("                      default: warn when it is larger than uncompressed)");
+    eprintln!("  --stats             After decoding, print distinct values, % nonzero, min/max");
+    eprintln!("  --progress          Report conversion progress (batch: files done) on stderr");
+    eprintln!("  --raw-values        Encode PNG values as already packed: no i3d lookup,");
+    eprintln!("                      header only from --channels/--compress-at");
+    eprintln!("  --print-params      Print the encoding parameters as one JSON line before encoding");
+    eprintln!("  --png-compression <level>");
+    eprintln!("                      PNG output compression: default, fast, best");
+    eprintln!("  --no-color-management");
+    eprintln!("                      Tag decoded PNGs with gamma 1.0 (no sRGB chunk) so the");
+    eprintln!("                      stored values are read as raw, linear data");
+    eprintln!("  --zero-transparent  Decode grayscale output as RGBA with value 0 fully transparent");
+    eprintln!("  --colorize <json>   Decode with a value -> \"#RRGGBB\" palette, for viewing only");
+    eprintln!("  --out-format <fmt>  Decode output: png (default), raw (+ .json sidecar), npy");
+    eprintln!("  --out-dir <dir>     Write decoded files into this directory");
+    eprintln!("  --name-template <t> Decoded file name from {{stem}}, {{layer}}, {{map}}, {{ext}}");
+    eprintln!("                      ({{layer}}/{{map}} come from the i3d, e.g. {{map}}_{{layer}}.png)");
+    eprintln!("  --pad <value>       Pad GRLE input up to multiples of 256 with this value");
+    eprintln!("  --crop <WxH>        Crop a decoded GRLE to its original size");
+    eprintln!("  --max-dimension <n> Refuse to decode files whose header declares a larger side");
+    eprintln!("                      (default {})", DEFAULT_MAX_DIMENSION);
+    eprintln!("  --strict            Treat header/data, channel and layer size mismatches as errors");
+    eprintln!("  --overwrite-if-newer");
+    eprintln!("                      Skip outputs at least as new as their input, replace older ones");
+    eprintln!("  -f, --force         Overwrite existing output; decode unknown GRLE versions");
+    eprintln!("  -q, --quiet         Only print errors");
+    eprintln!("  -v, -vv             Print more detail (i3d discovery, stream sizes)");
     eprintln!();
     eprintln!("The tool auto-discovers the map .i3d file by walking up the");
-    eprintln!("directory hierarchy from the input file location.");
+    eprintln!("directory hierarchy from the input file location, stopping");
+    eprintln!("at the mod root (modDesc.xml/dlcDesc.xml).");
+    eprintln!();
+    eprintln!("Parameter precedence: --channels, --compress-at and --type-index-channels");
+    eprintln!("each override just that field of the i3d layer; fields not given keep the");
+    eprintln!("i3d value. Without an i3d match, --channels is required for GDM output.");
+    eprintln!();
+    eprintln!("Exit status: 0 success, 1 usage or other error (batch: a file failed),");
+    eprintln!("2 conversion parameters could not be determined, conflict or do not fit the input,");
+    eprintln!("3 I/O error, 4 malformed or unsupported input file (or a --strict mismatch).");
+}
+
+/// Conversion direction given by the `decode`/`encode` subcommands
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Decode,
+    Encode,
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
 
     if args.len() < 2 {
         print_usage();
         std::process::exit(1);
     }
 
+    if args[1] == "histogram" {
+        if let Err(e) = run_histogram(&args[2..]) {
+            fail(e);
+        }
+        return;
+    }
+
+    if args[1] == "analyze" {
+        if let Err(e) = run_analyze(&args[2..]) {
+            fail(e);
+        }
+        return;
+    }
+
+    if args[1] == "states" {
+        if let Err(e) = run_states(&args[2..]) {
+            fail(e);
+        }
+        return;
+    }
+
+    if args[1] == "stitch" {
+        if let Err(e) = run_stitch(&args[2..]) {
+            fail(e);
+        }
+        return;
+    }
+
+    if args[1] == "batch" {
+        match run_batch(&args[2..]) {
+            Ok(all_ok) => std::process::exit(if all_ok { 0 } else { 1 }),
+            Err(e) => fail(e),
+        }
+    }
+
+    if args[1] == "selftest" {
+        std::process::exit(if run_selftest() { 0 } else { 1 });
+    }
+
+    // `decode`/`encode` fix the direction; without them it is inferred from
+    // the extensions
+    let direction = match args[1].as_str() {
+        "decode" => Some(Direction::Decode),
+        "encode" => Some(Direction::Encode),
+        _ => None,
+    };
+    if direction.is_some() {
+        args.remove(1);
+    }
+
     // Parse arguments
+    let mut encode_format: Option<String> = None;
     let mut input_path: Option<String> = None;
     let mut output_path: Option<String> = None;
+    // A PNG can be encoded to both formats: a second output path, or --emit
+    let mut second_output: Option<String> = None;
+    let mut emit: Option<Vec<String>> = None;
     let mut i3d_path: Option<String> = None;
     let mut manual_channels: Option<usize> = None;
-    let mut manual_compress_at: Option<usize> = None;
+    let mut manual_compress_at: Option<Vec<usize>> = None;
+    let mut template_path: Option<String> = None;
+    let mut manual_type_index: Option<usize> = None;
+    let mut strict = false;
+    let mut from_format: Option<String> = None;
+    let mut to_format: Option<String> = None;
+    let mut png_options = PngOptions::default();
+    let mut split_foliage = false;
+    let mut mask_alpha = false;
+    let mut print_params = false;
+    let mut raw_values = false;
+    let mut pad: Option<u8> = None;
+    let mut crop: Option<(usize, usize)> = None;
+    let mut out_format = OutFormat::Png;
+    let mut out_dir: Option<PathBuf> = None;
+    let mut name_template: Option<String> = None;
+    let mut max_bpp: Option<u8> = None;
+    let mut grle_flag: Option<u16> = None;
+    let mut grayscale = false;
+    let mut channel_range: Option<(usize, usize)> = None;
+    let mut channel_layout: Option<ChannelLayout> = None;
+    let mut assume_format: Option<String> = None;
+    let mut into_archive: Option<String> = None;
+    let mut archive_entry: Option<String> = None;
+    let mut list_layers: Option<String> = None;
+    let mut show_info = false;
 
     let mut i = 1;
     while i < args.len() {
@@ -862,20 +2673,294 @@ fn main() {
                 }
             }
             "--compress-at" => {
+                i += 1;
+                // An empty list asks for a single compression range
+                let splits: Option<Vec<usize>> = args.get(i).and_then(|v| match v.trim() {
+                    "" => Some(Vec::new()),
+                    list => list.split(',').map(|v| v.trim().parse().ok()).collect(),
+                });
+                manual_compress_at = match splits {
+                    Some(splits) => Some(splits),
+                    None => {
+                        eprintln!("--compress-at expects channel numbers separated by commas, e.g. 8 or 4,8");
+                        std::process::exit(1);
+                    }
+                };
+            }
+            "--type-index-channels" => {
+                i += 1;
+                if i < args.len() {
+                    manual_type_index = args[i].parse().ok();
+                }
+            }
+            "--template" => {
+                i += 1;
+                if i < args.len() {
+                    template_path = Some(args[i].clone());
+                }
+            }
+            "--from" => {
+                i += 1;
+                if i < args.len() {
+                    from_format = Some(args[i].to_lowercase());
+                }
+            }
+            "--format" if direction == Some(Direction::Encode) => {
+                i += 1;
+                encode_format = match args.get(i).map(|v| v.to_lowercase()) {
+                    Some(format) if format == "gdm" || format == "grle" => Some(format),
+                    _ => {
+                        eprintln!("--format expects gdm or grle");
+                        std::process::exit(1);
+                    }
+                };
+            }
+            "--assume-format" => {
+                i += 1;
+                assume_format = match args.get(i).map(|v| v.to_lowercase()) {
+                    Some(format) if format == "gdm" || format == "grle" => Some(format),
+                    _ => {
+                        eprintln!("--assume-format expects gdm or grle");
+                        std::process::exit(1);
+                    }
+                };
+            }
+            "--into" => {
+                i += 1;
+                if i < args.len() {
+                    into_archive = Some(args[i].clone());
+                }
+            }
+            "--entry" => {
+                i += 1;
+                if i < args.len() {
+                    archive_entry = Some(args[i].clone());
+                }
+            }
+            "--to" => {
+                i += 1;
+                if i < args.len() {
+                    to_format = Some(args[i].to_lowercase());
+                }
+            }
+            "--emit" => {
+                i += 1;
+                let mut formats: Vec<String> = Vec::new();
+                for format in args.get(i).map(|v| v.to_lowercase()).unwrap_or_default().split(',') {
+                    if format != "grle" && format != "gdm" {
+                        eprintln!("--emit expects grle, gdm or grle,gdm");
+                        std::process::exit(1);
+                    }
+                    if !formats.iter().any(|f| f == format) {
+                        formats.push(format.to_string());
+                    }
+                }
+                emit = Some(formats);
+            }
+            "--png-compression" => {
+                i += 1;
+                if i < args.len() {
+                    png_options.compression = match parse_png_compression(&args[i].to_lowercase()) {
+                        Some(c) => c,
+                        None => {
+                            eprintln!("Unknown PNG compression: {} (expected default, fast or best)", args[i]);
+                            std::process::exit(1);
+                        }
+                    };
+                }
+            }
+            "--out-format" => {
+                i += 1;
+                out_format = match args.get(i).and_then(|v| OutFormat::parse(&v.to_lowercase())) {
+                    Some(f) => f,
+                    None => {
+                        eprintln!("--out-format expects png, raw or npy");
+                        std::process::exit(1);
+                    }
+                };
+            }
+            "--out-dir" => {
+                i += 1;
+                if i < args.len() {
+                    out_dir = Some(PathBuf::from(&args[i]));
+                }
+            }
+            "--name-template" => {
                 i += 1;
                 if i < args.len() {
-                    manual_compress_at = args[i].parse().ok();
+                    name_template = Some(args[i].clone());
+                }
+            }
+            "--max-bpp" => {
+                i += 1;
+                max_bpp = match args.get(i).and_then(|v| v.parse().ok()) {
+                    Some(v) => Some(v),
+                    None => {
+                        eprintln!("--max-bpp expects a bit depth 0-255");
+                        std::process::exit(1);
+                    }
+                };
+            }
+            "--grle-flag" => {
+                i += 1;
+                grle_flag = match args.get(i).and_then(|v| v.parse().ok()) {
+                    Some(v) => Some(v),
+                    None => {
+                        eprintln!("--grle-flag expects a value 0-65535");
+                        std::process::exit(1);
+                    }
+                };
+            }
+            "--pad" => {
+                i += 1;
+                pad = match args.get(i).and_then(|v| v.parse().ok()) {
+                    Some(v) => Some(v),
+                    None => {
+                        eprintln!("--pad expects a fill value 0-255");
+                        std::process::exit(1);
+                    }
+                };
+            }
+            "--crop" => {
+                i += 1;
+                crop = match args.get(i).and_then(|v| parse_size(v)) {
+                    Some(size) => Some(size),
+                    None => {
+                        eprintln!("--crop expects a size like 300x300");
+                        std::process::exit(1);
+                    }
+                };
+            }
+            "--strict" => {
+                strict = true;
+            }
+            "--split-foliage" => {
+                split_foliage = true;
+            }
+            "--mask-alpha" => {
+                mask_alpha = true;
+            }
+            "--grayscale" => {
+                grayscale = true;
+            }
+            "--channel-range" => {
+                i += 1;
+                channel_range = match args.get(i).and_then(|v| parse_channel_range(v)) {
+                    Some(range) => Some(range),
+                    None => {
+                        eprintln!("--channel-range expects first:end channels such as 0:8");
+                        std::process::exit(1);
+                    }
+                };
+            }
+            "--channel-layout" => {
+                i += 1;
+                channel_layout = match args.get(i).map(|v| ChannelLayout::parse(v)) {
+                    Some(Ok(layout)) => Some(layout),
+                    Some(Err(e)) => {
+                        eprintln!("--channel-layout: {}", e);
+                        std::process::exit(1);
+                    }
+                    None => {
+                        eprintln!("--channel-layout expects fields like r:0-7,g:8-15");
+                        std::process::exit(1);
+                    }
+                };
+            }
+            "--zero-transparent" => {
+                png_options.zero_transparent = true;
+            }
+            "--no-color-management" => {
+                png_options.linear = true;
+            }
+            "--max-dimension" => {
+                i += 1;
+                match args.get(i).and_then(|v| v.parse().ok()) {
+                    Some(side) => set_max_dimension(side),
+                    None => {
+                        eprintln!("--max-dimension expects a side length in pixels");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--colorize" => {
+                i += 1;
+                png_options.colors = match args.get(i).map(|path| load_palette(path)) {
+                    Some(Ok(colors)) => Some(colors),
+                    Some(Err(e)) => {
+                        eprintln!("Error: {}", e);
+                        std::process::exit(1);
+                    }
+                    None => {
+                        eprintln!("--colorize expects a palette .json file");
+                        std::process::exit(1);
+                    }
+                };
+            }
+            "--print-params" => {
+                print_params = true;
+            }
+            "--raw-values" => {
+                raw_values = true;
+            }
+            "--info" => {
+                show_info = true;
+            }
+            "--list-layers" => {
+                i += 1;
+                list_layers = match args.get(i) {
+                    Some(path) => Some(path.clone()),
+                    None => {
+                        eprintln!("--list-layers expects an i3d file");
+                        std::process::exit(1);
+                    }
+                };
+            }
+            "--size-limit" => {
+                i += 1;
+                match args.get(i).and_then(|v| parse_byte_size(v)) {
+                    Some(limit) => SIZE_LIMIT.store(limit, Ordering::Relaxed),
+                    None => {
+                        eprintln!("--size-limit expects a size in bytes, e.g. 4000000, 512K or 8M");
+                        std::process::exit(1);
+                    }
                 }
             }
+            "--verify-header" => {
+                VERIFY_HEADER.store(true, Ordering::Relaxed);
+            }
+            "--progress" => {
+                PROGRESS.store(true, Ordering::Relaxed);
+            }
+            "--overwrite-if-newer" => {
+                OVERWRITE_IF_NEWER.store(true, Ordering::Relaxed);
+            }
+            "--stats" => {
+                STATS.store(true, Ordering::Relaxed);
+            }
+            "--force" | "-f" => {
+                FORCE.store(true, Ordering::Relaxed);
+            }
+            "--quiet" | "-q" => {
+                VERBOSITY.store(0, Ordering::Relaxed);
+            }
+            "-v" | "--verbose" => {
+                VERBOSITY.store(verbosity().max(1) + 1, Ordering::Relaxed);
+            }
+            "-vv" => {
+                VERBOSITY.store(3, Ordering::Relaxed);
+            }
             "--help" | "-h" => {
                 print_usage();
                 std::process::exit(0);
             }
-            arg if !arg.starts_with('-') => {
+            arg if arg == "-" || !arg.starts_with('-') => {
                 if input_path.is_none() {
                     input_path = Some(arg.to_string());
                 } else if output_path.is_none() {
                     output_path = Some(arg.to_string());
+                } else if second_output.is_none() {
+                    second_output = Some(arg.to_string());
                 }
             }
             _ => {
@@ -886,6 +2971,29 @@ fn main() {
         i += 1;
     }
 
+    if channel_layout.is_some() && (split_foliage || grayscale || channel_range.is_some() || png_options.colors.is_some()) {
+        eprintln!("Error: --channel-layout cannot be combined with --split-foliage, --grayscale, --channel-range or --colorize");
+        std::process::exit(1);
+    }
+
+    let png_channels = match channel_layout {
+        Some(ref layout) => PngChannels::Layout(layout.clone()),
+        None if split_foliage => PngChannels::SplitFoliage,
+        None => PngChannels::Packed,
+    };
+
+    // --grayscale alone keeps the low 8 channels
+    if grayscale && channel_range.is_none() {
+        channel_range = Some((0, 8));
+    }
+
+    if let Some(path) = list_layers {
+        if let Err(e) = run_list_layers(&path) {
+            fail(e);
+        }
+        return;
+    }
+
     let input_path = match input_path {
         Some(p) => p,
         None => {
@@ -894,28 +3002,150 @@ fn main() {
         }
     };
 
-    let input_ext = Path::new(&input_path)
-        .extension()
-        .and_then(|e| e.to_str())
-        .unwrap_or("")
-        .to_lowercase();
+    // Format comes from --from, or the input file extension
+    let input_ext = match direction {
+        Some(Direction::Encode) => {
+            if from_format.as_deref().is_some_and(|f| f != "png") {
+                eprintln!("Error: encode reads a PNG; --from can only be png");
+                std::process::exit(1);
+            }
+            "png".to_string()
+        }
+        _ => from_format.unwrap_or_else(|| file_extension(&input_path)),
+    };
+    if direction == Some(Direction::Decode) {
+        if input_ext != "gdm" && input_ext != "grle" {
+            eprintln!("Error: decode expects a .gdm or .grle input (or --from gdm|grle)");
+            std::process::exit(1);
+        }
+        if to_format.is_some() {
+            eprintln!("Error: --to is for encoding; decode writes PNG (or --out-format)");
+            std::process::exit(1);
+        }
+    }
+
+    if show_info {
+        if let Err(e) = run_info(&input_path, &input_ext) {
+            fail(e);
+        }
+        return;
+    }
+
+    if assume_format.is_some() && input_ext != "png" {
+        warn!("--assume-format only applies to encoding a PNG");
+    }
+    if (emit.is_some() || second_output.is_some()) && input_ext != "png" {
+        eprintln!("Error: two outputs (or --emit) are only for encoding a PNG");
+        std::process::exit(1);
+    }
+    if emit.is_some() && (output_path.is_some() || to_format.is_some() || encode_format.is_some() || input_path == "-") {
+        eprintln!("Error: --emit names the outputs after the input file; give no output path or --to");
+        std::process::exit(1);
+    }
+
+    // --into/--entry: encode to a scratch file, then store it in the archive.
+    // The entry's extension picks the format.
+    let bundle = match (into_archive, archive_entry) {
+        (Some(archive), Some(entry)) => Some((archive, entry.replace('\\', "/").trim_start_matches('/').to_string())),
+        (None, None) => None,
+        _ => {
+            eprintln!("Error: --into and --entry go together");
+            std::process::exit(1);
+        }
+    };
+    let scratch = bundle.as_ref().map(|(_, entry)| archive_scratch_path(entry));
+    if let Some((ref archive, ref entry)) = bundle {
+        let format = file_extension(entry);
+        if format != "gdm" && format != "grle" {
+            eprintln!("Error: --entry must name a .gdm or .grle file");
+            std::process::exit(1);
+        }
+        if input_ext != "png" || output_path.is_some() || emit.is_some() {
+            eprintln!("Error: --into stores one PNG encode; give no output path or --emit");
+            std::process::exit(1);
+        }
+        if to_format.iter().chain(&encode_format).any(|f| *f != format) {
+            eprintln!("Error: --entry {} is .{}; --to/--format disagree", entry, format);
+            std::process::exit(1);
+        }
+        if let Err(e) = check_archive_entry(archive, entry) {
+            fail(e);
+        }
+        output_path = scratch.clone();
+    }
 
+    // Streams have no filename to derive an output from, and --to without
+    // an output path means the caller wants the result on stdout
+    if output_path.is_none() && (input_path == "-" || to_format.is_some()) {
+        output_path = Some("-".to_string());
+    }
+    // encode --format picks the format; the output is still named after the input
+    if encode_format.is_some() {
+        to_format = encode_format;
+    }
+
+    // An output of the other density map format skips the PNG step (not
+    // with `decode`, which always decodes)
+    let output_ext = match direction {
+        Some(Direction::Decode) => None,
+        _ => to_format.clone().or_else(|| output_path.as_deref().map(file_extension)),
+    };
+
+    // Shared by output naming, GRLE channel lookup and encode parameter discovery
+    let mut i3d_cache = I3dCache::default();
     let result = match input_ext.as_str() {
-        "grle" => {
-            // Decode GRLE to PNG
-            let output = output_path.unwrap_or_else(|| {
-                let stem = Path::new(&input_path).file_stem().unwrap().to_str().unwrap();
-                format!("{}.png", stem)
-            });
-            convert_grle_to_png(&input_path, &output)
+        "gdm" if output_ext.as_deref() == Some("grle") => {
+            let output = output_path.unwrap_or_else(|| format!("{}.grle", base_stem(&input_path)));
+            if skip_up_to_date(&input_path, &output) {
+                return;
+            }
+            let header = match template_path {
+                Some(ref path) => read_grle_template(path),
+                None => Ok(GrleHeaderFields::default()),
+            };
+            header.map(|h| with_grle_flag(h, grle_flag)).and_then(|h| convert_gdm_to_grle(&input_path, &output, &h, pad))
         }
-        "gdm" => {
-            // Decode GDM to PNG
-            let output = output_path.unwrap_or_else(|| {
-                let stem = Path::new(&input_path).file_stem().unwrap().to_str().unwrap();
-                format!("{}.png", stem)
-            });
-            convert_gdm_to_png(&input_path, &output)
+        "grle" if output_ext.as_deref() == Some("gdm") => {
+            let output = output_path.unwrap_or_else(|| format!("{}.gdm", base_stem(&input_path)));
+            if skip_up_to_date(&input_path, &output) {
+                return;
+            }
+            // GRLE values are 8-bit, so 8 channels keep all of them
+            let num_channels = manual_channels.unwrap_or(8);
+            let header = match template_path {
+                Some(ref path) => read_gdm_template(path),
+                None => Ok(GdmHeaderFields::default()),
+            };
+            header.map(|h| with_max_bpp(h, max_bpp)).and_then(|header| {
+                let mut params = GdmParams::new(num_channels)
+                    .compression_channels(manual_compress_at.unwrap_or_default())
+                    .header(header);
+                params.type_index_channels = manual_type_index;
+                convert_grle_to_gdm(&input_path, &output, &params)
+            })
+        }
+        "grle" | "gdm" => {
+            // Decode to PNG (or --out-format)
+            let output = match output_path {
+                Some(path) => Ok(path),
+                None => decode_output_path(&input_path, out_format, out_dir.as_deref(), name_template.as_deref(), i3d_path.as_deref(), &mut i3d_cache),
+            };
+            output.and_then(|output| {
+                if skip_up_to_date(&input_path, &output) {
+                    Ok(())
+                } else if input_ext == "grle" {
+                    if channel_range.is_some() {
+                        warn!("--grayscale/--channel-range only apply to GDM input");
+                    }
+                    if channel_layout.is_some() {
+                        warn!("--channel-layout only applies to GDM files");
+                    }
+                    let num_channels = grle_channels(&input_path, i3d_path.as_deref(), manual_channels, &mut i3d_cache);
+                    convert_grle_to_png(&input_path, &output, strict, &png_options, crop, out_format, num_channels)
+                } else {
+                    convert_gdm_to_png(&input_path, &output, strict, &png_options, &png_channels, channel_range, out_format)
+                }
+            })
         }
         "png" => {
             // Encode PNG to GRLE or GDM
@@ -923,41 +3153,106 @@ fn main() {
             let filename = input_abs.file_name().and_then(|f| f.to_str()).unwrap_or(&input_path);
 
             // Try to find i3d and discover parameters
-            let i3d_file = if let Some(ref path) = i3d_path {
-                eprintln!("Using specified i3d: {}", path);
+            let i3d_file = if raw_values {
+                // Pixel values are already packed; the header comes from the flags
+                if i3d_path.is_some() {
+                    warn!("--i3d is ignored with --raw-values");
+                }
+                if split_foliage {
+                    eprintln!("Error: --raw-values takes packed values, not --split-foliage R/G");
+                    std::process::exit(1);
+                }
+                verbose!("--raw-values: skipping i3d discovery");
+                None
+            } else if let Some(ref path) = i3d_path {
+                verbose!("Using specified i3d: {}", path);
                 Some(PathBuf::from(path))
             } else {
-                eprintln!("Searching for i3d file...");
-                let found = find_i3d_file(&input_abs);
+                verbose!("Searching for i3d file...");
+                let found = match find_i3d_file(&input_abs, filename, &mut i3d_cache) {
+                    Ok(found) => found,
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        std::process::exit(EXIT_PARAMS);
+                    }
+                };
                 if let Some(ref p) = found {
-                    eprintln!("Found i3d: {}", p.display());
+                    verbose!("Found i3d: {}", p.display());
                 }
                 found
             };
 
-            let params = if let Some(ref i3d) = i3d_file {
-                parse_i3d_for_file(i3d, filename)
-            } else {
-                None
+            let params = i3d_file.as_ref().and_then(|path| {
+                let i3d = i3d_cache.get(path)?;
+                let mut params = layer_params_for_file(i3d, filename)?;
+                params.expected_size = existing_layer_size(path, i3d, filename);
+                Some(params)
+            });
+
+            // Output format: --to wins over the output path extension
+            let output_format = to_format
+                .clone()
+                .or_else(|| output_path.as_deref().map(file_extension));
+
+            // Every output as (path, is GRLE); more than one means the PNG is
+            // decoded once and encoded to each
+            let multi_targets: Option<Vec<(String, bool)>> = match (&emit, &output_path, &second_output) {
+                (Some(formats), _, _) => {
+                    let stem = base_stem(&input_path);
+                    Some(formats.iter().map(|f| (format!("{}.{}", stem, f), f == "grle")).collect())
+                }
+                (None, Some(first), Some(second)) => {
+                    let (first_ext, second_ext) = (file_extension(first), file_extension(second));
+                    let pair = [first_ext.as_str(), second_ext.as_str()];
+                    if pair != ["grle", "gdm"] && pair != ["gdm", "grle"] {
+                        eprintln!("Error: with two outputs, one must be .grle and the other .gdm");
+                        std::process::exit(1);
+                    }
+                    Some(vec![(first.clone(), first_ext == "grle"), (second.clone(), second_ext == "grle")])
+                }
+                _ => None,
             };
 
-            // Check if output extension explicitly specifies format
-            let explicit_grle = output_path.as_ref().map(|p| {
-                Path::new(p)
-                    .extension()
-                    .and_then(|e| e.to_str())
-                    .map(|e| e.to_lowercase() == "grle")
-                    .unwrap_or(false)
-            }).unwrap_or(false);
+            // --assume-format stands in for the filename guess when no i3d
+            // layer matches; an output extension or --to still decides
+            let named_format = output_format.as_deref().filter(|f| *f == "grle" || *f == "gdm");
+            if let (Some(assumed), Some(named)) = (assume_format.as_deref(), named_format) {
+                if assumed != named {
+                    eprintln!("Error: --assume-format {} conflicts with the .{} output", assumed, named);
+                    std::process::exit(EXIT_PARAMS);
+                }
+            }
+            if assume_format.is_some() && (params.is_some() || multi_targets.is_some()) {
+                verbose!("--assume-format unused: the {} decides the format", if params.is_some() { "i3d layer" } else { "outputs" });
+            }
+            let assumed = assume_format.as_deref().filter(|_| params.is_none());
+
+            // Check if output format is explicitly GRLE (the only output)
+            let explicit_grle = match multi_targets {
+                Some(ref targets) => targets.iter().all(|&(_, grle)| grle),
+                None => named_format.or(assumed) == Some("grle"),
+            };
 
             // Determine parameters
-            let params = match params {
-                Some(p) => p,
+            let from_i3d = params.is_some();
+            let mut params = match params {
+                Some(mut p) => {
+                    // Manual values override single fields; the rest still comes from the i3d
+                    if let Some(channels) = manual_channels {
+                        info!("Overriding i3d channel count {} with {}", p.num_channels, channels);
+                        p.num_channels = channels;
+                    }
+                    if let Some(ref splits) = manual_compress_at {
+                        info!("Overriding i3d compression split {:?} with {:?}", p.compression_channels, splits);
+                        p.compression_channels = splits.clone();
+                    }
+                    p
+                }
                 None => {
                     // Fall back to manual parameters or defaults
                     if let Some(channels) = manual_channels {
-                        eprintln!("Using manual parameters: {} channels", channels);
-                        let layer_type = if filename.contains("infoLayer") || explicit_grle {
+                        info!("Using manual parameters: {} channels", channels);
+                        let layer_type = if explicit_grle || (assumed.is_none() && filename.contains("infoLayer")) {
                             LayerType::InfoLayer
                         } else {
                             LayerType::GdmLayer
@@ -965,60 +3260,130 @@ fn main() {
                         LayerParams {
                             layer_type,
                             num_channels: channels,
-                            compression_channels: manual_compress_at,
+                            compression_channels: manual_compress_at.unwrap_or_default(),
+                            type_index_channels: None,
+                            expected_size: None,
                         }
                     } else if explicit_grle {
                         // GRLE output explicitly requested - use default params
                         // GRLE doesn't need channel count for encoding
-                        eprintln!("GRLE output requested, using default parameters");
+                        info!("GRLE output requested, using default parameters");
                         LayerParams {
                             layer_type: LayerType::InfoLayer,
                             num_channels: 1,
-                            compression_channels: None,
+                            compression_channels: Vec::new(),
+                            type_index_channels: None,
+                            expected_size: None,
                         }
+                    } else if raw_values {
+                        eprintln!("Error: --raw-values needs --channels <n> for a GDM header");
+                        std::process::exit(EXIT_PARAMS);
+                    } else if assumed.is_some() {
+                        eprintln!("Error: --assume-format gdm needs --channels <n> for a GDM header");
+                        std::process::exit(EXIT_PARAMS);
                     } else {
                         eprintln!("Error: Could not find i3d file or determine encoding parameters.");
                         eprintln!("Please specify --i3d <path> or --channels <n>");
-                        eprintln!("Or specify output path with .grle extension for GRLE format.");
-                        std::process::exit(1);
+                        eprintln!("Or specify output path with .grle extension (or --assume-format grle) for GRLE format.");
+                        std::process::exit(EXIT_PARAMS);
                     }
                 }
             };
 
+            if manual_type_index.is_some() {
+                params.type_index_channels = manual_type_index;
+            }
+
             // Determine output path and format
-            let (output, use_grle) = if let Some(ref out_path) = output_path {
-                let ext = Path::new(out_path)
-                    .extension()
-                    .and_then(|e| e.to_str())
-                    .unwrap_or("")
-                    .to_lowercase();
-                let is_grle = ext == "grle";
-                (out_path.clone(), is_grle)
-            } else {
-                let stem = Path::new(&input_path).file_stem().unwrap().to_str().unwrap();
-                match params.layer_type {
-                    LayerType::InfoLayer => (format!("{}.grle", stem), true),
-                    LayerType::GdmLayer => (format!("{}.gdm", stem), false),
+            let targets = multi_targets.unwrap_or_else(|| {
+                if let Some(ref out_path) = output_path {
+                    vec![(out_path.clone(), explicit_grle)]
+                } else {
+                    let stem = base_stem(&input_path);
+                    match (output_format.as_deref(), &params.layer_type) {
+                        (Some("grle"), _) | (None, LayerType::InfoLayer) => vec![(format!("{}.grle", stem), true)],
+                        _ => vec![(format!("{}.gdm", stem), false)],
+                    }
                 }
-            };
+            });
 
-            eprintln!("Output: {}", output);
+            if mask_alpha && template_path.is_none() {
+                eprintln!("Error: --mask-alpha needs --template <existing file> to take values from");
+                std::process::exit(1);
+            }
 
-            if use_grle {
-                convert_png_to_grle(&input_path, &output, &params)
-            } else {
-                convert_png_to_gdm(&input_path, &output, &params)
+            // With --overwrite-if-newer the PNG is only read if some output is stale
+            let several_outputs = targets.len() > 1;
+            let targets: Vec<(String, bool)> = targets.into_iter().filter(|(output, _)| !skip_up_to_date(&input_path, output)).collect();
+            if targets.is_empty() {
+                return;
             }
+
+            read_png(&input_path).and_then(|png| {
+                targets.iter().try_for_each(|(output, use_grle)| {
+                    verbose!("Output: {}", output);
+
+                    if print_params {
+                        let i3d_used = i3d_file.as_deref().filter(|_| from_i3d);
+                        let line = params_json(&input_path, output, *use_grle, &params, i3d_used);
+                        // Keep stdout clean when the encoded file goes there
+                        if output == "-" {
+                            eprintln!("{}", line);
+                        } else {
+                            println!("{}", line);
+                        }
+                    }
+
+                    // With two outputs the template only serves the one of its own format
+                    let format = if *use_grle { "grle" } else { "gdm" };
+                    let template = template_path.as_deref().filter(|path| !several_outputs || file_extension(path) == format);
+                    if template.is_none() && template_path.is_some() {
+                        warn!("--template is not a .{} file; {} uses the default header", format, output);
+                    }
+                    // Transparent pixels keep the template's values
+                    let mask_from = template.filter(|_| mask_alpha);
+
+                    if *use_grle {
+                        let header = match template {
+                            Some(path) => {
+                                verbose!("Using header fields from template: {}", path);
+                                read_grle_template(path)
+                            }
+                            None => Ok(GrleHeaderFields::default()),
+                        };
+                        header.map(|h| with_grle_flag(h, grle_flag)).and_then(|h| convert_png_to_grle(&png, output, &params, &h, strict, pad, mask_from))
+                    } else {
+                        let header = match template {
+                            Some(path) => {
+                                verbose!("Using header fields from template: {}", path);
+                                read_gdm_template(path)
+                            }
+                            None => Ok(GdmHeaderFields::default()),
+                        };
+                        header.map(|h| with_max_bpp(h, max_bpp)).and_then(|h| convert_png_to_gdm(&png, output, &params, &h, strict, &png_channels, mask_from))
+                    }
+                })
+            })
         }
         _ => {
-            eprintln!("Unknown file extension: {}", input_ext);
-            eprintln!("Supported: .grle, .gdm, .png");
+            eprintln!("Unknown input format: {}", input_ext);
+            eprintln!("Supported: .grle, .gdm, .png (or --from for stdin)");
             std::process::exit(1);
         }
     };
 
+    let result = match (bundle, scratch) {
+        (Some((archive, entry)), Some(scratch)) => match result {
+            Ok(()) => store_in_archive(&scratch, &archive, &entry),
+            Err(e) => {
+                let _ = std::fs::remove_file(&scratch);
+                Err(e)
+            }
+        },
+        _ => result,
+    };
+
     if let Err(e) = result {
-        eprintln!("Error: {}", e);
-        std::process::exit(1);
+        fail(e);
     }
 }
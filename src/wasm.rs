@@ -0,0 +1,61 @@
+//! Browser bindings for the decoders.
+//!
+//! Build with `cargo rustc --lib --release --features wasm --target
+//! wasm32-unknown-unknown --crate-type cdylib`, then generate the JavaScript
+//! glue with `wasm-bindgen --target web`. `decode_gdm(bytes)` and
+//! `decode_grle(bytes)` return an `RgbaImage` with `width`, `height` and
+//! `pixels` (a `Uint8Array`), or throw an `Error` with the decode message.
+
+use wasm_bindgen::prelude::*;
+
+use crate::ConvertError;
+
+/// An image as 8-bit RGBA, ready for a canvas `ImageData`
+#[wasm_bindgen(getter_with_clone)]
+pub struct RgbaImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+/// Decode a GRLE file to grey RGBA pixels
+pub fn grle_to_rgba(data: &[u8]) -> Result<RgbaImage, ConvertError> {
+    let grle = crate::decode_grle(data)?;
+    let pixels = grle.pixels.iter().flat_map(|&v| [v, v, v, 255]).collect();
+    Ok(RgbaImage { width: grle.width, height: grle.height, pixels })
+}
+
+/// Decode a GDM file to RGBA pixels, mapping channels the way the CLI's PNGs do
+///
+/// Up to 8 channels become grey, up to 24 go to R, G and B, and wider layers
+/// keep bits 24-31 in alpha. Alpha is opaque otherwise.
+pub fn gdm_to_rgba(data: &[u8]) -> Result<RgbaImage, ConvertError> {
+    let gdm = crate::decode_gdm(data)?;
+    let grey = gdm.num_channels <= 8;
+    let alpha = gdm.num_channels > 24;
+    let pixels = gdm.image.values.iter().flat_map(|&v| {
+        let [r, g, b, a] = v.to_le_bytes();
+        if grey {
+            [r, r, r, 255]
+        } else {
+            [r, g, b, if alpha { a } else { 255 }]
+        }
+    }).collect();
+    Ok(RgbaImage { width: gdm.image.width, height: gdm.image.height, pixels })
+}
+
+// ============================================================================
+// Exports
+// ============================================================================
+
+/// Decode a GDM file to RGBA pixels for the browser
+#[wasm_bindgen]
+pub fn decode_gdm(data: &[u8]) -> Result<RgbaImage, JsError> {
+    Ok(gdm_to_rgba(data)?)
+}
+
+/// Decode a GRLE file to grey RGBA pixels for the browser
+#[wasm_bindgen]
+pub fn decode_grle(data: &[u8]) -> Result<RgbaImage, JsError> {
+    Ok(grle_to_rgba(data)?)
+}
@@ -0,0 +1,268 @@
+//! Density maps inside zip archives, the way maps ship them.
+//!
+//! Just enough of the format to read one entry and to replace or add one:
+//! every other entry is copied byte for byte, so its compression method,
+//! timestamps and extra fields stay as the original packer wrote them.
+//! Sizes come from the central directory, so entries streamed with a data
+//! descriptor read fine. Zip64 and encrypted entries are not supported.
+
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use crate::{read_u16_le, read_u32_le, ConvertError};
+
+const LOCAL_HEADER_SIG: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIG: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIR_SIG: u32 = 0x0605_4b50;
+const LOCAL_HEADER_LEN: usize = 30;
+const CENTRAL_HEADER_LEN: usize = 46;
+const END_OF_CENTRAL_DIR_LEN: usize = 22;
+/// Offset of the u32 local header offset in a central directory record
+const CENTRAL_LOCAL_OFFSET: usize = 42;
+/// Compression methods the game loads
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+/// Zip 2.0: deflate, no zip64
+const VERSION: u16 = 20;
+/// General purpose flag bits
+const FLAG_ENCRYPTED: u16 = 1;
+const FLAG_UTF8: u16 = 1 << 11;
+
+/// One central directory record
+struct CentralEntry<'a> {
+    name: &'a [u8],
+    flags: u16,
+    method: u16,
+    compressed_size: usize,
+    local_offset: usize,
+    /// The whole record, copied when the archive is rewritten
+    record: &'a [u8],
+}
+
+struct CentralDirectory<'a> {
+    entries: Vec<CentralEntry<'a>>,
+    /// Where the central directory starts, which ends the last entry's data
+    offset: usize,
+    comment: &'a [u8],
+}
+
+fn malformed(msg: &str) -> ConvertError {
+    ConvertError::InvalidFormat(format!("Not a valid zip archive: {}", msg))
+}
+
+/// An empty buffer is an archive without entries, so a new one can be started
+fn read_central_directory(archive: &[u8]) -> Result<CentralDirectory<'_>, ConvertError> {
+    if archive.is_empty() {
+        return Ok(CentralDirectory { entries: Vec::new(), offset: 0, comment: &[] });
+    }
+    if archive.len() < END_OF_CENTRAL_DIR_LEN {
+        return Err(malformed("too short"));
+    }
+
+    // The end record comes last, followed only by a comment of up to 64 KiB
+    let last = archive.len() - END_OF_CENTRAL_DIR_LEN;
+    let end = (last.saturating_sub(u16::MAX as usize)..=last)
+        .rev()
+        .find(|&pos| {
+            read_u32_le(archive, pos) == END_OF_CENTRAL_DIR_SIG
+                && pos + END_OF_CENTRAL_DIR_LEN + read_u16_le(archive, pos + 20) as usize == archive.len()
+        })
+        .ok_or_else(|| malformed("no end of central directory record"))?;
+
+    let count = read_u16_le(archive, end + 10);
+    let size = read_u32_le(archive, end + 12);
+    let offset = read_u32_le(archive, end + 16);
+    if count == u16::MAX || size == u32::MAX || offset == u32::MAX {
+        return Err(ConvertError::InvalidFormat("Zip64 archives are not supported".into()));
+    }
+    let (size, offset) = (size as usize, offset as usize);
+    if offset + size > end {
+        return Err(malformed("central directory runs past its end record"));
+    }
+
+    let mut entries = Vec::with_capacity(count as usize);
+    let mut pos = offset;
+    for _ in 0..count {
+        if pos + CENTRAL_HEADER_LEN > offset + size || read_u32_le(archive, pos) != CENTRAL_HEADER_SIG {
+            return Err(malformed("bad central directory record"));
+        }
+        let name_len = read_u16_le(archive, pos + 28) as usize;
+        let record_len = CENTRAL_HEADER_LEN + name_len + read_u16_le(archive, pos + 30) as usize + read_u16_le(archive, pos + 32) as usize;
+        if pos + record_len > offset + size {
+            return Err(malformed("central directory record runs past the directory"));
+        }
+        let local_offset = read_u32_le(archive, pos + CENTRAL_LOCAL_OFFSET) as usize;
+        if local_offset >= offset {
+            return Err(malformed("entry starts inside the central directory"));
+        }
+        entries.push(CentralEntry {
+            name: &archive[pos + CENTRAL_HEADER_LEN..pos + CENTRAL_HEADER_LEN + name_len],
+            flags: read_u16_le(archive, pos + 8),
+            method: read_u16_le(archive, pos + 10),
+            compressed_size: read_u32_le(archive, pos + 20) as usize,
+            local_offset,
+            record: &archive[pos..pos + record_len],
+        });
+        pos += record_len;
+    }
+
+    Ok(CentralDirectory { entries, offset, comment: &archive[end + END_OF_CENTRAL_DIR_LEN..] })
+}
+
+/// Names of all entries, in central directory order
+pub fn entry_names(archive: &[u8]) -> Result<Vec<String>, ConvertError> {
+    let directory = read_central_directory(archive)?;
+    Ok(directory.entries.iter().map(|e| String::from_utf8_lossy(e.name).into_owned()).collect())
+}
+
+/// Contents of the entry called `name`, or `None` if the archive has none
+pub fn read_entry(archive: &[u8], name: &str) -> Result<Option<Vec<u8>>, ConvertError> {
+    let directory = read_central_directory(archive)?;
+    let Some(entry) = directory.entries.iter().find(|e| e.name == name.as_bytes()) else {
+        return Ok(None);
+    };
+    if entry.flags & FLAG_ENCRYPTED != 0 {
+        return Err(ConvertError::InvalidFormat(format!("{} is encrypted", name)));
+    }
+
+    let pos = entry.local_offset;
+    if pos + LOCAL_HEADER_LEN > archive.len() || read_u32_le(archive, pos) != LOCAL_HEADER_SIG {
+        return Err(malformed("bad local header"));
+    }
+    // The local extra field can differ from the central one
+    let start = pos + LOCAL_HEADER_LEN + read_u16_le(archive, pos + 26) as usize + read_u16_le(archive, pos + 28) as usize;
+    let data = archive
+        .get(start..start + entry.compressed_size)
+        .ok_or_else(|| malformed("entry data runs past the end"))?;
+
+    match entry.method {
+        STORED => Ok(Some(data.to_vec())),
+        DEFLATED => {
+            let mut out = Vec::new();
+            DeflateDecoder::new(data).read_to_end(&mut out)?;
+            Ok(Some(out))
+        }
+        method => Err(ConvertError::InvalidFormat(format!("{} uses unsupported compression method {}", name, method))),
+    }
+}
+
+/// DOS time and date of `t` in UTC, clamped to the 1980-2107 range zip can hold
+fn dos_timestamp(t: SystemTime) -> (u16, u16) {
+    let secs = t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, day_secs) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since 1970-01-01 (proleptic Gregorian)
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let year = year.min(2107);
+    let time = ((day_secs / 3600) << 11) | ((day_secs % 3600 / 60) << 5) | (day_secs % 60 / 2);
+    let date = ((year - 1980) << 9) | (month << 5) | day;
+    (time as u16, date as u16)
+}
+
+/// The archive with `name` set to `data`, modified at `modified`. An existing
+/// entry of that name is replaced and keeps its compression method (stored or
+/// deflated); a new one is deflated like the rest of a mod zip.
+pub fn write_entry(archive: &[u8], name: &str, data: &[u8], modified: SystemTime) -> Result<Vec<u8>, ConvertError> {
+    let directory = read_central_directory(archive)?;
+    let replaced = directory.entries.iter().position(|e| e.name == name.as_bytes());
+    let method = match replaced.map(|i| directory.entries[i].method) {
+        Some(STORED) => STORED,
+        _ => DEFLATED,
+    };
+    let compressed = match method {
+        STORED => data.to_vec(),
+        _ => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        }
+    };
+    if compressed.len() >= u32::MAX as usize || data.len() >= u32::MAX as usize {
+        return Err(ConvertError::InvalidParams(format!("{} is too large for a zip without zip64", name)));
+    }
+
+    // Each entry's bytes run up to the next entry, or the central directory
+    let mut starts: Vec<usize> = directory.entries.iter().map(|e| e.local_offset).collect();
+    starts.push(directory.offset);
+    starts.sort_unstable();
+    let end_of = |offset: usize| starts[starts.partition_point(|&s| s <= offset)];
+
+    let mut out = Vec::with_capacity(archive.len() + compressed.len());
+    let mut central = Vec::new();
+    let mut count = 0usize;
+    for (i, entry) in directory.entries.iter().enumerate() {
+        if Some(i) == replaced {
+            continue;
+        }
+        let new_offset = out.len() as u32;
+        out.extend_from_slice(&archive[entry.local_offset..end_of(entry.local_offset)]);
+        central.extend_from_slice(&entry.record[..CENTRAL_LOCAL_OFFSET]);
+        central.extend_from_slice(&new_offset.to_le_bytes());
+        central.extend_from_slice(&entry.record[CENTRAL_LOCAL_OFFSET + 4..]);
+        count += 1;
+    }
+
+    let (time, date) = dos_timestamp(modified);
+    let flags = if name.is_ascii() { 0 } else { FLAG_UTF8 };
+    let crc = crc32fast::hash(data);
+    // Fields shared by the local header and the central record, from "version needed"
+    let mut common = Vec::with_capacity(26);
+    for v in [VERSION, flags, method, time, date] {
+        common.extend_from_slice(&v.to_le_bytes());
+    }
+    for v in [crc, compressed.len() as u32, data.len() as u32] {
+        common.extend_from_slice(&v.to_le_bytes());
+    }
+    common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    common.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+
+    let local_offset = out.len();
+    if local_offset >= u32::MAX as usize {
+        return Err(ConvertError::InvalidParams("Archive is too large for a zip without zip64".into()));
+    }
+    out.extend_from_slice(&LOCAL_HEADER_SIG.to_le_bytes());
+    out.extend_from_slice(&common);
+    out.extend_from_slice(name.as_bytes());
+    out.extend_from_slice(&compressed);
+
+    central.extend_from_slice(&CENTRAL_HEADER_SIG.to_le_bytes());
+    central.extend_from_slice(&VERSION.to_le_bytes()); // version made by
+    central.extend_from_slice(&common);
+    for v in [0u16, 0, 0] {
+        central.extend_from_slice(&v.to_le_bytes()); // comment length, disk, internal attributes
+    }
+    central.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+    central.extend_from_slice(&(local_offset as u32).to_le_bytes());
+    central.extend_from_slice(name.as_bytes());
+    count += 1;
+
+    let central_offset = out.len();
+    if count >= u16::MAX as usize || central_offset + central.len() >= u32::MAX as usize {
+        return Err(ConvertError::InvalidParams("Archive is too large for a zip without zip64".into()));
+    }
+    out.extend_from_slice(&central);
+    out.extend_from_slice(&END_OF_CENTRAL_DIR_SIG.to_le_bytes());
+    for v in [0u16, 0, count as u16, count as u16] {
+        out.extend_from_slice(&v.to_le_bytes()); // this disk, directory disk, entries here, entries total
+    }
+    out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(central_offset as u32).to_le_bytes());
+    out.extend_from_slice(&(directory.comment.len() as u16).to_le_bytes());
+    out.extend_from_slice(directory.comment);
+    Ok(out)
+}
@@ -0,0 +1,1393 @@
+//! End-to-end runs of the grleconvert binary.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use grleconvert::{
+    decode_gdm, decode_grle, decode_grle_with_params, encode_gdm, encode_grle, DecodedImage, GdmHeaderFields, GdmParams,
+    GrleParams,
+};
+
+mod common;
+
+use common::scratch_dir;
+
+/// Run grleconvert quietly and assert it succeeded
+fn run(args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+        .arg("-q")
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+/// Run a grleconvert subcommand and return its stdout
+fn run_stdout(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_grleconvert")).args(args).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn write_png(path: &Path, width: u32, height: u32, color: png::ColorType, depth: png::BitDepth, data: &[u8]) {
+    let mut encoder = png::Encoder::new(fs::File::create(path).unwrap(), width, height);
+    encoder.set_color(color);
+    encoder.set_depth(depth);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(data).unwrap();
+}
+
+#[test]
+fn sixteen_bit_grayscale_to_gdm() {
+    let dir = scratch_dir("gray16");
+    let values: Vec<u32> = (0..64 * 64).map(|i| (i * 97 % 4096) as u32).collect();
+    let samples: Vec<u8> = values.iter().flat_map(|&v| (v as u16).to_be_bytes()).collect();
+
+    let png_path = dir.join("height.png");
+    let gdm_path = dir.join("height.gdm");
+    write_png(&png_path, 64, 64, png::ColorType::Grayscale, png::BitDepth::Sixteen, &samples);
+
+    run(&[png_path.to_str().unwrap(), gdm_path.to_str().unwrap(), "--channels", "12", "--compress-at", "8"]);
+
+    let decoded = decode_gdm(&fs::read(&gdm_path).unwrap()).unwrap();
+    assert_eq!(decoded.image.values, values);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn grle_rejects_pngs_that_are_not_8_bit() {
+    let dir = scratch_dir("gray16_grle");
+    let encode = |name: &str, depth: png::BitDepth, data: &[u8]| {
+        let png_path = dir.join(name);
+        write_png(&png_path, 256, 256, png::ColorType::Grayscale, depth, data);
+        let output = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+            .args([png_path.to_str().unwrap(), dir.join("out.grle").to_str().unwrap(), "--channels", "8"])
+            .output()
+            .unwrap();
+        (output.status.code(), String::from_utf8_lossy(&output.stderr).into_owned())
+    };
+
+    let (code, stderr) = encode("gray16.png", png::BitDepth::Sixteen, &vec![0x12; 256 * 256 * 2]);
+    assert_eq!(code, Some(4));
+    assert!(stderr.contains("16-bit PNGs cannot be encoded as GRLE"), "{}", stderr);
+
+    let (code, stderr) = encode("gray4.png", png::BitDepth::Four, &vec![0x12; 256 * 128]);
+    assert_eq!(code, Some(4));
+    assert!(stderr.contains("4-bit PNGs cannot be encoded as GRLE"), "{}", stderr);
+    assert!(!dir.join("out.grle").exists());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn gdm_rejects_packed_grayscale_pngs() {
+    let dir = scratch_dir("gray_packed_gdm");
+    let encode = |name: &str, depth: png::BitDepth, channels: &str, data: &[u8]| {
+        let png_path = dir.join(name);
+        write_png(&png_path, 64, 64, png::ColorType::Grayscale, depth, data);
+        let output = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+            .args([png_path.to_str().unwrap(), dir.join("out.gdm").to_str().unwrap(), "--channels", channels])
+            .output()
+            .unwrap();
+        (output.status.code(), String::from_utf8_lossy(&output.stderr).into_owned())
+    };
+
+    let (code, stderr) = encode("gray2.png", png::BitDepth::Two, "2", &[0x1b; 16 * 64]);
+    assert_eq!(code, Some(4));
+    assert!(stderr.contains("2-bit PNGs cannot be encoded as GDM"), "{}", stderr);
+
+    let (code, stderr) = encode("gray1.png", png::BitDepth::One, "1", &[0x55; 8 * 64]);
+    assert_eq!(code, Some(4));
+    assert!(stderr.contains("1-bit PNGs cannot be encoded as GDM"), "{}", stderr);
+    assert!(!dir.join("out.gdm").exists());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn selftest_passes() {
+    let output = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+        .arg("selftest")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("GRLE  PASS"));
+    assert!(stdout.contains("GDM   PASS"));
+}
+
+#[test]
+fn thirty_two_channel_gdm_through_rgba() {
+    let dir = scratch_dir("rgba");
+    let mut state = 0x1234_5678u32;
+    let values: Vec<u32> = (0..64 * 64)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        })
+        .collect();
+    let params = GdmParams {
+        num_channels: 32,
+        compression_channels: vec![8, 16, 24],
+        ..Default::default()
+    };
+    let gdm_path = dir.join("wide.gdm");
+    let png_path = dir.join("wide.png");
+    let back_path = dir.join("back.gdm");
+    fs::write(&gdm_path, encode_gdm(&DecodedImage { width: 64, height: 64, values: values.clone() }, &params).unwrap()).unwrap();
+
+    run(&[gdm_path.to_str().unwrap(), png_path.to_str().unwrap()]);
+    let reader = png::Decoder::new(fs::File::open(&png_path).unwrap()).read_info().unwrap();
+    assert_eq!(reader.info().color_type, png::ColorType::Rgba);
+
+    run(&[png_path.to_str().unwrap(), back_path.to_str().unwrap(), "--channels", "32", "--compress-at", "8,16,24"]);
+    let decoded = decode_gdm(&fs::read(&back_path).unwrap()).unwrap();
+    assert_eq!(decoded.image.values, values);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn channels_override_keeps_i3d_split() {
+    let dir = scratch_dir("override");
+    fs::write(dir.join("map.i3d"), r#"<i3D name="map">
+  <Files>
+    <File fileId="12" filename="densityMap_ground.png"/>
+  </Files>
+  <Layers>
+    <DetailLayer name="terrainDetail" densityMapId="12" numDensityMapChannels="10" compressionChannels="8"/>
+  </Layers>
+</i3D>
+"#).unwrap();
+    let png_path = dir.join("densityMap_ground.png");
+    write_png(&png_path, 64, 64, png::ColorType::Rgb, png::BitDepth::Eight, &vec![1u8; 64 * 64 * 3]);
+
+    let out = dir.join("ground.gdm");
+    run(&[png_path.to_str().unwrap(), out.to_str().unwrap(), "--channels", "12"]);
+    let decoded = decode_gdm(&fs::read(&out).unwrap()).unwrap();
+    assert_eq!((decoded.num_channels, decoded.num_compression_ranges), (12, 2));
+
+    run(&[png_path.to_str().unwrap(), out.to_str().unwrap(), "-f", "--compress-at", "4,8"]);
+    let decoded = decode_gdm(&fs::read(&out).unwrap()).unwrap();
+    assert_eq!((decoded.num_channels, decoded.num_compression_ranges), (10, 3));
+
+    // A list item that is not a number is an error, not silently dropped
+    let bad = dir.join("bad.gdm");
+    let output = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+        .args([png_path.to_str().unwrap(), bad.to_str().unwrap(), "--compress-at", "5,abc"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--compress-at expects channel numbers"));
+    assert!(!bad.exists());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn gdm_to_npy_and_raw() {
+    let dir = scratch_dir("arrays");
+    let values: Vec<u32> = (0..64 * 64).map(|i| (i * 37 % 1024) as u32).collect();
+    let params = GdmParams {
+        num_channels: 10,
+        compression_channels: vec![8],
+        ..Default::default()
+    };
+    let gdm_path = dir.join("ground.gdm");
+    fs::write(&gdm_path, encode_gdm(&DecodedImage { width: 64, height: 64, values: values.clone() }, &params).unwrap()).unwrap();
+    let expected: Vec<u8> = values.iter().flat_map(|&v| (v as u16).to_le_bytes()).collect();
+
+    let npy_path = dir.join("ground.npy");
+    run(&[gdm_path.to_str().unwrap(), npy_path.to_str().unwrap(), "--out-format", "npy"]);
+    let npy = fs::read(&npy_path).unwrap();
+    assert_eq!(&npy[..8], b"\x93NUMPY\x01\x00");
+    let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+    assert_eq!((10 + header_len) % 64, 0);
+    let header = std::str::from_utf8(&npy[10..10 + header_len]).unwrap();
+    assert!(header.starts_with("{'descr': '<u2', 'fortran_order': False, 'shape': (64, 64), }"));
+    assert!(header.ends_with('\n'));
+    assert_eq!(&npy[10 + header_len..], &expected[..]);
+
+    let raw_path = dir.join("ground.raw");
+    run(&[gdm_path.to_str().unwrap(), raw_path.to_str().unwrap(), "--out-format", "raw"]);
+    assert_eq!(fs::read(&raw_path).unwrap(), expected);
+    let sidecar = fs::read_to_string(dir.join("ground.raw.json")).unwrap();
+    assert!(sidecar.contains("\"dtype\": \"uint16\""));
+    assert!(sidecar.contains("\"width\": 64"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn histogram_with_i3d_meanings() {
+    let dir = scratch_dir("histogram");
+    fs::write(dir.join("map.i3d"), r#"<i3D name="map">
+  <Files>
+    <File fileId="5" filename="data/infoLayer_soil.png"/>
+  </Files>
+  <Layers>
+    <InfoLayer name="soil" fileId="5" numChannels="3">
+      <Group name="type" firstChannel="0" numChannels="3">
+        <Option value="2" name="clay"/>
+        <Option value="0x4" name="sand"/>
+      </Group>
+    </InfoLayer>
+  </Layers>
+</i3D>
+"#).unwrap();
+    // Quarter 2, quarter 7, half 4
+    let pixels: Vec<u8> = (0..256 * 256).map(|i| match (i % 256) / 64 { 0 => 2, 1 => 7, _ => 4 }).collect();
+    let grle = dir.join("infoLayer_soil.grle");
+    fs::write(&grle, encode_grle(&pixels, 256, 256)).unwrap();
+
+    let plain = run_stdout(&["histogram", grle.to_str().unwrap()]);
+    let rows: Vec<&str> = plain.lines().skip(1).map(|l| l.trim_end()).collect();
+    assert_eq!(rows, vec![
+        "         2      16384  25.00%",
+        "         4      32768  50.00%",
+        "         7      16384  25.00%",
+    ]);
+
+    let named = run_stdout(&["histogram", grle.to_str().unwrap(), "--i3d", dir.join("map.i3d").to_str().unwrap()]);
+    assert!(named.contains("         2      16384  25.00%  type: clay"));
+    assert!(named.contains("         4      32768  50.00%  type: sand"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn analyze_counts_blocks_by_bit_depth() {
+    let dir = scratch_dir("analyze");
+    // One two-valued chunk, three uniform ones
+    let values: Vec<u32> = (0..64 * 64).map(|i| if i % 64 < 32 && i / 64 < 32 { (i % 2) as u32 } else { 5 }).collect();
+    let params = GdmParams { num_channels: 3, ..Default::default() };
+    let gdm_path = dir.join("soil.gdm");
+    fs::write(&gdm_path, encode_gdm(&DecodedImage { width: 64, height: 64, values }, &params).unwrap()).unwrap();
+
+    let out = run_stdout(&["analyze", gdm_path.to_str().unwrap()]);
+    assert!(out.contains("2x2 chunks of 32x32, 1 range(s), 4 blocks"), "{}", out);
+    let rows: Vec<&str> = out.lines().skip_while(|l| !l.starts_with("Bit depth")).skip(1).collect();
+    assert_eq!(rows, vec![
+        "        0        3  75.00%         12   8.22%  1",
+        "        1        1  25.00%        134  91.78%  2",
+    ]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn batch_reports_every_file_in_order() {
+    let dir = scratch_dir("batch");
+    let mut inputs = Vec::new();
+    for n in 0..5u8 {
+        let path = dir.join(format!("infoLayer_{}.grle", n));
+        fs::write(&path, encode_grle(&vec![n; 256 * 256], 256, 256)).unwrap();
+        inputs.push(path.to_str().unwrap().to_string());
+    }
+    let broken = dir.join("broken.gdm");
+    fs::write(&broken, b"not a gdm").unwrap();
+    inputs.insert(2, broken.to_str().unwrap().to_string());
+
+    let mut args = vec!["batch", "-q", "--jobs", "3"];
+    args.extend(inputs.iter().map(|s| s.as_str()));
+    let output = Command::new(env!("CARGO_BIN_EXE_grleconvert")).args(&args).output().unwrap();
+    assert!(!output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 7, "{}", stdout);
+    for (line, input) in lines.iter().zip(&inputs) {
+        let expected = if input.ends_with("broken.gdm") { "FAILED " } else { "ok     " };
+        assert!(line.starts_with(&format!("{}{}", expected, input)), "{}", line);
+    }
+    assert_eq!(lines[6], "5 converted, 1 failed");
+    for n in 0..5 {
+        assert!(dir.join(format!("infoLayer_{}.grle.png", n)).exists());
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn i3d_is_read_once_per_run() {
+    let dir = scratch_dir("i3d_once");
+    fs::write(dir.join("modDesc.xml"), "<modDesc/>\n").unwrap();
+    // Not UTF-8, so every read of it warns
+    fs::write(dir.join("map.i3d"), b"\xff\xfe<i3D>").unwrap();
+    let inputs: Vec<String> = (0..2)
+        .map(|n| {
+            let path = dir.join(format!("infoLayer_{}.grle", n));
+            fs::write(&path, encode_grle(&vec![n; 256 * 256], 256, 256)).unwrap();
+            path.to_str().unwrap().to_string()
+        })
+        .collect();
+    let warnings = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_grleconvert")).args(args).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stderr).unwrap().matches("Could not read").count()
+    };
+
+    // Output naming and the channel lookup both need the i3d
+    let out_dir = dir.to_str().unwrap();
+    assert_eq!(warnings(&["-f", &inputs[0], "--out-dir", out_dir, "--name-template", "{map}_{stem}"]), 1);
+    assert!(dir.join("map_infoLayer_0.png").exists());
+    assert_eq!(warnings(&["batch", "-f", "--jobs", "2", "--name-template", "{map}_{stem}", &inputs[0], &inputs[1]]), 1);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn mask_alpha_keeps_template_values() {
+    let dir = scratch_dir("mask_alpha");
+    // Paint 1 over the right half; the left half is transparent
+    let rgba: Vec<u8> = (0..256 * 256)
+        .flat_map(|i| if i % 256 < 128 { [9, 9, 9, 0] } else { [1, 1, 1, 255] })
+        .collect();
+    let painting = dir.join("painting.png");
+    write_png(&painting, 256, 256, png::ColorType::Rgba, png::BitDepth::Eight, &rgba);
+    let expected = |i: usize, old: u32| if i % 256 < 128 { old } else { 1 };
+
+    let old_gdm: Vec<u32> = (0..256 * 256).map(|i| (i % 7) as u32).collect();
+    let template = dir.join("old.gdm");
+    let params = GdmParams { num_channels: 3, ..Default::default() };
+    fs::write(&template, encode_gdm(&DecodedImage { width: 256, height: 256, values: old_gdm.clone() }, &params).unwrap()).unwrap();
+    let out = dir.join("new.gdm");
+    run(&[painting.to_str().unwrap(), out.to_str().unwrap(), "--channels", "3",
+        "--template", template.to_str().unwrap(), "--mask-alpha"]);
+    let values = decode_gdm(&fs::read(&out).unwrap()).unwrap().image.values;
+    assert!(values.iter().enumerate().all(|(i, &v)| v == expected(i, old_gdm[i])));
+
+    let old_grle: Vec<u8> = (0..256 * 256).map(|i| (i % 5) as u8).collect();
+    let template = dir.join("old.grle");
+    fs::write(&template, encode_grle(&old_grle, 256, 256)).unwrap();
+    let out = dir.join("new.grle");
+    run(&[painting.to_str().unwrap(), out.to_str().unwrap(),
+        "--template", template.to_str().unwrap(), "--mask-alpha"]);
+    let pixels = decode_grle(&fs::read(&out).unwrap()).unwrap().pixels;
+    assert!(pixels.iter().enumerate().all(|(i, &v)| v as u32 == expected(i, old_grle[i] as u32)));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn grayscale_alpha_uses_the_gray_sample() {
+    let dir = scratch_dir("gray_alpha");
+    // Gray 3 on the left, 1 on the right; the left half is transparent
+    let ga: Vec<u8> = (0..256 * 256)
+        .flat_map(|i| if i % 256 < 128 { [3, 0] } else { [1, 255] })
+        .collect();
+    let painting = dir.join("painting.png");
+    write_png(&painting, 256, 256, png::ColorType::GrayscaleAlpha, png::BitDepth::Eight, &ga);
+    let gray = |i: usize| if i % 256 < 128 { 3 } else { 1 };
+
+    let out = dir.join("plain.grle");
+    run(&[painting.to_str().unwrap(), out.to_str().unwrap()]);
+    let pixels = decode_grle(&fs::read(&out).unwrap()).unwrap().pixels;
+    assert!(pixels.iter().enumerate().all(|(i, &v)| v as u32 == gray(i)));
+
+    let out = dir.join("plain.gdm");
+    run(&[painting.to_str().unwrap(), out.to_str().unwrap(), "--channels", "2"]);
+    let values = decode_gdm(&fs::read(&out).unwrap()).unwrap().image.values;
+    assert!(values.iter().enumerate().all(|(i, &v)| v == gray(i)));
+
+    // Alpha masks against a template just like RGBA does
+    let template = dir.join("old.grle");
+    fs::write(&template, encode_grle(&vec![2u8; 256 * 256], 256, 256)).unwrap();
+    let out = dir.join("masked.grle");
+    run(&[painting.to_str().unwrap(), out.to_str().unwrap(),
+        "--template", template.to_str().unwrap(), "--mask-alpha"]);
+    let pixels = decode_grle(&fs::read(&out).unwrap()).unwrap().pixels;
+    assert!(pixels.iter().enumerate().all(|(i, &v)| v == if i % 256 < 128 { 2 } else { 1 }));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn channel_layout_maps_png_channels_to_bits() {
+    let dir = scratch_dir("channel_layout");
+    // R holds bits 0-3 and G bits 4-9; anything above those is dropped
+    let rgb: Vec<u8> = (0..64 * 64).flat_map(|i| [(i % 16) as u8, (i % 64) as u8, 0xAA]).collect();
+    let png_path = dir.join("layout.png");
+    write_png(&png_path, 64, 64, png::ColorType::Rgb, png::BitDepth::Eight, &rgb);
+    let gdm = dir.join("layout.gdm");
+    run(&[png_path.to_str().unwrap(), gdm.to_str().unwrap(), "--channels", "10", "--channel-layout", "r:0-3,g:4-9"]);
+    let values = decode_gdm(&fs::read(&gdm).unwrap()).unwrap().image.values;
+    assert!(values.iter().enumerate().all(|(i, &v)| v == (i % 16) as u32 | ((i % 64) as u32) << 4));
+
+    // Decoding with the same layout gives the channels back; B is unused
+    let back = dir.join("back.png");
+    run(&[gdm.to_str().unwrap(), back.to_str().unwrap(), "--channel-layout", "r:0-3,g:4-9"]);
+    let mut reader = png::Decoder::new(fs::File::open(&back).unwrap()).read_info().unwrap();
+    let mut samples = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut samples).unwrap();
+    assert_eq!(reader.info().color_type, png::ColorType::Rgb);
+    assert!(samples.chunks(3).enumerate().all(|(i, px)| px == [(i % 16) as u8, (i % 64) as u8, 0]));
+
+    // Fields that overlap, or bits past the layer, are refused
+    let output = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+        .args([png_path.to_str().unwrap(), gdm.to_str().unwrap(), "--channels", "10", "--channel-layout", "r:0-7,g:4-9"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("overlaps"));
+    let output = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+        .args([png_path.to_str().unwrap(), gdm.to_str().unwrap(), "--channels", "8", "--channel-layout", "r:0-3,g:4-9"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn assume_format_picks_the_target_without_an_i3d() {
+    let dir = scratch_dir("assume_format");
+    let gray: Vec<u8> = (0..256 * 256).map(|i| (i % 4) as u8).collect();
+    write_png(&dir.join("painting.png"), 256, 256, png::ColorType::Grayscale, png::BitDepth::Eight, &gray);
+    fs::copy(dir.join("painting.png"), dir.join("infoLayer_paint.png")).unwrap();
+    // Outputs are named after the input, in the working directory
+    let encode = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_grleconvert")).arg("-q").args(args).current_dir(&dir).status().unwrap().code()
+    };
+
+    assert_eq!(encode(&["painting.png", "--assume-format", "grle"]), Some(0));
+    assert_eq!(decode_grle(&fs::read(dir.join("painting.grle")).unwrap()).unwrap().pixels, gray);
+
+    // The name would suggest GRLE; the assumption wins
+    assert_eq!(encode(&["infoLayer_paint.png", "--assume-format", "gdm", "--channels", "2"]), Some(0));
+    let values = decode_gdm(&fs::read(dir.join("infoLayer_paint.gdm")).unwrap()).unwrap().image.values;
+    assert!(values.iter().zip(&gray).all(|(&v, &g)| v == g as u32));
+
+    // GDM still needs a channel count, and the output extension must agree
+    assert_eq!(encode(&["painting.png", "--assume-format", "gdm"]), Some(2));
+    assert_eq!(encode(&["painting.png", "out.grle", "--assume-format", "gdm"]), Some(2));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn encode_into_a_zip_entry() {
+    let dir = scratch_dir("into_zip");
+    let gray: Vec<u8> = (0..256 * 256).map(|i| (i % 3) as u8).collect();
+    let painting = dir.join("painting.png");
+    write_png(&painting, 256, 256, png::ColorType::Grayscale, png::BitDepth::Eight, &gray);
+    let archive = dir.join("map.zip");
+    fs::write(&archive, grleconvert::zip::write_entry(&[], "modDesc.xml", b"<modDesc/>", std::time::SystemTime::now()).unwrap()).unwrap();
+
+    let into = |entry: &str, extra: &[&str]| {
+        let mut args = vec![painting.to_str().unwrap(), "--into", archive.to_str().unwrap(), "--entry", entry];
+        args.extend_from_slice(extra);
+        Command::new(env!("CARGO_BIN_EXE_grleconvert")).arg("-q").args(&args).status().unwrap().success()
+    };
+    assert!(into("maps/densityMap_paint.gdm", &["--channels", "2"]));
+    assert!(into("maps/infoLayer_paint.grle", &[]));
+
+    let zip = fs::read(&archive).unwrap();
+    assert_eq!(grleconvert::zip::read_entry(&zip, "modDesc.xml").unwrap().unwrap(), b"<modDesc/>");
+    let gdm = grleconvert::zip::read_entry(&zip, "maps/densityMap_paint.gdm").unwrap().unwrap();
+    assert!(decode_gdm(&gdm).unwrap().image.values.iter().zip(&gray).all(|(&v, &g)| v == g as u32));
+    let grle = grleconvert::zip::read_entry(&zip, "maps/infoLayer_paint.grle").unwrap().unwrap();
+    assert_eq!(decode_grle(&grle).unwrap().pixels, gray);
+
+    // Replacing an entry needs --force, like overwriting a file
+    assert!(!into("maps/infoLayer_paint.grle", &[]));
+    assert!(into("maps/infoLayer_paint.grle", &["--force"]));
+    assert_eq!(grleconvert::zip::entry_names(&fs::read(&archive).unwrap()).unwrap().len(), 3);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn oversized_gdm_is_flagged() {
+    let dir = scratch_dir("size_limit");
+    // Noise gives every chunk nearly all 256 values, so palettes cost more than they save
+    let mut seed = 1u32;
+    let noise: Vec<u8> = (0..256 * 256)
+        .map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect();
+    let smooth: Vec<u8> = (0..256 * 256).map(|i| (i / 256 / 64) as u8).collect();
+    let encode = |name: &str, pixels: &[u8], extra: &[&str]| {
+        let png_path = dir.join(format!("{}.png", name));
+        write_png(&png_path, 256, 256, png::ColorType::Grayscale, png::BitDepth::Eight, pixels);
+        let gdm = dir.join(format!("{}.gdm", name));
+        let output = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+            .args([png_path.to_str().unwrap(), gdm.to_str().unwrap(), "--channels", "8"])
+            .args(extra)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+
+    let stderr = encode("noise", &noise, &[]);
+    assert!(stderr.contains("more than its uncompressed size of 65536 bytes"), "{}", stderr);
+    assert!(stderr.contains("blocks are 8 bits per pixel"), "{}", stderr);
+    assert!(!encode("smooth", &smooth, &[]).contains("Warning"));
+    assert!(encode("limited", &smooth, &["--size-limit", "100"]).contains("more than --size-limit of 100 bytes"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn name_template_with_i3d_layer() {
+    let dir = scratch_dir("name_template");
+    fs::create_dir_all(dir.join("data")).unwrap();
+    fs::write(dir.join("modDesc.xml"), "<modDesc/>\n").unwrap();
+    fs::write(dir.join("mapUS.i3d"), r#"<i3D name="mapUS">
+  <Files>
+    <File fileId="5" filename="data/infoLayer_soil.png"/>
+  </Files>
+  <Layers>
+    <InfoLayer name="soilMap" fileId="5" numChannels="3"/>
+  </Layers>
+</i3D>
+"#).unwrap();
+    let grle = dir.join("data/infoLayer_soil.grle");
+    fs::write(&grle, encode_grle(&vec![3; 256 * 256], 256, 256)).unwrap();
+    let out_dir = dir.join("exported");
+
+    run(&[grle.to_str().unwrap(), "--out-dir", out_dir.to_str().unwrap(), "--name-template", "{map}_{layer}"]);
+    assert!(out_dir.join("mapUS_soilMap.png").exists());
+
+    run_stdout(&["batch", "-q", grle.to_str().unwrap(), "--out-dir", out_dir.to_str().unwrap(),
+        "--name-template", "{stem}.{ext}", "--out-format", "npy"]);
+    assert!(out_dir.join("infoLayer_soil.npy").exists());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn size_checked_against_existing_layer_file() {
+    let dir = scratch_dir("layer_size");
+    fs::write(dir.join("map.i3d"), r#"<i3D name="map">
+  <Files>
+    <File fileId="12" filename="densityMap_ground.png"/>
+  </Files>
+  <Layers>
+    <DetailLayer name="terrainDetail" densityMapId="12" numDensityMapChannels="8"/>
+  </Layers>
+</i3D>
+"#).unwrap();
+    let params = GdmParams { num_channels: 8, ..Default::default() };
+    let existing = DecodedImage { width: 128, height: 128, values: vec![0; 128 * 128] };
+    fs::write(dir.join("densityMap_ground.gdm"), encode_gdm(&existing, &params).unwrap()).unwrap();
+    let png_path = dir.join("densityMap_ground.png");
+    write_png(&png_path, 64, 64, png::ColorType::Grayscale, png::BitDepth::Eight, &vec![1u8; 64 * 64]);
+    let out = dir.join("new.gdm");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+        .args([png_path.to_str().unwrap(), out.to_str().unwrap(), "--strict"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Image is 64x64 but the layer's existing"), "{}", stderr);
+    assert!(stderr.contains("densityMap_ground.gdm is 128x128"), "{}", stderr);
+
+    // Only a warning without --strict
+    run(&[png_path.to_str().unwrap(), out.to_str().unwrap()]);
+    assert!(out.exists());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn gdm_and_grle_convert_directly() {
+    let dir = scratch_dir("direct");
+    let values: Vec<u32> = (0..256 * 256).map(|i| (i % 251) as u32).collect();
+    let params = GdmParams { num_channels: 8, ..Default::default() };
+    let gdm = dir.join("layer.gdm");
+    fs::write(&gdm, encode_gdm(&DecodedImage { width: 256, height: 256, values: values.clone() }, &params).unwrap()).unwrap();
+
+    let grle = dir.join("layer.grle");
+    run(&[gdm.to_str().unwrap(), grle.to_str().unwrap()]);
+    let pixels = decode_grle(&fs::read(&grle).unwrap()).unwrap().pixels;
+    assert!(pixels.iter().zip(&values).all(|(&p, &v)| p as u32 == v));
+
+    let back = dir.join("back.gdm");
+    run(&[grle.to_str().unwrap(), back.to_str().unwrap(), "--channels", "8"]);
+    let decoded = decode_gdm(&fs::read(&back).unwrap()).unwrap();
+    assert_eq!(decoded.num_channels, 8);
+    assert_eq!(decoded.image.values, values);
+
+    // Values above 255 don't fit in a GRLE
+    let wide = dir.join("wide.gdm");
+    let params = GdmParams { num_channels: 10, ..Default::default() };
+    fs::write(&wide, encode_gdm(&DecodedImage { width: 256, height: 256, values: vec![300; 256 * 256] }, &params).unwrap()).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+        .args([wide.to_str().unwrap(), dir.join("wide.grle").to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("65536 pixels do not fit in GRLE's 8 bits (largest value 300)"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn print_params_as_json() {
+    let dir = scratch_dir("print_params");
+    let i3d = dir.join("map.i3d");
+    fs::write(&i3d, r#"<i3D name="map">
+  <Files>
+    <File fileId="12" filename="densityMap_ground.png"/>
+  </Files>
+  <Layers>
+    <DetailLayer name="terrainDetail" densityMapId="12" numDensityMapChannels="10" compressionChannels="8"/>
+  </Layers>
+</i3D>
+"#).unwrap();
+    let png_path = dir.join("densityMap_ground.png");
+    write_png(&png_path, 64, 64, png::ColorType::Rgb, png::BitDepth::Eight, &vec![1u8; 64 * 64 * 3]);
+    let out = dir.join("ground.gdm");
+
+    let stdout = run_stdout(&[png_path.to_str().unwrap(), out.to_str().unwrap(), "--i3d", i3d.to_str().unwrap(), "--print-params", "-q"]);
+    assert_eq!(stdout, format!(
+        "{{\"input\": \"{}\", \"output\": \"{}\", \"format\": \"gdm\", \"layer_type\": \"GdmLayer\", \"num_channels\": 10, \"compression_channels\": [8], \"type_index_channels\": null, \"i3d\": \"{}\"}}\n",
+        png_path.display(), out.display(), i3d.display()
+    ));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn states_writes_one_mask_per_growth_state() {
+    let dir = scratch_dir("states");
+    // Type index in the low 3 bits, growth state 1, 2 or 5 above it
+    let values: Vec<u32> = (0..64 * 64).map(|i| [1u32, 2, 5][i % 3] << 3 | (i % 8) as u32).collect();
+    let params = GdmParams { num_channels: 8, type_index_channels: Some(3), ..Default::default() };
+    let gdm = dir.join("foliage.gdm");
+    fs::write(&gdm, encode_gdm(&DecodedImage { width: 64, height: 64, values }, &params).unwrap()).unwrap();
+
+    run_stdout(&["states", gdm.to_str().unwrap(), "-q"]);
+    for (state, offset) in [(1, 0), (2, 1), (5, 2)] {
+        let decoder = png::Decoder::new(fs::File::open(dir.join(format!("foliage_state{}.png", state))).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut mask = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut mask).unwrap();
+        let expected: Vec<u8> = (0..6).map(|i| if i % 3 == offset { 255 } else { 0 }).collect();
+        assert_eq!(&mask[..6], &expected[..], "state {}", state);
+    }
+    assert!(!dir.join("foliage_state0.png").exists());
+
+    run_stdout(&["states", gdm.to_str().unwrap(), "--apng", "-q"]);
+    let decoder = png::Decoder::new(fs::File::open(dir.join("foliage_states.png")).unwrap());
+    let reader = decoder.read_info().unwrap();
+    assert_eq!(reader.info().animation_control.map(|a| a.num_frames), Some(3));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn empty_input_is_an_error_not_a_panic() {
+    let dir = scratch_dir("empty_input");
+    for name in ["empty.grle", "empty.gdm"] {
+        let input = dir.join(name);
+        fs::write(&input, b"").unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+            .args([input.to_str().unwrap(), dir.join("out.png").to_str().unwrap()])
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success());
+        assert!(stderr.starts_with("Error: File too small: need at least"), "{}: {}", name, stderr);
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn verify_header_checks_written_files() {
+    let dir = scratch_dir("verify_header");
+    let png_path = dir.join("layer.png");
+    write_png(&png_path, 256, 256, png::ColorType::Grayscale, png::BitDepth::Eight, &vec![3u8; 256 * 256]);
+
+    for (output, extra) in [("layer.grle", &[][..]), ("layer.gdm", &["--channels", "10", "--compress-at", "4,8"][..])] {
+        let out = dir.join(output);
+        let result = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+            .args([png_path.to_str().unwrap(), out.to_str().unwrap(), "--verify-header", "-v"])
+            .args(extra)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        assert!(result.status.success(), "{}: {}", output, stderr);
+        assert!(stderr.contains(&format!("Header of {} verified", out.display())), "{}: {}", output, stderr);
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn stitch_places_tiles_by_row_and_column() {
+    let dir = scratch_dir("stitch");
+    let params = GdmParams { num_channels: 8, ..Default::default() };
+    for r in 0..2u32 {
+        for c in 0..3u32 {
+            let values = vec![r * 10 + c; 32 * 32];
+            let file = encode_gdm(&DecodedImage { width: 32, height: 32, values }, &params).unwrap();
+            fs::write(dir.join(format!("tile_{}_{}.gdm", r, c)), file).unwrap();
+        }
+    }
+    let pattern = dir.join("tile_{r}_{c}.gdm");
+    let out = dir.join("stitched.png");
+    run_stdout(&["stitch", "--pattern", pattern.to_str().unwrap(), "--grid", "3x2", out.to_str().unwrap(), "-q"]);
+
+    let mut reader = png::Decoder::new(fs::File::open(&out).unwrap()).read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).unwrap();
+    assert_eq!((info.width, info.height), (96, 64));
+    assert_eq!([pixels[0], pixels[40], pixels[95], pixels[33 * 96 + 70]], [0, 1, 2, 12]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn zero_transparent_grle_output() {
+    let dir = scratch_dir("zero_transparent");
+    let pixels: Vec<u8> = (0..256 * 256).map(|i| (i % 3) as u8 * 40).collect();
+    let grle = dir.join("infoLayer_test.grle");
+    fs::write(&grle, encode_grle(&pixels, 256, 256)).unwrap();
+    let out = dir.join("overlay.png");
+    run(&[grle.to_str().unwrap(), out.to_str().unwrap(), "--zero-transparent"]);
+
+    let mut reader = png::Decoder::new(fs::File::open(&out).unwrap()).read_info().unwrap();
+    let mut rgba = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut rgba).unwrap();
+    assert_eq!(info.color_type, png::ColorType::Rgba);
+    assert_eq!(&rgba[..12], &[0, 0, 0, 0, 40, 40, 40, 255, 80, 80, 80, 255]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn colorize_maps_values_through_palette() {
+    let dir = scratch_dir("colorize");
+    let pixels: Vec<u8> = (0..256 * 256).map(|i| (i % 3) as u8).collect();
+    let grle = dir.join("infoLayer_test.grle");
+    fs::write(&grle, encode_grle(&pixels, 256, 256)).unwrap();
+    let palette = dir.join("palette.json");
+    fs::write(&palette, "{\n  \"0\": \"#102030\",\n  \"1\": \"#FF8000\"\n}\n").unwrap();
+    let out = dir.join("colored.png");
+    run(&[grle.to_str().unwrap(), out.to_str().unwrap(), "--colorize", palette.to_str().unwrap()]);
+
+    let mut reader = png::Decoder::new(fs::File::open(&out).unwrap()).read_info().unwrap();
+    let mut rgb = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut rgb).unwrap();
+    assert_eq!(info.color_type, png::ColorType::Rgb);
+    // Value 2 has no palette entry and is black
+    assert_eq!(&rgb[..9], &[0x10, 0x20, 0x30, 0xFF, 0x80, 0x00, 0, 0, 0]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn max_dimension_limits_decoding() {
+    let dir = scratch_dir("max_dimension");
+    let grle = dir.join("infoLayer_test.grle");
+    fs::write(&grle, encode_grle(&vec![1u8; 512 * 512], 512, 512)).unwrap();
+    let decode = |limit: &str, output: &str| {
+        Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+            .args([grle.to_str().unwrap(), dir.join(output).to_str().unwrap(), "--max-dimension", limit, "-q"])
+            .output()
+            .unwrap()
+    };
+
+    let refused = decode("256", "refused.png");
+    assert!(!refused.status.success());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("512x512, larger than the 256 pixel maximum side"));
+    assert!(!dir.join("refused.png").exists());
+    assert!(decode("512", "allowed.png").status.success());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn batch_summary_totals_bytes_per_format() {
+    let dir = scratch_dir("batch_summary");
+    let grle = dir.join("infoLayer_a.grle");
+    fs::write(&grle, encode_grle(&vec![1u8; 256 * 256], 256, 256)).unwrap();
+    let gdm = dir.join("densityMap_b.gdm");
+    let params = GdmParams::new(8);
+    fs::write(&gdm, encode_gdm(&DecodedImage { width: 64, height: 64, values: vec![2; 64 * 64] }, &params).unwrap()).unwrap();
+
+    let stdout = run_stdout(&["batch", "-q", "--summary", grle.to_str().unwrap(), gdm.to_str().unwrap()]);
+    let size = |path: PathBuf| fs::metadata(path).unwrap().len();
+    let (grle_in, grle_out) = (size(grle.clone()), size(dir.join("infoLayer_a.grle.png")));
+    let (gdm_in, gdm_out) = (size(gdm.clone()), size(dir.join("densityMap_b.gdm.png")));
+
+    let table: Vec<Vec<&str>> = stdout.lines().skip(4).map(|l| l.split_whitespace().collect()).collect();
+    assert_eq!(table[0], ["Format", "Files", "Input", "bytes", "Output", "bytes", "Ratio"]);
+    assert_eq!(&table[1][..4], ["gdm", "1", &gdm_in.to_string(), &gdm_out.to_string()]);
+    assert_eq!(&table[2][..4], ["grle", "1", &grle_in.to_string(), &grle_out.to_string()]);
+    assert_eq!(&table[3][..4], ["total", "2", &(grle_in + gdm_in).to_string(), &(grle_out + gdm_out).to_string()]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn batch_encodes_pngs_by_their_i3d_layer() {
+    let dir = scratch_dir("batch_encode");
+    fs::write(dir.join("modDesc.xml"), "<modDesc/>\n").unwrap();
+    fs::write(dir.join("map.i3d"), r#"<i3D name="map">
+  <Files>
+    <File fileId="5" filename="infoLayer_soil.png"/>
+    <File fileId="12" filename="densityMap_ground.png"/>
+  </Files>
+  <Layers>
+    <InfoLayer name="soilMap" fileId="5" numChannels="3"/>
+    <DetailLayer name="terrainDetail" densityMapId="12" numDensityMapChannels="8"/>
+  </Layers>
+</i3D>
+"#).unwrap();
+    let (soil, ground) = (dir.join("infoLayer_soil.png"), dir.join("densityMap_ground.png"));
+    write_png(&soil, 256, 256, png::ColorType::Grayscale, png::BitDepth::Eight, &vec![3u8; 256 * 256]);
+    write_png(&ground, 64, 64, png::ColorType::Grayscale, png::BitDepth::Eight, &vec![7u8; 64 * 64]);
+    let batch = || run_stdout(&["batch", "-q", "--overwrite-if-newer", "--summary", soil.to_str().unwrap(), ground.to_str().unwrap()]);
+
+    let stdout = batch();
+    let (grle, gdm) = (dir.join("infoLayer_soil.grle"), dir.join("densityMap_ground.gdm"));
+    assert_eq!(decode_grle(&fs::read(&grle).unwrap()).unwrap().pixels, vec![3u8; 256 * 256]);
+    assert_eq!(decode_gdm(&fs::read(&gdm).unwrap()).unwrap().image.values, vec![7; 64 * 64]);
+
+    let size = |path: &Path| fs::metadata(path).unwrap().len();
+    let table: Vec<Vec<&str>> = stdout.lines().skip(4).map(|l| l.split_whitespace().collect()).collect();
+    assert_eq!(&table[1][..4], ["png->gdm", "1", &size(&ground).to_string(), &size(&gdm).to_string()], "{}", stdout);
+    assert_eq!(&table[2][..4], ["png->grle", "1", &size(&soil).to_string(), &size(&grle).to_string()], "{}", stdout);
+    assert_eq!(&table[3][..4], ["total", "2", &(size(&soil) + size(&ground)).to_string(), &(size(&grle) + size(&gdm)).to_string()]);
+
+    // Only the PNG edited after its output is encoded again
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    write_png(&soil, 256, 256, png::ColorType::Grayscale, png::BitDepth::Eight, &vec![5u8; 256 * 256]);
+    let stdout = batch();
+    assert!(stdout.contains(&format!("skip   {} -> {} (up to date)", ground.display(), gdm.display())), "{}", stdout);
+    assert!(stdout.contains("1 converted, 1 skipped, 0 failed\n"), "{}", stdout);
+    assert_eq!(decode_grle(&fs::read(&grle).unwrap()).unwrap().pixels, vec![5u8; 256 * 256]);
+
+    // A PNG no layer names has no channel count to encode with
+    let stray = dir.join("stray.png");
+    write_png(&stray, 256, 256, png::ColorType::Grayscale, png::BitDepth::Eight, &vec![0u8; 256 * 256]);
+    let output = Command::new(env!("CARGO_BIN_EXE_grleconvert")).args(["batch", "-q", stray.to_str().unwrap()]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("FAILED"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Grayscale 8-bit PNG with Adam7 interlacing, which the png crate cannot write.
+/// The zlib stream uses stored (uncompressed) blocks.
+fn write_interlaced_png(path: &Path, width: usize, height: usize, pixels: &[u8]) {
+    const PASSES: [(usize, usize, usize, usize); 7] =
+        [(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)];
+    let mut raw = Vec::new();
+    for (x0, y0, dx, dy) in PASSES {
+        if x0 >= width || y0 >= height {
+            continue;
+        }
+        for y in (y0..height).step_by(dy) {
+            raw.push(0); // filter: none
+            raw.extend((x0..width).step_by(dx).map(|x| pixels[y * width + x]));
+        }
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = raw.chunks(65535).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push((i + 1 == blocks.len()) as u8);
+        zlib.extend((block.len() as u16).to_le_bytes());
+        zlib.extend((!(block.len() as u16)).to_le_bytes());
+        zlib.extend(*block);
+    }
+    let (a, b) = raw.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    zlib.extend(((b << 16) | a).to_be_bytes());
+
+    let crc = |data: &[u8]| {
+        !data.iter().fold(!0u32, |crc, &byte| {
+            (0..8).fold(crc ^ byte as u32, |c, _| if c & 1 == 1 { (c >> 1) ^ 0xEDB8_8320 } else { c >> 1 })
+        })
+    };
+    let mut file = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut ihdr = Vec::new();
+    ihdr.extend((width as u32).to_be_bytes());
+    ihdr.extend((height as u32).to_be_bytes());
+    ihdr.extend([8, 0, 0, 0, 1]); // 8-bit grayscale, Adam7
+    for (kind, data) in [(&b"IHDR"[..], ihdr), (&b"IDAT"[..], zlib), (&b"IEND"[..], Vec::new())] {
+        let body = [kind, &data[..]].concat();
+        file.extend((data.len() as u32).to_be_bytes());
+        file.extend(&body);
+        file.extend(crc(&body).to_be_bytes());
+    }
+    fs::write(path, file).unwrap();
+}
+
+#[test]
+fn interlaced_png_encodes_in_pixel_order() {
+    let dir = scratch_dir("interlaced");
+    let pixels: Vec<u8> = (0..256 * 256).map(|i| ((i % 256) * 7 + (i / 256) * 13) as u8).collect();
+    let png_path = dir.join("infoLayer_test.png");
+    write_interlaced_png(&png_path, 256, 256, &pixels);
+
+    let grle = dir.join("infoLayer_test.grle");
+    run(&[png_path.to_str().unwrap(), grle.to_str().unwrap()]);
+    assert_eq!(decode_grle(&fs::read(&grle).unwrap()).unwrap().pixels, pixels);
+
+    let gdm = dir.join("densityMap_test.gdm");
+    run(&[png_path.to_str().unwrap(), gdm.to_str().unwrap(), "--channels", "8"]);
+    let values: Vec<u32> = pixels.iter().map(|&p| p as u32).collect();
+    assert_eq!(decode_gdm(&fs::read(&gdm).unwrap()).unwrap().image.values, values);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn twelve_channel_info_layer_round_trips_through_rgb() {
+    let dir = scratch_dir("wide_grle");
+    fs::write(dir.join("modDesc.xml"), "<modDesc/>\n").unwrap();
+    fs::write(dir.join("map.i3d"), r#"<i3D name="map">
+  <Files>
+    <File fileId="5" filename="infoLayer_wide.png"/>
+  </Files>
+  <Layers>
+    <InfoLayer name="wide" fileId="5" numChannels="12"/>
+  </Layers>
+</i3D>
+"#).unwrap();
+    let values: Vec<u32> = (0..256 * 256).map(|i| (i * 37) & 0xFFF).collect();
+    let rgb: Vec<u8> = values.iter().flat_map(|&v| [v as u8, (v >> 8) as u8, 0]).collect();
+    let png_path = dir.join("infoLayer_wide.png");
+    write_png(&png_path, 256, 256, png::ColorType::Rgb, png::BitDepth::Eight, &rgb);
+
+    let grle = dir.join("infoLayer_wide.grle");
+    run(&[png_path.to_str().unwrap(), grle.to_str().unwrap()]);
+    let decoded = decode_grle_with_params(&fs::read(&grle).unwrap(), &GrleParams::new().num_channels(12)).unwrap();
+    assert_eq!(decoded.values(), values);
+
+    let out = dir.join("decoded.png");
+    run(&[grle.to_str().unwrap(), out.to_str().unwrap()]);
+    let mut reader = png::Decoder::new(fs::File::open(&out).unwrap()).read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).unwrap();
+    assert_eq!(info.color_type, png::ColorType::Rgb);
+    assert_eq!(pixels, rgb);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn list_layers_prints_every_layer() {
+    let dir = scratch_dir("list_layers");
+    let i3d = dir.join("map.i3d");
+    fs::write(&i3d, r#"<i3D name="map">
+  <Files>
+    <File fileId="10" filename="data/densityMap_fruits.png"/>
+    <File fileId="11" filename="data/infoLayer_farmlands.png"/>
+    <File fileId="12" filename="data/densityMap_ground.png"/>
+  </Files>
+  <Layers>
+    <InfoLayer name="farmlands" fileId="11" numChannels="8"/>
+    <DetailLayer name="terrainDetail" densityMapId="12" numDensityMapChannels="10" compressionChannels="8"/>
+    <FoliageMultiLayer densityMapId="10" numChannels="7" numTypeIndexChannels="3" compressionChannels="3"/>
+  </Layers>
+</i3D>
+"#).unwrap();
+
+    let stdout = run_stdout(&["--list-layers", i3d.to_str().unwrap()]);
+    let rows: Vec<Vec<&str>> = stdout.lines().map(|l| l.split_whitespace().collect()).collect();
+    assert_eq!(rows[0], ["Name", "Kind", "Format", "Channels", "Compression", "Type", "index", "File"]);
+    assert_eq!(rows[1], ["farmlands", "InfoLayer", "GRLE", "8", "-", "-", "data/infoLayer_farmlands.png"]);
+    assert_eq!(rows[2], ["terrainDetail", "DetailLayer", "GDM", "10", "8", "-", "data/densityMap_ground.png"]);
+    assert_eq!(rows[3], ["-", "FoliageMultiLayer", "GDM", "7", "3", "3", "data/densityMap_fruits.png"]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn grle_flag_sets_the_offset_12_field() {
+    let dir = scratch_dir("grle_flag");
+    let png_path = dir.join("infoLayer_test.png");
+    write_png(&png_path, 256, 256, png::ColorType::Grayscale, png::BitDepth::Eight, &[7; 256 * 256]);
+
+    let default = dir.join("default.grle");
+    run(&[png_path.to_str().unwrap(), default.to_str().unwrap()]);
+    assert_eq!(&fs::read(&default).unwrap()[12..14], &256u16.to_le_bytes());
+
+    let flagged = dir.join("flagged.grle");
+    run(&[png_path.to_str().unwrap(), flagged.to_str().unwrap(), "--grle-flag", "512", "--verify-header"]);
+    let data = fs::read(&flagged).unwrap();
+    assert_eq!(&data[12..14], &512u16.to_le_bytes());
+    assert_eq!(decode_grle(&data).unwrap().pixels, vec![7; 256 * 256]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn channel_range_decodes_selected_bits_as_grayscale() {
+    let dir = scratch_dir("channel_range");
+    let values: Vec<u32> = (0..64 * 64).map(|i| (i * 97) as u32 & 0x3FF).collect();
+    let params = GdmParams::new(10).compression_channels(vec![8]);
+    let gdm = dir.join("densityMap_ground.gdm");
+    fs::write(&gdm, encode_gdm(&DecodedImage { width: 64, height: 64, values: values.clone() }, &params).unwrap()).unwrap();
+
+    let read_grey = |path: &Path| {
+        let mut reader = png::Decoder::new(fs::File::open(path).unwrap()).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!(info.color_type, png::ColorType::Grayscale);
+        pixels
+    };
+
+    let low = dir.join("low.png");
+    run(&[gdm.to_str().unwrap(), low.to_str().unwrap(), "--grayscale"]);
+    assert_eq!(read_grey(&low), values.iter().map(|&v| v as u8).collect::<Vec<_>>());
+
+    // Channels 6-9 cross the compression split at 8
+    let high = dir.join("high.png");
+    run(&[gdm.to_str().unwrap(), high.to_str().unwrap(), "--channel-range", "6:10"]);
+    assert_eq!(read_grey(&high), values.iter().map(|&v| (v >> 6) as u8).collect::<Vec<_>>());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn implausible_max_bpp_byte_is_flagged() {
+    let dir = scratch_dir("max_bpp_flag");
+    let gdm = dir.join("densityMap_short.gdm");
+    let mut file = b"!MDF\x00\x05\xc8\x01\x01".to_vec();
+    file.extend([0, 1, 1, 0]);
+    fs::write(&gdm, &file).unwrap();
+
+    let decode = |extra: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+            .args([gdm.to_str().unwrap(), dir.join("out.png").to_str().unwrap(), "-f"])
+            .args(extra)
+            .output()
+            .unwrap()
+    };
+    let lenient = decode(&[]);
+    assert!(lenient.status.success());
+    assert!(String::from_utf8_lossy(&lenient.stderr).contains("max_bpp byte is 200"));
+    assert!(!decode(&["--strict"]).status.success());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn template_max_bpp_does_not_undercut_deeper_blocks() {
+    let dir = scratch_dir("max_bpp_template");
+    let header = GdmHeaderFields { max_bpp: Some(2), ..Default::default() };
+    let params = GdmParams { num_channels: 8, header, ..Default::default() };
+    let template = dir.join("original.gdm");
+    fs::write(&template, encode_gdm(&DecodedImage { width: 32, height: 32, values: vec![1; 1024] }, &params).unwrap()).unwrap();
+
+    // 64 distinct values in the one chunk need a 6-bit palette
+    let edited = dir.join("edited.png");
+    let pixels: Vec<u8> = (0..1024).map(|i| (i % 64) as u8).collect();
+    write_png(&edited, 32, 32, png::ColorType::Grayscale, png::BitDepth::Eight, &pixels);
+    let encode = |name: &str, extra: &[&str]| {
+        let out = dir.join(name);
+        let mut args = vec![edited.to_str().unwrap(), out.to_str().unwrap(), "--channels", "8", "--template", template.to_str().unwrap()];
+        args.extend_from_slice(extra);
+        run(&args);
+        fs::read(&out).unwrap()[10]
+    };
+    assert_eq!(encode("deeper.gdm", &[]), 6);
+    // An explicit --max-bpp is still written as given
+    assert_eq!(encode("explicit.gdm", &["--max-bpp", "3"]), 3);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn progress_reaches_100_percent_on_stderr() {
+    let dir = scratch_dir("progress");
+    let grle = dir.join("infoLayer_a.grle");
+    fs::write(&grle, encode_grle(&vec![1u8; 256 * 256], 256, 256)).unwrap();
+    let gdm = dir.join("densityMap_b.gdm");
+    let values = (0..64 * 64).map(|i| i as u32 & 0xFF).collect();
+    fs::write(&gdm, encode_gdm(&DecodedImage { width: 64, height: 64, values }, &GdmParams::new(8)).unwrap()).unwrap();
+
+    let stderr = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_grleconvert")).args(args).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stderr).unwrap()
+    };
+    for input in [&grle, &gdm] {
+        let out = dir.join("out.png");
+        let log = stderr(&["-q", "--progress", "-f", input.to_str().unwrap(), out.to_str().unwrap()]);
+        assert!(log.ends_with("Decoding: 100%\n"), "{:?}", log);
+    }
+
+    let log = stderr(&["batch", "-q", "--progress", "-f", grle.to_str().unwrap(), gdm.to_str().unwrap()]);
+    assert!(log.ends_with("\r2/2 files\n"), "{:?}", log);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn one_png_encodes_to_grle_and_gdm() {
+    let dir = scratch_dir("two_outputs");
+    let png_path = dir.join("layer.png");
+    let values: Vec<u8> = (0..256 * 256).map(|i| (i / 300 % 200) as u8).collect();
+    write_png(&png_path, 256, 256, png::ColorType::Grayscale, png::BitDepth::Eight, &values);
+
+    let (grle, gdm) = (dir.join("out.grle"), dir.join("out.gdm"));
+    run(&[png_path.to_str().unwrap(), grle.to_str().unwrap(), gdm.to_str().unwrap(), "--channels", "8"]);
+    assert_eq!(decode_grle(&fs::read(&grle).unwrap()).unwrap().pixels, values);
+    let decoded = decode_gdm(&fs::read(&gdm).unwrap()).unwrap();
+    assert_eq!(decoded.image.values, values.iter().map(|&v| v as u32).collect::<Vec<_>>());
+
+    // --emit names them after the input instead
+    let status = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+        .current_dir(&dir)
+        .args(["-q", "layer.png", "--emit", "gdm,grle", "--channels", "8"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(fs::read(dir.join("layer.grle")).unwrap(), fs::read(&grle).unwrap());
+    assert_eq!(fs::read(dir.join("layer.gdm")).unwrap(), fs::read(&gdm).unwrap());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn raw_values_skip_the_i3d() {
+    let dir = scratch_dir("raw_values");
+    // An i3d next to the PNG that would give the layer different parameters
+    fs::write(dir.join("map.i3d"), r#"<i3D name="map">
+  <Files>
+    <File fileId="1" filename="densityMap_test.png"/>
+  </Files>
+  <Layers>
+    <DetailLayer name="test" densityMapId="1" numDensityMapChannels="4"/>
+  </Layers>
+</i3D>
+"#).unwrap();
+    let png_path = dir.join("densityMap_test.png");
+    let values: Vec<u8> = (0..64 * 64).map(|i| (i % 251) as u8).collect();
+    write_png(&png_path, 64, 64, png::ColorType::Grayscale, png::BitDepth::Eight, &values);
+
+    let gdm = dir.join("out.gdm");
+    run(&[png_path.to_str().unwrap(), gdm.to_str().unwrap(), "--raw-values", "--channels", "8"]);
+    let decoded = decode_gdm(&fs::read(&gdm).unwrap()).unwrap();
+    assert_eq!(decoded.num_channels, 8);
+    assert_eq!(decoded.image.values, values.iter().map(|&v| v as u32).collect::<Vec<_>>());
+
+    // Without --channels there is nothing to build the header from
+    let output = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+        .args([png_path.to_str().unwrap(), dir.join("other.gdm").to_str().unwrap(), "--raw-values"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--raw-values needs --channels"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn exit_status_tells_failures_apart() {
+    let dir = scratch_dir("exit_status");
+    let status = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_grleconvert")).arg("-q").args(args).output().unwrap().status.code()
+    };
+
+    // No i3d and no --channels: the GDM header cannot be built
+    let png_path = dir.join("densityMap_test.png");
+    write_png(&png_path, 32, 32, png::ColorType::Grayscale, png::BitDepth::Eight, &[0; 32 * 32]);
+    assert_eq!(status(&[png_path.to_str().unwrap(), dir.join("out.gdm").to_str().unwrap()]), Some(2));
+
+    let missing = dir.join("missing.gdm");
+    assert_eq!(status(&[missing.to_str().unwrap(), dir.join("out.png").to_str().unwrap()]), Some(3));
+
+    let junk = dir.join("junk.grle");
+    fs::write(&junk, b"GRLE not really").unwrap();
+    assert_eq!(status(&[junk.to_str().unwrap(), dir.join("out.png").to_str().unwrap()]), Some(4));
+
+    let future = dir.join("future.grle");
+    let mut file = encode_grle(&vec![1u8; 256 * 256], 256, 256);
+    file[4..6].copy_from_slice(&2u16.to_le_bytes());
+    fs::write(&future, file).unwrap();
+    assert_eq!(status(&[future.to_str().unwrap(), dir.join("future.png").to_str().unwrap()]), Some(4));
+
+    // A GRLE must be a multiple of 256 pixels on each side
+    assert_eq!(status(&[png_path.to_str().unwrap(), dir.join("out.grle").to_str().unwrap()]), Some(4));
+
+    // A compression boundary byte past the channel count is a corrupt file,
+    // while a --channel-range past it is a parameter problem
+    let params = GdmParams { num_channels: 10, compression_channels: vec![8], ..Default::default() };
+    let gdm = encode_gdm(&DecodedImage { width: 64, height: 64, values: vec![3; 64 * 64] }, &params).unwrap();
+    let gdm_path = dir.join("ranges.gdm");
+    fs::write(&gdm_path, &gdm).unwrap();
+    let range_png = dir.join("ranges.png");
+    assert_eq!(status(&[gdm_path.to_str().unwrap(), range_png.to_str().unwrap(), "--channel-range", "12:14"]), Some(2));
+    let mut bad_boundary = gdm;
+    assert_eq!(bad_boundary[16], 8);
+    bad_boundary[16] = 11;
+    let bad_path = dir.join("bad_ranges.gdm");
+    fs::write(&bad_path, bad_boundary).unwrap();
+    assert_eq!(status(&[bad_path.to_str().unwrap(), dir.join("bad_ranges.png").to_str().unwrap()]), Some(4));
+
+    // A {layer} name from an i3d without that layer
+    let i3d = dir.join("map.i3d.xml");
+    fs::write(&i3d, "<i3D name=\"map\">\n</i3D>\n").unwrap();
+    let out_dir = dir.to_str().unwrap();
+    let args = [future.to_str().unwrap(), "--force", "--i3d", i3d.to_str().unwrap(), "--out-dir", out_dir, "--name-template", "{layer}"];
+    assert_eq!(status(&args), Some(2));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn stats_summarize_decoded_values() {
+    let dir = scratch_dir("stats");
+    let grle = dir.join("infoLayer_a.grle");
+    let pixels: Vec<u8> = (0..256 * 256).map(|i| if i < 256 * 64 { (i % 3 + 2) as u8 } else { 0 }).collect();
+    fs::write(&grle, encode_grle(&pixels, 256, 256)).unwrap();
+
+    let stdout = run_stdout(&["-q", "--stats", grle.to_str().unwrap(), dir.join("a.png").to_str().unwrap()]);
+    assert_eq!(stdout, "Stats: 4 distinct values, 25.00% nonzero, min 0, max 4\n");
+
+    let gdm = dir.join("densityMap_b.gdm");
+    let image = DecodedImage { width: 64, height: 64, values: vec![0; 64 * 64] };
+    fs::write(&gdm, encode_gdm(&image, &GdmParams::new(8)).unwrap()).unwrap();
+    let stdout = run_stdout(&["-q", "--stats", gdm.to_str().unwrap(), dir.join("b.png").to_str().unwrap()]);
+    assert_eq!(stdout, "Stats: 1 distinct values, 0.00% nonzero, min 0, max 0\n");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn decode_and_encode_subcommands_fix_the_direction() {
+    let dir = scratch_dir("subcommands");
+    let pixels: Vec<u8> = (0..256 * 256).map(|i| (i / 256 % 5) as u8).collect();
+    let grle = dir.join("infoLayer_a.grle");
+    fs::write(&grle, encode_grle(&pixels, 256, 256)).unwrap();
+
+    // Without `decode` a .gdm output would mean GRLE -> GDM
+    let png_path = dir.join("decoded.gdm");
+    run_stdout(&["decode", "-q", grle.to_str().unwrap(), png_path.to_str().unwrap()]);
+    assert!(fs::read(&png_path).unwrap().starts_with(b"\x89PNG"));
+
+    // A PNG without the .png extension, encoded to GRLE by --format alone
+    let status = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+        .current_dir(&dir)
+        .args(["encode", "-q", "decoded.gdm", "--format", "grle"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(decode_grle(&fs::read(dir.join("decoded.grle")).unwrap()).unwrap().pixels, pixels);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_grleconvert")).args(["decode", png_path.to_str().unwrap()]).output().unwrap();
+    assert!(!output.status.success());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn no_color_management_tags_png_as_linear() {
+    let dir = scratch_dir("linear");
+    let grle = dir.join("infoLayer_a.grle");
+    let pixels: Vec<u8> = (0..256 * 256).map(|i| (i % 97) as u8).collect();
+    fs::write(&grle, encode_grle(&pixels, 256, 256)).unwrap();
+
+    let decode = |name: &str, extra: &[&str]| {
+        let out = dir.join(name);
+        let mut args = vec![grle.to_str().unwrap(), out.to_str().unwrap()];
+        args.extend_from_slice(extra);
+        run(&args);
+        let mut reader = png::Decoder::new(fs::File::open(&out).unwrap()).read_info().unwrap();
+        let tags = (reader.info().srgb.is_some(), reader.info().source_gamma.map(|g| g.into_scaled()));
+        let mut buf = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut buf).unwrap();
+        (tags, buf)
+    };
+    let (plain_tags, plain) = decode("plain.png", &[]);
+    let (tagged_tags, tagged) = decode("tagged.png", &["--no-color-management"]);
+    assert_eq!(plain_tags, (false, None));
+    assert_eq!(tagged_tags, (false, Some(100000)));
+    assert_eq!(tagged, plain);
+    assert_eq!(plain, pixels);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn info_prints_header_fields() {
+    let dir = scratch_dir("info");
+    let gdm = dir.join("densityMap_a.gdm");
+    let params = GdmParams::new(12).compression_channels(vec![8]);
+    let image = DecodedImage { width: 64, height: 64, values: vec![0x123; 64 * 64] };
+    fs::write(&gdm, encode_gdm(&image, &params).unwrap()).unwrap();
+
+    let stdout = run_stdout(&[gdm.to_str().unwrap(), "--info"]);
+    for line in ["Format: GDM (\"MDF)", "Version: 0", "Size: 64x64", "Channels: 12", "Compression ranges: 2", "Compression split: 8"] {
+        assert!(stdout.lines().any(|l| l == line), "{}\n{}", line, stdout);
+    }
+    assert!(!dir.join("densityMap_a.gdm.png").exists());
+
+    let grle = dir.join("infoLayer_b.grle");
+    fs::write(&grle, encode_grle(&vec![3u8; 256 * 512], 256, 512)).unwrap();
+    let stdout = run_stdout(&[grle.to_str().unwrap(), "--info"]);
+    assert!(stdout.starts_with("Format: GRLE\nVersion: 1\nSize: 256x512\nOffset 12 field: 256\n"), "{}", stdout);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn overwrite_if_newer_skips_up_to_date_outputs() {
+    let dir = scratch_dir("incremental");
+    let (a, b) = (dir.join("infoLayer_a.grle"), dir.join("infoLayer_b.grle"));
+    fs::write(&a, encode_grle(&vec![1u8; 256 * 256], 256, 256)).unwrap();
+    fs::write(&b, encode_grle(&vec![2u8; 256 * 256], 256, 256)).unwrap();
+    let batch = || run_stdout(&["batch", "-q", "--overwrite-if-newer", a.to_str().unwrap(), b.to_str().unwrap()]);
+
+    assert!(batch().ends_with("2 converted, 0 skipped, 0 failed\n"));
+    // Only the input written after its output is converted again
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    fs::write(&b, encode_grle(&vec![3u8; 256 * 256], 256, 256)).unwrap();
+    let stdout = batch();
+    assert!(stdout.contains(&format!("skip   {} -> ", a.display())), "{}", stdout);
+    assert!(stdout.ends_with("1 converted, 1 skipped, 0 failed\n"), "{}", stdout);
+
+    // Single conversions too: no --force needed for a stale output
+    let png_path = dir.join("infoLayer_b.grle.png");
+    let grle = dir.join("out.grle");
+    run(&[png_path.to_str().unwrap(), grle.to_str().unwrap()]);
+    fs::write(&grle, b"left alone").unwrap();
+    run(&[png_path.to_str().unwrap(), grle.to_str().unwrap(), "--overwrite-if-newer"]);
+    assert_eq!(fs::read(&grle).unwrap(), b"left alone");
+
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    write_png(&png_path, 256, 256, png::ColorType::Grayscale, png::BitDepth::Eight, &vec![4u8; 256 * 256]);
+    run(&[png_path.to_str().unwrap(), grle.to_str().unwrap(), "--overwrite-if-newer"]);
+    assert_eq!(decode_grle(&fs::read(&grle).unwrap()).unwrap().pixels, vec![4u8; 256 * 256]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn compare_pngs_counts_pixels_not_bytes() {
+    let dir = scratch_dir("compare");
+    let (a, b) = (dir.join("a.png"), dir.join("b.png"));
+    let mut samples = vec![0u8; 4 * 4 * 3];
+    write_png(&a, 4, 4, png::ColorType::Rgb, png::BitDepth::Eight, &samples);
+    // One pixel differs in all three channels, another in one
+    samples[5 * 3..6 * 3].copy_from_slice(&[9, 9, 9]);
+    samples[10 * 3 + 1] = 9;
+    write_png(&b, 4, 4, png::ColorType::Rgb, png::BitDepth::Eight, &samples);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_compare_pngs")).arg(&a).arg(&b).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout.contains("Total different pixels: 2 (tolerance 0)"), "{}", stdout);
+    assert!(stdout.contains("(1, 1) byte 2: 0 vs 9"), "{}", stdout);
+    assert!(stdout.contains("Non-zero pixels in orig: 2"), "{}", stdout);
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,96 @@
+//! Synthetic inputs for the codec tests and benchmarks, and helpers shared by
+//! the end-to-end tests.
+
+#![allow(dead_code)]
+
+/// Pixels in one GDM chunk (32x32)
+pub const CHUNK_PIXELS: usize = 32 * 32;
+
+/// Small deterministic xorshift generator, so inputs are stable across runs
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed.max(1))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 32) as u32
+    }
+
+    pub fn below(&mut self, n: u32) -> u32 {
+        self.next_u32() % n
+    }
+}
+
+// ============================================================================
+// GDM chunks
+// ============================================================================
+
+/// Chunk with a single value everywhere (bit depth 0)
+pub fn uniform_chunk(value: u16) -> Vec<u16> {
+    vec![value; CHUNK_PIXELS]
+}
+
+/// Chunk drawing from `values` at random (palette encoded when there are at most 4)
+pub fn palette_chunk(values: &[u16], seed: u64) -> Vec<u16> {
+    let mut rng = Rng::new(seed);
+    (0..CHUNK_PIXELS)
+        .map(|_| values[rng.below(values.len() as u32) as usize])
+        .collect()
+}
+
+/// Chunk with four distinct values (2-bit palette)
+pub fn four_value_chunk(seed: u64) -> Vec<u16> {
+    palette_chunk(&[0, 3, 7, 12], seed)
+}
+
+/// Chunk of uniformly random values below 2^bits
+pub fn random_chunk(bits: u32, seed: u64) -> Vec<u16> {
+    let mut rng = Rng::new(seed);
+    (0..CHUNK_PIXELS)
+        .map(|_| (rng.next_u32() & ((1u32 << bits) - 1)) as u16)
+        .collect()
+}
+
+// ============================================================================
+// GRLE images
+// ============================================================================
+
+/// Image with a single value everywhere (one long run)
+pub fn uniform_image(width: usize, height: usize, value: u8) -> Vec<u8> {
+    vec![value; width * height]
+}
+
+/// Field-like image: rectangular regions of a few values, so mostly long runs
+pub fn region_image(width: usize, height: usize, seed: u64) -> Vec<u8> {
+    let mut rng = Rng::new(seed);
+    let block = 64;
+    let cols = (width + block - 1) / block;
+    let rows = (height + block - 1) / block;
+    let ids: Vec<u8> = (0..cols * rows).map(|_| rng.below(8) as u8).collect();
+    (0..width * height)
+        .map(|i| ids[(i / width / block) * cols + (i % width) / block])
+        .collect()
+}
+
+/// Image of uniformly random bytes (no runs, worst case for RLE)
+pub fn random_image(width: usize, height: usize, seed: u64) -> Vec<u8> {
+    let mut rng = Rng::new(seed);
+    (0..width * height).map(|_| rng.next_u32() as u8).collect()
+}
+
+// ============================================================================
+// Scratch files
+// ============================================================================
+
+/// Fresh scratch directory for one test
+pub fn scratch_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("grleconvert_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
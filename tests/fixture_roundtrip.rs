@@ -0,0 +1,84 @@
+//! Byte-exact round trips of generated GDM and GRLE fixtures through the
+//! binary: decode to PNG in a scratch directory, encode back, compare.
+
+use std::fs;
+use std::process::Command;
+
+use grleconvert::{encode_gdm, encode_grle, DecodedImage, GdmParams};
+
+mod common;
+
+use common::{four_value_chunk, random_chunk, region_image, uniform_chunk, CHUNK_PIXELS};
+
+/// Run grleconvert quietly and assert it succeeded
+fn run(args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_grleconvert"))
+        .arg("-q")
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+/// Write `file` as `<name>.<ext>`, decode it to PNG and encode that back with
+/// `encode_args`; returns the re-encoded bytes
+fn through_png(name: &str, ext: &str, file: &[u8], encode_args: &[&str]) -> Vec<u8> {
+    let dir = std::env::temp_dir().join(format!("grleconvert_fixture_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let original = dir.join(format!("{}.{}", name, ext));
+    let png_path = dir.join(format!("{}.png", name));
+    let back = dir.join(format!("back.{}", ext));
+    fs::write(&original, file).unwrap();
+
+    run(&[original.to_str().unwrap(), png_path.to_str().unwrap()]);
+    let mut args = vec![png_path.to_str().unwrap(), back.to_str().unwrap()];
+    args.extend_from_slice(encode_args);
+    run(&args);
+
+    let bytes = fs::read(&back).unwrap();
+    let _ = fs::remove_dir_all(&dir);
+    bytes
+}
+
+/// Image from row-major 32x32 chunks
+fn image_from_chunks(chunks: &[Vec<u16>], chunks_per_dim: usize) -> DecodedImage {
+    let side = chunks_per_dim * 32;
+    let mut values = vec![0u32; side * side];
+    for (i, chunk) in chunks.iter().enumerate() {
+        let (base_y, base_x) = (i / chunks_per_dim * 32, i % chunks_per_dim * 32);
+        for (p, &v) in chunk.iter().enumerate().take(CHUNK_PIXELS) {
+            values[(base_y + p / 32) * side + base_x + p % 32] = v as u32;
+        }
+    }
+    DecodedImage { width: side, height: side, values }
+}
+
+#[test]
+fn grayscale_gdm() {
+    let chunks = [uniform_chunk(5), four_value_chunk(1), random_chunk(8, 2), random_chunk(3, 3)];
+    let file = encode_gdm(&image_from_chunks(&chunks, 2), &GdmParams::new(8)).unwrap();
+    assert_eq!(through_png("densityMap_grey", "gdm", &file, &["--channels", "8"]), file);
+}
+
+#[test]
+fn rgb_gdm_with_split() {
+    let chunks = [random_chunk(12, 4), uniform_chunk(0xABC), four_value_chunk(5), random_chunk(10, 6)];
+    let params = GdmParams::new(12).compression_channels(vec![8]);
+    let file = encode_gdm(&image_from_chunks(&chunks, 2), &params).unwrap();
+    assert_eq!(through_png("densityMap_rgb", "gdm", &file, &["--channels", "12", "--compress-at", "8"]), file);
+}
+
+#[test]
+fn uniform_chunk_gdm() {
+    let chunks: Vec<Vec<u16>> = (0..16).map(|i| uniform_chunk(i * 3)).collect();
+    let file = encode_gdm(&image_from_chunks(&chunks, 4), &GdmParams::new(6)).unwrap();
+    assert_eq!(through_png("densityMap_uniform", "gdm", &file, &["--channels", "6"]), file);
+}
+
+#[test]
+fn basic_grle() {
+    let file = encode_grle(&region_image(512, 256, 7), 512, 256);
+    assert_eq!(through_png("infoLayer_regions", "grle", &file, &[]), file);
+}
@@ -0,0 +1,41 @@
+#!/bin/sh
+# Regenerates the archives tests/zip.rs reads, with packers other than ours:
+#   infozip_streamed.zip  Info-ZIP writing to a pipe: data descriptors (flag
+#                         bit 3, zero sizes in the local headers) and its
+#                         UT/ux extra fields
+#   python_extra.zip      Python's zipfile on an unseekable stream: data
+#                         descriptors, a custom extra field, a stored and a
+#                         deflated entry, and an archive comment
+set -e
+out=$(cd "$(dirname "$0")" && pwd)
+work=$(mktemp -d)
+trap 'rm -r "$work"' EXIT
+cd "$work"
+
+mkdir maps
+printf '<?xml version="1.0" encoding="utf-8"?>\n<modDesc descVersion="79"/>\n' > modDesc.xml
+python3 -c 'import sys; sys.stdout.buffer.write(bytes(i * 7 // 300 % 256 for i in range(3000)))' > maps/densityMap_ground.gdm
+TZ=UTC touch -d '2024-02-29 12:34:56' modDesc.xml maps/densityMap_ground.gdm
+
+TZ=UTC zip -q - modDesc.xml maps/densityMap_ground.gdm | cat > "$out/infozip_streamed.zip"
+
+python3 - "$out/python_extra.zip" <<'EOF'
+import struct, sys, zipfile
+
+class Unseekable:
+    def __init__(self, f):
+        self.f = f
+    def write(self, b):
+        return self.f.write(b)
+    def flush(self):
+        self.f.flush()
+
+with open(sys.argv[1], "wb") as f, zipfile.ZipFile(Unseekable(f), "w") as z:
+    z.comment = b"packed for the grleconvert zip tests"
+    for name, method in [("modDesc.xml", zipfile.ZIP_STORED), ("maps/densityMap_ground.gdm", zipfile.ZIP_DEFLATED)]:
+        info = zipfile.ZipInfo(name, date_time=(2024, 2, 29, 12, 34, 56))
+        info.compress_type = method
+        # An unregistered header id with a 4-byte payload
+        info.extra = struct.pack("<HH", 0x6666, 4) + b"gren"
+        z.writestr(info, open(name, "rb").read())
+EOF
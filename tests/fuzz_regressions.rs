@@ -0,0 +1,49 @@
+//! Inputs the fuzz targets once crashed on, from `fuzz/regressions/<target>/`.
+//! Each is fed to the same entry point as its target and must come back as
+//! `Err`; a panic fails the test.
+
+use std::fs;
+use std::path::Path;
+
+use grleconvert::{decode_gdm, decode_grle, ConvertError};
+
+fn regressions(target: &str) -> Vec<(String, Vec<u8>)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/regressions").join(target);
+    let mut inputs: Vec<_> = fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("{}: {}", dir.display(), e))
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            (path.file_name().unwrap().to_string_lossy().into_owned(), fs::read(&path).unwrap())
+        })
+        .collect();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "no inputs in {}", dir.display());
+    inputs
+}
+
+#[test]
+fn decode_gdm_regressions_are_rejected() {
+    for (name, data) in regressions("decode_gdm") {
+        assert!(decode_gdm(&data).is_err(), "{} decoded", name);
+    }
+}
+
+#[test]
+fn decode_grle_regressions_are_rejected() {
+    for (name, data) in regressions("decode_grle") {
+        assert!(decode_grle(&data).is_err(), "{} decoded", name);
+    }
+}
+
+#[test]
+fn gdm_header_limits_are_invalid_format() {
+    let message = |name: &str| {
+        let data = fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/regressions/decode_gdm").join(name)).unwrap();
+        match decode_gdm(&data) {
+            Err(ConvertError::InvalidFormat(msg)) => msg,
+            other => panic!("{}: expected InvalidFormat, got {:?}", name, other.err()),
+        }
+    };
+    assert_eq!(message("chunk-log2-6"), "GDM header declares chunk_log2 6; only 5 (32x32 chunks) is supported");
+    assert_eq!(message("48-channels"), "GDM header declares 48 channels, more than 32");
+}
@@ -0,0 +1,450 @@
+//! GDM block encode/decode round trips across palette and raw bit depths.
+
+use std::collections::BTreeSet;
+
+use proptest::prelude::*;
+
+use grleconvert::{
+    decode_gdm, decode_gdm_block, decode_gdm_from_reader, encode_gdm, encode_gdm_block, encode_gdm_to_writer, index_gdm,
+    gdm_decoded_len, parse_gdm_header, ConvertError, DecodedImage, GdmHeader, GdmHeaderFields, GdmParams, GdmVariant, DEFAULT_MAX_DIMENSION,
+};
+
+mod common;
+
+use common::{four_value_chunk, palette_chunk, random_chunk, uniform_chunk, CHUNK_PIXELS};
+
+fn assert_block_roundtrip(chunk: &[u16]) {
+    let block = encode_gdm_block(chunk, 32);
+    let (decoded, size) = decode_gdm_block(&block, 0, 32);
+    assert_eq!(size, block.len(), "block size");
+    if let Some(i) = decoded.iter().zip(chunk).position(|(a, b)| a != b) {
+        panic!(
+            "bit depth {}: first mismatch at {} (got {}, expected {})",
+            block[0], i, decoded[i], chunk[i]
+        );
+    }
+}
+
+/// Chunk using every value in `values` at least once
+fn chunk_with_values(values: &[u16], picks: &[usize]) -> Vec<u16> {
+    (0..CHUNK_PIXELS)
+        .map(|i| if i < values.len() { values[i] } else { values[picks[i] % values.len()] })
+        .collect()
+}
+
+// ============================================================================
+// Fixed inputs
+// ============================================================================
+
+#[test]
+fn uniform_chunks() {
+    for &value in &[0u16, 1, 255, 256, u16::MAX] {
+        assert_block_roundtrip(&uniform_chunk(value));
+    }
+}
+
+#[test]
+fn palette_chunks() {
+    assert_block_roundtrip(&palette_chunk(&[0, 1], 1));
+    assert_block_roundtrip(&palette_chunk(&[300, 60000, 7], 2));
+    assert_block_roundtrip(&four_value_chunk(3));
+}
+
+#[test]
+fn wide_palettes_when_smaller_than_raw() {
+    // n values of magnitude >= 2^15: a palette of ceil(log2 n) bits beats 16-bit raw
+    for (n, palette_bits) in [(5usize, 3u8), (8, 3), (9, 4), (100, 7), (255, 8)] {
+        let values: Vec<u16> = (0..n).map(|i| 40000 + 97 * i as u16).collect();
+        let picks: Vec<usize> = (0..CHUNK_PIXELS).map(|i| i * 7 + i / 3).collect();
+        let chunk = chunk_with_values(&values, &picks);
+        let block = encode_gdm_block(&chunk, 32);
+        assert_eq!((block[0], block[1] as usize), (palette_bits, n), "{} values", n);
+        assert_block_roundtrip(&chunk);
+    }
+
+    // Small values stay raw: a 5-entry palette at 3 bits is no smaller than 3-bit raw
+    let chunk = chunk_with_values(&[0, 1, 2, 3, 4], &[3; CHUNK_PIXELS]);
+    let block = encode_gdm_block(&chunk, 32);
+    assert_eq!((block[0], block[1]), (3, 0));
+    assert_block_roundtrip(&chunk);
+}
+
+#[test]
+fn raw_chunks_every_bit_depth() {
+    for bits in 3..=16 {
+        let mut chunk = random_chunk(bits, bits as u64);
+        // Pin the maximum so the encoder picks exactly `bits`
+        chunk[0] = ((1u32 << bits) - 1) as u16;
+        let block = encode_gdm_block(&chunk, 32);
+        assert_eq!(block[0] as u32, bits);
+        assert_block_roundtrip(&chunk);
+    }
+}
+
+#[test]
+fn full_file_roundtrip() {
+    let mut rng = common::Rng::new(9);
+    let image = DecodedImage {
+        width: 64,
+        height: 64,
+        values: (0..64 * 64).map(|_| rng.next_u32() & 0xfff).collect(),
+    };
+    let params = GdmParams {
+        num_channels: 12,
+        compression_channels: vec![4, 8],
+        ..Default::default()
+    };
+    let decoded = decode_gdm(&encode_gdm(&image, &params).unwrap()).unwrap();
+    assert_eq!(decoded.num_channels, 12);
+    assert_eq!(decoded.num_compression_ranges, 3);
+    assert_eq!(decoded.image.values, image.values);
+}
+
+#[test]
+fn single_range_with_stored_boundary_byte() {
+    let mut rng = common::Rng::new(11);
+    let image = DecodedImage {
+        width: 64,
+        height: 64,
+        values: (0..64 * 64).map(|_| rng.next_u32() & 0x3f).collect(),
+    };
+    let params = GdmParams {
+        num_channels: 6,
+        ..Default::default()
+    };
+    let mut data = encode_gdm(&image, &params).unwrap();
+    // Reserved boundary byte after the 16-byte header
+    data.insert(16, 0);
+
+    let decoded = decode_gdm(&data).unwrap();
+    assert_eq!(decoded.num_compression_ranges, 1);
+    assert_eq!(decoded.bytes_consumed, data.len());
+    assert_eq!(decoded.image.values, image.values);
+}
+
+#[test]
+fn splice_one_chunk_using_index() {
+    let mut rng = common::Rng::new(5);
+    let image = DecodedImage {
+        width: 64,
+        height: 64,
+        values: (0..64 * 64).map(|_| rng.next_u32() & 0xff).collect(),
+    };
+    let params = GdmParams {
+        num_channels: 8,
+        ..Default::default()
+    };
+    let data = encode_gdm(&image, &params).unwrap();
+
+    let index = index_gdm(&data).unwrap();
+    assert_eq!(index.chunks_per_dim, 2);
+    assert_eq!(index.blocks.len(), 4);
+    let last = index.blocks.last().unwrap();
+    assert_eq!(last.offset + last.len, data.len());
+    assert!(index.chunk(2, 0).is_none());
+
+    // Replace chunk (row 1, col 0) with a uniform block
+    let block = index.chunk(1, 0).unwrap()[0];
+    let mut spliced = data[..block.offset].to_vec();
+    spliced.extend(encode_gdm_block(&uniform_chunk(42), 32));
+    spliced.extend_from_slice(&data[block.offset + block.len..]);
+
+    let decoded = decode_gdm(&spliced).unwrap().image.values;
+    for y in 0..64 {
+        for x in 0..64 {
+            let expected = if y >= 32 && x < 32 { 42 } else { image.values[y * 64 + x] };
+            assert_eq!(decoded[y * 64 + x], expected, "pixel {},{}", x, y);
+        }
+    }
+}
+
+#[test]
+fn same_image_same_file() {
+    let mut rng = common::Rng::new(9);
+    let values: Vec<u32> = (0..128 * 128).map(|i| if i % 3 == 0 { rng.next_u32() & 0x3ff } else { (i / 640) as u32 }).collect();
+    let params = GdmParams {
+        num_channels: 10,
+        compression_channels: vec![8],
+        ..Default::default()
+    };
+    let first = encode_gdm(&DecodedImage { width: 128, height: 128, values: values.clone() }, &params).unwrap();
+    let second = encode_gdm(&DecodedImage { width: 128, height: 128, values }, &params).unwrap();
+    assert_eq!(first, second);
+}
+
+#[test]
+fn illegal_dimensions_suggest_a_size() {
+    let params = GdmParams { num_channels: 8, ..Default::default() };
+    let error = |width: usize, height: usize| {
+        let image = DecodedImage { width, height, values: vec![0; width * height] };
+        encode_gdm(&image, &params).unwrap_err().to_string()
+    };
+    assert!(error(600, 600).ends_with("must have a power-of-two side, got 600x600; nearest legal size: 512x512 (or 1024x1024)"));
+    assert!(error(900, 900).ends_with("got 900x900; nearest legal size: 1024x1024 (or 512x512)"));
+    assert!(error(1024, 512).ends_with("must be square, got 1024x512; nearest legal size: 1024x1024"));
+    assert!(error(16, 16).ends_with("must be at least 32x32, got 16x16; nearest legal size: 32x32"));
+}
+
+#[test]
+fn max_bpp_is_the_deepest_block() {
+    // 64 distinct values in the one chunk need a 6-bit palette
+    let image = DecodedImage { width: 32, height: 32, values: (0..1024).map(|i| i % 64).collect() };
+    let params = GdmParams { num_channels: 8, ..Default::default() };
+    let file = encode_gdm(&image, &params).unwrap();
+    assert_eq!(file[16], 6, "block bit depth");
+    assert_eq!(file[10], 6, "max_bpp");
+
+    let params = GdmParams { header: GdmHeaderFields { max_bpp: Some(2), ..Default::default() }, ..params };
+    assert_eq!(encode_gdm(&image, &params).unwrap()[10], 2, "max_bpp from the header fields");
+
+    // A floor (a template's byte) only raises the computed depth
+    let floor = |max_bpp_floor| {
+        let header = GdmHeaderFields { max_bpp_floor, ..Default::default() };
+        encode_gdm(&image, &GdmParams { header, ..params.clone() }).unwrap()[10]
+    };
+    assert_eq!(floor(2), 6);
+    assert_eq!(floor(8), 8);
+}
+
+#[test]
+fn decreasing_compression_ranges_are_rejected() {
+    let image = DecodedImage { width: 32, height: 32, values: vec![0; 1024] };
+    let mut file = encode_gdm(&image, &GdmParams::new(10).compression_channels(vec![4, 8])).unwrap();
+    // Boundary bytes follow the 16-byte header
+    file[16] = 9;
+    match decode_gdm(&file) {
+        Err(ConvertError::BadCompressionRanges { boundaries, num_channels }) => {
+            assert_eq!((boundaries, num_channels), (vec![0, 9, 8, 10], 10));
+        }
+        other => panic!("expected BadCompressionRanges, got {:?}", other.err()),
+    }
+    file[16] = 4;
+    file[17] = 12;
+    assert!(matches!(decode_gdm(&file), Err(ConvertError::BadCompressionRanges { .. })));
+}
+
+#[test]
+fn compression_ranges_wider_than_a_block_are_rejected() {
+    // 32x32 !MDF file, one uniform block per range
+    let file = |channels: u8, splits: &[u8]| {
+        let mut file = vec![b'!', b'M', b'D', b'F', 0, 5, 1, channels, splits.len() as u8 + 1];
+        file.extend_from_slice(splits);
+        for _ in 0..=splits.len() {
+            file.extend([0, 1, 1, 0]);
+        }
+        file
+    };
+    match decode_gdm(&file(20, &[])) {
+        Err(ConvertError::BadCompressionRanges { boundaries, num_channels }) => {
+            assert_eq!((boundaries, num_channels), (vec![0, 20], 20));
+        }
+        other => panic!("expected BadCompressionRanges, got {:?}", other.err()),
+    }
+    assert!(matches!(decode_gdm(&file(32, &[8])), Err(ConvertError::BadCompressionRanges { .. })));
+
+    let decoded = decode_gdm(&file(32, &[16])).unwrap();
+    assert_eq!(decoded.image.values[0], 1 | 1 << 16);
+}
+
+#[test]
+fn more_than_32_channels_are_rejected() {
+    let image = DecodedImage { width: 32, height: 32, values: vec![0; 1024] };
+    let params = GdmParams::new(48).compression_channels(vec![16, 32]);
+    assert!(matches!(encode_gdm(&image, &params), Err(ConvertError::InvalidParams(_))));
+    assert!(encode_gdm(&image, &GdmParams::new(32).compression_channels(vec![16])).is_ok());
+}
+
+#[test]
+fn oversized_header_is_rejected_before_decoding() {
+    let image = DecodedImage { width: 32, height: 32, values: vec![0; 1024] };
+    let mut file = encode_gdm(&image, &GdmParams::new(8)).unwrap();
+    // dim_log2 11 declares 65536x65536
+    file[8] = 11;
+    match decode_gdm(&file) {
+        Err(ConvertError::TooLarge { width: 65536, height: 65536, max }) => assert_eq!(max, DEFAULT_MAX_DIMENSION),
+        other => panic!("expected TooLarge, got {:?}", other.err()),
+    }
+    // A dim_log2 past the width of usize must not overflow either
+    file[8] = 200;
+    assert!(matches!(index_gdm(&file), Err(ConvertError::TooLarge { .. })));
+}
+
+#[test]
+fn short_input_is_too_small() {
+    let too_small = |data: &[u8]| match decode_gdm(data) {
+        Err(ConvertError::TooSmall { need, got }) => (need, got),
+        other => panic!("expected TooSmall, got {:?}", other.err()),
+    };
+    assert_eq!(too_small(b""), (9, 0));
+    assert_eq!(too_small(b"\"MDF\0\0\0\0\x05"), (16, 9));
+    assert_eq!(too_small(b"!MDF\x05"), (9, 5));
+    // Three ranges need two boundary bytes after the 9-byte header
+    assert_eq!(too_small(b"!MDF\x05\x05\x02\x0c\x03\x04"), (11, 10));
+    assert!(index_gdm(b"").is_err());
+}
+
+#[test]
+fn corrupt_palette_count_names_the_chunk() {
+    // Four values per chunk: 2-bit blocks with a 4-entry palette
+    let values: Vec<u32> = (0..64 * 64).map(|i| (i % 4) as u32 * 10).collect();
+    let image = DecodedImage { width: 64, height: 64, values };
+    let data = encode_gdm(&image, &GdmParams::new(8)).unwrap();
+    let blocks = index_gdm(&data).unwrap().blocks;
+    assert_eq!(&data[blocks[2].offset..blocks[2].offset + 2], &[2, 4]);
+
+    let error = |data: &[u8]| match decode_gdm(data) {
+        Err(ConvertError::InvalidFormat(msg)) => msg,
+        other => panic!("expected InvalidFormat, got {:?}", other.map(|g| g.bytes_consumed)),
+    };
+
+    let mut corrupt = data.clone();
+    corrupt[blocks[2].offset + 1] = 200;
+    assert_eq!(error(&corrupt), "Block of chunk 2 declares 200 palette entries, more than its 2-bit indices can address");
+    assert!(index_gdm(&corrupt).is_err());
+
+    let truncated = &data[..blocks[3].offset + 4];
+    assert_eq!(error(truncated), "Palette of chunk 3 (4 entries) runs past the end of the data");
+}
+
+#[test]
+fn short_header_max_bpp_is_reported() {
+    // 32x32 !MDF file with one uniform block of value 1
+    let mut file = b"!MDF\x00\x05\x01\x01\x01".to_vec();
+    file.extend([0, 1, 1, 0]);
+    let decoded = decode_gdm(&file).unwrap();
+    assert_eq!((decoded.max_bpp, decoded.deepest_block_bits), (1, 0));
+    assert_eq!(decoded.image.values, vec![1; 32 * 32]);
+
+    // Offset 6 does not change how the blocks are read
+    file[6] = 200;
+    let decoded = decode_gdm(&file).unwrap();
+    assert_eq!(decoded.max_bpp, 200);
+    assert_eq!(decoded.image.values, vec![1; 32 * 32]);
+}
+
+#[test]
+fn header_parses_without_decoding() {
+    let image = DecodedImage { width: 64, height: 64, values: (0..64 * 64).map(|i| i as u32 & 0x3FF).collect() };
+    let header = GdmHeaderFields { reserved: [7, 9], ..Default::default() };
+    let params = GdmParams::new(10).compression_channels(vec![4, 8]).header(header);
+    let file = encode_gdm(&image, &params).unwrap();
+    assert_eq!(
+        parse_gdm_header(&file).unwrap(),
+        GdmHeader {
+            variant: GdmVariant::Long,
+            version: Some(0),
+            dimension: 64,
+            chunk_size: 32,
+            max_bpp: file[10],
+            num_channels: 10,
+            num_compression_ranges: 3,
+            compression_channels: vec![4, 8],
+            type_index_channels: 0,
+            reserved: [7, 9],
+        }
+    );
+
+    // The block stream is not needed, and !MDF has no version
+    let header = GdmHeader::parse(b"!MDF\x01\x05\x04\x0c\x02\x08").unwrap();
+    assert_eq!((header.variant, header.version, header.dimension), (GdmVariant::Short, None, 64));
+    assert_eq!(gdm_decoded_len(&header), 64 * 64 * 4);
+    assert_eq!((header.num_channels, header.compression_channels), (12, vec![8]));
+    assert!(matches!(GdmHeader::parse(b"!MDF\x01\x05\x04\x0c\x02"), Err(ConvertError::TooSmall { need: 10, .. })));
+
+    // The decoded size is known up front, to the byte
+    let decoded = decode_gdm(&file).unwrap();
+    assert_eq!(gdm_decoded_len(&parse_gdm_header(&file).unwrap()), decoded.image.values.len() * 4);
+}
+
+/// Reader that fails after handing out `left` bytes, like a truncated archive entry
+struct FailingReader {
+    data: Vec<u8>,
+    left: usize,
+}
+
+impl std::io::Read for FailingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.left == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "entry cut short"));
+        }
+        let n = buf.len().min(self.left);
+        let start = self.data.len() - self.left;
+        buf[..n].copy_from_slice(&self.data[start..start + n]);
+        self.left -= n;
+        Ok(n)
+    }
+}
+
+#[test]
+fn streams_match_the_buffer_functions() {
+    let values: Vec<u32> = (0..64 * 64).map(|i| (i % 61) as u32).collect();
+    let image = DecodedImage { width: 64, height: 64, values };
+    let params = GdmParams::new(6);
+    let mut file = Vec::new();
+    let written = encode_gdm_to_writer(&mut file, &image, &params).unwrap();
+    assert_eq!(written, file.len());
+    assert_eq!(file, encode_gdm(&image, &params).unwrap());
+
+    assert_eq!(decode_gdm_from_reader(file.as_slice()).unwrap().image.values, image.values);
+    let cut = FailingReader { left: file.len() / 2, data: file };
+    assert!(matches!(decode_gdm_from_reader(cut), Err(ConvertError::Io(_))));
+}
+
+// ============================================================================
+// Properties
+// ============================================================================
+
+fn chunk_with_unique(n: usize) -> impl Strategy<Value = Vec<u16>> {
+    (
+        prop::collection::btree_set(any::<u16>(), n),
+        prop::collection::vec(any::<usize>(), CHUNK_PIXELS),
+    )
+        .prop_map(|(values, picks): (BTreeSet<u16>, Vec<usize>)| {
+            let values: Vec<u16> = values.into_iter().collect();
+            chunk_with_values(&values, &picks)
+        })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(128))]
+
+    #[test]
+    fn one_value(chunk in chunk_with_unique(1)) {
+        assert_block_roundtrip(&chunk);
+    }
+
+    #[test]
+    fn two_values(chunk in chunk_with_unique(2)) {
+        assert_block_roundtrip(&chunk);
+    }
+
+    #[test]
+    fn four_values(chunk in chunk_with_unique(4)) {
+        assert_block_roundtrip(&chunk);
+    }
+
+    #[test]
+    fn sixteen_values(chunk in chunk_with_unique(16)) {
+        assert_block_roundtrip(&chunk);
+    }
+
+    #[test]
+    fn many_values(chunk in chunk_with_unique(256)) {
+        assert_block_roundtrip(&chunk);
+    }
+
+    #[test]
+    fn small_values(chunk in prop::collection::vec(0u16..256, CHUNK_PIXELS)) {
+        assert_block_roundtrip(&chunk);
+    }
+
+    #[test]
+    fn full_u16_range(chunk in prop::collection::vec(any::<u16>(), CHUNK_PIXELS)) {
+        assert_block_roundtrip(&chunk);
+    }
+
+    #[test]
+    fn same_chunk_same_bytes(chunk in chunk_with_unique(16)) {
+        prop_assert_eq!(encode_gdm_block(&chunk, 32), encode_gdm_block(&chunk.clone(), 32));
+    }
+}
@@ -0,0 +1,367 @@
+//! GRLE encode/decode round trips, with an emphasis on the transition/backup
+//! handling of single pixels in `decode_grle_rle`.
+
+use proptest::prelude::*;
+
+use grleconvert::{
+    decode_grle, decode_grle_any_version, decode_grle_from_reader, decode_grle_rle, decode_grle_rle_counted, encode_grle,
+    encode_grle_rle, encode_grle_to_writer, encode_grle_with_params, grle_decoded_len, ConvertError, GrleHeader, GrleHeaderFields,
+    GrleParams,
+};
+
+mod common;
+
+use common::*;
+
+fn assert_roundtrip(pixels: &[u8]) {
+    let stream = encode_grle_rle(pixels);
+    let decoded = decode_grle_rle(&stream, pixels.len());
+    if let Some(i) = decoded.iter().zip(pixels).position(|(a, b)| a != b) {
+        panic!(
+            "{} pixels: first mismatch at {} (got {}, expected {})",
+            pixels.len(), i, decoded[i], pixels[i]
+        );
+    }
+}
+
+/// Pixels built from (value, run length) pairs, repeated until `len` is filled
+fn from_runs(runs: &[(u8, usize)], len: usize) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity(len);
+    for &(value, run) in runs.iter().cycle() {
+        let n = run.min(len - pixels.len());
+        pixels.extend(std::iter::repeat(value).take(n));
+        if pixels.len() == len {
+            break;
+        }
+    }
+    pixels
+}
+
+// ============================================================================
+// Known-tricky patterns
+// ============================================================================
+
+#[test]
+fn uniform_images() {
+    for &(w, h) in &[(256, 256), (512, 256), (1024, 1024), (2048, 2048)] {
+        for &value in &[0u8, 1, 255] {
+            assert_roundtrip(&uniform_image(w, h, value));
+        }
+    }
+}
+
+#[test]
+fn uniform_image_ending_in_a_single_pixel() {
+    // The last pixel has no byte to pair with unless the encoder closes the stream
+    for &value in &[0u8, 1, 255] {
+        let mut pixels = uniform_image(2048, 2048, value);
+        *pixels.last_mut().unwrap() = value.wrapping_add(7);
+        assert_roundtrip(&pixels);
+    }
+}
+
+#[test]
+fn checkerboard() {
+    for &(w, h) in &[(256, 256), (512, 768)] {
+        let pixels: Vec<u8> = (0..w * h).map(|i| (((i % w) + (i / w)) % 2) as u8).collect();
+        assert_roundtrip(&pixels);
+    }
+}
+
+#[test]
+fn all_distinct_neighbours() {
+    let pixels: Vec<u8> = (0..256 * 256).map(|i| i as u8).collect();
+    assert_roundtrip(&pixels);
+}
+
+#[test]
+fn single_pixel_at_either_end() {
+    let mut pixels = uniform_image(256, 256, 0);
+    pixels[0] = 7;
+    assert_roundtrip(&pixels);
+
+    let mut pixels = uniform_image(256, 256, 0);
+    *pixels.last_mut().unwrap() = 7;
+    assert_roundtrip(&pixels);
+}
+
+#[test]
+fn runs_around_count_byte_boundaries() {
+    // Run counts are stored as len - 2 with 0xff continuation bytes
+    for &run in &[2usize, 3, 256, 257, 258, 511, 512, 513] {
+        assert_roundtrip(&from_runs(&[(4, run), (9, 1)], 256 * 256));
+        assert_roundtrip(&from_runs(&[(4, 1), (9, run)], 256 * 256));
+    }
+}
+
+#[test]
+fn short_streams() {
+    assert_roundtrip(&[]);
+    assert_roundtrip(&[5]);
+    assert_roundtrip(&[1, 2]);
+    assert_roundtrip(&[1, 1]);
+    assert_roundtrip(&[1, 2, 2]);
+    assert_roundtrip(&[1, 1, 2]);
+    assert_roundtrip(&[1, 2, 1]);
+}
+
+#[test]
+fn tiny_images_have_exact_streams() {
+    // A lone pixel is closed by one different byte, a run by its count byte
+    assert_eq!(encode_grle_rle(&[5]), [0, 5, 6]);
+    assert_eq!(encode_grle_rle(&[255]), [0, 255, 0]);
+    assert_eq!(encode_grle_rle(&[3, 3]), [0, 3, 3, 0]);
+    assert_eq!(encode_grle_rle(&[3, 4]), [0, 3, 4, 5]);
+    assert_eq!(encode_grle_rle(&[7, 7, 7]), [0, 7, 7, 1]);
+    assert_eq!(encode_grle_rle(&[7, 7, 2]), [0, 7, 7, 0, 2, 3]);
+    assert_eq!(encode_grle_rle(&[2, 7, 7]), [0, 2, 7, 7, 0]);
+    assert_eq!(encode_grle_rle(&[1, 2, 1]), [0, 1, 2, 1, 2]);
+
+    // Every 1-, 2- and 3-pixel image over values that include the 255 wrap
+    let values = [0u8, 1, 254, 255];
+    for len in 1..=3u32 {
+        for code in 0..values.len().pow(len) {
+            let pixels: Vec<u8> = (0..len).map(|k| values[code / values.len().pow(k) % values.len()]).collect();
+            assert_roundtrip(&pixels);
+        }
+    }
+}
+
+/// Whether every run in the stream has its count byte. The decoder tolerates a
+/// missing final count, but the encoder never relies on that.
+fn runs_have_counts(stream: &[u8]) -> bool {
+    let mut i = 1;
+    while i + 1 < stream.len() {
+        if stream[i] == stream[i + 1] {
+            i += 2;
+            while i < stream.len() && stream[i] == 0xff {
+                i += 1;
+            }
+            if i == stream.len() {
+                return false;
+            }
+        }
+        i += 1;
+    }
+    true
+}
+
+#[test]
+fn no_shorter_stream_exists() {
+    // Every pixel sequence up to 5 long over {1, 2}: no flagged stream over the
+    // bytes 0-4 that is shorter than the encoder's decodes to the same pixels
+    for len in 1..=5u32 {
+        for bits in 0..1u32 << len {
+            let pixels: Vec<u8> = (0..len).map(|i| 1 + (bits >> i & 1) as u8).collect();
+            let encoded = encode_grle_rle(&pixels).len();
+            for stream_len in 2..encoded {
+                for mut code in 0..5usize.pow(stream_len as u32 - 1) {
+                    let mut stream = vec![0u8];
+                    for _ in 1..stream_len {
+                        stream.push((code % 5) as u8);
+                        code /= 5;
+                    }
+                    if runs_have_counts(&stream) {
+                        assert_ne!(decode_grle_rle(&stream, pixels.len()), pixels, "{:?} beats the encoder", stream);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Length of the shortest stream that decodes every pixel, over all ways of
+/// cutting the image into transitions and runs. A transition costs 1 byte and
+/// needs a different byte after it; a run of `k` costs 3 + (k - 2) / 255.
+fn cheapest_stream_len(pixels: &[u8]) -> usize {
+    let n = pixels.len();
+    let mut cost = vec![usize::MAX; n + 1];
+    cost[n] = 0;
+    for i in (0..n).rev() {
+        if i + 1 == n {
+            cost[i] = 2; // the pixel and the closing byte
+        } else if pixels[i + 1] != pixels[i] {
+            cost[i] = 1 + cost[i + 1];
+        }
+        let mut k = 2;
+        while i + k <= n && pixels[i + k - 1] == pixels[i] {
+            cost[i] = cost[i].min(3 + (k - 2) / 255 + cost[i + k]);
+            k += 1;
+        }
+    }
+    1 + cost[0] // the flag byte
+}
+
+#[test]
+fn length_two_runs_cost_no_more_than_any_split() {
+    // Pairs of equal pixels packed between singles, where a cost-based choice
+    // between a short run and singles would matter if it could
+    let mut rng = common::Rng::new(7);
+    let mut pixels = Vec::new();
+    while pixels.len() < 256 * 256 {
+        let value = rng.below(4) as u8;
+        let len = [1, 2, 2, 2, 3][rng.below(5) as usize];
+        pixels.extend(std::iter::repeat(value).take(len));
+    }
+    pixels.truncate(256 * 256);
+    assert_roundtrip(&pixels);
+    assert_eq!(encode_grle_rle(&pixels).len(), cheapest_stream_len(&pixels));
+
+    for pixels in [
+        from_runs(&[(1, 2), (2, 1)], 3001),
+        from_runs(&[(1, 2), (2, 2)], 3000),
+        from_runs(&[(1, 257), (2, 2), (1, 256), (2, 1), (1, 512)], 5000),
+        random_image(256, 256, 3),
+    ] {
+        assert_eq!(encode_grle_rle(&pixels).len(), cheapest_stream_len(&pixels));
+    }
+}
+
+#[test]
+fn region_and_random_images() {
+    for seed in 1..4 {
+        assert_roundtrip(&region_image(1024, 1024, seed));
+        assert_roundtrip(&random_image(512, 512, seed));
+    }
+}
+
+#[test]
+fn stream_without_leading_flag() {
+    // Without the 0x00 flag the first byte is the first pixel and must not be dropped
+    for pixels in [vec![5u8, 5, 5, 9, 1, 1], vec![200, 3, 3, 3, 3], vec![7, 8, 9]] {
+        let stream = encode_grle_rle(&pixels);
+        assert_eq!(stream[0], 0x00);
+        assert_eq!(decode_grle_rle(&stream[1..], pixels.len()), pixels);
+        assert_eq!(decode_grle_rle(&stream, pixels.len()), pixels);
+    }
+}
+
+#[test]
+fn unknown_version_is_rejected() {
+    let pixels = region_image(256, 256, 7);
+    let mut file = encode_grle(&pixels, 256, 256);
+    file[4] = 2;
+
+    match decode_grle(&file) {
+        Err(ConvertError::UnsupportedVersion { format: "GRLE", version: 2 }) => {}
+        other => panic!("expected UnsupportedVersion, got {:?}", other.map(|g| g.version)),
+    }
+    let decoded = decode_grle_any_version(&file).unwrap();
+    assert_eq!(decoded.version, 2);
+    assert_eq!(decoded.pixels, pixels);
+}
+
+#[test]
+fn params_header_is_written() {
+    let params = GrleParams::new().header(GrleHeaderFields { version: 1, unknown: 512 });
+    let file = encode_grle_with_params(&[5; 256 * 256], 256, 256, &params);
+    assert_eq!(&file[12..14], &512u16.to_le_bytes());
+    assert_eq!(decode_grle(&file).unwrap().pixels, vec![5; 256 * 256]);
+}
+
+#[test]
+fn header_fields_are_parsed() {
+    let file = encode_grle(&region_image(512, 256, 3), 512, 256);
+    let header = GrleHeader::parse(&file).unwrap();
+    assert_eq!((header.version, header.width, header.height, header.unknown), (1, 512, 256, 256));
+    assert_eq!(header.declared_len, file.len() - 20);
+    assert_eq!(grle_decoded_len(&header, 8), decode_grle(&file).unwrap().pixels.len());
+    // Wider info layers take several bytes per pixel
+    assert_eq!(grle_decoded_len(&header, 12), 512 * 256 * 2);
+}
+
+#[test]
+fn implausibly_large_header_is_rejected() {
+    let mut file = encode_grle(&[7; 256 * 256], 256, 256);
+    // 65535x65535 units would be 2^32 pixels from a stream of a few hundred bytes
+    file[6..8].copy_from_slice(&u16::MAX.to_le_bytes());
+    file[10..12].copy_from_slice(&u16::MAX.to_le_bytes());
+    match decode_grle(&file) {
+        Err(ConvertError::InvalidFormat(msg)) => assert!(msg.contains("more pixels than its"), "{}", msg),
+        other => panic!("expected InvalidFormat, got {:?}", other.map(|g| g.width)),
+    }
+}
+
+#[test]
+fn short_input_is_too_small() {
+    for len in [0, 3, 6, 19] {
+        let file = &encode_grle(&[0; 256 * 256], 256, 256)[..len];
+        match decode_grle(file) {
+            Err(ConvertError::TooSmall { need: 20, got }) => assert_eq!(got, len),
+            other => panic!("{} bytes: expected TooSmall, got {:?}", len, other.map(|g| g.version)),
+        }
+    }
+}
+
+#[test]
+fn truncated_stream_reports_decoded_pixels() {
+    let pixels: Vec<u8> = (0..256 * 256).map(|i| (i % 2) as u8).collect();
+    let file = encode_grle(&pixels, 256, 256);
+    assert_eq!(decode_grle(&file).unwrap().decoded_pixels, 256 * 256);
+
+    // Alternating values take one stream byte each, after the 0x00 flag; the
+    // last byte is only emitted once the next one is seen
+    let truncated = decode_grle(&file[..20 + 1 + 1000]).unwrap();
+    assert_eq!(truncated.decoded_pixels, 999);
+    assert_eq!(truncated.pixels.len(), 256 * 256);
+    assert_eq!(decode_grle_rle_counted(&file[20..20 + 1 + 1000], 256 * 256).1, 999);
+}
+
+#[test]
+fn streams_match_the_buffer_functions() {
+    let pixels: Vec<u8> = (0..256 * 256).map(|i| (i / 300) as u8).collect();
+    let mut file = Vec::new();
+    let written = encode_grle_to_writer(&mut file, &pixels, 256, 256, &GrleParams::new()).unwrap();
+    assert_eq!(written, file.len());
+    assert_eq!(file, encode_grle(&pixels, 256, 256));
+
+    let decoded = decode_grle_from_reader(file.as_slice(), &GrleParams::new()).unwrap();
+    assert_eq!(decoded.pixels, pixels);
+}
+
+// ============================================================================
+// Properties
+// ============================================================================
+
+fn dimensions() -> impl Strategy<Value = (usize, usize)> {
+    (1usize..=2, 1usize..=2).prop_map(|(w, h)| (w * 256, h * 256))
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn random_bytes_roundtrip(
+        ((w, h), pixels) in dimensions().prop_flat_map(|(w, h)| {
+            (Just((w, h)), prop::collection::vec(any::<u8>(), w * h))
+        })
+    ) {
+        prop_assert_eq!(decode_grle_rle(&encode_grle_rle(&pixels), w * h), pixels);
+    }
+
+    #[test]
+    fn random_runs_roundtrip(
+        (w, h) in dimensions(),
+        runs in prop::collection::vec((0u8..4, 1usize..600), 1..64),
+    ) {
+        let pixels = from_runs(&runs, w * h);
+        prop_assert_eq!(decode_grle_rle(&encode_grle_rle(&pixels), w * h), pixels);
+    }
+
+    #[test]
+    fn mostly_single_pixels_roundtrip(pixels in prop::collection::vec(0u8..3, 0..2048)) {
+        prop_assert_eq!(decode_grle_rle(&encode_grle_rle(&pixels), pixels.len()), pixels);
+    }
+
+    #[test]
+    fn file_roundtrip(
+        (w, h) in dimensions(),
+        runs in prop::collection::vec((any::<u8>(), 1usize..300), 1..64),
+    ) {
+        let pixels = from_runs(&runs, w * h);
+        let decoded = decode_grle(&encode_grle(&pixels, w, h)).unwrap();
+        prop_assert_eq!((decoded.width, decoded.height), (w, h));
+        prop_assert_eq!(decoded.declared_len, decoded.available_len);
+        prop_assert_eq!(decoded.pixels, pixels);
+    }
+}
@@ -0,0 +1,153 @@
+//! Layer parameter discovery from map i3d files.
+
+use grleconvert::{decode_gdm, encode_gdm, read_xml, DecodedImage, GdmParams, I3d, LayerKind};
+
+const MAP_I3D: &str = r#"<?xml version="1.0" encoding="iso-8859-1"?>
+<i3D name="map">
+  <Files>
+    <File fileId="10" filename="data/densityMap_fruits.png"/>
+    <File fileId="11" filename="data/infoLayer_farmlands.png"/>
+    <File fileId="12" filename="data/densityMap_ground.png"/>
+    <File fileId="100" filename="$data/foliage/wheat/wheat.xml"/>
+  </Files>
+  <Scene>
+    <TerrainTransformGroup name="terrain">
+      <Layers>
+        <InfoLayer name="farmlands" fileId="11" numChannels="8"/>
+        <DetailLayer name="terrainDetail" densityMapId="12" numDensityMapChannels="10" compressionChannels="8"/>
+        <FoliageMultiLayer densityMapId="10" numChannels="7" numTypeIndexChannels="3" compressionChannels="3">
+          <FoliageType name="wheat" foliageXmlId="100"/>
+        </FoliageMultiLayer>
+      </Layers>
+    </TerrainTransformGroup>
+  </Scene>
+</i3D>
+"#;
+
+#[test]
+fn files_by_id() {
+    let i3d = I3d::parse_str(MAP_I3D);
+    assert_eq!(i3d.file("11"), Some("data/infoLayer_farmlands.png"));
+    assert_eq!(i3d.file("100"), Some("$data/foliage/wheat/wheat.xml"));
+    assert_eq!(i3d.file("99"), None);
+}
+
+#[test]
+fn layers_by_png_name() {
+    let i3d = I3d::parse_str(MAP_I3D);
+
+    let info = i3d.layer_for_png("infoLayer_farmlands.png").unwrap();
+    assert_eq!(info.kind, LayerKind::InfoLayer);
+    assert_eq!(info.num_channels, 8);
+
+    let detail = i3d.layer_for_png("densityMap_ground.png").unwrap();
+    assert_eq!(detail.kind, LayerKind::DetailLayer);
+    assert_eq!(detail.num_channels, 10);
+    assert_eq!(detail.compression_channels, vec![8]);
+
+    let foliage = i3d.layer_for_png("densityMap_fruits.png").unwrap();
+    assert_eq!(foliage.kind, LayerKind::FoliageMultiLayer);
+    assert_eq!(foliage.num_channels, 7);
+    assert_eq!(foliage.type_index_channels, Some(3));
+
+    assert!(i3d.layer_for_png("densityMap_weed.png").is_none());
+    // Basename must match exactly, not as a substring
+    assert!(i3d.layer_for_png("Map_ground.png").is_none());
+}
+
+#[test]
+fn single_quoted_attributes() {
+    let i3d = I3d::parse_str(r#"<i3D name='map'>
+  <Files>
+    <File fileId='42' filename='data/densityMap_ground.png'/>
+  </Files>
+  <Layers>
+    <DetailLayer name='terrainDetail' densityMapId='42'
+                 numDensityMapChannels='10' compressionChannels='8'/>
+  </Layers>
+</i3D>
+"#);
+    assert_eq!(i3d.file("42"), Some("data/densityMap_ground.png"));
+    let layer = i3d.layer_for_png("densityMap_ground.png").unwrap();
+    assert_eq!(layer.num_channels, 10);
+    assert_eq!(layer.compression_channels, vec![8]);
+}
+
+#[test]
+fn quotes_in_comments_do_not_swallow_later_tags() {
+    let i3d = I3d::parse_str(r#"<?xml version='1.0' encoding="iso-8859-1"?>
+<i3D name="map">
+  <Files>
+    <File fileId="11" filename="data/infoLayer_farmlands.png"/>
+    <!-- don't touch -->
+    <File fileId="12" filename="data/densityMap_ground.png"/>
+  </Files>
+  <Layers>
+    <InfoLayer name="farmlands" fileId="11" numChannels="8"/>
+    <!-- the editor's "ground" layer, a > b
+         wrapped over two lines -->
+    <DetailLayer name="terrainDetail" densityMapId="12"
+                 numDensityMapChannels="10" compressionChannels="8"/>
+  </Layers>
+</i3D>
+"#);
+    assert_eq!(i3d.layers.len(), 2);
+    let layer = i3d.layer_for_png("densityMap_ground.png").unwrap();
+    assert_eq!(layer.kind, LayerKind::DetailLayer);
+    assert_eq!(layer.num_channels, 10);
+    assert_eq!(layer.compression_channels, vec![8]);
+}
+
+#[test]
+fn gdm_params_from_layer() {
+    let i3d = I3d::parse_str(MAP_I3D);
+    let fruits = GdmParams::from(i3d.layer_for_png("densityMap_fruits.png").unwrap());
+    let built = GdmParams::new(7).compression_channels(vec![3]).type_index_channels(3);
+    assert_eq!(
+        (fruits.num_channels, fruits.compression_channels, fruits.type_index_channels),
+        (built.num_channels, built.compression_channels, built.type_index_channels)
+    );
+}
+
+#[test]
+fn several_compression_boundaries() {
+    let i3d = I3d::parse_str(r#"<i3D name="map">
+  <Files>
+    <File fileId="1" filename="data/densityMap_a.png"/>
+    <File fileId="2" filename="data/densityMap_b.png"/>
+    <File fileId="3" filename="data/densityMap_c.png"/>
+  </Files>
+  <Layers>
+    <DetailLayer name="a" densityMapId="1" numDensityMapChannels="16" compressionChannels="4 8 12"/>
+    <DetailLayer name="b" densityMapId="2" numDensityMapChannels="16" compressionChannels="0,4,8,16"/>
+    <DetailLayer name="c" densityMapId="3" numDensityMapChannels="16" compressionChannels="4 eight"/>
+  </Layers>
+</i3D>
+"#);
+    let splits = |png: &str| i3d.layer_for_png(png).unwrap().compression_channels.clone();
+    assert_eq!(splits("densityMap_a.png"), vec![4, 8, 12]);
+    assert_eq!(splits("densityMap_b.png"), vec![4, 8]);
+    assert!(splits("densityMap_c.png").is_empty());
+
+    let layer = i3d.layer_for_png("densityMap_a.png").unwrap();
+    let image = DecodedImage { width: 32, height: 32, values: (0..32 * 32).map(|i| (i * 61) & 0xFFFF).collect() };
+    let decoded = decode_gdm(&encode_gdm(&image, &GdmParams::from(layer)).unwrap()).unwrap();
+    assert_eq!(decoded.num_compression_ranges, 4);
+    assert_eq!(decoded.image.values, image.values);
+}
+
+#[test]
+fn windows_line_endings_and_bom() {
+    // BOM, lone CRs (one between tags, one inside a wrapped tag), a doubled CR and CRLF
+    let text = "\u{feff}<File fileId=\"12\" filename=\"data/densityMap_ground.png\"/>\r\
+                <DetailLayer name=\"terrainDetail\"\r\r\n densityMapId=\"12\"\r numDensityMapChannels=\"10\"/>\r\n";
+    let i3d = I3d::parse_str(text);
+    assert_eq!(i3d.file("12"), Some("data/densityMap_ground.png"));
+    let layer = i3d.layer_for_png("densityMap_ground.png").unwrap();
+    assert_eq!((layer.name.as_str(), layer.num_channels), ("terrainDetail", 10));
+
+    let path = std::env::temp_dir().join(format!("grleconvert_bom_{}.xml", std::process::id()));
+    std::fs::write(&path, "\u{feff}<map>\r\n  <fruitTypes/>\r</map>\r\n").unwrap();
+    assert_eq!(read_xml(&path).unwrap(), "<map>\n  <fruitTypes/>\n</map>\n");
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,310 @@
+//! End-to-end runs of the pixel_guide binary against small i3d/XML fixtures.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use grleconvert::encode_grle;
+
+mod common;
+
+use common::scratch_dir;
+
+/// Run pixel_guide and return the generated markdown
+fn run_guide(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_pixel_guide"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+const HEIGHT_TYPES_XML: &str = r#"<map>
+    <densityMapHeightTypes>
+        <densityMapHeightType fillTypeName="wheat" maxSurfaceAngle="26"/>
+        <densityMapHeightType fillTypeName="stone" maxSurfaceAngle="40"/>
+    </densityMapHeightTypes>
+</map>
+"#;
+
+const HEIGHT_I3D: &str = r#"<i3D name="map">
+  <Files>
+    <File fileId="20" filename="data/densityMap_height.png"/>
+  </Files>
+  <Layers>
+    <DetailLayer name="terrainDetailHeight"
+                 densityMapId="20"
+                 numDensityMapChannels="12">
+      <HeightParameters heightFirstChannel="6" heightNumChannels="6"
+                        maxHeight="4" combinedValuesChannels="0 6 0"/>
+    </DetailLayer>
+  </Layers>
+</i3D>
+"#;
+
+#[test]
+fn multi_line_height_layer() {
+    let dir = scratch_dir("height");
+    fs::create_dir_all(dir.join("data/maps")).unwrap();
+    fs::write(dir.join("data/maps/maps_densityMapHeightTypes.xml"), HEIGHT_TYPES_XML).unwrap();
+
+    let i3d = dir.join("map.i3d");
+    fs::write(&i3d, HEIGHT_I3D).unwrap();
+
+    let guide = run_guide(&[i3d.to_str().unwrap(), "--data-dir", dir.join("data").to_str().unwrap()]);
+
+    assert!(guide.contains("**File:** `densityMap_height.gdm`"));
+    assert!(guide.contains("- **Height**: Bits 6-11 (G channel, values 0-63, representing 0-4.0m)"));
+    assert!(guide.contains("### Fill Types (R channel)"));
+    assert!(guide.contains("| `1` | `#01` | Wheat |"));
+    assert!(guide.contains("| `2` | `#02` | Stone |"));
+    assert!(guide.contains("### Height Values (G channel)"));
+    assert!(guide.contains("| `63` | `#3F` | 4.0m (max) |"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn html_format_has_swatches() {
+    let dir = scratch_dir("html");
+    fs::create_dir_all(dir.join("data/maps")).unwrap();
+    fs::write(dir.join("data/maps/maps_densityMapHeightTypes.xml"), HEIGHT_TYPES_XML).unwrap();
+    let i3d = dir.join("map.i3d");
+    fs::write(&i3d, HEIGHT_I3D).unwrap();
+
+    let page = run_guide(&[
+        i3d.to_str().unwrap(),
+        "--data-dir",
+        dir.join("data").to_str().unwrap(),
+        "--format",
+        "html",
+    ]);
+
+    assert!(page.starts_with("<!DOCTYPE html>"));
+    assert!(!page.contains("<link") && !page.contains("<script"));
+    // R-channel fill types and G-channel heights get their own channel colors
+    assert!(page.contains(r#"<td class="swatch" style="background:#020000;color:#fff"><code>#02</code></td><td>Stone</td>"#));
+    assert!(page.contains(r#"<td class="swatch" style="background:#003F00;color:#fff"><code>#3F</code></td><td>4.0m (max)</td>"#));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn hex_and_missing_option_values() {
+    let dir = scratch_dir("options");
+    let i3d = dir.join("map.i3d");
+    fs::write(&i3d, r#"<i3D name="map">
+  <Files>
+    <File fileId="5" filename="data/infoLayer_test.png"/>
+  </Files>
+  <Layers>
+    <InfoLayer name="test" fileId="5" numChannels="4">
+      <Group name="kind" firstChannel="0" numChannels="4">
+        <Option value="1" name="alpha"/>
+        <Option value="0x04" name="beta"/>
+        <Option value="0xA" name="gamma"/>
+        <Option name="computed"/>
+      </Group>
+    </InfoLayer>
+  </Layers>
+</i3D>
+"#).unwrap();
+
+    let guide = run_guide(&[i3d.to_str().unwrap()]);
+
+    assert!(guide.contains("| `1` | `#01` | alpha |"));
+    assert!(guide.contains("| `4` | `#04` | beta |"));
+    assert!(guide.contains("| `10` | `#0A` | gamma |"));
+    assert!(!guide.contains("computed"));
+    assert!(!guide.contains("| `0` |"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Mod with farmlands 1, 255 and 512 and a farmland InfoLayer of the given width
+fn farmland_mod(name: &str, num_channels: u32) -> PathBuf {
+    let dir = scratch_dir(name);
+    fs::create_dir_all(dir.join("maps")).unwrap();
+    fs::write(dir.join("modDesc.xml"), r#"<modDesc>
+    <maps>
+        <map id="test" configFilename="maps/map.xml"/>
+    </maps>
+</modDesc>
+"#).unwrap();
+    fs::write(dir.join("maps/map.xml"), r#"<map>
+    <farmlands filename="maps/farmlands.xml"/>
+</map>
+"#).unwrap();
+    fs::write(dir.join("maps/farmlands.xml"), r#"<farmlands>
+    <farmland id="1" defaultFarmProperty="true"/>
+    <farmland id="255"/>
+    <farmland id="512"/>
+</farmlands>
+"#).unwrap();
+    fs::write(dir.join("maps/map.i3d"), format!(r#"<i3D name="map">
+  <Files>
+    <File fileId="3" filename="data/infoLayer_farmlands.png"/>
+  </Files>
+  <Layers>
+    <InfoLayer name="farmlands" fileId="3" numChannels="{}"/>
+  </Layers>
+</i3D>
+"#, num_channels)).unwrap();
+    dir
+}
+
+#[test]
+fn multi_byte_farmland_ids() {
+    let dir = farmland_mod("farmlands_rgb", 10);
+    let guide = run_guide(&[dir.join("maps/map.i3d").to_str().unwrap()]);
+
+    assert!(guide.contains("**Color Mode:** RGB (10 channels)"));
+    assert!(guide.contains("| `1, 0, 0` | `#010000` | Farmland 1 (starting) |"));
+    assert!(guide.contains("| `255, 0, 0` | `#FF0000` | Farmland 255 |"));
+    assert!(guide.contains("| `0, 2, 0` | `#000200` | Farmland 512 |"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn farmland_ids_wider_than_layer_are_flagged() {
+    let dir = farmland_mod("farmlands_gray", 8);
+    let guide = run_guide(&[dir.join("maps/map.i3d").to_str().unwrap()]);
+
+    assert!(guide.contains("| `255` | `#FF` | Farmland 255 |"));
+    assert!(guide.contains("| `0` | `#00` | Farmland 512 (does not fit in 8 channels) |"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn base_map_config_with_underscore() {
+    // Laid out like the base game data folder: $data/maps/map_US/map_US.xml
+    let data = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/base_map");
+    let i3d = data.join("maps/map_US/map_US.i3d");
+    let guide = run_guide(&[i3d.to_str().unwrap(), "--data-dir", data.to_str().unwrap()]);
+
+    assert!(guide.contains("| `1` | `#01` | Farmland 1 (starting) |"));
+    assert!(guide.contains("| `2` | `#02` | Farmland 2 |"));
+}
+
+#[test]
+fn sample_marks_used_options() {
+    let dir = farmland_mod("farmlands_sample", 8);
+    let pixels: Vec<u8> = (0..256 * 256).map(|i| if i < 256 * 192 { 1 } else { 7 }).collect();
+    let sample = dir.join("maps/infoLayer_farmlands.grle");
+    fs::write(&sample, encode_grle(&pixels, 256, 256)).unwrap();
+    let i3d = dir.join("maps/map.i3d");
+
+    let guide = run_guide(&[i3d.to_str().unwrap(), "--sample", sample.to_str().unwrap()]);
+    assert!(guide.contains("### Values in `infoLayer_farmlands.grle`"));
+    assert!(guide.contains("| Values | `1` | 49152 | 75.00% | Farmland 1 (starting) |"), "{}", guide);
+    assert!(guide.contains("| Values | `7` | 16384 | 25.00% | (no option) |"));
+    assert!(guide.contains("| Values | `255` | unused |  | Farmland 255 |"));
+    assert!(guide.contains("| `#FF` | Farmland 255"));
+
+    let used = run_guide(&[i3d.to_str().unwrap(), "--sample", sample.to_str().unwrap(), "--used-only"]);
+    assert!(used.contains("| `#01` | Farmland 1 (starting)"));
+    assert!(!used.contains("Farmland 255"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn repeated_layer_names_get_unique_anchors() {
+    let dir = scratch_dir("anchors");
+    let i3d = dir.join("map.i3d");
+    fs::write(&i3d, r#"<i3D name="map">
+  <Files>
+    <File fileId="5" filename="data/infoLayer_a.png"/>
+    <File fileId="6" filename="data/infoLayer_b.png"/>
+    <File fileId="7" filename="data/infoLayer_c.png"/>
+  </Files>
+  <Layers>
+    <InfoLayer name="mask" fileId="5" numChannels="1"/>
+    <InfoLayer name="mask" fileId="6" numChannels="1"/>
+    <InfoLayer name="mask" fileId="7" numChannels="1"/>
+  </Layers>
+</i3D>
+"#).unwrap();
+
+    let guide = run_guide(&[i3d.to_str().unwrap()]);
+    assert!(guide.contains("- [Mask (InfoLayer)](#mask-infolayer)\n- [Mask (InfoLayer)](#mask-infolayer-1)\n- [Mask (InfoLayer)](#mask-infolayer-2)\n"));
+
+    let page = run_guide(&[i3d.to_str().unwrap(), "--format", "html"]);
+    for anchor in ["mask-infolayer", "mask-infolayer-1", "mask-infolayer-2"] {
+        assert_eq!(page.matches(&format!("<h2 id=\"{}\">", anchor)).count(), 1, "{}", anchor);
+        assert!(page.contains(&format!("href=\"#{}\"", anchor)));
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn single_group_is_shifted_to_its_first_channel() {
+    let dir = scratch_dir("first_channel");
+    let i3d = dir.join("map.i3d");
+    fs::write(&i3d, r#"<i3D name="map">
+  <Files>
+    <File fileId="5" filename="data/infoLayer_test.png"/>
+  </Files>
+  <Layers>
+    <InfoLayer name="test" fileId="5" numChannels="8">
+      <Group name="level" firstChannel="4" numChannels="4">
+        <Option value="3" name="high"/>
+        <Option value="15" name="max"/>
+      </Group>
+    </InfoLayer>
+  </Layers>
+</i3D>
+"#).unwrap();
+
+    let guide = run_guide(&[i3d.to_str().unwrap()]);
+    assert!(guide.contains("| `48` | `#30` | high |"), "{}", guide);
+    assert!(guide.contains("| `240` | `#F0` | max |"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn headings_split_acronyms_and_digits() {
+    let dir = scratch_dir("titlecase");
+    let i3d = dir.join("map.i3d");
+    let names = ["navigationCollision2", "GRLEInfo", "type2State", "RGBValue", "indoorMask", "i3dLayer"];
+    let files: String = (0..names.len()).map(|i| format!("    <File fileId=\"{}\" filename=\"data/infoLayer_{}.png\"/>\n", i + 1, i)).collect();
+    let layers: String = names.iter().enumerate().map(|(i, name)| format!("    <InfoLayer name=\"{}\" fileId=\"{}\" numChannels=\"1\"/>\n", name, i + 1)).collect();
+    fs::write(&i3d, format!("<i3D name=\"map\">\n  <Files>\n{}  </Files>\n  <Layers>\n{}  </Layers>\n</i3D>\n", files, layers)).unwrap();
+
+    let guide = run_guide(&[i3d.to_str().unwrap()]);
+    for heading in ["Navigation Collision 2", "GRLE Info", "Type 2 State", "RGB Value", "Indoor Mask", "I 3d Layer"] {
+        assert!(guide.contains(&format!("## {} (InfoLayer)", heading)), "{}", heading);
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn file_extension_follows_the_layer_type() {
+    let dir = scratch_dir("layer_files");
+    let i3d = dir.join("map.i3d");
+    fs::write(&i3d, r#"<i3D name="map">
+  <Files>
+    <File fileId="5" filename="data/fields.png"/>
+    <File fileId="6" filename="data/groundDetail.png"/>
+  </Files>
+  <Layers>
+    <InfoLayer name="fields" fileId="5" numChannels="4"/>
+    <DetailLayer name="terrainDetail" densityMapId="6" numDensityMapChannels="8"/>
+  </Layers>
+</i3D>
+"#).unwrap();
+
+    let guide = run_guide(&[i3d.to_str().unwrap()]);
+
+    assert!(guide.contains("**File:** `fields.grle`"), "{}", guide);
+    assert!(guide.contains("**File:** `groundDetail.gdm`"), "{}", guide);
+    assert!(!guide.contains(".png`"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
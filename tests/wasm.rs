@@ -0,0 +1,46 @@
+//! Browser bindings: RGBA conversion and the wasm-bindgen exports.
+//!
+//! Run with `cargo test --features wasm`.
+
+#![cfg(feature = "wasm")]
+
+use grleconvert::wasm::{self, gdm_to_rgba, grle_to_rgba};
+use grleconvert::{encode_gdm, encode_grle, DecodedImage, GdmParams};
+
+#[test]
+fn grle_pixels_become_grey() {
+    let pixels: Vec<u8> = (0..256 * 256).map(|i| (i % 7) as u8).collect();
+    let image = grle_to_rgba(&encode_grle(&pixels, 256, 256)).unwrap();
+    assert_eq!((image.width, image.height), (256, 256));
+    assert_eq!(&image.pixels[4..8], &[1, 1, 1, 255]);
+}
+
+#[test]
+fn wide_gdm_values_spread_over_rgb() {
+    let values: Vec<u32> = (0..64 * 64).map(|i| i as u32 & 0xFFF).collect();
+    let image = DecodedImage { width: 64, height: 64, values };
+    let params = GdmParams { num_channels: 12, ..Default::default() };
+    let rgba = gdm_to_rgba(&encode_gdm(&image, &params).unwrap()).unwrap();
+    assert_eq!(&rgba.pixels[0x123 * 4..0x124 * 4], &[0x23, 0x01, 0, 255]);
+}
+
+#[test]
+fn exports_return_the_rgba_image() {
+    // Only the success path runs natively: building a JsError needs a JS host
+    let file = encode_grle(&vec![9u8; 256 * 256], 256, 256);
+    let image = wasm::decode_grle(&file).unwrap_or_else(|_| panic!("GRLE did not decode"));
+    assert_eq!((image.width, image.height), (256, 256));
+    assert_eq!(&image.pixels[..4], &[9, 9, 9, 255]);
+
+    let params = GdmParams { num_channels: 4, ..Default::default() };
+    let file = encode_gdm(&DecodedImage { width: 32, height: 32, values: vec![5; 32 * 32] }, &params).unwrap();
+    let image = wasm::decode_gdm(&file).unwrap_or_else(|_| panic!("GDM did not decode"));
+    assert_eq!(image.pixels.len(), 32 * 32 * 4);
+    assert_eq!(&image.pixels[..4], &[5, 5, 5, 255]);
+}
+
+#[test]
+fn decode_errors_carry_the_message() {
+    let err = gdm_to_rgba(b"not a density map").err().unwrap();
+    assert!(!err.to_string().is_empty());
+}
@@ -0,0 +1,142 @@
+//! Writing density maps into zip archives and reading them back.
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use grleconvert::zip::{entry_names, read_entry, write_entry};
+
+/// 2024-02-29 12:34:56 UTC
+fn when() -> std::time::SystemTime {
+    UNIX_EPOCH + Duration::from_secs(1_709_210_096)
+}
+
+#[test]
+fn entries_are_added_and_replaced() {
+    let archive = write_entry(&[], "modDesc.xml", b"<modDesc/>", when()).unwrap();
+    let archive = write_entry(&archive, "maps/densityMap_ground.gdm", &[7; 5000], when()).unwrap();
+    assert_eq!(entry_names(&archive).unwrap(), ["modDesc.xml", "maps/densityMap_ground.gdm"]);
+    // New entries are deflated
+    assert!(archive.len() < 1000);
+
+    let updated = write_entry(&archive, "maps/densityMap_ground.gdm", b"new", when()).unwrap();
+    assert_eq!(entry_names(&updated).unwrap(), ["modDesc.xml", "maps/densityMap_ground.gdm"]);
+    assert_eq!(read_entry(&updated, "maps/densityMap_ground.gdm").unwrap().unwrap(), b"new");
+    assert_eq!(read_entry(&updated, "modDesc.xml").unwrap().unwrap(), b"<modDesc/>");
+    assert_eq!(read_entry(&updated, "missing.gdm").unwrap(), None);
+
+    // The untouched entry is copied byte for byte: local header, data, timestamp
+    let untouched = &archive[..archive.windows(4).skip(1).position(|w| w == b"PK\x03\x04").unwrap() + 1];
+    assert_eq!(&updated[..untouched.len()], untouched);
+    // DOS time 12:34:56 and date 2024-02-29
+    assert_eq!(&untouched[10..14], &[(12 << 11 | 34 << 5 | 28) as u16, (44 << 9 | 2 << 5 | 29) as u16]
+        .iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>()[..]);
+}
+
+#[test]
+fn stored_entries_stay_stored() {
+    // A one-entry stored archive with a comment, as some packers write it
+    let mut archive = Vec::new();
+    let (name, data) = (b"a.grle", b"GRLE");
+    let crc = 0x12c5_f050u32.to_le_bytes(); // crc32 of "GRLE"
+    archive.extend_from_slice(b"PK\x03\x04\x0a\x00\x00\x00\x00\x00\x00\x00\x21\x00");
+    archive.extend_from_slice(&crc);
+    archive.extend_from_slice(&[4, 0, 0, 0, 4, 0, 0, 0, 6, 0, 0, 0]);
+    archive.extend_from_slice(name);
+    archive.extend_from_slice(data);
+    let central = archive.len();
+    archive.extend_from_slice(b"PK\x01\x02\x14\x00\x0a\x00\x00\x00\x00\x00\x00\x00\x21\x00");
+    archive.extend_from_slice(&crc);
+    archive.extend_from_slice(&[4, 0, 0, 0, 4, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    archive.extend_from_slice(name);
+    let end = archive.len();
+    archive.extend_from_slice(b"PK\x05\x06\x00\x00\x00\x00\x01\x00\x01\x00");
+    archive.extend_from_slice(&((end - central) as u32).to_le_bytes());
+    archive.extend_from_slice(&(central as u32).to_le_bytes());
+    archive.extend_from_slice(b"\x03\x00map");
+    assert_eq!(read_entry(&archive, "a.grle").unwrap().unwrap(), b"GRLE");
+
+    let updated = write_entry(&archive, "a.grle", &[1; 100], when()).unwrap();
+    assert_eq!(read_entry(&updated, "a.grle").unwrap().unwrap(), vec![1; 100]);
+    // Method 0 in the local header, the data as is, and the comment kept
+    assert_eq!(&updated[8..10], &[0, 0]);
+    assert!(updated.windows(100).any(|w| w == [1; 100]));
+    assert!(updated.ends_with(b"\x03\x00map"));
+}
+
+#[test]
+fn non_zip_input_is_rejected() {
+    assert!(entry_names(b"definitely not a zip archive").is_err());
+    assert!(write_entry(b"PK\x05\x06", "a.gdm", b"", when()).is_err());
+}
+
+// ============================================================================
+// Archives from other packers (tests/fixtures/zip/make_fixtures.sh)
+// ============================================================================
+
+const MOD_DESC: &[u8] = b"<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<modDesc descVersion=\"79\"/>\n";
+
+fn density_map() -> Vec<u8> {
+    (0..3000u32).map(|i| (i * 7 / 300 % 256) as u8).collect()
+}
+
+fn fixture(name: &str) -> Vec<u8> {
+    std::fs::read(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/zip").join(name)).unwrap()
+}
+
+/// Local header flags and extra field length of each entry, in file order
+fn local_headers(archive: &[u8]) -> Vec<(u16, u16)> {
+    let u16_at = |pos: usize| u16::from_le_bytes([archive[pos], archive[pos + 1]]);
+    (0..archive.len() - 4)
+        .filter(|&pos| archive[pos..pos + 4] == *b"PK\x03\x04")
+        .map(|pos| (u16_at(pos + 6), u16_at(pos + 28)))
+        .collect()
+}
+
+/// Central directory offset and archive comment, from the end record
+fn end_record(archive: &[u8]) -> (usize, &[u8]) {
+    let end = archive.windows(4).rposition(|w| w == b"PK\x05\x06").unwrap();
+    let offset = u32::from_le_bytes(archive[end + 16..end + 20].try_into().unwrap());
+    (offset as usize, &archive[end + 22..])
+}
+
+fn check_foreign_archive(archive: &[u8], comment: &[u8]) {
+    assert_eq!(end_record(archive).1, comment);
+    let names = ["modDesc.xml", "maps/densityMap_ground.gdm"];
+    assert_eq!(entry_names(archive).unwrap(), names);
+    assert_eq!(read_entry(archive, names[0]).unwrap().unwrap(), MOD_DESC);
+    assert_eq!(read_entry(archive, names[1]).unwrap().unwrap(), density_map());
+
+    // Replacing either entry leaves the other one, its data descriptor and
+    // extra fields, and the archive comment as they were
+    for (i, name) in names.iter().enumerate() {
+        let updated = write_entry(archive, name, b"replaced", when()).unwrap();
+        let other = names[1 - i];
+        assert_eq!(entry_names(&updated).unwrap(), [other, name]);
+        assert_eq!(read_entry(&updated, name).unwrap().unwrap(), b"replaced");
+        assert_eq!(read_entry(&updated, other).unwrap().unwrap(), read_entry(archive, other).unwrap().unwrap());
+        assert_eq!(local_headers(&updated)[0], local_headers(archive)[1 - i]);
+        assert_eq!(end_record(&updated).1, comment);
+    }
+
+    let added = write_entry(archive, "maps/infoLayer_farmlands.grle", b"GRLE", when()).unwrap();
+    // Adding one copies both entries through unchanged
+    let entries_end = end_record(archive).0;
+    assert_eq!(&added[..entries_end], &archive[..entries_end]);
+    assert_eq!(read_entry(&added, names[1]).unwrap().unwrap(), density_map());
+    assert_eq!(read_entry(&added, "maps/infoLayer_farmlands.grle").unwrap().unwrap(), b"GRLE");
+    assert_eq!(end_record(&added).1, comment);
+}
+
+#[test]
+fn info_zip_streamed_archive() {
+    let archive = fixture("infozip_streamed.zip");
+    // Data descriptors (bit 3) and Info-ZIP's UT/ux extra fields
+    assert!(local_headers(&archive).iter().all(|&(flags, extra)| flags & 8 != 0 && extra > 0));
+    check_foreign_archive(&archive, b"");
+}
+
+#[test]
+fn python_zipfile_archive_with_extra_fields_and_comment() {
+    let archive = fixture("python_extra.zip");
+    assert_eq!(local_headers(&archive), [(8, 8), (8, 8)]);
+    check_foreign_archive(&archive, b"packed for the grleconvert zip tests");
+}